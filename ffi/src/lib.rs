@@ -0,0 +1,293 @@
+//! The few system calls downscaler makes directly, behind safe wrappers
+//!
+//! downscaler itself forbids unsafe code, so every call into libc lives here, where it can be
+//! reviewed in one place. Like downscaler, it only builds on Unix.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+pub use libc::c_int;
+
+// The terminal, for `--tui`
+
+/// How the terminal was set up before `raw_mode`, to put back with `restore`
+pub struct Saved(libc::termios);
+
+/// Have keys typed on stdin arrive one at a time without echo - Ctrl-C still interrupts as usual
+pub fn raw_mode() -> io::Result<Saved> {
+    // SAFETY: termios is plain data, filled in by tcgetattr before it is read
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    // SAFETY: raw is a valid termios, copied from the one the terminal gave us
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Saved(saved))
+}
+
+impl Saved {
+    pub fn restore(&self) {
+        // SAFETY: the termios tcgetattr gave us
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+/// Columns and rows of the terminal on stdout, if it is one
+pub fn terminal_size() -> Option<(u16, u16)> {
+    // SAFETY: winsize is plain data, filled in by the ioctl before it is read
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 && size.ws_row > 0 => Some((size.ws_col, size.ws_row)),
+        _ => None,
+    }
+}
+
+/// Read what has been typed on stdin into `buffer`, waiting up to `wait` for something - without
+/// the buffering `io::stdin` does, which would hide keys from the wait
+pub fn read_stdin(buffer: &mut [u8], wait: Duration) -> usize {
+    let mut poll = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let wait = wait.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: one valid pollfd
+    if unsafe { libc::poll(&mut poll, 1, wait) } <= 0 {
+        return 0;
+    }
+    // SAFETY: reads at most buffer.len() bytes into buffer
+    let read = unsafe {
+        libc::read(
+            libc::STDIN_FILENO,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    read.max(0) as usize
+}
+
+// Signals
+
+/// What a signal does to its flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnSignal {
+    /// Set it
+    Set,
+    /// Flip it
+    Toggle,
+    /// Set it, and put every `SetOnce` signal back to its default - so a second one acts as if
+    /// there were no handler
+    SetOnce,
+}
+
+/// Signal numbers go up to 64 on Linux, and fewer elsewhere
+const SIGNALS: usize = 65;
+
+/// The flag each signal acts on, and how - the handler only ever reads these
+static FLAGS: [AtomicPtr<AtomicBool>; SIGNALS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; SIGNALS];
+static ACTIONS: [AtomicU8; SIGNALS] = [const { AtomicU8::new(0) }; SIGNALS];
+
+impl OnSignal {
+    fn code(self) -> u8 {
+        match self {
+            OnSignal::Set => 1,
+            OnSignal::Toggle => 2,
+            OnSignal::SetOnce => 3,
+        }
+    }
+}
+
+fn slot(signal: c_int) -> Option<usize> {
+    usize::try_from(signal)
+        .ok()
+        .filter(|&s| s > 0 && s < SIGNALS)
+}
+
+/// The only signal handler - it touches nothing but atomics and signal(), which are
+/// async-signal-safe
+extern "C" fn handle(signal: c_int) {
+    let Some(slot) = slot(signal) else { return };
+    let flag = FLAGS[slot].load(Ordering::SeqCst);
+    if flag.is_null() {
+        return;
+    }
+    // SAFETY: only ever set from a &'static AtomicBool, in `flag_on_signal`
+    let flag = unsafe { &*flag };
+    match ACTIONS[slot].load(Ordering::SeqCst) {
+        2 => {
+            flag.fetch_xor(true, Ordering::SeqCst);
+        }
+        3 => {
+            flag.store(true, Ordering::SeqCst);
+            for (slot, action) in ACTIONS.iter().enumerate() {
+                if action.load(Ordering::SeqCst) == OnSignal::SetOnce.code() {
+                    // SAFETY: signal() is async-signal-safe, and SIG_DFL is always valid
+                    unsafe { libc::signal(slot as c_int, libc::SIG_DFL) };
+                }
+            }
+        }
+        _ => flag.store(true, Ordering::SeqCst),
+    }
+}
+
+/// Act on `flag` whenever `signal` arrives
+pub fn flag_on_signal(
+    signal: c_int,
+    flag: &'static AtomicBool,
+    action: OnSignal,
+) -> io::Result<()> {
+    let slot = slot(signal).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    ACTIONS[slot].store(action.code(), Ordering::SeqCst);
+    FLAGS[slot].store(ptr::from_ref(flag).cast_mut(), Ordering::SeqCst);
+    // SAFETY: handle is a plain function for the lifetime of the process, and only does
+    // async-signal-safe things
+    let previous =
+        unsafe { libc::signal(signal, handle as extern "C" fn(c_int) as libc::sighandler_t) };
+    match previous {
+        libc::SIG_ERR => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Send `signal` to the process `pid` - never to a process group, which pid 0 or a pid too big
+/// for `pid_t` would otherwise mean
+pub fn kill(pid: u32, signal: c_int) -> io::Result<()> {
+    let pid = libc::pid_t::try_from(pid)
+        .ok()
+        .filter(|&pid| pid > 0)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: kill takes no pointers
+    match unsafe { libc::kill(pid, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Processes
+
+/// Lower the priority of the calling thread - plain syscalls, so safe between fork and exec
+fn set_priority(nice: Option<i32>, idle_io: bool) {
+    if let Some(nice) = nice {
+        // SAFETY: setpriority takes no pointers - 0 is the calling thread on Linux
+        unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    }
+    #[cfg(target_os = "linux")]
+    if idle_io {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        // SAFETY: ioprio_set takes no pointers
+        unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = idle_io;
+}
+
+/// Have `cmd` start at a lower CPU priority, and on Linux at idle disk priority too
+///
+/// The priority is set in the child between fork and exec, so every thread it starts inherits it.
+/// Failing to lower it isn't worth abandoning the command for, so failures are ignored.
+pub fn lower_priority(cmd: &mut Command, nice: Option<i32>, idle_io: bool) {
+    // SAFETY: only async-signal-safe calls are allowed between fork and exec - these are plain
+    // syscalls, touching nothing but the values moved into the closure
+    unsafe {
+        cmd.pre_exec(move || {
+            set_priority(nice, idle_io);
+            Ok(())
+        });
+    }
+}
+
+/// The same for the calling thread, for good - an unprivileged process can't raise it again.
+/// Only Linux keeps priorities per thread; elsewhere this lowers the whole process.
+pub fn lower_thread_priority(nice: Option<i32>, idle_io: bool) {
+    set_priority(nice, idle_io);
+}
+
+// Filesystems and time
+
+/// Bytes free to unprivileged users on the filesystem holding `path`
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain data, filled in by statvfs() before it is read
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the field types vary between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// The hour and minute it is now in the local time zone, if that can be worked out
+pub fn local_time() -> Option<(u32, u32)> {
+    // SAFETY: time() accepts a null pointer, and tm is plain data filled in by localtime_r
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut local) }.is_null() {
+        return None;
+    }
+    Some((local.tm_hour as u32, local.tm_min as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kill_refuses_pids_that_mean_process_groups() {
+        for pid in [0, i32::MAX as u32 + 1, u32::MAX] {
+            let error = kill(pid, 0).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "for {}", pid);
+        }
+        assert!(kill(std::process::id(), 0).is_ok());
+    }
+
+    #[test]
+    fn signals_set_and_toggle_their_flags() {
+        static STATUS: AtomicBool = AtomicBool::new(false);
+        static PAUSED: AtomicBool = AtomicBool::new(false);
+        flag_on_signal(libc::SIGUSR1, &STATUS, OnSignal::Set).unwrap();
+        flag_on_signal(libc::SIGUSR2, &PAUSED, OnSignal::Toggle).unwrap();
+        for (signal, flag, expected) in [
+            (libc::SIGUSR1, &STATUS, true),
+            (libc::SIGUSR1, &STATUS, true),
+            (libc::SIGUSR2, &PAUSED, true),
+            (libc::SIGUSR2, &PAUSED, false),
+        ] {
+            kill(std::process::id(), signal).unwrap();
+            let waited = std::time::Instant::now();
+            while flag.load(Ordering::SeqCst) != expected
+                && waited.elapsed() < Duration::from_secs(5)
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(flag.load(Ordering::SeqCst), expected);
+        }
+        assert!(flag_on_signal(0, &STATUS, OnSignal::Set).is_err());
+    }
+}
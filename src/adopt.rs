@@ -0,0 +1,182 @@
+//! `downscaler adopt` - taking over a mirror made by hand, so it isn't encoded all over again
+//!
+//! Each source is matched to a mirror file with the same name - in the same relative directory
+//! if there is one, otherwise anywhere in the mirror as long as only one file has that name -
+//! then accepted if ffprobe finds a video stream, audio if the source has it, and the same
+//! duration. Accepted files are moved to the path downscaler would have written them to, where
+//! later runs see them as done, and can be given checksums as if they had just been encoded.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Args;
+use log::info;
+use log::warn;
+
+use crate::budget::find_videos;
+use crate::checksum;
+use crate::config::Config;
+use crate::integrity;
+use crate::probe;
+use crate::storage;
+use crate::streams::Container;
+use crate::workers::Slot;
+
+#[derive(Debug, Args)]
+pub struct AdoptOpts {
+    #[clap(value_parser, short, long)]
+    source: PathBuf,
+    /// Where downscaler will put its outputs
+    #[clap(value_parser, short, long)]
+    destination: PathBuf,
+    /// The existing mirror, if it isn't already in the destination
+    #[clap(value_parser, short, long)]
+    mirror: Option<PathBuf>,
+    /// The container future runs will use, as for the main `--container`
+    #[clap(value_enum, long)]
+    container: Option<Container>,
+    /// Config file with any `[env]` settings for ffprobe
+    #[clap(value_parser, short, long)]
+    config: Option<PathBuf>,
+    /// Write a `.sha256` sidecar for each adopted file, as `--checksums` does
+    #[clap(value_parser, long)]
+    checksums: bool,
+    /// Add adopted files to the destination's `downscaler.sha256`, as `--checksum-manifest` does
+    #[clap(value_parser, long)]
+    checksum_manifest: bool,
+    /// Only list what would be adopted
+    #[clap(value_parser, long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    adopted: usize,
+    done: usize,
+    unmatched: usize,
+    rejected: usize,
+}
+
+fn stem(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+/// The mirror file for `relative`, a source path relative to the source root
+fn find_match<'a>(
+    relative: &Path,
+    mirror: &Path,
+    by_stem: &'a BTreeMap<String, Vec<PathBuf>>,
+) -> Option<&'a PathBuf> {
+    let candidates = by_stem.get(&stem(relative))?;
+    let dir = mirror.join(relative.parent().unwrap_or(Path::new("")));
+    candidates
+        .iter()
+        .find(|c| c.parent() == Some(dir.as_path()))
+        .or(match candidates.as_slice() {
+            [only] => Some(only),
+            _ => None,
+        })
+}
+
+pub fn run(opts: &AdoptOpts) -> Result<()> {
+    let mirror = opts.mirror.as_ref().unwrap_or(&opts.destination);
+    for dir in [&opts.source, mirror] {
+        if !dir.is_dir() {
+            return Err(anyhow!("{:?} is not a directory", dir));
+        }
+    }
+    let config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut sources = Vec::new();
+    find_videos(&opts.source, &mut sources)?;
+    sources.sort();
+    let mut mirrored = Vec::new();
+    find_videos(mirror, &mut mirrored)?;
+    let mut by_stem: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for file in mirrored {
+        by_stem.entry(stem(&file)).or_default().push(file);
+    }
+    let manifest = match (opts.checksum_manifest, opts.dry_run) {
+        (true, false) => Some(checksum::Manifest::open(&opts.destination)?),
+        _ => None,
+    };
+
+    let mut counts = Counts::default();
+    for source in &sources {
+        let relative = source.strip_prefix(&opts.source).unwrap_or(source);
+        let mut dest = opts.destination.join(relative);
+        if let Some(container) = opts.container {
+            dest.set_extension(container.extension());
+        }
+        if dest.exists() {
+            counts.done += 1;
+            continue;
+        }
+        let candidate = match find_match(relative, mirror, &by_stem) {
+            Some(candidate) => candidate,
+            None => {
+                counts.unmatched += 1;
+                continue;
+            }
+        };
+        if candidate.extension() != dest.extension() {
+            warn!(
+                "{:?} is not the container downscaler would write for {:?} - not adopting it",
+                candidate, source
+            );
+            counts.rejected += 1;
+            continue;
+        }
+        let checked = probe::probe(source, &config, Slot::default()).and_then(|info| {
+            integrity::check_output(candidate, &info, info.duration(), &config, Slot::default())
+        });
+        if let Err(e) = checked {
+            warn!("{:?} doesn't match {:?}: {:#}", candidate, source, e);
+            counts.rejected += 1;
+            continue;
+        }
+        counts.adopted += 1;
+        if opts.dry_run {
+            info!("would adopt {:?} as {:?}", candidate, dest);
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        storage::move_file(candidate, &dest)
+            .with_context(|| format!("moving {:?} to {:?}", candidate, dest))?;
+        info!("adopted {:?} as {:?}", candidate, dest);
+        if opts.checksums || manifest.is_some() {
+            let hash = checksum::sha256_file(&dest)?;
+            if opts.checksums {
+                checksum::write_sidecar(&dest, &hash)?;
+            }
+            if let Some(manifest) = &manifest {
+                manifest.record(dest.strip_prefix(&opts.destination)?, &hash)?;
+            }
+        }
+    }
+    info!(
+        "{} {} files, {} were already in place, {} had no match and {} didn't match well enough",
+        if opts.dry_run {
+            "would adopt"
+        } else {
+            "adopted"
+        },
+        counts.adopted,
+        counts.done,
+        counts.unmatched,
+        counts.rejected
+    );
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Taking the one file to encode from Sonarr or Radarr, for `--arr`
+//!
+//! Set up as a custom script connection, they run it after each import with the file's details in
+//! environment variables. Their Test button runs it with an event type of `Test`, which only
+//! checks the script can be run, and other events have no file to encode.
+
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+/// Why Sonarr or Radarr ran us
+pub enum Event {
+    /// A file was imported, or upgraded
+    Imported(PathBuf),
+    /// Anything else, by its event type
+    Other(String),
+}
+
+/// The event in the environment, if either of them set it
+pub fn event() -> Result<Event> {
+    for (app, file) in [
+        ("sonarr", "sonarr_episodefile_path"),
+        ("radarr", "radarr_moviefile_path"),
+    ] {
+        let event = match env::var(format!("{}_eventtype", app)) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if event != "Download" {
+            return Ok(Event::Other(event));
+        }
+        return match env::var_os(file) {
+            Some(path) if !path.is_empty() => Ok(Event::Imported(PathBuf::from(path))),
+            _ => Err(anyhow!("{} sent a Download event without {}", app, file)),
+        };
+    }
+    Err(anyhow!(
+        "--arr is for running from Sonarr or Radarr, which set sonarr_eventtype or radarr_eventtype"
+    ))
+}
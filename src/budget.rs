@@ -0,0 +1,331 @@
+//! `downscaler budget` - projecting output sizes from the configured policies, without encoding
+//!
+//! Sizes are estimated from each file's duration, resolution and frame rate, using a rough
+//! bits-per-pixel figure for the encoder at the chosen CRF. They won't match real encodes to the
+//! byte, but directories are estimated the same way, so they compare fairly against each other -
+//! which is what matters when tuning `[override]` sections towards a storage goal.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use clap::Args;
+use log::info;
+use log::warn;
+
+use crate::config::Config;
+use crate::overrides;
+use crate::probe;
+use crate::probe::ProbeInfo;
+use crate::rate::Bitrate;
+use crate::report::human_size;
+use crate::settings::Settings;
+use crate::settings::DEFAULT_MAX_HEIGHT;
+use crate::size::Size;
+use crate::workers::Slot;
+
+/// Bits per output pixel for libx265 at CRF 28 - about 1.2 Mbit/s for 720p at 30 fps
+const BITS_PER_PIXEL: f64 = 0.045;
+
+/// For audio streams that don't say their bitrate
+const DEFAULT_AUDIO_BITRATE: f64 = 192_000.0;
+
+/// Directories using this many times the average bytes per hour are flagged
+const DISPROPORTIONATE: f64 = 2.0;
+
+/// How to project sizes - shared with `downscaler estimate`
+#[derive(Debug, Args)]
+pub struct ProjectionOpts {
+    #[clap(value_parser, short, long)]
+    pub source: PathBuf,
+    /// Config file with the `[override]` and `[screen-recording]` policies to project
+    #[clap(value_parser, short, long)]
+    config: Option<PathBuf>,
+    /// Project as if running with these `--flag`s, for overrides with `when = NAME`
+    #[clap(value_parser, long = "flag", value_name = "NAME")]
+    flags: Vec<String>,
+    /// ffmpeg video encoder the run will use
+    #[clap(value_parser, long, default_value = "libx265")]
+    pub encoder: String,
+    /// CRF for directories without an override
+    #[clap(value_parser, long, default_value_t = 28)]
+    crf: u32,
+    #[clap(value_parser, long)]
+    max_fps: Option<f64>,
+    #[clap(value_parser, long, default_value_t = DEFAULT_MAX_HEIGHT)]
+    max_height: u32,
+    #[clap(value_parser, long)]
+    detect_screen_recordings: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BudgetOpts {
+    #[clap(flatten)]
+    projection: ProjectionOpts,
+    /// Total size to aim for, e.g. `2T` - the report says how far over or under it the projection
+    /// is
+    #[clap(value_parser, long)]
+    goal: Option<Size>,
+}
+
+/// Relative size for the same quality - newer codecs need fewer bits
+fn encoder_factor(encoder: &str) -> f64 {
+    let software = if encoder.contains("av1") {
+        0.75
+    } else if encoder.contains("265") || encoder.contains("hevc") {
+        1.0
+    } else {
+        1.6
+    };
+    // hardware encoders trade size for speed
+    if encoder.ends_with("_nvenc") || encoder.ends_with("_qsv") {
+        software * 1.25
+    } else {
+        software
+    }
+}
+
+/// Projected bytes for one file - from `bitrate` if encoding to one, otherwise the CRF
+pub fn estimate(
+    info: &ProbeInfo,
+    settings: &Settings,
+    encoder: &str,
+    bitrate: Option<Bitrate>,
+) -> Option<u64> {
+    let video_bits = match bitrate {
+        Some(bitrate) => bitrate.bits_per_second() as f64,
+        None => video_bitrate(info, settings, encoder)?,
+    };
+    estimate_from(info, video_bits)
+}
+
+/// Projected bytes for one file with video at `video_bits` per second, and its audio copied
+pub fn estimate_from(info: &ProbeInfo, video_bits: f64) -> Option<u64> {
+    let duration = info.duration()?;
+    // audio is copied as it is
+    let audio_bits: f64 = info
+        .streams
+        .iter()
+        .filter(|s| s.is_audio())
+        .map(|s| {
+            s.get("bit_rate")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(DEFAULT_AUDIO_BITRATE)
+        })
+        .sum();
+    Some(((video_bits + audio_bits) * duration / 8.0) as u64)
+}
+
+/// Projected video bits per second at the CRF in `settings`
+pub fn video_bitrate(info: &ProbeInfo, settings: &Settings, encoder: &str) -> Option<f64> {
+    let video = info.video()?;
+    let (width, height) = (f64::from(video.width()?), f64::from(video.height()?));
+    let out_height = height.min(f64::from(settings.max_height));
+    let out_width = width * out_height / height;
+    let fps = video.frame_rate().unwrap_or(30.0);
+    let fps = settings.max_fps.map_or(fps, |max| fps.min(max));
+    // each 6 CRF roughly halves or doubles the size
+    let quality = 2f64.powf((28.0 - settings.crf as f64) / 6.0);
+    Some(out_width * out_height * fps * BITS_PER_PIXEL * quality * encoder_factor(encoder))
+}
+
+#[derive(Debug, Default)]
+pub struct DirBudget {
+    pub files: usize,
+    /// Files that couldn't be probed or estimated
+    pub unknown: usize,
+    pub seconds: f64,
+    pub source_size: u64,
+    pub projected: u64,
+    /// The source size of the files that were estimated, to compare with `projected`
+    pub estimated_source: u64,
+}
+
+impl DirBudget {
+    fn bytes_per_hour(&self) -> f64 {
+        if self.seconds > 0.0 {
+            self.projected as f64 * 3600.0 / self.seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+pub fn find_videos(dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_videos(&path, found)?;
+        } else if file_type.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "mp4" || ext == "mkv")
+        {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+impl ProjectionOpts {
+    pub fn load_config(&self) -> Result<Config> {
+        if !self.source.is_dir() {
+            return Err(anyhow!("Source path {:?} does not exist", self.source));
+        }
+        match &self.config {
+            Some(path) => Config::load(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// The settings a run would choose for `source`, at `relative` to the source root
+    pub fn settings(
+        &self,
+        config: &Config,
+        source: &Path,
+        relative: &Path,
+        info: &ProbeInfo,
+    ) -> Settings {
+        let mut settings = Settings {
+            crf: self.crf,
+            preset: String::new(),
+            tune: None,
+            max_fps: self.max_fps,
+            max_height: self.max_height,
+            deinterlace: false,
+            crop: None,
+        };
+        if self.detect_screen_recordings {
+            config.screen.apply(source, info, &mut settings);
+        }
+        for found in overrides::matching(&config.overrides, relative, &self.flags) {
+            found.apply(&mut settings);
+        }
+        settings
+    }
+}
+
+/// The source videos, in order
+pub fn sources(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut sources = Vec::new();
+    find_videos(root, &mut sources)?;
+    sources.sort();
+    Ok(sources)
+}
+
+/// Probe every source and project its output, by directory - with the estimated video size
+/// multiplied by `calibration`
+pub fn project(
+    opts: &ProjectionOpts,
+    config: &Config,
+    sources: &[PathBuf],
+    calibration: f64,
+) -> BTreeMap<PathBuf, DirBudget> {
+    info!("probing {} files", sources.len());
+    let mut dirs: BTreeMap<PathBuf, DirBudget> = BTreeMap::new();
+    for source in sources {
+        let relative = source.strip_prefix(&opts.source).unwrap_or(source);
+        let dir = relative.parent().unwrap_or(Path::new("")).to_owned();
+        let budget = dirs.entry(dir).or_default();
+        budget.files += 1;
+        let size = fs::metadata(source).map(|m| m.len()).unwrap_or(0);
+        budget.source_size += size;
+        let info = match probe::probe(source, config, Slot::default()) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("could not probe {:?}: {:#}", source, e);
+                budget.unknown += 1;
+                continue;
+            }
+        };
+        let settings = opts.settings(config, source, relative, &info);
+        let projected = video_bitrate(&info, &settings, &opts.encoder)
+            .and_then(|bits| estimate_from(&info, bits * calibration));
+        match projected {
+            Some(bytes) => {
+                budget.projected += bytes;
+                budget.estimated_source += size;
+                budget.seconds += info.duration().unwrap_or(0.0);
+            }
+            None => budget.unknown += 1,
+        }
+    }
+    dirs
+}
+
+/// How a directory is shown in reports - `.` for the source root itself
+pub fn dir_label(dir: &Path) -> String {
+    if dir.as_os_str().is_empty() {
+        ".".to_owned()
+    } else {
+        dir.to_string_lossy().into_owned()
+    }
+}
+
+pub fn run(opts: &BudgetOpts) -> Result<()> {
+    let config = opts.projection.load_config()?;
+    let sources = sources(&opts.projection.source)?;
+    let dirs = project(&opts.projection, &config, &sources, 1.0);
+
+    let total_projected: u64 = dirs.values().map(|d| d.projected).sum();
+    let total_source: u64 = dirs.values().map(|d| d.source_size).sum();
+    let total_seconds: f64 = dirs.values().map(|d| d.seconds).sum();
+    let average_per_hour = if total_seconds > 0.0 {
+        total_projected as f64 * 3600.0 / total_seconds
+    } else {
+        0.0
+    };
+
+    println!(
+        "{:<40} {:>6} {:>8} {:>11} {:>11} {:>11}",
+        "directory", "files", "hours", "source", "projected", "per hour"
+    );
+    let mut flagged = 0;
+    for (dir, budget) in &dirs {
+        let heavy =
+            average_per_hour > 0.0 && budget.bytes_per_hour() > average_per_hour * DISPROPORTIONATE;
+        if heavy {
+            flagged += 1;
+        }
+        println!(
+            "{:<40} {:>6} {:>8.1} {:>11} {:>11} {:>11}{}{}",
+            dir_label(dir),
+            budget.files,
+            budget.seconds / 3600.0,
+            human_size(budget.source_size as i64),
+            human_size(budget.projected as i64),
+            human_size(budget.bytes_per_hour() as i64),
+            if heavy { "  !" } else { "" },
+            match budget.unknown {
+                0 => String::new(),
+                n => format!("  ({} not estimated)", n),
+            }
+        );
+    }
+    println!(
+        "\ntotal: {} projected from {} of sources, {} per hour on average",
+        human_size(total_projected as i64),
+        human_size(total_source as i64),
+        human_size(average_per_hour as i64)
+    );
+    if flagged > 0 {
+        println!(
+            "{} directories marked ! use over {} times the average per hour - a higher crf in an [override] would bring them down",
+            flagged, DISPROPORTIONATE
+        );
+    }
+    if let Some(goal) = opts.goal {
+        let difference = total_projected as i64 - goal.0 as i64;
+        if difference > 0 {
+            println!("{} over the goal of {}", human_size(difference), goal);
+        } else {
+            println!("{} under the goal of {}", human_size(-difference), goal);
+        }
+    }
+    Ok(())
+}
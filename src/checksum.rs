@@ -0,0 +1,302 @@
+//! SHA-256 checksums of outputs, in `sha256sum` compatible sidecar files and/or one manifest
+//! for the whole destination
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+
+use crate::priority;
+use crate::state;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A streaming SHA-256 hasher, per FIPS 180-4
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buffer: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256::default()
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let wanted = (64 - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..wanted]);
+            data = &data[wanted..];
+            if self.buffer.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.buffer);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    /// The digest as lowercase hex
+    pub fn finish(mut self) -> String {
+        let bit_length = self.length.wrapping_mul(8);
+        let mut padding = vec![0x80u8];
+        let used = (self.length % 64) as usize;
+        let zeros = if used < 56 { 55 - used } else { 119 - used };
+        padding.extend(std::iter::repeat_n(0, zeros));
+        padding.extend_from_slice(&bit_length.to_be_bytes());
+        self.update(&padding);
+        self.state
+            .iter()
+            .map(|word| format!("{:08x}", word))
+            .collect()
+    }
+}
+
+/// Hashed on a thread of its own at the `--nice`/`--ionice` priority, as reading a whole output
+/// can take as long as some encodes
+pub fn sha256_file(path: &Path) -> Result<String> {
+    thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                priority::lower_this_thread();
+                hash_file(path)
+            })
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("hashing {:?} panicked", path)))
+    })
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("opening {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(hasher.finish())
+}
+
+/// `movie.mkv` has its checksum in `movie.mkv.sha256`
+pub fn sidecar_path(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_owned();
+    name.push(".sha256");
+    file.with_file_name(name)
+}
+
+/// Write a sidecar for `file`, in the same format as `sha256sum` so `sha256sum -c` can check it
+pub fn write_sidecar(file: &Path, hash: &str) -> Result<()> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let sidecar = sidecar_path(file);
+    state::write_atomic(&sidecar, format!("{}  {}\n", hash, name).as_bytes())
+}
+
+/// The manifest's name, in the destination root
+pub const MANIFEST: &str = "downscaler.sha256";
+
+/// Split a `sha256sum` line into the hash and file name
+fn parse_line(line: &str) -> Result<(&str, &str)> {
+    let (hash, name) = line
+        .trim_end()
+        .split_once("  ")
+        .ok_or_else(|| anyhow!("not a sha256sum line"))?;
+    // `*` marks binary mode, which is no different on any platform we care about
+    Ok((hash, name.trim_start_matches('*')))
+}
+
+fn check(file: &Path, expected: &str) -> Result<()> {
+    let actual = sha256_file(file)?;
+    if actual != expected.to_lowercase() {
+        return Err(anyhow!("checksum mismatch for {:?}", file));
+    }
+    Ok(())
+}
+
+/// Check one sidecar against the file it describes
+fn verify_sidecar(sidecar: &Path) -> Result<()> {
+    let text = fs::read_to_string(sidecar)?;
+    let (expected, name) = parse_line(&text)?;
+    check(&sidecar.with_file_name(name), expected)
+}
+
+/// Every output's checksum in one `sha256sum -c` compatible file at the destination root, with
+/// paths relative to it - rewritten as each file finishes
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, String>>,
+}
+
+impl Manifest {
+    /// The manifest for `root`, keeping whatever it already lists
+    pub fn open(root: &Path) -> Result<Manifest> {
+        let path = root.join(MANIFEST);
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            let text = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+            for (number, line) in text.lines().enumerate() {
+                let (hash, name) =
+                    parse_line(line).with_context(|| format!("{:?} line {}", path, number + 1))?;
+                entries.insert(name.to_owned(), hash.to_owned());
+            }
+        }
+        Ok(Manifest {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Add or replace the checksum for `relative`, a path from the destination root
+    pub fn record(&self, relative: &Path, hash: &str) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        // sha256sum wants forward slashes, and no separator is valid in a name anyway
+        let name = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        entries.insert(name, hash.to_owned());
+        let text: String = entries
+            .iter()
+            .map(|(name, hash)| format!("{}  {}\n", hash, name))
+            .collect();
+        // still holding the lock, so two workers can't write it at once
+        state::write_atomic(&self.path, text.as_bytes())
+    }
+}
+
+/// Check every file listed in the manifest at `root`
+fn verify_manifest(root: &Path, counts: &mut VerifyCounts) -> Result<()> {
+    let manifest = Manifest::open(root)?;
+    for (name, hash) in manifest.entries.lock().unwrap().iter() {
+        let file = root.join(name);
+        match check(&file, hash) {
+            Ok(()) => counts.ok += 1,
+            Err(e) => {
+                warn!("{:?}: {:#}", file, e);
+                counts.failed += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct VerifyCounts {
+    ok: usize,
+    failed: usize,
+}
+
+/// Check every `.sha256` sidecar under `root`
+fn verify_tree(root: &Path, counts: &mut VerifyCounts) -> Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            verify_tree(&path, counts)?;
+        } else if path.extension().is_some_and(|e| e == "sha256") && entry.file_name() != MANIFEST {
+            match verify_sidecar(&path) {
+                Ok(()) => counts.ok += 1,
+                Err(e) => {
+                    warn!("{:?}: {:#}", path, e);
+                    counts.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The `verify` subcommand - checks the manifest if there is one, and any sidecars
+pub fn verify(root: &Path) -> Result<()> {
+    let mut counts = VerifyCounts::default();
+    if root.join(MANIFEST).exists() {
+        verify_manifest(root, &mut counts)?;
+    }
+    verify_tree(root, &mut counts)?;
+    info!("{} files verified, {} failed", counts.ok, counts.failed);
+    if counts.failed > 0 {
+        return Err(anyhow!("{} files failed verification", counts.failed));
+    }
+    Ok(())
+}
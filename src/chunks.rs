@@ -0,0 +1,163 @@
+//! Encoding a big file as several pieces at once, for `--chunks N`
+//!
+//! One encode of one video stream only keeps so many cores busy. With `--chunks`, a file at least
+//! `--chunk-min-length` long has its video split into N pieces at keyframes, without re-encoding,
+//! and the pieces are encoded side by side with the file's usual settings. The audio and subtitles
+//! are done in one go meanwhile, and everything is joined back up at the end.
+//!
+//! The pieces start on keyframes, so nothing is lost or doubled at the joins - but the encoder
+//! starts afresh on each piece, which costs a few keyframes more than one encode would.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+
+use crate::watchdog;
+
+const QUIET: [&str; 4] = ["-loglevel", "warning", "-nostats", "-hide_banner"];
+
+/// How to encode one file in pieces
+pub struct Chunked<'a> {
+    pub pieces: u32,
+    /// Of the source, in seconds
+    pub duration: f64,
+    /// `ffmpeg` with the options every command gets, to run on the pieces
+    pub ffmpeg: &'a dyn Fn() -> Command,
+    /// `ffmpeg` with the source as its first input
+    pub source: &'a dyn Fn() -> Command,
+    /// The options to encode a piece's video with
+    pub video: Vec<String>,
+    /// The command to write everything but the video, less its output - `None` if there is nothing
+    /// but the video
+    pub rest: Option<Command>,
+    /// The metadata options for the finished file, if it is being stripped
+    pub metadata: Vec<&'static str>,
+}
+
+/// The directory the pieces are kept in, removed when dropped
+struct Pieces {
+    dir: PathBuf,
+}
+
+impl Pieces {
+    fn create(output: &Path) -> Result<Pieces> {
+        let dir = output.with_extension("chunks");
+        // anything left from an attempt that failed would be joined in too
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).with_context(|| format!("creating {:?}", dir))?;
+        Ok(Pieces { dir })
+    }
+
+    /// The pieces the source was split into, in order
+    fn split(&self) -> Result<Vec<PathBuf>> {
+        let mut pieces = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("piece") {
+                pieces.push(path);
+            }
+        }
+        pieces.sort();
+        Ok(pieces)
+    }
+}
+
+impl Drop for Pieces {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A line of an ffmpeg concat list
+fn concat_line(path: &Path) -> String {
+    format!("file '{}'\n", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Run `cmd` on another thread, watched like this one's commands
+fn spawn<'s>(
+    scope: &'s thread::Scope<'s, '_>,
+    cmd: Command,
+    what: String,
+) -> thread::ScopedJoinHandle<'s, Result<()>> {
+    let watch = watchdog::watch();
+    scope.spawn(move || {
+        watch.enter();
+        crate::run_command(cmd).context(what)
+    })
+}
+
+pub fn encode(chunked: Chunked<'_>, output: &Path) -> Result<()> {
+    let pieces = Pieces::create(output)?;
+    let times: Vec<String> = (1..chunked.pieces)
+        .map(|n| format!("{:.3}", chunked.duration * n as f64 / chunked.pieces as f64))
+        .collect();
+    let mut split = (chunked.source)();
+    split
+        .args(["-map", "0:V:0", "-c", "copy", "-f", "segment"])
+        .args(["-segment_times", &times.join(",")])
+        .args(["-reset_timestamps", "1"])
+        .args(QUIET)
+        .arg(pieces.dir.join("piece%04d.mkv"));
+    crate::run_command(split).context("splitting the video at keyframes")?;
+    let split = pieces.split()?;
+    debug!("encoding {} pieces in {:?}", split.len(), pieces.dir);
+
+    let encoded: Vec<PathBuf> = split.iter().map(|p| p.with_extension("out.mkv")).collect();
+    let extension = output.extension().unwrap_or_default().to_string_lossy();
+    let rest = pieces.dir.join(format!("rest.{}", extension));
+    let has_rest = chunked.rest.is_some();
+    thread::scope(|scope| {
+        let mut running = Vec::new();
+        for (n, (piece, encoded)) in split.iter().zip(&encoded).enumerate() {
+            let mut cmd = (chunked.ffmpeg)();
+            cmd.arg("-i")
+                .arg(piece)
+                .args(["-map", "0:V:0"])
+                .args(&chunked.video)
+                .args(QUIET)
+                .arg(encoded);
+            running.push(spawn(scope, cmd, format!("encoding piece {}", n + 1)));
+        }
+        if let Some(mut cmd) = chunked.rest {
+            cmd.args(["-vn"]).args(QUIET).arg(&rest);
+            running.push(spawn(scope, cmd, "the audio and subtitles".to_owned()));
+        }
+        // every piece is waited for, even once one has failed
+        let results: Vec<Result<()>> = running
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect();
+        results.into_iter().collect::<Result<()>>()
+    })?;
+
+    let list = pieces.dir.join("pieces.txt");
+    let text: String = encoded.iter().map(|p| concat_line(p)).collect();
+    fs::write(&list, text).with_context(|| format!("writing {:?}", list))?;
+    let mut join = (chunked.ffmpeg)();
+    join.args(["-f", "concat", "-safe", "0", "-i"]).arg(&list);
+    if has_rest {
+        join.arg("-i").arg(&rest).args(["-map", "0:V", "-map", "1"]);
+        join.args(["-map_chapters", "1"]);
+        if chunked.metadata.is_empty() {
+            join.args(["-map_metadata", "1"]);
+        }
+    } else {
+        join.args(["-map", "0:V"]);
+    }
+    join.args(["-c", "copy"])
+        .args(&chunked.metadata)
+        .args(QUIET)
+        .arg(output);
+    crate::run_command(join).context("joining the pieces back up")
+}
@@ -0,0 +1,222 @@
+//! Claim files, so several machines can share one destination without encoding the same file twice
+//!
+//! Before encoding, a job creates `.<output name>.downscaler.claim` next to its output - named
+//! from the output alone, so every machine picks the same file wherever the share is mounted.
+//! Creation is exclusive, so only one machine wins. A heartbeat thread keeps the claim's
+//! modification time fresh while the job runs; a claim that hasn't been touched for a whole lease,
+//! plus a margin for the machines' clocks disagreeing, is assumed to belong to a machine that
+//! died, and can be taken over.
+//!
+//! A takeover writes a claim of our own and renames it over the stale one, then reads it back -
+//! if two machines take over at once, only the one whose claim is still there goes ahead.
+
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+use log::info;
+use log::warn;
+
+/// How far apart the clocks of machines sharing a destination may be
+const CLOCK_SKEW: Duration = Duration::from_secs(120);
+
+/// An exclusive claim on one output - released when dropped
+pub struct Claim {
+    path: PathBuf,
+    owner: String,
+    stop: Option<mpsc::Sender<()>>,
+    heartbeat: Option<thread::JoinHandle<()>>,
+}
+
+fn claim_path(dest: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(".downscaler.claim");
+    dest.with_file_name(name)
+}
+
+/// What a claim made by this process holds
+fn owner() -> String {
+    format!("{} {}\n", hostname(), process::id())
+}
+
+fn holds(path: &Path, owner: &str) -> bool {
+    fs::read_to_string(path).is_ok_and(|text| text == owner)
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn is_stale(path: &Path, lease: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .is_some_and(|age| age > lease + CLOCK_SKEW)
+}
+
+fn create(path: &Path, owner: &str) -> std::io::Result<()> {
+    let mut file = File::options().write(true).create_new(true).open(path)?;
+    file.write_all(owner.as_bytes())
+}
+
+/// Replace the stale claim at `path` with ours, returning whether ours is the one left there
+fn take_over(path: &Path, owner: &str) -> Result<bool> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}.tmp", process::id()));
+    let ours = path.with_file_name(name);
+    let _ = fs::remove_file(&ours);
+    create(&ours, owner).with_context(|| format!("creating {:?}", ours))?;
+    if let Err(e) = fs::rename(&ours, path) {
+        let _ = fs::remove_file(&ours);
+        return Err(e).with_context(|| format!("replacing {:?}", path));
+    }
+    Ok(holds(path, owner))
+}
+
+/// Keep touching the claim until told to stop - or until another machine has taken it
+fn heartbeat(path: PathBuf, owner: String, interval: Duration, stop: mpsc::Receiver<()>) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        if !holds(&path, &owner) {
+            warn!("lost claim {:?} to another machine", path);
+            return;
+        }
+        let touched = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            warn!("could not refresh claim {:?}: {}", path, e);
+        }
+    }
+}
+
+impl Claim {
+    /// Claim `dest`, or return `None` if another machine is working on it
+    pub fn acquire(dest: &Path, lease: Duration) -> Result<Option<Claim>> {
+        let path = claim_path(dest);
+        let owner = owner();
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+        }
+        match create(&path, &owner) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if !is_stale(&path, lease) {
+                    return Ok(None);
+                }
+                let stale = fs::read_to_string(&path).unwrap_or_default();
+                info!(
+                    "taking over stale claim on {:?} from {}",
+                    dest,
+                    stale.trim()
+                );
+                if !take_over(&path, &owner)? {
+                    // someone else took it over at the same time, and won
+                    return Ok(None);
+                }
+            }
+            Err(e) => return Err(e).with_context(|| format!("creating {:?}", path)),
+        }
+        debug!("claimed {:?}", dest);
+        let (stop, stopped) = mpsc::channel();
+        let interval = lease / 3;
+        let (heartbeat_path, heartbeat_owner) = (path.clone(), owner.clone());
+        let heartbeat =
+            thread::spawn(move || heartbeat(heartbeat_path, heartbeat_owner, interval, stopped));
+        Ok(Some(Claim {
+            path,
+            owner,
+            stop: Some(stop),
+            heartbeat: Some(heartbeat),
+        }))
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+        // another machine's, if it took this one over
+        if !holds(&self.path, &self.owner) {
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("could not remove claim {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("downscaler-claims-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn claims_are_named_from_the_output_alone() {
+        assert_eq!(
+            claim_path(Path::new("/mnt/a/tv/show.mkv")),
+            Path::new("/mnt/a/tv/.show.mkv.downscaler.claim")
+        );
+    }
+
+    #[test]
+    fn a_live_claim_keeps_others_out_until_dropped() {
+        let dest = dir("live").join("out.mkv");
+        let lease = Duration::from_secs(600);
+        let claim = Claim::acquire(&dest, lease).unwrap().unwrap();
+        assert!(Claim::acquire(&dest, lease).unwrap().is_none());
+        drop(claim);
+        assert!(!claim_path(&dest).exists());
+        assert!(Claim::acquire(&dest, lease).unwrap().is_some());
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+
+    #[test]
+    fn stale_claims_are_taken_over_and_lost_claims_left_alone() {
+        let dest = dir("stale").join("out.mkv");
+        let path = claim_path(&dest);
+        fs::write(&path, "elsewhere 1\n").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let lease = Duration::from_secs(600);
+        let claim = Claim::acquire(&dest, lease).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), owner());
+        // another machine takes it over in turn
+        fs::write(&path, "elsewhere 2\n").unwrap();
+        drop(claim);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "elsewhere 2\n");
+        let _ = fs::remove_dir_all(dest.parent().unwrap());
+    }
+}
@@ -0,0 +1,206 @@
+//! Optional config file, for settings that don't fit well on the command line
+//!
+//! The format is a simple ini-style file:
+//!
+//! ```text
+//! # comments start with a hash
+//! [env]
+//! LD_LIBRARY_PATH = /opt/ffmpeg/lib
+//! FFREPORT = file=/tmp/ffmpeg-worker-{worker}.log
+//!
+//! [screen-recording]
+//! crf = 34
+//!
+//! [mqtt]
+//! host = homeassistant.local
+//!
+//! # what counts as busy, for --only-when-idle
+//! [idle]
+//! max_load = 0.5
+//!
+//! # media servers to tell about new outputs
+//! [library]
+//! jellyfin = http://jellyfin.local:8096
+//!
+//! # where to say that a run has ended, or a file failed
+//! [notify ntfy]
+//! url = https://ntfy.sh/my-downscaler
+//!
+//! # settings for everything under a directory, relative to the source
+//! [override downloads]
+//! verify_source = true
+//! ```
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::idle::Idle;
+use crate::library::Library;
+use crate::mqtt::Mqtt;
+use crate::notify::Notifier;
+use crate::ocr::OcrCommand;
+use crate::overrides::Override;
+use crate::priority;
+use crate::screen::ScreenProfile;
+use crate::workers::Slot;
+
+/// One `key = value` line, with its line number for error messages
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+impl Entry {
+    pub fn parse<T: FromStr>(&self) -> Result<T> {
+        self.value
+            .parse()
+            .map_err(|_| anyhow!("line {}: bad value for {}", self.line, self.key))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<Entry>,
+}
+
+/// Parse the raw ini structure - entries before any `[section]` header go in a section named ""
+pub fn parse_sections(text: &str) -> Result<Vec<Section>> {
+    let mut sections = vec![Section {
+        name: String::new(),
+        entries: Vec::new(),
+    }];
+    for (index, raw) in text.lines().enumerate() {
+        let line_no = index + 1;
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("line {}: unterminated section header", line_no))?;
+            sections.push(Section {
+                name: name.trim().to_owned(),
+                entries: Vec::new(),
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected `key = value`", line_no))?;
+        sections.last_mut().unwrap().entries.push(Entry {
+            key: key.trim().to_owned(),
+            value: value.trim().to_owned(),
+            line: line_no,
+        });
+    }
+    Ok(sections)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Extra environment variables for ffmpeg / ffprobe children
+    pub env: Vec<(String, String)>,
+    /// Used with `--detect-screen-recordings`
+    pub screen: ScreenProfile,
+    /// Used with `--only-when-idle`
+    pub idle: Idle,
+    /// Where to publish progress, if anywhere
+    pub mqtt: Option<Mqtt>,
+    /// Media servers to scan new outputs into, if any
+    pub library: Option<Library>,
+    /// Used with `--ocr-subs`
+    pub ocr: Option<OcrCommand>,
+    /// `[notify <kind>]` sections
+    pub notifiers: Vec<Notifier>,
+    /// `[override <dir>]` sections, in file order
+    pub overrides: Vec<Override>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading config {:?}", path))?;
+        Config::parse(&text).with_context(|| format!("parsing config {:?}", path))
+    }
+
+    fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        for section in parse_sections(text)? {
+            match section.name.as_str() {
+                "" if section.entries.is_empty() => {}
+                "env" => {
+                    for entry in section.entries {
+                        config.env.push((entry.key, entry.value));
+                    }
+                }
+                "screen-recording" => {
+                    for entry in &section.entries {
+                        config.screen.set(entry)?;
+                    }
+                }
+                "idle" => {
+                    for entry in &section.entries {
+                        config.idle.set(entry)?;
+                    }
+                }
+                "mqtt" => {
+                    let mqtt = config.mqtt.get_or_insert_with(Mqtt::default);
+                    for entry in &section.entries {
+                        mqtt.set(entry)?;
+                    }
+                }
+                "library" => {
+                    let library = config.library.get_or_insert_with(Library::default);
+                    for entry in &section.entries {
+                        library.set(entry)?;
+                    }
+                }
+                "ocr" => config.ocr = Some(OcrCommand::from_entries(&section.entries)?),
+                name if name.starts_with("notify ") => {
+                    let kind = name["notify ".len()..].trim();
+                    config
+                        .notifiers
+                        .push(Notifier::new(kind, &section.entries)?);
+                }
+                name if name.starts_with("override ") => {
+                    let dir = name["override ".len()..].trim();
+                    config.overrides.push(Override::new(dir, &section.entries)?);
+                }
+                "" => {
+                    return Err(anyhow!(
+                        "line {}: settings must be inside a [section]",
+                        section.entries[0].line
+                    ))
+                }
+                other => return Err(anyhow!("unknown config section [{}]", other)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// A command for an external tool, with any configured environment applied
+    ///
+    /// Values can use `{worker}` and `{gpu}`, replaced with the worker index and assigned GPU
+    pub fn command(&self, program: &str, slot: Slot) -> Command {
+        let gpu = slot.gpu.map(|g| g.to_string()).unwrap_or_default();
+        let mut cmd = Command::new(program);
+        for (key, value) in &self.env {
+            let value = value
+                .replace("{worker}", &slot.worker.to_string())
+                .replace("{gpu}", &gpu);
+            cmd.env(key, value);
+        }
+        priority::apply(&mut cmd);
+        cmd
+    }
+}
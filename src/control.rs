@@ -0,0 +1,212 @@
+//! A Unix socket for controlling a running instance, and `downscaler ctl` to talk to it
+//!
+//! With `--control-socket PATH`, a run listens on `PATH` for one-line commands and answers each
+//! with one line of text: `status`, `pause`, `resume`, `skip` (abandon the files being encoded
+//! right now, and move on) and `stop` (finish the files being encoded, then end the run).
+
+use std::error::Error;
+use std::fmt;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Subcommand;
+use log::info;
+use log::warn;
+
+use crate::progress::Progress;
+use crate::signals;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static SKIPPING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, Subcommand)]
+pub enum CtlCommand {
+    /// Print where the run is
+    Status,
+    /// Freeze the running encodes, and start no new ones
+    Pause,
+    Resume,
+    /// Abandon the files being encoded right now, and move on to the next
+    Skip,
+    /// Finish the files being encoded, then end the run
+    Stop,
+}
+
+impl CtlCommand {
+    fn name(self) -> &'static str {
+        match self {
+            CtlCommand::Status => "status",
+            CtlCommand::Pause => "pause",
+            CtlCommand::Resume => "resume",
+            CtlCommand::Skip => "skip",
+            CtlCommand::Stop => "stop",
+        }
+    }
+}
+
+/// A file was abandoned from the control socket
+#[derive(Debug)]
+pub struct Skipped;
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "skipped from the control socket")
+    }
+}
+
+impl Error for Skipped {}
+
+/// Should no more files be started?
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Finish the files being encoded, then end the run
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+/// Abandon encoding these sources
+pub fn skip(sources: Vec<PathBuf>) {
+    SKIPPING.lock().unwrap().extend(sources);
+}
+
+/// Has someone asked to abandon `source`?
+pub fn skip_requested(source: &Path) -> bool {
+    SKIPPING.lock().unwrap().iter().any(|s| s == source)
+}
+
+/// Forget any stop or skips asked for, before another pass over the sources
+pub fn reset() {
+    STOPPING.store(false, Ordering::SeqCst);
+    SKIPPING.lock().unwrap().clear();
+}
+
+/// Forgets a skip asked for `source` once its job is over, when dropped - so it can be encoded
+/// again if it is queued again
+pub struct Skippable(PathBuf);
+
+impl Skippable {
+    pub fn new(source: &Path) -> Skippable {
+        Skippable(source.to_owned())
+    }
+}
+
+impl Drop for Skippable {
+    fn drop(&mut self) {
+        SKIPPING.lock().unwrap().retain(|s| s != &self.0);
+    }
+}
+
+pub fn answer(command: &str, progress: &Progress) -> String {
+    match command {
+        "status" => progress.status(),
+        "pause" => {
+            signals::set_paused(true);
+            "paused".to_owned()
+        }
+        "resume" => {
+            signals::set_paused(false);
+            "resumed".to_owned()
+        }
+        "skip" => {
+            let active = progress.active();
+            let answer = format!("skipping {} files", active.len());
+            skip(active);
+            answer
+        }
+        "stop" => {
+            stop();
+            "stopping after the files being encoded".to_owned()
+        }
+        other => format!("unknown command {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+pub fn serve(path: &Path, progress: &Progress, finished: &AtomicBool) -> Result<()> {
+    use std::os::unix::net::UnixListener;
+
+    // a socket left behind by a run that crashed
+    if path.exists() {
+        std::fs::remove_file(path).with_context(|| format!("removing old socket {:?}", path))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("listening on {:?}", path))?;
+    // polled, so the loop notices when the run is over
+    listener.set_nonblocking(true)?;
+    info!("listening for commands on {:?}", path);
+    while !finished.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = stream.set_nonblocking(false);
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                let mut command = String::new();
+                if BufReader::new(&stream).read_line(&mut command).is_ok() {
+                    let command = command.trim();
+                    let reply = answer(command, progress);
+                    info!("control socket: {} - {}", command, reply);
+                    let _ = writeln!(&stream, "{}", reply);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(250))
+            }
+            Err(e) => warn!("control socket: {}", e),
+        }
+    }
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_: &Path, _: &Progress, _: &AtomicBool) -> Result<()> {
+    Err(anyhow!("--control-socket needs Unix domain sockets"))
+}
+
+/// `downscaler ctl` - send one command to a running instance, and print its answer
+#[cfg(unix)]
+pub fn send(path: &Path, command: CtlCommand) -> Result<()> {
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("connecting to {:?} - is downscaler running?", path))?;
+    writeln!(stream, "{}", command.name())?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    if reply.is_empty() {
+        return Err(anyhow!("no answer from {:?}", path));
+    }
+    print!("{}", reply);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn send(_: &Path, _: CtlCommand) -> Result<()> {
+    Err(anyhow!("ctl needs Unix domain sockets"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_are_forgotten_once_the_job_is_over() {
+        let source = Path::new("/videos/skipped.mkv");
+        let job = Skippable::new(source);
+        skip(vec![source.to_owned()]);
+        assert!(skip_requested(source));
+        drop(job);
+        assert!(!skip_requested(source));
+    }
+}
@@ -0,0 +1,119 @@
+//! Detecting letterbox and pillarbox bars with ffmpeg's `cropdetect` filter
+//!
+//! A single point in a film can be misleading - a dark scene looks like it is all bars - so we
+//! sample several points and keep the smallest crop that covers the picture in all of them.
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+
+use crate::config::Config;
+use crate::workers::Slot;
+
+/// Where in the video to sample, as fractions of the duration - skipping the start and end credits
+const SAMPLE_POINTS: [f64; 5] = [0.15, 0.3, 0.5, 0.7, 0.85];
+
+/// Frames to look at from each sample point
+const SAMPLE_FRAMES: &str = "50";
+
+/// Crops that save less than this many pixels from an edge aren't worth it
+const MIN_CROP: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+impl Crop {
+    /// Parse the last `crop=w:h:x:y` suggestion in ffmpeg's log
+    fn from_log(log: &str) -> Option<Crop> {
+        let (_, suggestion) = log.rsplit_once("crop=")?;
+        let mut values = suggestion
+            .split_whitespace()
+            .next()?
+            .split(':')
+            .map(|v| v.parse().ok());
+        Some(Crop {
+            width: values.next()??,
+            height: values.next()??,
+            x: values.next()??,
+            y: values.next()??,
+        })
+    }
+
+    /// The smallest crop containing both
+    fn union(self, other: Crop) -> Crop {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Crop {
+            width: right - x,
+            height: bottom - y,
+            x,
+            y,
+        }
+    }
+
+    /// The width and height left after cropping
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// How far in from the top left corner the picture starts
+    pub fn offset(&self) -> (u32, u32) {
+        (self.x, self.y)
+    }
+
+    /// The `crop` filter for this
+    pub fn filter(&self) -> String {
+        format!("crop={}", self)
+    }
+}
+
+fn sample(input: &Path, at: f64, config: &Config, slot: Slot) -> Result<Option<Crop>> {
+    let mut cmd = config.command("ffmpeg", slot);
+    cmd.args(["-nostdin", "-hide_banner", "-nostats", "-loglevel", "info"])
+        .args(["-ss", &format!("{:.1}", at)])
+        .arg("-i")
+        .arg(input)
+        .args(["-map", "0:V:0", "-filter:V", "cropdetect=round=2"])
+        .args(["-frames:v", SAMPLE_FRAMES, "-an", "-sn", "-f", "null", "-"]);
+    let log = crate::run_for_log(cmd).context("detecting crop")?;
+    Ok(Crop::from_log(&log))
+}
+
+/// Find the crop for `input`, if any is worthwhile - `width` and `height` are the source size
+pub fn detect(
+    input: &Path,
+    duration: f64,
+    (width, height): (u32, u32),
+    config: &Config,
+    slot: Slot,
+) -> Result<Option<Crop>> {
+    let mut found: Option<Crop> = None;
+    for point in SAMPLE_POINTS {
+        if let Some(crop) = sample(input, duration * point, config, slot)? {
+            debug!("crop at {:.0}s of {:?}: {}", duration * point, input, crop);
+            found = Some(found.map_or(crop, |f| f.union(crop)));
+        }
+    }
+    Ok(found.filter(|crop| {
+        // cropdetect's idea of a dark frame can be bigger than the frame itself
+        crop.x + crop.width <= width
+            && crop.y + crop.height <= height
+            && (width - crop.width >= MIN_CROP || height - crop.height >= MIN_CROP)
+    }))
+}
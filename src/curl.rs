@@ -0,0 +1,149 @@
+//! Running curl, for the HTTP requests made to notification services, media servers and webhooks
+//!
+//! Anything secret - tokens, passwords, and URLs that are as good as one - goes in a curl config
+//! file only this user can read, passed with `--config`, rather than on curl's command line where
+//! `ps` shows it to everyone.
+
+use std::env;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use crate::secrets::SecretFile;
+
+/// One curl request being put together
+pub struct Curl {
+    cmd: Command,
+    /// curl config lines with secrets in
+    secret: String,
+    /// What to send on stdin, for `@-` arguments
+    input: Option<Vec<u8>>,
+}
+
+impl Curl {
+    /// A request giving up after `timeout` seconds, failing on HTTP errors
+    pub fn new(timeout: u32) -> Curl {
+        let mut cmd = Command::new("curl");
+        cmd.args(["-s", "-S", "-f", "-m", &timeout.to_string()]);
+        Curl {
+            cmd,
+            secret: String::new(),
+            input: None,
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Curl {
+        self.cmd.arg(arg);
+        self
+    }
+
+    pub fn args<S: AsRef<OsStr>>(&mut self, args: impl IntoIterator<Item = S>) -> &mut Curl {
+        self.cmd.args(args);
+        self
+    }
+
+    /// The long option `name` without its dashes, e.g. `header`, given in the config file
+    pub fn secret(&mut self, name: &str, value: &str) -> &mut Curl {
+        self.secret.push_str(&option(name, value));
+        self
+    }
+
+    /// A URL that is as good as a password
+    pub fn secret_url(&mut self, url: &str) -> &mut Curl {
+        self.secret("url", url)
+    }
+
+    /// Send `input` on stdin
+    pub fn input(&mut self, input: impl Into<Vec<u8>>) -> &mut Curl {
+        self.input = Some(input.into());
+        self
+    }
+
+    /// Run it, returning what it printed - or its error
+    pub fn run(&mut self) -> Result<String> {
+        // kept until curl has finished with it
+        let _config = match self.secret.is_empty() {
+            true => None,
+            false => {
+                let config = SecretFile::create_in(&env::temp_dir(), "curlrc", &self.secret)?;
+                self.cmd.arg("--config").arg(&config.path);
+                Some(config)
+            }
+        };
+        let stdin = match self.input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+        let mut child = self
+            .cmd
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("could not run curl: {}", e))?;
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), &self.input) {
+            stdin.write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false => Err(anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+}
+
+/// One line of a curl config file
+pub fn option(name: &str, value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    format!("{} = \"{}\"\n", name, quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_quoted() {
+        assert_eq!(
+            option("url", "https://ntfy.sh/topic"),
+            "url = \"https://ntfy.sh/topic\"\n"
+        );
+    }
+
+    #[test]
+    fn quotes_and_backslashes_are_escaped() {
+        assert_eq!(
+            option("user", r#"me:pa"ss\word"#),
+            "user = \"me:pa\\\"ss\\\\word\"\n"
+        );
+    }
+
+    #[test]
+    fn line_breaks_cant_start_another_option() {
+        let line = option("header", "X-Token: a\nurl = \"https://elsewhere\"\r\tb");
+        assert_eq!(line.lines().count(), 1);
+        assert_eq!(
+            line,
+            "header = \"X-Token: a\\nurl = \\\"https://elsewhere\\\"\\r\\tb\"\n"
+        );
+    }
+}
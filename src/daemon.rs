@@ -0,0 +1,143 @@
+//! Running as a service, for `--daemon`
+//!
+//! The daemon encodes whatever needs doing, waits `--scan-every`, and scans again, until SIGTERM
+//! (or `downscaler ctl stop`). SIGTERM during a pass abandons the files being encoded and cleans
+//! up after them, as Ctrl-C does, and the daemon exits successfully.
+//!
+//! Under systemd with `Type=notify`, it says when it is ready and keeps its status line up to
+//! date through `$NOTIFY_SOCKET`. `--pid-file` is written while it runs, for anything else that
+//! wants to find it.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Parser;
+use log::info;
+use log::warn;
+
+use crate::control;
+use crate::signals;
+use crate::Opts;
+
+/// Tell systemd something, like `READY=1` or `STATUS=...` - nothing happens outside a
+/// `Type=notify` service
+pub fn notify(message: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let socket = match env::var_os("NOTIFY_SOCKET") {
+            Some(socket) => socket,
+            None => return,
+        };
+        let sender = match UnixDatagram::unbound() {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+        let socket = socket.to_string_lossy();
+        // `@` is a Linux abstract socket
+        let sent = match socket.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|address| sender.send_to_addr(message.as_bytes(), &address))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return,
+            None => sender.send_to(message.as_bytes(), socket.as_ref()),
+        };
+        if let Err(e) = sent {
+            log::debug!("could not notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = message;
+}
+
+/// Holds `--pid-file` for as long as the daemon runs
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: &Path) -> Result<PidFile> {
+        if let Ok(text) = fs::read_to_string(path) {
+            if let Ok(pid) = text.trim().parse::<u32>() {
+                if pid != process::id() && signals::is_running(pid) {
+                    return Err(anyhow!(
+                        "{:?} says downscaler process {} is already running",
+                        path,
+                        pid
+                    ));
+                }
+            }
+        }
+        fs::write(path, format!("{}\n", process::id()))
+            .with_context(|| format!("writing pid file {:?}", path))?;
+        Ok(PidFile(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Run passes over the sources until stopped - `args` are the command line, re-read for each pass
+pub fn run(args: Vec<OsString>, every: Duration, pid_file: Option<&Path>) -> Result<()> {
+    let _pid_file = pid_file.map(PidFile::create).transpose()?;
+    signals::install();
+    notify("READY=1");
+    info!(
+        "running as a daemon - scanning for new files every {}",
+        humantime::format_duration(every)
+    );
+    loop {
+        notify("STATUS=scanning for new files");
+        control::reset();
+        let outcome = crate::run(Opts::try_parse_from(&args)?, None, args.clone());
+        if signals::interrupted() {
+            info!(
+                "stopped by a signal - the files in progress were abandoned, and their temp files removed"
+            );
+            break;
+        }
+        let last = match outcome {
+            Ok(()) => "the last pass finished".to_owned(),
+            // a source that has gone away may well be back next time
+            Err(e) => {
+                warn!("this pass failed: {:#}", e);
+                format!("the last pass failed: {:#}", e)
+            }
+        };
+        if control::stopping() {
+            info!("stopping, as asked");
+            break;
+        }
+        notify(&format!(
+            "STATUS=waiting - {}, next scan in {}",
+            last,
+            humantime::format_duration(every)
+        ));
+        let waiting = Instant::now();
+        while waiting.elapsed() < every && !signals::interrupted() {
+            thread::sleep(Duration::from_millis(250));
+        }
+        if signals::interrupted() {
+            info!("stopped while waiting for the next scan");
+            break;
+        }
+    }
+    notify("STOPPING=1");
+    Ok(())
+}
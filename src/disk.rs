@@ -0,0 +1,29 @@
+//! How much room is left on a filesystem
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use crate::report::human_size;
+
+/// Bytes free to unprivileged users on the filesystem holding `path`
+pub fn free_space(path: &Path) -> Result<u64> {
+    downscaler_ffi::free_space(path)
+        .map_err(|e| anyhow!("checking free space on {:?}: {}", path, e))
+}
+
+/// Fail unless `dir` has room for `needed` more bytes of `what`
+pub fn check_room(dir: &Path, needed: u64, what: &str) -> Result<()> {
+    let free = free_space(dir)?;
+    if free < needed {
+        return Err(anyhow!(
+            "not enough space in {:?} for {} - {} needed, {} free",
+            dir,
+            what,
+            human_size(needed as i64),
+            human_size(free as i64)
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! `downscaler doctor` - checking the tools and directories a run needs, before starting one
+//!
+//! Each check prints one line, `ok`, `warning` or `problem`, saying what to do about anything that
+//! isn't ok. Given the options for a run after `--`, it checks what that run needs: its encoder -
+//! with a tiny test encode, as hardware encoders can be built in but have no device to use - its
+//! source and destination, and room in the temp directory for the biggest source.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use clap::Parser;
+
+use crate::budget;
+use crate::config::Config;
+use crate::disk;
+use crate::remote;
+use crate::report::human_size;
+use crate::staging::RunDir;
+use crate::storage;
+use crate::workers::Slot;
+use crate::Opts;
+
+/// The encoder a run uses without `--encoder`
+const DEFAULT_ENCODER: &str = "libx265";
+
+#[derive(Debug, Default)]
+struct Checkup {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Checkup {
+    fn ok(&mut self, what: &str, detail: &str) {
+        println!("ok       {}: {}", what, detail);
+    }
+
+    fn warning(&mut self, what: &str, detail: &str) {
+        self.warnings += 1;
+        println!("warning  {}: {}", what, detail);
+    }
+
+    fn problem(&mut self, what: &str, detail: &str) {
+        self.problems += 1;
+        println!("problem  {}: {}", what, detail);
+    }
+}
+
+/// Run a tool, returning its stdout - or why it couldn't be run
+fn output(config: &Config, program: &str, args: &[&str]) -> Result<String, String> {
+    let mut cmd = config.command(program, Slot::default());
+    cmd.args(args);
+    let (mut cmd, _) = remote::prepare(cmd).map_err(|e| format!("{:#}", e))?;
+    cmd.stdin(Stdio::null()).stderr(Stdio::piped());
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(output) => Err(String::from_utf8_lossy(&output.stderr)
+            .lines()
+            // ffmpeg's first complaint is usually the cause, the rest what followed from it
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("failed with no message")
+            .to_owned()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err("not found on the PATH - install ffmpeg, which comes with ffprobe".to_owned())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Fail unless ffmpeg and ffprobe can be run, before a run starts on the files
+pub fn require_tools(config: &Config) -> Result<()> {
+    for program in ["ffmpeg", "ffprobe"] {
+        output(config, program, &["-version"]).map_err(|e| anyhow!("{}: {}", program, e))?;
+    }
+    Ok(())
+}
+
+fn tools(config: &Config, checkup: &mut Checkup) -> bool {
+    let mut found = true;
+    for program in ["ffmpeg", "ffprobe"] {
+        match output(config, program, &["-version"]) {
+            Ok(text) => checkup.ok(program, text.lines().next().unwrap_or_default()),
+            Err(e) => {
+                checkup.problem(program, &e);
+                found = false;
+            }
+        }
+    }
+    found
+}
+
+/// The names in `ffmpeg -encoders` or `-filters`, after the ` ---` line under the legend
+fn listed(text: &str) -> Vec<String> {
+    text.lines()
+        .skip_while(|l| !l.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|name| name.to_owned())
+        .collect()
+}
+
+fn encoder(config: &Config, encoder: &str, checkup: &mut Checkup) {
+    let what = format!("encoder {}", encoder);
+    let encoders = match output(config, "ffmpeg", &["-hide_banner", "-encoders"]) {
+        Ok(text) => listed(&text),
+        Err(e) => return checkup.problem(&what, &format!("could not list encoders: {}", e)),
+    };
+    if !encoders.iter().any(|e| e == encoder) {
+        return checkup.problem(
+            &what,
+            "this ffmpeg wasn't built with it - `ffmpeg -encoders` lists the ones it has",
+        );
+    }
+    let test = [
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-f",
+        "lavfi",
+        "-i",
+        "color=size=320x240:duration=0.2",
+        "-c:v",
+        encoder,
+        "-f",
+        "null",
+        "-",
+    ];
+    match output(config, "ffmpeg", &test) {
+        Ok(_) => checkup.ok(&what, "a test encode worked"),
+        Err(e) => checkup.problem(
+            &what,
+            &format!(
+                "ffmpeg has it, but a test encode failed - for a hardware encoder, check its drivers and device: {}",
+                e
+            ),
+        ),
+    }
+}
+
+fn vmaf(config: &Config, checkup: &mut Checkup) {
+    match output(config, "ffmpeg", &["-hide_banner", "-filters"]) {
+        Ok(text) if listed(&text).iter().any(|f| f == "libvmaf") => {
+            checkup.ok("--auto-crf", "ffmpeg has libvmaf")
+        }
+        Ok(_) => checkup.problem(
+            "--auto-crf",
+            "this ffmpeg wasn't built with libvmaf, which scoring needs",
+        ),
+        Err(e) => checkup.problem("--auto-crf", &format!("could not list filters: {}", e)),
+    }
+}
+
+/// Can we make, and remove, a file in `dir`?
+fn writable(dir: &Path) -> Result<()> {
+    // a working file's name, so `downscaler clean` would tidy it up if we were killed here
+    let test = dir.join(format!(".downscaler_doctor{}.working", process::id()));
+    fs::write(&test, b"")?;
+    fs::remove_file(&test)?;
+    Ok(())
+}
+
+fn free(dir: &Path) -> String {
+    match disk::free_space(dir) {
+        Ok(free) => format!("{} free", human_size(free as i64)),
+        Err(_) => "free space unknown".to_owned(),
+    }
+}
+
+/// The biggest file `--source` would scan, if it can be found
+fn largest_source(opts: &Opts) -> Option<u64> {
+    opts.source
+        .iter()
+        .filter_map(|root| budget::sources(&root.path).ok())
+        .flatten()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|m| m.len())
+        .max()
+}
+
+fn directories(opts: &Opts, checkup: &mut Checkup) {
+    match opts.sources() {
+        Ok(sources) => {
+            for (source, _) in sources {
+                let what = format!("source {:?}", source);
+                if let Err(e) = storage::open(source) {
+                    checkup.problem(&what, &format!("{:#}", e));
+                    continue;
+                }
+                match fs::read_dir(source) {
+                    Ok(entries) => {
+                        checkup.ok(&what, &format!("readable, {} entries", entries.count()))
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        checkup.problem(&what, "doesn't exist - is the share mounted?")
+                    }
+                    Err(e) => checkup.problem(&what, &format!("can't be read: {}", e)),
+                }
+            }
+        }
+        Err(e) => checkup.problem("sources", &format!("{:#}", e)),
+    }
+
+    let destination = opts.destination();
+    let what = format!("destination {:?}", destination);
+    let existing = crate::existing_dir(destination);
+    match writable(&existing) {
+        Ok(()) if existing == destination => checkup.ok(&what, &format!("writable, {}", free(&existing))),
+        Ok(()) => checkup.warning(
+            &what,
+            &format!(
+                "doesn't exist yet, and will be made in {:?} ({}) - check it isn't a share that isn't mounted",
+                existing,
+                free(&existing)
+            ),
+        ),
+        Err(e) => checkup.problem(&what, &format!("can't write to {:?}: {}", existing, e)),
+    }
+    if let (Some(min), Ok(free)) = (opts.dest_free_min, disk::free_space(&existing)) {
+        if free < min.0 {
+            checkup.problem(
+                &what,
+                &format!(
+                    "only {} free, under --dest-free-min {} - nothing would be encoded",
+                    human_size(free as i64),
+                    min
+                ),
+            );
+        }
+    }
+
+    let temp = opts.temp_dir();
+    let what = format!("temp directory {:?}", temp);
+    if let Err(e) = RunDir::create(&temp) {
+        return checkup.problem(
+            &what,
+            &format!("{:#} - use --temp-dir for somewhere writable", e),
+        );
+    }
+    // staged runs hold a source and its output at once, otherwise just the output
+    let largest = largest_source(opts);
+    let needed = largest.map(|size| if opts.no_stage { size } else { size * 2 });
+    match (disk::free_space(&temp), needed) {
+        (Ok(free), Some(needed)) if free < needed => checkup.problem(
+            &what,
+            &format!(
+                "{} free, but the biggest source ({}) needs about {} - use --temp-dir for somewhere bigger",
+                human_size(free as i64),
+                human_size(largest.unwrap_or_default() as i64),
+                human_size(needed as i64)
+            ),
+        ),
+        (Ok(free), Some(needed)) => checkup.ok(
+            &what,
+            &format!(
+                "{} free, room for the biggest source and its output ({})",
+                human_size(free as i64),
+                human_size(needed as i64)
+            ),
+        ),
+        _ => checkup.ok(&what, &format!("writable, {}", free(&temp))),
+    }
+}
+
+pub fn run(options: &[OsString]) -> Result<()> {
+    let mut checkup = Checkup::default();
+    let opts = match options.is_empty() {
+        true => None,
+        false => {
+            let args = std::iter::once(OsString::from("downscaler")).chain(options.iter().cloned());
+            Some(Opts::try_parse_from(args)?)
+        }
+    };
+    let config = match opts.as_ref().and_then(|o| o.config.as_ref()) {
+        Some(path) => match Config::load(path) {
+            Ok(config) => {
+                checkup.ok(&format!("config {:?}", path), "read");
+                config
+            }
+            Err(e) => {
+                checkup.problem(&format!("config {:?}", path), &format!("{:#}", e));
+                Config::default()
+            }
+        },
+        None => Config::default(),
+    };
+
+    if tools(&config, &mut checkup) {
+        let chosen = opts
+            .as_ref()
+            .map_or(DEFAULT_ENCODER, |o| o.encoder.as_str());
+        encoder(&config, chosen, &mut checkup);
+        // hardware failures are retried in software
+        if let Some(fallback) = crate::software_fallback(chosen) {
+            encoder(&config, fallback, &mut checkup);
+        }
+        if opts.as_ref().is_some_and(|o| o.auto_crf) {
+            vmaf(&config, &mut checkup);
+        }
+    }
+    match &opts {
+        Some(opts) => directories(opts, &mut checkup),
+        None => {
+            let temp = std::env::temp_dir();
+            match RunDir::create(&temp) {
+                Ok(_) => checkup.ok(
+                    &format!("temp directory {:?}", temp),
+                    &format!("writable, {}", free(&temp)),
+                ),
+                Err(e) => checkup.problem(
+                    &format!("temp directory {:?}", temp),
+                    &format!("{:#} - use --temp-dir for somewhere writable", e),
+                ),
+            }
+            println!("\ngive a run's options after `--` to check its encoder and directories too, e.g. `downscaler doctor -- -s videos -d small`");
+        }
+    }
+
+    println!(
+        "\n{} problems, {} warnings",
+        checkup.problems, checkup.warnings
+    );
+    match checkup.problems {
+        0 => Ok(()),
+        n => Err(anyhow!("{} problems would stop or spoil a run", n)),
+    }
+}
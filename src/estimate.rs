@@ -0,0 +1,210 @@
+//! `downscaler estimate` - how much space a run would save, per directory, before starting it
+//!
+//! Sizes are projected the same way as `downscaler budget`. With `--calibrate N`, a short piece
+//! of N files spread over the tree is encoded for real first, and every projection is scaled by
+//! how those encodes compared with their own projections - which takes account of how well this
+//! particular material compresses, where the heuristic only knows about frame sizes.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use clap::Args;
+use log::info;
+use log::warn;
+
+use crate::budget;
+use crate::budget::DirBudget;
+use crate::budget::ProjectionOpts;
+use crate::config::Config;
+use crate::probe;
+use crate::rate::Rate;
+use crate::report::human_size;
+use crate::staging::path_hash;
+use crate::staging::RunDir;
+use crate::workers::Slot;
+
+/// Seconds encoded from the middle of each calibration file
+const SAMPLE_LENGTH: f64 = 20.0;
+
+#[derive(Debug, Args)]
+pub struct EstimateOpts {
+    #[clap(flatten)]
+    projection: ProjectionOpts,
+    /// Encode a short piece of this many files, spread over the tree, to calibrate the projections
+    #[clap(value_parser, long, default_value_t = 0)]
+    calibrate: usize,
+    /// Encoder speed preset for calibration encodes - the one the run will use
+    #[clap(value_parser, long, default_value = "fast")]
+    preset: String,
+}
+
+/// Encode a piece of `source` as a run would, returning its bits and the bits the heuristic
+/// projected for it
+fn sample(
+    opts: &EstimateOpts,
+    config: &Config,
+    source: &Path,
+    temp: &RunDir,
+) -> Result<Option<(f64, f64)>> {
+    let info = probe::probe(source, config, Slot::default())?;
+    let relative = source
+        .strip_prefix(&opts.projection.source)
+        .unwrap_or(source);
+    let mut settings = opts.projection.settings(config, source, relative, &info);
+    settings.preset = opts.preset.clone();
+    let (bits, duration) = match (
+        budget::video_bitrate(&info, &settings, &opts.projection.encoder),
+        info.duration(),
+    ) {
+        (Some(bits), Some(duration)) if duration > 0.0 => (bits, duration),
+        _ => return Ok(None),
+    };
+    let length = SAMPLE_LENGTH.min(duration);
+    let start = (duration - length) / 2.0;
+    let mut filters = vec![format!("scale=-2:'min({},ih)'", settings.max_height)];
+    let source_fps = info.video().and_then(|v| v.frame_rate());
+    if let Some(max_fps) = settings
+        .max_fps
+        .filter(|max| source_fps.is_some_and(|fps| fps > *max))
+    {
+        filters.insert(0, format!("fps={}", max_fps));
+    }
+    let output = temp
+        .path
+        .join(format!("downscaler_{}_estimate.mkv", path_hash(source)));
+    let mut cmd = config.command("ffmpeg", Slot::default());
+    cmd.arg("-nostdin")
+        .args([
+            "-ss",
+            &format!("{:.2}", start),
+            "-t",
+            &format!("{:.2}", length),
+        ])
+        .arg("-i")
+        .arg(source)
+        .args(["-map", "0:V:0"])
+        .args(crate::video_args(
+            &opts.projection.encoder,
+            &settings,
+            Rate::Quality,
+            Slot::default(),
+            None,
+        ))
+        .args(["-vf", &filters.join(",")])
+        .args(["-an", "-sn", "-y"])
+        .args(["-loglevel", "warning", "-nostats", "-hide_banner"])
+        .arg(&output);
+    let encoded = crate::run_command(cmd)
+        .and_then(|_| Ok(fs::metadata(&output)?.len()))
+        .context("encoding a calibration sample");
+    let _ = fs::remove_file(&output);
+    Ok(Some((encoded? as f64 * 8.0, bits * length)))
+}
+
+/// How real encodes of some of `sources` compare with their projections - 1.0 if none could be
+/// made
+fn calibration(opts: &EstimateOpts, config: &Config, sources: &[PathBuf]) -> Result<f64> {
+    let count = opts.calibrate.min(sources.len());
+    if count == 0 {
+        return Ok(1.0);
+    }
+    let temp = RunDir::create(&env::temp_dir())?;
+    info!("encoding a piece of {} files to calibrate", count);
+    let step = sources.len() as f64 / count as f64;
+    let (mut actual, mut projected, mut samples) = (0.0, 0.0, 0);
+    for i in 0..count {
+        let source = &sources[(step * (i as f64 + 0.5)) as usize];
+        match sample(opts, config, source, &temp) {
+            Ok(Some((real, estimate))) => {
+                actual += real;
+                projected += estimate;
+                samples += 1;
+            }
+            Ok(None) => warn!("can't estimate {:?} - not calibrating with it", source),
+            Err(e) => warn!("not calibrating with {:?}: {:#}", source, e),
+        }
+    }
+    if samples == 0 || projected <= 0.0 || actual <= 0.0 {
+        warn!("no calibration encodes worked - using the projections as they are");
+        return Ok(1.0);
+    }
+    let factor = actual / projected;
+    info!(
+        "calibrated from {} files - their video came out at {:.0}% of the projection",
+        samples,
+        factor * 100.0
+    );
+    Ok(factor)
+}
+
+/// Negative where outputs are projected to be bigger than their sources
+fn saving(source: u64, projected: u64) -> i64 {
+    source as i64 - projected as i64
+}
+
+fn percent(part: i64, whole: u64) -> String {
+    match whole {
+        0 => "-".to_owned(),
+        whole => format!("{:.0}%", part as f64 * 100.0 / whole as f64),
+    }
+}
+
+fn print(dirs: &BTreeMap<PathBuf, DirBudget>) {
+    println!(
+        "{:<40} {:>6} {:>8} {:>11} {:>11} {:>11} {:>6}",
+        "directory", "files", "hours", "source", "projected", "saved", ""
+    );
+    for (dir, budget) in dirs {
+        let saved = saving(budget.estimated_source, budget.projected);
+        println!(
+            "{:<40} {:>6} {:>8.1} {:>11} {:>11} {:>11} {:>6}{}",
+            budget::dir_label(dir),
+            budget.files,
+            budget.seconds / 3600.0,
+            human_size(budget.source_size as i64),
+            human_size(budget.projected as i64),
+            human_size(saved),
+            percent(saved, budget.estimated_source),
+            match budget.unknown {
+                0 => String::new(),
+                n => format!("  ({} not estimated)", n),
+            }
+        );
+    }
+    let source: u64 = dirs.values().map(|d| d.source_size).sum();
+    let estimated: u64 = dirs.values().map(|d| d.estimated_source).sum();
+    let projected: u64 = dirs.values().map(|d| d.projected).sum();
+    let saved = saving(estimated, projected);
+    println!(
+        "\ntotal: {} of sources projected to {} - saving {} ({})",
+        human_size(source as i64),
+        human_size(projected as i64),
+        human_size(saved),
+        percent(saved, estimated)
+    );
+    let unknown: usize = dirs.values().map(|d| d.unknown).sum();
+    if unknown > 0 {
+        println!(
+            "{} files could not be estimated, and aren't counted in the savings",
+            unknown
+        );
+    }
+}
+
+pub fn run(opts: &EstimateOpts) -> Result<()> {
+    let config = opts.projection.load_config()?;
+    let sources = budget::sources(&opts.projection.source)?;
+    let factor = calibration(opts, &config, &sources)?;
+    print(&budget::project(
+        &opts.projection,
+        &config,
+        &sources,
+        factor,
+    ));
+    Ok(())
+}
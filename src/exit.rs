@@ -0,0 +1,80 @@
+//! What the exit status says about how a run went, for wrapper scripts and systemd
+//!
+//! Errors are marked with the status they should exit with where they are made; anything not
+//! marked is a run that failed.
+
+use std::error::Error;
+use std::fmt;
+use std::process::ExitCode;
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The run failed part way, usually because a file failed
+    Failed = 1,
+    /// The run finished, but some files failed - quarantined, or given up on by the watchdog
+    SomeFailed = 2,
+    /// Bad options or config, so nothing was tried
+    Config = 3,
+    /// Something the run needs isn't there - ffmpeg, or a source
+    Environment = 4,
+    /// Another run holds the lock on these directories
+    Busy = 5,
+    /// Stopped by Ctrl-C or SIGTERM, like shells report SIGINT
+    Interrupted = 130,
+}
+
+impl Status {
+    /// `error`, marked to exit with this status
+    pub fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Marked {
+            status: self,
+            error,
+        })
+    }
+}
+
+/// Marks an error as bad options or config
+pub fn config(error: anyhow::Error) -> anyhow::Error {
+    Status::Config.wrap(error)
+}
+
+/// Marks an error as something missing from the machine
+pub fn environment(error: anyhow::Error) -> anyhow::Error {
+    Status::Environment.wrap(error)
+}
+
+/// Reads exactly as the error it marks
+#[derive(Debug)]
+struct Marked {
+    status: Status,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for Marked {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// The status an error should exit with
+pub fn status_of(error: &anyhow::Error) -> Status {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Marked>().map(|m| m.status))
+        .unwrap_or(Status::Failed)
+}
+
+pub fn code(outcome: &Result<()>) -> ExitCode {
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => ExitCode::from(status_of(e) as u8),
+    }
+}
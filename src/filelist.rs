@@ -0,0 +1,39 @@
+//! Reading the files to encode from a list, for `--files-from`, instead of scanning the sources
+//!
+//! The list is one path per line, or separated by NULs with `--null` as `find -print0` and
+//! `fd -0` write them, so any name gets through. `-` reads it from stdin.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+
+pub fn read(list: &Path, null: bool) -> Result<Vec<PathBuf>> {
+    let bytes = match list == Path::new("-") {
+        true => {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .context("reading the file list from stdin")?;
+            bytes
+        }
+        false => fs::read(list).with_context(|| format!("reading file list {:?}", list))?,
+    };
+    let separator = if null { b'\0' } else { b'\n' };
+    Ok(bytes
+        .split(|b| *b == separator)
+        .map(|path| match null {
+            true => path,
+            // written on Windows, perhaps
+            false => path.strip_suffix(b"\r").unwrap_or(path),
+        })
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsString::from_vec(path.to_vec())))
+        .collect())
+}
@@ -0,0 +1,220 @@
+//! Building the video filter graph - scaling, plus anything else that needs to touch the pixels
+
+use std::path::Path;
+
+use clap::ValueEnum;
+use log::info;
+
+use crate::probe::ProbeInfo;
+use crate::probe::Stream;
+use crate::settings::Settings;
+use crate::streams;
+use crate::Opts;
+
+/// Escape a value for use as a filter option inside a filter graph
+///
+/// This is two levels of escaping - see "Notes on filtergraph escaping" in the ffmpeg docs
+pub fn escape_value(value: &str) -> String {
+    let mut option = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option.push('\\');
+        }
+        option.push(c);
+    }
+    let mut graph = String::with_capacity(option.len());
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+        graph.push(c);
+    }
+    graph
+}
+
+/// Tone mapping for HDR sources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Tonemap {
+    /// Map HDR (BT.2020 / PQ / HLG) video to SDR BT.709, for devices that can't show HDR
+    Sdr,
+}
+
+/// How hard to denoise - grain is expensive to encode and rarely survives downscaling anyway
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Denoise {
+    /// hqdn3d at its default strength - fast, and safe on most sources
+    Light,
+    /// Stronger hqdn3d, for visibly grainy sources
+    Medium,
+    /// nlmeans - much slower, for very noisy sources
+    Heavy,
+}
+
+impl Denoise {
+    fn filter(self) -> &'static str {
+        match self {
+            Denoise::Light => "hqdn3d=4:3:6:4.5",
+            Denoise::Medium => "hqdn3d=8:6:12:9",
+            Denoise::Heavy => "nlmeans=s=4:p=7:r=15",
+        }
+    }
+}
+
+/// Linearise, tone map with hable, then back to BT.709 - see the ffmpeg `tonemap` filter docs
+const TONEMAP_SDR: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+
+/// An image subtitle stream drawn on the video, after the first `at` filters of the chain
+#[derive(Debug)]
+struct Overlay {
+    index: usize,
+    at: usize,
+    /// Where the subtitles' full-frame canvas goes, so they stay put on a cropped picture
+    x: i64,
+    y: i64,
+}
+
+/// How the video stream gets filtered
+#[derive(Debug, Default)]
+pub struct VideoFilters {
+    /// Filters applied in order to the source video
+    chain: Vec<String>,
+    /// An image subtitle stream to overlay partway through the chain, which needs a complex filter
+    /// graph
+    overlay: Option<Overlay>,
+    /// The subtitle stream we are burning in, so it isn't also copied
+    pub burned: Option<usize>,
+}
+
+impl VideoFilters {
+    /// `input` is the file ffmpeg reads, `source` the original we refer to in messages
+    pub fn new(
+        input: &Path,
+        source: &Path,
+        info: Option<&ProbeInfo>,
+        settings: &Settings,
+        opts: &Opts,
+    ) -> VideoFilters {
+        let mut filters = VideoFilters::default();
+        if settings.deinterlace {
+            // bwdif keeps one frame per frame, and deinterlaces before anything is drawn on
+            filters.chain.push("bwdif".to_owned());
+        }
+        if let Some(crop) = settings.crop {
+            // before subtitles, so they are drawn inside the picture rather than cut off with the
+            // bars
+            filters.chain.push(crop.filter());
+        }
+        if let Some(denoise) = opts.denoise {
+            // before subtitles are drawn on, so their edges aren't smudged
+            filters.chain.push(denoise.filter().to_owned());
+        }
+        if let (true, Some(info)) = (opts.burn_forced_subs, info) {
+            filters.burn_forced(input, source, info, settings, opts);
+        }
+        if let Some(max_fps) = settings.max_fps {
+            let source_fps = info.and_then(|i| i.video()).and_then(|v| v.frame_rate());
+            // only ever drop frames - unknown rates are left alone
+            if source_fps.is_some_and(|fps| fps > max_fps) {
+                filters.chain.push(format!("fps={}", max_fps));
+            }
+        }
+        filters
+            .chain
+            .push(format!("scale=-2:'min({},ih)'", settings.max_height));
+        // after scaling, as tone mapping in 32 bit float is slow
+        if let (Some(Tonemap::Sdr), Some(video)) = (opts.tonemap, info.and_then(|i| i.video())) {
+            if video.is_hdr() {
+                info!("tone mapping HDR video in {:?} to SDR", source);
+                filters.chain.push(TONEMAP_SDR.to_owned());
+            }
+        }
+        filters
+    }
+
+    fn burn_forced(
+        &mut self,
+        input: &Path,
+        source: &Path,
+        info: &ProbeInfo,
+        settings: &Settings,
+        opts: &Opts,
+    ) {
+        let subtitles: Vec<&Stream> = info.subtitle_streams().collect();
+        let forced = subtitles.iter().enumerate().filter(|(_, s)| {
+            s.is_forced()
+                || s.get("tags.title")
+                    .is_some_and(|t| t.to_lowercase().contains("forced"))
+        });
+        // prefer the first wanted language, if we were given any
+        let chosen = opts
+            .sub_langs
+            .iter()
+            .find_map(|lang| {
+                forced
+                    .clone()
+                    .find(|(_, s)| s.language().eq_ignore_ascii_case(lang))
+            })
+            .or_else(|| forced.clone().next());
+        let (relative_index, stream) = match chosen {
+            Some(found) => found,
+            None => return,
+        };
+        info!(
+            "burning in forced {} subtitles from stream {} of {:?}",
+            stream.language(),
+            stream.index,
+            source
+        );
+        self.burned = Some(stream.index);
+        if streams::is_text_subtitle(stream.codec_name()) {
+            self.chain.push(format!(
+                "subtitles=filename={}:si={}",
+                escape_value(&input.to_string_lossy()),
+                relative_index
+            ));
+        } else {
+            let (x, y) = settings.crop.map_or((0, 0), |crop| crop.offset());
+            self.overlay = Some(Overlay {
+                index: stream.index,
+                at: self.chain.len(),
+                x: -i64::from(x),
+                y: -i64::from(y),
+            });
+        }
+    }
+
+    /// The filter arguments for ffmpeg
+    pub fn args(&self) -> Vec<String> {
+        let overlay = match &self.overlay {
+            None => return vec!["-filter:V".to_owned(), self.chain.join(",")],
+            Some(overlay) => overlay,
+        };
+        let (before, after) = self.chain.split_at(overlay.at);
+        let video = match before.is_empty() {
+            true => "[0:V:0]".to_owned(),
+            false => format!("[0:V:0]{}[base];[base]", before.join(",")),
+        };
+        let position = match (overlay.x, overlay.y) {
+            (0, 0) => String::new(),
+            (x, y) => format!("=x={}:y={}", x, y),
+        };
+        vec![
+            "-filter_complex".to_owned(),
+            format!(
+                "{}[0:{}]overlay{},{}[v]",
+                video,
+                overlay.index,
+                position,
+                after.join(",")
+            ),
+        ]
+    }
+
+    /// What to `-map` for the output video
+    pub fn video_map(&self) -> &'static str {
+        match self.overlay {
+            None => "0:V:0",
+            Some(_) => "[v]",
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Running your own commands to choose files, after each file and after the run, for
+//! `--filter-hook`, `--post-hook` and `--post-run-hook`
+//!
+//! Commands are run with `sh -c`, with what happened in `DOWNSCALER_...` environment variables. A
+//! post hook that fails is logged as a warning - the file it ran for is still done.
+
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::warn;
+
+use crate::progress::Counts;
+use crate::report::FileResult;
+
+/// Before a file is chosen for the run - why the hook turned it down, if it did
+///
+/// The source is also the hook's `$1`. A non-zero exit skips the file, with the first line the
+/// hook printed as the reason.
+pub fn filter(command: &str, source: &Path, dest: &Path) -> Result<Option<String>> {
+    let output = Command::new("sh")
+        .args(["-c", command, "sh"])
+        .arg(source)
+        .env("DOWNSCALER_SOURCE", source)
+        .env("DOWNSCALER_DEST", dest)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow!("could not run --filter-hook: {}", e))?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(
+        match stdout.lines().map(str::trim).find(|l| !l.is_empty()) {
+            Some(reason) => format!("skipped by --filter-hook: {}", reason),
+            None => "skipped by --filter-hook".to_owned(),
+        },
+    ))
+}
+
+/// After a file has been encoded
+pub fn after_file(command: &str, result: &FileResult) {
+    let output_size = result.output_size.unwrap_or_default();
+    run(
+        command,
+        &format!("--post-hook for {:?}", result.source),
+        vec![
+            ("STATUS", "succeeded".to_owned()),
+            ("SOURCE", result.source.to_string_lossy().into_owned()),
+            ("DEST", result.dest.to_string_lossy().into_owned()),
+            ("SOURCE_SIZE", result.source_size.to_string()),
+            ("OUTPUT_SIZE", output_size.to_string()),
+            (
+                "SAVED",
+                (result.source_size as i64 - output_size as i64).to_string(),
+            ),
+            ("ELAPSED", format!("{:.0}", result.elapsed)),
+            ("RULE", result.rule.clone()),
+            ("NOTES", result.notes.join("; ")),
+        ],
+    );
+}
+
+/// After the run, however it ended
+pub fn after_run(
+    command: &str,
+    counts: Counts,
+    elapsed: Duration,
+    outcome: &Result<()>,
+    sources: &[&Path],
+    destination: &Path,
+) {
+    let sources: Vec<String> = sources
+        .iter()
+        .map(|source| source.to_string_lossy().into_owned())
+        .collect();
+    run(
+        command,
+        "--post-run-hook",
+        vec![
+            (
+                "STATUS",
+                match outcome {
+                    Ok(()) => "succeeded",
+                    Err(_) => "failed",
+                }
+                .to_owned(),
+            ),
+            (
+                "ERROR",
+                outcome
+                    .as_ref()
+                    .err()
+                    .map(|e| format!("{:#}", e))
+                    .unwrap_or_default(),
+            ),
+            // one per line, for `while read`
+            ("SOURCE", sources.join("\n")),
+            ("DEST", destination.to_string_lossy().into_owned()),
+            ("FILES", counts.total.to_string()),
+            ("DONE", counts.done.to_string()),
+            ("FAILED", counts.failed.to_string()),
+            ("SAVED", counts.saved.to_string()),
+            ("ELAPSED", elapsed.as_secs().to_string()),
+        ],
+    );
+}
+
+fn run(command: &str, what: &str, env: Vec<(&str, String)>) {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command]).stdin(Stdio::null());
+    for (key, value) in env {
+        cmd.env(format!("DOWNSCALER_{}", key), value);
+    }
+    // not through the watchdog, so the run hook still runs after Ctrl-C
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("{} failed: {}", what, status),
+        Err(e) => warn!("could not run {}: {}", what, e),
+    }
+}
@@ -0,0 +1,220 @@
+//! Only starting encodes while nothing else wants the machine, for `--only-when-idle`
+//!
+//! Before each file, the checks in the `[idle]` config section are run, and the file waits until
+//! they all pass. Encodes already running are left to finish, so activity starting up pauses the
+//! run gracefully between files rather than killing work in progress.
+
+use std::fs;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::info;
+use log::warn;
+
+use crate::config::Entry;
+use crate::curl::Curl;
+use crate::signals;
+
+/// How often to look at the load average again while it is over `--max-load`
+const LOAD_CHECK: Duration = Duration::from_secs(5);
+
+/// The `[idle]` config section
+#[derive(Debug, Clone)]
+pub struct Idle {
+    /// Highest 1-minute load average that counts as idle - only checked when none of our encodes
+    /// are running, as they would count towards it
+    pub max_load: f64,
+    /// Anyone logged in, according to `who`, counts as activity
+    pub sessions: bool,
+    /// Base URL of a Jellyfin server, and an API key for it
+    pub jellyfin: Option<String>,
+    pub jellyfin_token: Option<String>,
+    /// Base URL of a Plex server, and a token for it
+    pub plex: Option<String>,
+    pub plex_token: Option<String>,
+    /// How long to wait between checks while busy
+    pub check_every: Duration,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle {
+            max_load: 1.0,
+            sessions: false,
+            jellyfin: None,
+            jellyfin_token: None,
+            plex: None,
+            plex_token: None,
+            check_every: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Idle {
+    pub fn set(&mut self, entry: &Entry) -> Result<()> {
+        let url = || entry.value.trim_end_matches('/').to_owned();
+        match entry.key.as_str() {
+            "max_load" => self.max_load = entry.parse()?,
+            "sessions" => self.sessions = entry.parse()?,
+            "jellyfin" => self.jellyfin = Some(url()),
+            "jellyfin_token" => self.jellyfin_token = Some(entry.value.clone()),
+            "plex" => self.plex = Some(url()),
+            "plex_token" => self.plex_token = Some(entry.value.clone()),
+            "check_every" => {
+                self.check_every = *entry
+                    .value
+                    .parse::<humantime::Duration>()
+                    .map_err(|_| anyhow!("line {}: check_every should be like `1m`", entry.line))?
+            }
+            other => {
+                return Err(anyhow!(
+                    "line {}: unknown idle setting {}",
+                    entry.line,
+                    other
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// What is keeping the machine busy, if anything
+    ///
+    /// `running` is how many of our own encodes are in progress
+    pub fn activity(&self, running: usize) -> Option<String> {
+        if running == 0 {
+            match load_average() {
+                Some(load) if load > self.max_load => {
+                    return Some(format!("load average is {:.2}", load))
+                }
+                Some(_) => {}
+                None => warn!("could not read the load average"),
+            }
+        }
+        if self.sessions {
+            let users = command_lines(Command::new("who"));
+            if !users.is_empty() {
+                return Some(format!("{} logged in", users.len()));
+            }
+        }
+        if let Some(url) = &self.jellyfin {
+            let mut curl = Curl::new(10);
+            curl.arg(format!("{}/Sessions?activeWithinSeconds=60", url));
+            if let Some(token) = &self.jellyfin_token {
+                curl.secret("header", &format!("X-Emby-Token: {}", token));
+            }
+            // good enough without a JSON parser - only sessions playing something have this
+            let playing = curl
+                .run()
+                .unwrap_or_default()
+                .matches("\"NowPlayingItem\"")
+                .count();
+            if playing > 0 {
+                return Some(format!("{} Jellyfin streams playing", playing));
+            }
+        }
+        if let Some(url) = &self.plex {
+            let mut curl = Curl::new(10);
+            curl.arg(format!("{}/status/sessions", url));
+            if let Some(token) = &self.plex_token {
+                curl.secret("header", &format!("X-Plex-Token: {}", token));
+            }
+            let xml = curl.run().unwrap_or_default();
+            let playing = xml
+                .split_once("size=\"")
+                .and_then(|(_, rest)| rest.split('"').next())
+                .and_then(|size| size.parse::<usize>().ok())
+                .unwrap_or(0);
+            if playing > 0 {
+                return Some(format!("{} Plex streams playing", playing));
+            }
+        }
+        None
+    }
+
+    /// Block until the machine is idle, or the run is interrupted
+    pub fn wait(&self, running: impl Fn() -> usize) {
+        let mut waiting = false;
+        while !signals::interrupted() {
+            let reason = match self.activity(running()) {
+                Some(reason) => reason,
+                None => break,
+            };
+            if !waiting {
+                info!("waiting for the machine to be idle - {}", reason);
+                waiting = true;
+            }
+            // check often enough to notice Ctrl-C
+            let mut slept = Duration::ZERO;
+            while slept < self.check_every && !signals::interrupted() {
+                thread::sleep(Duration::from_millis(250));
+                slept += Duration::from_millis(250);
+            }
+        }
+        if waiting && !signals::interrupted() {
+            info!("the machine is idle again");
+        }
+    }
+}
+
+/// Block while the 1-minute load average is over `max`, or until the run is interrupted, for
+/// `--max-load`
+///
+/// Our own encodes count towards the load, as they do for `make -l`, so this also holds back
+/// parallel jobs that would overload the machine by themselves.
+pub fn wait_for_load(max: f64) {
+    let mut waiting = false;
+    while !signals::interrupted() {
+        let load = match load_average() {
+            Some(load) if load > max => load,
+            _ => break,
+        };
+        if !waiting {
+            info!(
+                "load average is {:.2}, over --max-load {} - waiting to start more files",
+                load, max
+            );
+            waiting = true;
+        }
+        // the load average is only recalculated every few seconds
+        let mut slept = Duration::ZERO;
+        while slept < LOAD_CHECK && !signals::interrupted() {
+            thread::sleep(Duration::from_millis(250));
+            slept += Duration::from_millis(250);
+        }
+    }
+    if waiting && !signals::interrupted() {
+        info!("load average is under --max-load again");
+    }
+}
+
+/// The 1-minute load average
+fn load_average() -> Option<f64> {
+    let text = fs::read_to_string("/proc/loadavg")
+        .ok()
+        // BSD and macOS: `{ 1.23 1.10 1.00 }`
+        .or_else(|| {
+            let mut cmd = Command::new("sysctl");
+            cmd.args(["-n", "vm.loadavg"]);
+            Some(command_lines(cmd).concat())
+        })?;
+    text.split_whitespace()
+        .find(|word| *word != "{")?
+        .parse()
+        .ok()
+}
+
+/// The non-empty lines a command prints - nothing if it fails
+fn command_lines(mut cmd: Command) -> Vec<String> {
+    match cmd.stdin(Stdio::null()).stderr(Stdio::null()).output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| l.to_owned())
+            .collect(),
+        Ok(_) | Err(_) => Vec::new(),
+    }
+}
@@ -0,0 +1,152 @@
+//! Checking that videos decode cleanly, and that outputs are what we meant to write
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::debug;
+
+use crate::config::Config;
+use crate::probe;
+use crate::probe::ProbeInfo;
+use crate::workers::Slot;
+
+/// How thoroughly to check each finished output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Verify {
+    /// ffprobe it - it opens, has the streams it should, and is as long as the source
+    Probe,
+    /// Probe it, then decode all of it - catches truncation or corruption partway through, at about
+    /// the cost of playing it
+    Full,
+}
+
+/// How far an output's duration may be from what we expected, as a fraction - containers
+/// round differently, and audio can run a little past the video
+const DURATION_TOLERANCE: f64 = 0.01;
+
+/// ...but always allow this many seconds
+const MIN_DURATION_TOLERANCE: f64 = 2.0;
+
+/// Probe a finished output, failing if it doesn't look like an encode of `source`
+///
+/// `expected` is how long it should be - the source duration, unless only part was encoded
+pub fn check_output(
+    output: &Path,
+    source: &ProbeInfo,
+    expected: Option<f64>,
+    config: &Config,
+    slot: Slot,
+) -> Result<()> {
+    debug!("checking {:?}", output);
+    let info =
+        probe::probe(output, config, slot).map_err(|e| anyhow!("ffprobe failed: {:#}", e))?;
+    if info.video().is_none() {
+        return Err(anyhow!("no video stream"));
+    }
+    let had_audio = source.streams.iter().any(|s| s.is_audio());
+    if had_audio && !info.streams.iter().any(|s| s.is_audio()) {
+        return Err(anyhow!("no audio streams"));
+    }
+    if let Some(expected) = expected {
+        let actual = info
+            .duration()
+            .ok_or_else(|| anyhow!("no duration - it may be truncated"))?;
+        let tolerance = (expected * DURATION_TOLERANCE).max(MIN_DURATION_TOLERANCE);
+        if (actual - expected).abs() > tolerance {
+            return Err(anyhow!(
+                "{:.1}s long, but should be {:.1}s",
+                actual,
+                expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Decode every video and audio frame of `input`, failing on any decoding error
+///
+/// This is about as slow as playing the file at full speed with no display, so is opt-in
+pub fn decode_check(input: &Path, config: &Config, slot: Slot) -> Result<()> {
+    debug!("decoding {:?} to check it", input);
+    let mut cmd = config.command("ffmpeg", slot);
+    cmd.args(["-nostdin", "-hide_banner", "-nostats", "-loglevel", "error"])
+        .arg("-i")
+        .arg(input)
+        .args(["-map", "0:V?", "-map", "0:a?", "-f", "null", "-"]);
+    let log = crate::run_for_log(cmd)?;
+    // ffmpeg carries on past most corruption, only logging it
+    match log.lines().find(|l| !l.trim().is_empty()) {
+        Some(first) => Err(anyhow!("decoding errors, starting with: {}", first.trim())),
+        None => Ok(()),
+    }
+}
+
+/// Where to look for blank frames, as fractions of the output's length
+const FRAME_POINTS: [f64; 5] = [0.1, 0.3, 0.5, 0.7, 0.9];
+
+/// A frame whose brightest and darkest pixels are this close is one flat colour
+const FLAT_RANGE: u32 = 12;
+
+/// Is the frame at `at` seconds one flat colour, like the black or grey frames broken hardware
+/// decoding produces?
+fn flat_frame(input: &Path, at: f64, config: &Config, slot: Slot) -> Result<bool> {
+    let mut cmd = config.command("ffmpeg", slot);
+    cmd.args(["-nostdin", "-hide_banner", "-nostats", "-loglevel", "info"])
+        .args(["-ss", &format!("{:.1}", at)])
+        .arg("-i")
+        .arg(input)
+        .args([
+            "-map",
+            "0:V:0",
+            "-filter:V",
+            "signalstats,metadata=mode=print",
+        ])
+        .args(["-frames:v", "1", "-an", "-sn", "-f", "null", "-"]);
+    let log = crate::run_for_log(cmd)?;
+    let value = |key: &str| -> Option<u32> {
+        log.lines()
+            .filter_map(|line| line.split_once(key))
+            .find_map(|(_, value)| value.trim().parse().ok())
+    };
+    Ok(
+        match (
+            value("lavfi.signalstats.YMIN="),
+            value("lavfi.signalstats.YMAX="),
+        ) {
+            (Some(min), Some(max)) => max.saturating_sub(min) < FLAT_RANGE,
+            // no frame there, so nothing to judge
+            _ => false,
+        },
+    )
+}
+
+/// Check a few frames of `output` aren't flat where the same point in `input` has a picture
+///
+/// `offset` is where in `input` the output starts. Returns what looks wrong, if anything - most
+/// checked frames have to be blank, so the odd fade to black doesn't count
+pub fn blank_frames(
+    output: &Path,
+    input: &Path,
+    duration: f64,
+    offset: f64,
+    config: &Config,
+    slot: Slot,
+) -> Result<Option<String>> {
+    let mut blank = 0;
+    for point in FRAME_POINTS {
+        let at = duration * point;
+        if flat_frame(output, at, config, slot)? && !flat_frame(input, offset + at, config, slot)? {
+            debug!("frame at {:.0}s of {:?} is blank", at, output);
+            blank += 1;
+        }
+    }
+    Ok((blank * 2 > FRAME_POINTS.len()).then(|| {
+        format!(
+            "{} of {} sampled frames are blank where the source isn't",
+            blank,
+            FRAME_POINTS.len()
+        )
+    }))
+}
@@ -0,0 +1,75 @@
+//! Detecting interlaced video with ffmpeg's `idet` filter
+//!
+//! Container flags are often wrong for old TV rips, so we look at actual frames instead.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::debug;
+
+use crate::config::Config;
+use crate::workers::Slot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Deinterlace {
+    /// Deinterlace only if a scan of the first frames finds interlacing
+    Auto,
+    /// Always deinterlace
+    Always,
+}
+
+/// How many frames `idet` looks at - enough to get past most intros and black frames
+const SAMPLE_FRAMES: &str = "1000";
+
+/// The counts `idet` reports for its multi-frame detection
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counts {
+    tff: u64,
+    bff: u64,
+    progressive: u64,
+}
+
+/// Parse the last `Multi frame detection:` line from ffmpeg's log
+fn parse_idet(log: &str) -> Option<Counts> {
+    let line = log
+        .lines()
+        .filter_map(|l| l.split_once("Multi frame detection:").map(|(_, rest)| rest))
+        .next_back()?;
+    let mut counts = Counts::default();
+    let mut words = line.split_whitespace();
+    while let (Some(label), Some(value)) = (words.next(), words.next()) {
+        let value = value.parse().ok()?;
+        match label {
+            "TFF:" => counts.tff = value,
+            "BFF:" => counts.bff = value,
+            "Progressive:" => counts.progressive = value,
+            _ => {}
+        }
+    }
+    Some(counts)
+}
+
+/// Does `input` look interlaced?
+pub fn detect(input: &Path, config: &Config, slot: Slot) -> Result<bool> {
+    let mut cmd = config.command("ffmpeg", slot);
+    cmd.args(["-nostdin", "-hide_banner", "-nostats", "-loglevel", "info"])
+        .arg("-i")
+        .arg(input)
+        .args([
+            "-map",
+            "0:V:0",
+            "-filter:V",
+            "idet",
+            "-frames:v",
+            SAMPLE_FRAMES,
+        ])
+        .args(["-an", "-sn", "-f", "null", "-"]);
+    let log = crate::run_for_log(cmd).context("detecting interlacing")?;
+    let counts =
+        parse_idet(&log).ok_or_else(|| anyhow!("no interlace detection results from ffmpeg"))?;
+    debug!("idet counts for {:?}: {:?}", input, counts);
+    Ok(counts.tff + counts.bff > counts.progressive)
+}
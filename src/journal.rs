@@ -0,0 +1,232 @@
+//! Picking up a run where it stopped, for `--journal FILE`
+//!
+//! Once a run has decided what to encode, the journal is written with every file it chose, and
+//! then has a line appended as each one finishes. If the run is stopped - Ctrl-C, a crash, a file
+//! that failed - running it again with the same journal goes straight to the files that are left,
+//! without scanning the sources or deciding again which files need doing. Files that failed are
+//! not tried again; delete their lines to retry them. Once every file is accounted for the journal
+//! is removed, so the next run starts from scratch.
+//!
+//! The format is line based like a plan's, one tab-separated record per line after the header:
+//!
+//! ```text
+//! downscaler-journal 1
+//! destination /media/out
+//! job <source> <dest>
+//! done <source>
+//! failed <source> <error>
+//! skipped <source> <reason>
+//! ```
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+
+use crate::state;
+use crate::state::Format;
+
+const FORMAT: Format = Format {
+    name: "downscaler-journal",
+    version: 1,
+    migrations: &[],
+};
+
+fn utf8(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("can't save {:?} in a journal - it isn't valid UTF-8", path))
+}
+
+/// What an earlier run got through
+#[derive(Debug, Default)]
+pub struct Saved {
+    pub destination: PathBuf,
+    jobs: Vec<PathBuf>,
+    /// Each finished source, and whether it failed
+    finished: HashMap<PathBuf, bool>,
+}
+
+impl Saved {
+    pub fn load(path: &Path) -> Result<Saved> {
+        let lines = FORMAT.load(path)?;
+        Saved::parse(&lines).with_context(|| format!("parsing journal {:?}", path))
+    }
+
+    fn parse(lines: &[String]) -> Result<Saved> {
+        let mut saved = Saved::default();
+        for (index, line) in lines.iter().enumerate() {
+            let fields: Vec<String> = line.split('\t').map(state::unescape).collect();
+            match (fields[0].as_str(), fields.len()) {
+                ("destination", 2) => saved.destination = PathBuf::from(&fields[1]),
+                ("job", 3) => saved.jobs.push(PathBuf::from(&fields[1])),
+                ("done", 2) | ("skipped", 3) => {
+                    saved.finished.insert(PathBuf::from(&fields[1]), false);
+                }
+                ("failed", 3) => {
+                    saved.finished.insert(PathBuf::from(&fields[1]), true);
+                }
+                _ if line.is_empty() => {}
+                // cut short by a crash while it was written
+                _ if index == lines.len() - 1 => {}
+                // after the header
+                _ => return Err(anyhow!("line {}: bad record", index + 2)),
+            }
+        }
+        Ok(saved)
+    }
+
+    /// The sources still to do
+    pub fn remaining(&self) -> Vec<PathBuf> {
+        self.jobs
+            .iter()
+            .filter(|source| !self.finished.contains_key(*source))
+            .cloned()
+            .collect()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.finished.values().filter(|failed| **failed).count()
+    }
+
+    pub fn total(&self) -> usize {
+        self.jobs.len()
+    }
+}
+
+/// A journal being written to
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The journal's jobs this run has yet to record - files queued since aren't in it
+    pending: Mutex<HashSet<PathBuf>>,
+}
+
+impl Journal {
+    /// Start a journal of `jobs`, as (source, dest)
+    pub fn create<'a>(
+        path: &Path,
+        destination: &Path,
+        jobs: impl Iterator<Item = (&'a Path, &'a Path)>,
+    ) -> Result<Journal> {
+        let mut text = format!("destination\t{}\n", state::escape(utf8(destination)?));
+        let mut sources = Vec::new();
+        for (source, dest) in jobs {
+            text.push_str(&format!(
+                "job\t{}\t{}\n",
+                state::escape(utf8(source)?),
+                state::escape(utf8(dest)?)
+            ));
+            sources.push(source);
+        }
+        FORMAT.save(path, &text)?;
+        Journal::append(path, sources.into_iter())
+    }
+
+    /// Carry on with an existing journal, which has the jobs for `remaining` left
+    pub fn append<'a>(path: &Path, remaining: impl Iterator<Item = &'a Path>) -> Result<Journal> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening journal {:?}", path))?;
+        Ok(Journal {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            pending: Mutex::new(remaining.map(Path::to_owned).collect()),
+        })
+    }
+
+    fn record(&self, source: &Path, fields: &[&str]) {
+        let line: Vec<String> = fields.iter().map(|f| state::escape(f)).collect();
+        let mut file = self.file.lock().unwrap();
+        let written = writeln!(file, "{}", line.join("\t")).and_then(|_| file.sync_data());
+        match written {
+            Ok(()) => {
+                self.pending.lock().unwrap().remove(source);
+            }
+            Err(e) => warn!("could not write to journal {:?}: {}", self.path, e),
+        }
+    }
+
+    pub fn done(&self, source: &Path) {
+        self.record(source, &["done", &source.to_string_lossy()]);
+    }
+
+    pub fn failed(&self, source: &Path, error: &str) {
+        self.record(source, &["failed", &source.to_string_lossy(), error]);
+    }
+
+    pub fn skipped(&self, source: &Path, reason: &str) {
+        self.record(source, &["skipped", &source.to_string_lossy(), reason]);
+    }
+
+    /// The run is over - remove the journal if every file is accounted for
+    pub fn finish(&self) {
+        let left = self.pending.lock().unwrap().len();
+        if left > 0 {
+            info!(
+                "{} files are left in journal {:?} - run again with it to carry on",
+                left, self.path
+            );
+            return;
+        }
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => info!(
+                "every file in journal {:?} is done with - removed it",
+                self.path
+            ),
+            Err(e) => warn!("could not remove finished journal {:?}: {}", self.path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_queued_later_dont_count_towards_the_journal() {
+        let path = std::env::temp_dir().join(format!("downscaler-journal-{}", std::process::id()));
+        let (a, b) = (Path::new("/in/a.mkv"), Path::new("/in/b.mkv"));
+        let journal = Journal::create(
+            &path,
+            Path::new("/out"),
+            [(a, Path::new("/out/a.mkv")), (b, Path::new("/out/b.mkv"))].into_iter(),
+        )
+        .unwrap();
+        journal.done(a);
+        journal.done(Path::new("/in/enqueued.mkv"));
+        journal.finish();
+        let saved = Saved::load(&path).unwrap();
+        assert_eq!(saved.remaining(), [b]);
+        assert_eq!(saved.total(), 2);
+        journal.failed(b, "broken\tbadly");
+        journal.finish();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_last_line_cut_short_is_ignored() {
+        let lines = [
+            "destination\t/out",
+            "job\t/in/a.mkv\t/out/a.mkv",
+            "failed\t/in/a.mkv\tx",
+            "do",
+        ]
+        .map(str::to_owned);
+        let saved = Saved::parse(&lines).unwrap();
+        assert!(saved.remaining().is_empty());
+        assert_eq!(saved.failed(), 1);
+        let early = ["do", "job\t/in/a.mkv\t/out/a.mkv"].map(str::to_owned);
+        assert!(Saved::parse(&early).is_err());
+    }
+}
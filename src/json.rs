@@ -0,0 +1,79 @@
+//! Just enough JSON output for reports - we only ever write JSON, never read it
+
+use std::fmt::Write;
+
+/// A JSON string literal, quoted and escaped
+pub fn string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Builds a JSON object one field at a time
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    /// Add a field whose value is already JSON
+    pub fn raw(mut self, key: &str, json: String) -> Object {
+        self.fields.push(format!("{}:{}", string(key), json));
+        self
+    }
+
+    pub fn str(self, key: &str, value: &str) -> Object {
+        self.raw(key, string(value))
+    }
+
+    pub fn num<N: std::fmt::Display>(self, key: &str, value: N) -> Object {
+        self.raw(key, value.to_string())
+    }
+
+    pub fn opt_str(self, key: &str, value: Option<&str>) -> Object {
+        match value {
+            Some(value) => self.str(key, value),
+            None => self.raw(key, "null".to_owned()),
+        }
+    }
+
+    pub fn opt_num<N: std::fmt::Display>(self, key: &str, value: Option<N>) -> Object {
+        match value {
+            Some(value) => self.num(key, value),
+            None => self.raw(key, "null".to_owned()),
+        }
+    }
+
+    /// Add all of another object's fields
+    pub fn extend(mut self, other: Object) -> Object {
+        self.fields.extend(other.fields);
+        self
+    }
+
+    pub fn build(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+/// A JSON array of values that are already JSON
+pub fn array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
@@ -0,0 +1,231 @@
+//! Asking Plex and Jellyfin to pick up new outputs straight away, with a `[library]` config section
+//!
+//! After each file lands in the destination, its folder is scanned: Jellyfin is told the file was
+//! created, and Plex runs a partial scan of the folder in whichever library section holds it. If
+//! the destination is mounted somewhere else on the media server, `path_map` rewrites the paths
+//! they are sent. Everything is sent with `curl`, tokens through its config file, and failures are
+//! only warnings.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::debug;
+use log::warn;
+
+use crate::config::Entry;
+use crate::curl::Curl;
+use crate::json;
+
+/// The `[library]` config section
+#[derive(Debug, Clone, Default)]
+pub struct Library {
+    /// Base URL of a Jellyfin server, and an API key for it
+    pub jellyfin: Option<String>,
+    pub jellyfin_token: Option<String>,
+    /// Base URL of a Plex server, and a token for it
+    pub plex: Option<String>,
+    pub plex_token: Option<String>,
+    /// Destination path prefixes, and what they are on the media server
+    pub path_map: Vec<(String, String)>,
+    /// Plex's library sections, as (key, folders), once fetched
+    sections: OnceLock<Vec<(String, Vec<String>)>>,
+}
+
+impl Library {
+    pub fn set(&mut self, entry: &Entry) -> Result<()> {
+        let url = || entry.value.trim_end_matches('/').to_owned();
+        match entry.key.as_str() {
+            "jellyfin" => self.jellyfin = Some(url()),
+            "jellyfin_token" => self.jellyfin_token = Some(entry.value.clone()),
+            "plex" => self.plex = Some(url()),
+            "plex_token" => self.plex_token = Some(entry.value.clone()),
+            "path_map" => {
+                let (from, to) = entry.value.split_once('=').ok_or_else(|| {
+                    anyhow!(
+                        "line {}: path_map should be like `/mnt/media=/data`",
+                        entry.line
+                    )
+                })?;
+                self.path_map
+                    .push((from.trim().to_owned(), to.trim().to_owned()));
+            }
+            other => {
+                return Err(anyhow!(
+                    "line {}: unknown library setting {}",
+                    entry.line,
+                    other
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// `path` as the media server sees it
+    fn server_path(&self, path: &Path) -> String {
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+        let path = absolute.to_string_lossy().into_owned();
+        for (from, to) in &self.path_map {
+            if let Ok(rest) = Path::new(&path).strip_prefix(from) {
+                return Path::new(to).join(rest).to_string_lossy().into_owned();
+            }
+        }
+        path
+    }
+
+    /// Tell the media servers about a new output
+    pub fn refresh(&self, output: &Path) {
+        let file = self.server_path(output);
+        let folder = Path::new(&file)
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|| file.clone());
+        if let Some(url) = &self.jellyfin {
+            if let Err(e) = self.refresh_jellyfin(url, &file) {
+                warn!("could not ask Jellyfin to scan {:?}: {:#}", folder, e);
+            }
+        }
+        if let Some(url) = &self.plex {
+            if let Err(e) = self.refresh_plex(url, &folder) {
+                warn!("could not ask Plex to scan {:?}: {:#}", folder, e);
+            }
+        }
+    }
+
+    fn refresh_jellyfin(&self, url: &str, file: &str) -> Result<()> {
+        let update = json::Object::new()
+            .str("Path", file)
+            .str("UpdateType", "Created")
+            .build();
+        let body = json::Object::new()
+            .raw("Updates", json::array([update]))
+            .build();
+        let mut curl = Curl::new(30);
+        curl.arg(format!("{}/Library/Media/Updated", url))
+            .args(["-X", "POST", "-H", "Content-Type: application/json"])
+            .args(["--data-binary", "@-"])
+            .input(body);
+        if let Some(token) = &self.jellyfin_token {
+            curl.secret("header", &format!("X-Emby-Token: {}", token));
+        }
+        curl.run()?;
+        debug!("asked Jellyfin to scan {:?}", file);
+        Ok(())
+    }
+
+    /// A request to the Plex server at `url`
+    fn plex(&self, url: &str) -> Curl {
+        let mut curl = Curl::new(30);
+        curl.arg(url);
+        if let Some(token) = &self.plex_token {
+            curl.secret("header", &format!("X-Plex-Token: {}", token));
+        }
+        curl
+    }
+
+    fn refresh_plex(&self, url: &str, folder: &str) -> Result<()> {
+        let sections = match self.sections.get() {
+            Some(sections) => sections,
+            None => {
+                let xml = self.plex(&format!("{}/library/sections", url)).run()?;
+                // not kept, so a server that wasn't ready is asked again next time
+                let sections = plex_sections(&xml);
+                if sections.is_empty() {
+                    return Err(anyhow!("Plex listed no library sections"));
+                }
+                self.sections.get_or_init(|| sections)
+            }
+        };
+        let section = sections
+            .iter()
+            .find(|(_, folders)| folders.iter().any(|f| Path::new(folder).starts_with(f)))
+            .map(|(key, _)| key)
+            .ok_or_else(|| anyhow!("no Plex library section has this folder"))?;
+        self.plex(&format!("{}/library/sections/{}/refresh", url, section))
+            .args(["-G", "--data-urlencode", &format!("path={}", folder)])
+            .run()?;
+        debug!("asked Plex to scan {:?} in section {}", folder, section);
+        Ok(())
+    }
+}
+
+/// Each `<Directory key="...">` in Plex's section list, with its `<Location path="...">`s -
+/// good enough without an XML parser
+fn plex_sections(xml: &str) -> Vec<(String, Vec<String>)> {
+    let attribute = |tag: &str, name: &str| {
+        let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+        let end = tag[start..].find('"')?;
+        Some(unescape(&tag[start..start + end]))
+    };
+    xml.split("<Directory")
+        .skip(1)
+        .filter_map(|directory| {
+            let key = attribute(directory, "key")?;
+            let folders = directory
+                .split("<Location")
+                .skip(1)
+                .filter_map(|location| attribute(location, "path"))
+                .collect();
+            Some((key, folders))
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_have_their_folders() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MediaContainer size="2">
+<Directory art="/:/resources/movie-fanart.jpg" key="1" type="movie" title="Films">
+<Location id="1" path="/data/films" />
+<Location id="3" path="/more/films" />
+</Directory>
+<Directory key="2" type="show" title="TV"><Location id="2" path="/data/tv" /></Directory>
+</MediaContainer>"#;
+        assert_eq!(
+            plex_sections(xml),
+            [
+                (
+                    "1".to_owned(),
+                    vec!["/data/films".to_owned(), "/more/films".to_owned()]
+                ),
+                ("2".to_owned(), vec!["/data/tv".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_are_unescaped() {
+        let xml = r#"<Directory key="1"><Location path="/films/Tom &amp; Jerry &quot;&lt;&gt;&apos; &amp;lt;" /></Directory>"#;
+        assert_eq!(plex_sections(xml)[0].1, ["/films/Tom & Jerry \"<>' &lt;"]);
+    }
+
+    #[test]
+    fn attributes_are_matched_whole() {
+        // `monkey="..."` isn't the key, and a section without one is left out
+        let xml = r#"<Directory monkey="9" key="4"><Location id="1" path="/a" /></Directory><Directory title="no key"></Directory>"#;
+        assert_eq!(
+            plex_sections(xml),
+            [("4".to_owned(), vec!["/a".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn nothing_listed_is_no_sections() {
+        assert!(plex_sections("").is_empty());
+        assert!(plex_sections(r#"<MediaContainer size="0"></MediaContainer>"#).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+//! `--dry-run` listings as a table, JSON or CSV - one row per file the run would consider
+
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::json;
+use crate::report::human_size;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Aligned columns, for reading
+    Table,
+    /// A JSON array of objects, one per file
+    Json,
+    /// Comma-separated, with a header row
+    Csv,
+}
+
+/// One file in a dry run
+#[derive(Debug)]
+pub struct Row<'a> {
+    pub source: &'a Path,
+    pub dest: &'a Path,
+    /// `encode` or `skip`
+    pub action: &'static str,
+    /// Why a file would be skipped
+    pub reason: Option<String>,
+    /// What chose the settings, e.g. `override movies/4k`
+    pub rule: Option<String>,
+    pub settings: Option<String>,
+    /// Projected output size in bytes, if the source could be probed
+    pub estimated_size: Option<u64>,
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+impl Row<'_> {
+    fn fields(&self) -> [String; 7] {
+        [
+            self.source.to_string_lossy().into_owned(),
+            self.dest.to_string_lossy().into_owned(),
+            self.action.to_owned(),
+            self.reason.clone().unwrap_or_default(),
+            self.rule.clone().unwrap_or_default(),
+            self.settings.clone().unwrap_or_default(),
+            self.estimated_size
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+        ]
+    }
+
+    fn to_json(&self) -> String {
+        json::Object::new()
+            .str("source", &self.source.to_string_lossy())
+            .str("dest", &self.dest.to_string_lossy())
+            .str("action", self.action)
+            .opt_str("reason", self.reason.as_deref())
+            .opt_str("rule", self.rule.as_deref())
+            .opt_str("settings", self.settings.as_deref())
+            .opt_num("estimated_size", self.estimated_size)
+            .build()
+    }
+}
+
+const HEADERS: [&str; 7] = [
+    "source",
+    "dest",
+    "action",
+    "reason",
+    "rule",
+    "settings",
+    "estimated_size",
+];
+
+pub fn render(rows: &[Row<'_>], format: ListFormat) -> String {
+    match format {
+        ListFormat::Json => json::array(rows.iter().map(|r| r.to_json())) + "\n",
+        ListFormat::Csv => {
+            let mut text = HEADERS.join(",") + "\n";
+            for row in rows {
+                let fields: Vec<String> = row.fields().iter().map(|f| csv_field(f)).collect();
+                text.push_str(&fields.join(","));
+                text.push('\n');
+            }
+            text
+        }
+        ListFormat::Table => {
+            // the destination mirrors the source, so it is left out to keep rows readable
+            let table: Vec<[String; 5]> = rows
+                .iter()
+                .map(|row| {
+                    [
+                        row.source.to_string_lossy().into_owned(),
+                        match &row.reason {
+                            Some(reason) => format!("{} ({})", row.action, reason),
+                            None => row.action.to_owned(),
+                        },
+                        row.rule.clone().unwrap_or_default(),
+                        row.settings.clone().unwrap_or_default(),
+                        row.estimated_size
+                            .map(|s| human_size(s as i64))
+                            .unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            let headers = ["source", "action", "rule", "settings", "estimate"];
+            let mut widths = headers.map(|h| h.len());
+            for row in &table {
+                for (width, field) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(field.chars().count());
+                }
+            }
+            let line = |fields: &[&str]| {
+                let padded: Vec<String> = fields
+                    .iter()
+                    .zip(widths)
+                    .map(|(field, width)| format!("{:<width$}", field, width = width))
+                    .collect();
+                padded.join("  ").trim_end().to_owned() + "\n"
+            };
+            let mut text = line(&headers);
+            for row in &table {
+                text.push_str(&line(&row.each_ref().map(|f| f.as_str())));
+            }
+            text
+        }
+    }
+}
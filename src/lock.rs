@@ -0,0 +1,89 @@
+//! Stopping two runs on the same source and destination from stomping on each other
+//!
+//! The lock is an OS file lock on `downscaler_<hash>.lock` in the temp directory, where the hash
+//! is of the source roots and the destination - so it is released however the run ends, even if the
+//! machine crashes, and runs on other directories aren't held up. The file holds the pid of the
+//! run holding it, for the error message.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+
+use crate::exit;
+use crate::staging::path_hash;
+
+/// Held for as long as the run goes on
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+fn lock_path(sources: &[&Path], destination: &Path) -> PathBuf {
+    // the same directories however they were given
+    let absolute = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    let mut key = OsString::new();
+    for source in sources {
+        key.push(absolute(source));
+        key.push("\n");
+    }
+    key.push(absolute(destination));
+    env::temp_dir().join(format!("downscaler_{}.lock", path_hash(Path::new(&key))))
+}
+
+impl RunLock {
+    /// Take the lock for `sources` and `destination` - waiting for it if `wait` is set, otherwise
+    /// failing if another run holds it
+    pub fn acquire(sources: &[&Path], destination: &Path, wait: bool) -> Result<RunLock> {
+        let path = lock_path(sources, destination);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("opening lock file {:?}", path))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => "another downscaler".to_owned(),
+                    pid => format!("downscaler process {}", pid),
+                };
+                if !wait {
+                    return Err(exit::Status::Busy.wrap(anyhow!(
+                        "{} is already running on this source and destination - wait for it to finish, or use --wait-for-lock (the lock is {:?})",
+                        holder, path
+                    )));
+                }
+                info!(
+                    "waiting for {} to finish with this source and destination",
+                    holder
+                );
+                file.lock().with_context(|| format!("locking {:?}", path))?;
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("locking {:?}", path))
+            }
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", process::id())?;
+        Ok(RunLock { _file: file })
+    }
+}
@@ -0,0 +1,154 @@
+//! Writing the log to a file as well as the console, for `--log-file`
+//!
+//! The file gets every line the console would, in `--log-format` and without colours, whatever
+//! the console is doing - even while `--tui` has the screen. Once it is bigger than
+//! `--log-max-size` or older than `--log-rotate-every`, it is renamed to `PATH.1` (an older
+//! `PATH.1` becomes `PATH.2`, and so on) and a new one started, keeping `--log-keep` old files.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::logging;
+
+/// When to start a new file, and how many old ones to keep
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub every: Option<Duration>,
+    pub keep: usize,
+}
+
+struct Open {
+    file: File,
+    size: u64,
+    started: SystemTime,
+}
+
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    open: Mutex<Open>,
+}
+
+fn open(path: &Path) -> Result<Open> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening log file {:?}", path))?;
+    let metadata = file.metadata()?;
+    // an existing file carries on from when it was started - as near as the filesystem knows
+    let started = metadata
+        .created()
+        .or_else(|_| metadata.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+    Ok(Open {
+        file,
+        size: metadata.len(),
+        started,
+    })
+}
+
+fn open_fresh(path: &Path) -> Result<Open> {
+    let mut open = open(path)?;
+    // a new file may reuse the old one's inode, and with it its creation time
+    open.started = SystemTime::now();
+    Ok(open)
+}
+
+impl LogFile {
+    pub fn create(path: &Path, rotation: Rotation) -> Result<LogFile> {
+        let open = open(path)?;
+        Ok(LogFile {
+            path: path.to_owned(),
+            rotation,
+            open: Mutex::new(open),
+        })
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn due(&self, open: &Open) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max| open.size > 0 && open.size >= max);
+        let too_old = self.rotation.every.is_some_and(|every| {
+            SystemTime::now()
+                .duration_since(open.started)
+                .is_ok_and(|age| age >= every)
+        });
+        too_big || too_old
+    }
+
+    /// Shift `PATH.n` along to make room, and start a new `PATH`
+    fn rotate(&self, open: &mut Open) -> std::io::Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.numbered(keep));
+            for n in (1..keep).rev() {
+                let _ = fs::rename(self.numbered(n), self.numbered(n + 1));
+            }
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+        if let Ok(new) = open_fresh(&self.path) {
+            *open = new;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, line: &str) {
+        let mut open = self.open.lock().unwrap();
+        if self.due(&open) && self.rotate(&mut open).is_err() {
+            // a log file that can't be rotated keeps growing, rather than losing lines - and
+            // isn't tried again until it is due again
+            open.size = 0;
+            open.started = SystemTime::now();
+        }
+        if writeln!(open.file, "{}", line).is_ok() {
+            open.size += line.len() as u64 + 1;
+        }
+    }
+}
+
+/// Sends each line to the console logger, and to the file
+pub struct Logger {
+    pub console: Box<dyn log::Log>,
+    /// Only used for its levels
+    pub filter: env_logger::Logger,
+    pub file: LogFile,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        self.console.log(record);
+        if self.filter.matches(record) {
+            self.file.write(&logging::line(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        let _ = self.file.open.lock().unwrap().file.flush();
+    }
+}
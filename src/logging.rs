@@ -0,0 +1,118 @@
+//! How log lines look, for `--log-format` - env_logger's text, or JSON to ship to Loki or
+//! Elasticsearch
+//!
+//! In JSON, each line is an object with the `time`, `level`, `target` and `message`. Lines about a
+//! file also have an `event` - `file_started`, `file_succeeded` or `file_failed` - with the `file`
+//! and whatever is known about it by then: `dest`, `rule`, `source_size`, `output_size`,
+//! `duration` and `elapsed` in seconds, and for failures the `error` and its `error_kind`.
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+
+use crate::json;
+use crate::report::FileResult;
+use crate::watchdog;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Fields for the line being logged on this thread
+    static FIELDS: RefCell<Option<json::Object>> = const { RefCell::new(None) };
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Log something with structured fields, which only show in JSON
+pub fn with_fields(fields: json::Object, log: impl FnOnce()) {
+    FIELDS.with(|f| *f.borrow_mut() = Some(fields));
+    log();
+    FIELDS.with(|f| f.borrow_mut().take());
+}
+
+/// A log line in the chosen format - for text, env_logger's, less the colours
+pub fn line(record: &log::Record<'_>) -> String {
+    let time = humantime::format_rfc3339_seconds(SystemTime::now());
+    if !is_json() {
+        return format!(
+            "[{} {:<5} {}] {}",
+            time,
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+    let object = json::Object::new()
+        .str("time", &time.to_string())
+        .str("level", &record.level().as_str().to_lowercase())
+        .str("target", record.target())
+        .str("message", &record.args().to_string());
+    match FIELDS.with(|f| f.borrow().clone()) {
+        Some(fields) => object.extend(fields),
+        None => object,
+    }
+    .build()
+}
+
+/// The fields for a file about to be encoded
+pub fn file_started(source: &Path, dest: &Path) -> json::Object {
+    json::Object::new()
+        .str("event", "file_started")
+        .str("file", &source.to_string_lossy())
+        .str("dest", &dest.to_string_lossy())
+}
+
+/// The fields for a file that has been encoded, or failed - `error` is why
+pub fn file_finished(result: &FileResult, error: Option<&anyhow::Error>) -> json::Object {
+    let event = match result.succeeded() {
+        true => "file_succeeded",
+        false => "file_failed",
+    };
+    json::Object::new()
+        .str("event", event)
+        .str("file", &result.source.to_string_lossy())
+        .str("dest", &result.dest.to_string_lossy())
+        .str("rule", &result.rule)
+        .num("source_size", result.source_size)
+        .opt_num("output_size", result.output_size)
+        .opt_num("duration", result.duration.map(|d| format!("{:.1}", d)))
+        .num("elapsed", format!("{:.1}", result.elapsed))
+        .opt_str("error", result.error.as_deref())
+        .opt_str("error_kind", error.map(error_kind))
+}
+
+/// A rough category for why a file failed, to count failures by
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    let says = |text: &str| error.chain().any(|e| e.to_string().starts_with(text));
+    if watchdog::is_killed(error) {
+        "killed"
+    } else if says("not enough space") {
+        "disk_space"
+    } else if says("source failed its integrity check") {
+        "bad_source"
+    } else if says("the output failed verification") {
+        "verification"
+    } else if says("the source changed") {
+        "source_changed"
+    } else if error.chain().any(|e| e.is::<std::io::Error>()) {
+        "io"
+    } else {
+        "encode"
+    }
+}
@@ -2,122 +2,2707 @@
 #![warn(clippy::all)]
 #![warn(rust_2018_idioms)]
 
+use std::collections::HashSet;
+use std::env;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
+use std::io::Write as _;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use anyhow::anyhow;
+use anyhow::Context as _;
 use anyhow::Result;
 use env_logger::Env;
 
 use clap::Parser;
+use clap::Subcommand;
 use log::debug;
 use log::info;
 use log::warn;
 
-fn downscale(input: OsString, output: OsString) -> Result<()> {
-    info!("downscaling {:?} to {:?}", input, output);
-
-    let mut cmd = Command::new("ffmpeg");
-    cmd.arg("-i")
-        .arg(input)
-        .args([
-            "-c:v",
-            "libx265",
-            "-crf",
-            "28",
-            "-preset",
-            "fast",
-            "-c:a",
-            "copy",
-            "-vf",
-            "scale=-2:'min(720,ih)'",
-            "-loglevel",
-            "warning",
-            "-nostats",
-            "-hide_banner",
-            "-x265-params",
-            "log-level=error",
-        ])
+mod adopt;
+mod arr;
+mod budget;
+mod checksum;
+mod chunks;
+mod claims;
+mod config;
+mod control;
+mod crop;
+mod curl;
+mod daemon;
+mod disk;
+mod doctor;
+mod estimate;
+mod exit;
+mod filelist;
+mod filters;
+mod hooks;
+mod idle;
+mod integrity;
+mod interlace;
+mod journal;
+mod json;
+mod library;
+mod listing;
+mod lock;
+mod logfile;
+mod logging;
+mod mqtt;
+mod notices;
+mod notify;
+mod ocr;
+mod order;
+mod overrides;
+mod plan;
+mod prefetch;
+mod priority;
+mod probe;
+mod progress;
+mod quarantine;
+mod rate;
+mod rclone;
+mod remote;
+mod report;
+mod review;
+mod roots;
+mod s3;
+mod screen;
+mod secrets;
+mod select;
+mod settings;
+mod signals;
+mod size;
+mod staging;
+mod state;
+mod storage;
+mod streams;
+mod sweep;
+mod tui;
+mod vmaf;
+mod watchdog;
+mod web;
+mod webhook;
+mod window;
+mod workers;
+
+use claims::Claim;
+use config::Config;
+use filters::Denoise;
+use filters::Tonemap;
+use filters::VideoFilters;
+use integrity::Verify;
+use interlace::Deinterlace;
+use listing::ListFormat;
+use plan::Plan;
+use plan::Planned;
+use probe::ProbeInfo;
+use progress::Progress;
+use rate::Bitrate;
+use rate::Pass;
+use rate::Rate;
+use report::human_size;
+use report::FileResult;
+use report::Report;
+use roots::SourceRoot;
+use select::Comparison;
+use settings::Settings;
+use size::Percent;
+use size::Size;
+use staging::Staging;
+use storage::Kind;
+use storage::Storage;
+use storage::Stores;
+use streams::Container;
+use streams::SubtitlePolicy;
+use workers::Balance;
+use workers::GpuPool;
+use workers::Slot;
+
+/// Everything a run needs, threaded through the walk and each encode
+struct Context {
+    opts: Opts,
+    config: Config,
+    gpus: Option<GpuPool>,
+    stores: Stores,
+    /// Set with `--checksum-manifest`
+    manifest: Option<checksum::Manifest>,
+    /// Where this run's temp files go
+    temp: staging::RunDir,
+    /// Set with `--quarantine-after`
+    quarantine: Option<quarantine::Quarantine>,
+    progress: Progress,
+    results: Mutex<Vec<FileResult>>,
+    /// Set once the destination drops below `--dest-free-min`
+    low_space: AtomicBool,
+    /// When `--max-runtime` runs out
+    ends: Option<Instant>,
+    /// Files not started because the run-time budget ran out
+    out_of_time: AtomicUsize,
+    /// Set with `--webhook`
+    webhook: Option<webhook::Webhook>,
+    /// Set with `--journal`
+    journal: Option<journal::Journal>,
+    /// Set with `--prefetch`
+    prefetch: Option<prefetch::Prefetch>,
+}
+
+impl Context {
+    /// Record something in the `--journal`, if there is one
+    fn journal(&self, record: impl FnOnce(&journal::Journal)) {
+        if let Some(journal) = &self.journal {
+            record(journal);
+        }
+    }
+
+    /// The `[override]` sections that apply to a source file, least specific first
+    fn overrides(&self, source: &Path) -> Vec<&overrides::Override> {
+        overrides::matching(
+            &self.config.overrides,
+            &self.opts.relative_source(source),
+            &self.opts.flags,
+        )
+    }
+}
+
+/// A file to be downscaled
+#[derive(Debug)]
+struct Job {
+    source: PathBuf,
+    dest: PathBuf,
+    /// Set when running a saved plan
+    planned: Option<Planned>,
+}
+
+/// Video codec arguments - each family of encoder has its own idea of quality and speed settings
+///
+/// `threads` limits how many threads a software encoder uses
+fn video_args(
+    encoder: &str,
+    settings: &Settings,
+    rate: Rate<'_>,
+    slot: Slot,
+    threads: Option<u32>,
+) -> Vec<String> {
+    // `V` rather than `v` so attached pictures like cover art are never re-encoded
+    let mut args = vec!["-c:V".to_owned(), encoder.to_owned()];
+    let nvenc = encoder.ends_with("_nvenc");
+    // nvenc presets are p1 to p7, not the x264 names
+    let preset = if nvenc {
+        "p4"
+    } else {
+        settings.preset.as_str()
+    };
+    let mut x265_params = vec!["log-level=error".to_owned()];
+    match rate {
+        Rate::Quality => {
+            let crf = settings.crf.to_string();
+            let quality: Vec<&str> = if nvenc {
+                vec!["-rc", "vbr", "-cq", &crf]
+            } else if encoder.ends_with("_qsv") {
+                vec!["-global_quality", &crf]
+            } else {
+                vec!["-crf", &crf]
+            };
+            args.extend(quality.iter().map(|a| a.to_string()));
+        }
+        Rate::Bitrate { bitrate, pass } => {
+            if nvenc {
+                args.extend(["-rc".to_owned(), "vbr".to_owned()]);
+            }
+            args.extend(bitrate.args());
+            match pass {
+                // nvenc does both passes in one run
+                Some(_) if nvenc => args.extend(["-multipass".to_owned(), "fullres".to_owned()]),
+                // libx265 ignores ffmpeg's pass options
+                Some(pass) if encoder == "libx265" => {
+                    x265_params.push(format!("pass={}", pass.number));
+                    x265_params.push(format!("stats={}", pass.log.to_string_lossy()));
+                }
+                Some(pass) => {
+                    args.extend(["-pass".to_owned(), pass.number.to_string()]);
+                    args.push("-passlogfile".to_owned());
+                    args.push(pass.log.to_string_lossy().into_owned());
+                }
+                None => {}
+            }
+        }
+    }
+    args.extend(["-preset".to_owned(), preset.to_owned()]);
+    if let (Some(tune), "libx264" | "libx265") = (&settings.tune, encoder) {
+        args.push("-tune".to_owned());
+        args.push(tune.clone());
+    }
+    if let (true, Some(gpu)) = (nvenc, slot.gpu) {
+        args.push("-gpu".to_owned());
+        args.push(gpu.to_string());
+    }
+    if let Some(threads) = threads {
+        match encoder {
+            // x265 sizes its own thread pool, and ignores ffmpeg's -threads
+            "libx265" => x265_params.push(format!("pools={}", threads)),
+            "libsvtav1" => args.extend(["-svtav1-params".to_owned(), format!("lp={}", threads)]),
+            _ if encoder.starts_with("lib") => {
+                args.extend(["-threads".to_owned(), threads.to_string()])
+            }
+            // hardware encoders hardly use the CPU
+            _ => {}
+        }
+    }
+    if encoder == "libx265" {
+        args.push("-x265-params".to_owned());
+        args.push(x265_params.join(":"));
+    }
+    args
+}
+
+/// The software encoder for the same codec as a hardware one, to retry with if it fails
+fn software_fallback(encoder: &str) -> Option<&'static str> {
+    let (codec, device) = encoder.split_once('_')?;
+    if !matches!(device, "nvenc" | "qsv" | "vaapi" | "amf" | "videotoolbox") {
+        return None;
+    }
+    match codec {
+        "hevc" => Some("libx265"),
+        "h264" => Some("libx264"),
+        "av1" => Some("libsvtav1"),
+        _ => None,
+    }
+}
+
+/// The `-pix_fmt` to encode with - `auto` keeps the source's bit depth, in a format the encoder
+/// takes
+fn pix_fmt_args(encoder: &str, pix_fmt: Option<&str>, info: Option<&ProbeInfo>) -> Vec<String> {
+    let pix_fmt = match pix_fmt {
+        Some("auto") => {
+            let depth = info.and_then(|i| i.video()).and_then(|v| v.bit_depth());
+            let hardware = encoder.ends_with("_nvenc") || encoder.ends_with("_qsv");
+            match depth {
+                // hardware encoders only go up to 10 bit
+                Some(10..) if hardware => "p010le",
+                Some(12..) => "yuv420p12le",
+                Some(10..) => "yuv420p10le",
+                Some(_) => "yuv420p",
+                None => return Vec::new(),
+            }
+        }
+        Some(pix_fmt) => pix_fmt,
+        None => return Vec::new(),
+    };
+    vec!["-pix_fmt".to_owned(), pix_fmt.to_owned()]
+}
+
+/// With `--sample`, the start and length of the part to encode - from the middle, where there
+/// are no credits
+fn sample_window(info: Option<&ProbeInfo>, opts: &Opts) -> Option<(f64, f64)> {
+    let length = opts.sample?.as_secs_f64();
+    let duration = info.and_then(|i| i.duration())?;
+    if duration <= length {
+        return None;
+    }
+    Some(((duration - length) / 2.0, length))
+}
+
+/// One way of running the encode
+#[derive(Debug, Clone, Copy)]
+struct Attempt<'a> {
+    encoder: &'a str,
+    /// Carry on past decoding errors in the source
+    lenient: bool,
+}
+
+impl fmt::Display for Attempt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.lenient {
+            true => write!(f, "{} ignoring decoding errors", self.encoder),
+            false => write!(f, "{}", self.encoder),
+        }
+    }
+}
+
+/// Run ffmpeg over the staged input - messages refer to the original source
+fn downscale(
+    job: &Job,
+    staging: &Staging,
+    info: Option<&ProbeInfo>,
+    settings: &Settings,
+    attempt: Attempt<'_>,
+    slot: Slot,
+    ctx: &Context,
+) -> Result<()> {
+    let encoder = attempt.encoder;
+    let (input, output) = (&staging.input, &staging.output);
+    debug!("running ffmpeg on {:?} to {:?}", input, output);
+
+    let opts = &ctx.opts;
+    let filters = VideoFilters::new(input, &job.source, info, settings, opts);
+    let ocr = match info {
+        Some(info) if opts.ocr_subs => {
+            ocr::convert(input, &job.source, output, info, &filters, slot, ctx)?
+        }
+        _ => ocr::OcrSubs::default(),
+    };
+    let maps = streams::map_args(&job.source, output, info, &filters, &ocr.subs, opts);
+    let audio = streams::audio_args(&job.source, info, opts);
+
+    let window = sample_window(info, opts);
+    let ffmpeg = || {
+        let mut cmd = ctx.config.command("ffmpeg", slot);
+        if opts.jobs() > 1 {
+            // parallel ffmpegs fighting over the terminal's stdin is no fun
+            cmd.arg("-nostdin");
+        }
+        if let Some((start, length)) = window {
+            cmd.args([
+                "-ss",
+                &format!("{:.2}", start),
+                "-t",
+                &format!("{:.2}", length),
+            ]);
+        }
+        if attempt.lenient {
+            cmd.args(["-err_detect", "ignore_err", "-fflags", "+discardcorrupt"]);
+        }
+        cmd.arg("-i").arg(input);
+        cmd
+    };
+    let two_pass = opts.two_pass && !encoder.ends_with("_nvenc");
+    let pass = |number| Pass {
+        number,
+        log: &staging.pass_log,
+    };
+    let rate = match opts.bitrate {
+        Some(bitrate) => Rate::Bitrate {
+            bitrate,
+            pass: opts.two_pass.then(|| pass(2)),
+        },
+        None => Rate::Quality,
+    };
+    if let (true, Rate::Bitrate { bitrate, .. }) = (two_pass, rate) {
+        // the first pass only analyses the video, so needs nothing else
+        let first = Rate::Bitrate {
+            bitrate,
+            pass: Some(pass(1)),
+        };
+        let mut cmd = ffmpeg();
+        cmd.args(["-map", filters.video_map()])
+            .args(video_args(
+                encoder,
+                settings,
+                first,
+                slot,
+                opts.encode_threads,
+            ))
+            .args(pix_fmt_args(encoder, opts.pix_fmt.as_deref(), info))
+            .args(filters.args())
+            .args(["-an", "-sn", "-f", "null"])
+            .args(["-loglevel", "warning", "-nostats", "-hide_banner", "-"]);
+        debug!("first pass for {:?}", job.source);
+        run_command(cmd).context("first pass")?;
+    }
+
+    let metadata = metadata_args(opts);
+    let duration = info.and_then(|i| i.duration()).unwrap_or_default();
+    let chunked = opts.chunks.filter(|_| {
+        software_fallback(encoder).is_none()
+            && window.is_none()
+            && !two_pass
+            && !opts.keep_all_streams
+            // burning in subtitles needs them alongside the video
+            && filters.video_map() == "0:V:0"
+            && duration >= opts.chunk_min_length.as_secs_f64()
+    });
+    if let Some(pieces) = chunked {
+        let bare = || {
+            let mut cmd = ctx.config.command("ffmpeg", slot);
+            cmd.arg("-nostdin");
+            cmd
+        };
+        let mut video = video_args(encoder, settings, rate, slot, opts.encode_threads);
+        video.extend(pix_fmt_args(encoder, opts.pix_fmt.as_deref(), info));
+        video.extend(filters.args());
+        video.extend(metadata.iter().map(|a| a.to_string()));
+        let subtitles = info.is_some_and(|i| i.subtitle_streams().next().is_some())
+            && !maps.iter().any(|a| a == "-sn");
+        let audio_streams = info.is_some_and(|i| i.audio_streams().next().is_some());
+        let rest = (audio_streams || subtitles || !ocr.subs.is_empty()).then(|| {
+            let mut cmd = ffmpeg();
+            for sub in &ocr.subs {
+                cmd.arg("-i").arg(&sub.srt);
+            }
+            // the video comes from the pieces
+            let mut maps = maps.iter();
+            while let Some(arg) = maps.next() {
+                match maps.as_slice().first() {
+                    Some(map) if arg == "-map" && map == filters.video_map() => {
+                        maps.next();
+                    }
+                    _ => {
+                        cmd.arg(arg);
+                    }
+                }
+            }
+            cmd.args(&audio).args(&metadata);
+            cmd
+        });
+        debug!("encoding {:?} in {} pieces", job.source, pieces);
+        let chunked = chunks::Chunked {
+            pieces,
+            duration,
+            ffmpeg: &bare,
+            source: &ffmpeg,
+            video,
+            rest,
+            metadata,
+        };
+        return chunks::encode(chunked, output);
+    }
+
+    let mut cmd = ffmpeg();
+    for sub in &ocr.subs {
+        cmd.arg("-i").arg(&sub.srt);
+    }
+    cmd.args(maps)
+        .args(video_args(
+            encoder,
+            settings,
+            rate,
+            slot,
+            opts.encode_threads,
+        ))
+        .args(pix_fmt_args(encoder, opts.pix_fmt.as_deref(), info))
+        .args(audio)
+        .args(filters.args())
+        .args(metadata);
+    cmd.args(["-loglevel", "warning", "-nostats", "-hide_banner"])
         .arg(output);
 
-    let status = cmd.status()?;
+    run_command(cmd)
+}
+
+/// The options for stripping metadata from the output, with `--strip-metadata`
+fn metadata_args(opts: &Opts) -> Vec<&'static str> {
+    if !opts.strip_metadata {
+        return Vec::new();
+    }
+    // bitexact stops ffmpeg adding its own encoder tags back in
+    vec![
+        "-map_metadata",
+        "-1",
+        "-map_metadata:s",
+        "-1",
+        "-fflags",
+        "+bitexact",
+        "-flags:v",
+        "+bitexact",
+        "-flags:a",
+        "+bitexact",
+    ]
+}
+
+/// Run a command, passing its stderr through - if it fails, the error ends with its last stderr
+/// line
+fn run_command(cmd: Command) -> Result<()> {
+    let (status, tail) = watchdog::run(cmd)?;
+    let text = String::from_utf8_lossy(&tail);
+    // ffmpeg's progress lines end in \r, so split on that too
+    let last = text
+        .split(['\n', '\r'])
+        .map(str::trim)
+        .rev()
+        .find(|line| !line.is_empty());
 
-    match status.code() {
+    match (status.code(), last) {
+        (Some(0), _) => Ok(()),
+        (Some(code), Some(last)) => Err(anyhow!("Exited with status code: {} - {}", code, last)),
+        (Some(code), None) => Err(anyhow!("Exited with status code: {}", code)),
+        (None, _) => Err(anyhow!("Process terminated.")),
+    }
+}
+
+/// The settings for one file - from the plan if there is one, otherwise the options and config
+///
+/// Also returns which rule decided them, e.g. `override movies/4k`, `screen-recording` or
+/// `defaults`
+fn choose_settings(job: &Job, info: Option<&ProbeInfo>, ctx: &Context) -> (Settings, String) {
+    if let Some(planned) = &job.planned {
+        return (planned.settings.clone(), "plan".to_owned());
+    }
+    let mut settings = Settings::from_opts(&ctx.opts);
+    let mut rule = "defaults".to_owned();
+    if let (true, Some(info)) = (ctx.opts.detect_screen_recordings, info) {
+        if ctx.config.screen.apply(&job.source, info, &mut settings) {
+            rule = "screen-recording".to_owned();
+        }
+    }
+    // an explicit setting for a directory beats anything guessed from the content
+    for found in ctx.overrides(&job.source) {
+        found.apply(&mut settings);
+        rule = format!("override {}", found.dir.display());
+    }
+    (settings, rule)
+}
+
+/// Run an analysis command like `ffmpeg -f null`, returning what it logged to stderr
+fn run_for_log(cmd: Command) -> Result<String> {
+    let (mut cmd, fetch) = remote::prepare(cmd)?;
+    let output = cmd.output()?;
+    match output.status.code() {
         Some(0) => {
-            info!("Succeeded");
-            Ok(())
+            fetch.fetch()?;
+            Ok(String::from_utf8_lossy(&output.stderr).into_owned())
         }
         Some(code) => Err(anyhow!("Exited with status code: {}", code)),
         None => Err(anyhow!("Process terminated.")),
     }
 }
 
-fn downscale_recursive(root_source: &Path, root_dest: &Path, suffix: &Vec<OsString>) -> Result<()> {
+/// Why the encode should be thrown away in favour of the original, if it should
+fn not_worth_it(staging: &Staging, opts: &Opts) -> Result<Option<String>> {
+    if opts.sample.is_some() {
+        // a sample is always smaller than the whole source
+        return Ok(None);
+    }
+    let source_size = fs::metadata(&staging.input)?.len();
+    let output_size = fs::metadata(&staging.output)?.len();
+    if opts.no_grow && output_size >= source_size {
+        return Ok(Some("no smaller than the source".to_owned()));
+    }
+    if let (Some(min), true) = (opts.min_savings, source_size > 0) {
+        let saved = 100.0 * (1.0 - output_size as f64 / source_size as f64);
+        if saved < min.0 {
+            return Ok(Some(format!(
+                "only {:.1}% smaller than the source, under --min-savings {}",
+                saved.max(0.0),
+                min
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Replace the encoded output with the original - remuxed, if the container is changing
+fn keep_original(source: &Path, staging: &Staging, slot: Slot, ctx: &Context) -> Result<()> {
+    fs::remove_file(&staging.output)?;
+    let container = Container::from_path(&staging.output);
+    if Container::from_path(&staging.input) == container {
+        fs::copy(&staging.input, &staging.output)?;
+        return Ok(());
+    }
+    let remux = |subtitles: bool| {
+        let mut cmd = ctx.config.command("ffmpeg", slot);
+        cmd.args(["-nostdin", "-y"])
+            .arg("-i")
+            .arg(&staging.input)
+            .args(["-map", "0:V", "-map", "0:a?", "-c", "copy"]);
+        if subtitles {
+            cmd.args(["-map", "0:s?", "-c:s", container.text_codec()]);
+        }
+        cmd.args(["-loglevel", "warning", "-nostats", "-hide_banner"])
+            .arg(&staging.output);
+        run_command(cmd)
+    };
+    // bitmap subtitles can't be converted to the new container's text format
+    remux(true)
+        .or_else(|_| {
+            notices::warn(source, "dropping subtitles the new container can't hold");
+            remux(false)
+        })
+        .context("remuxing the original")
+}
+
+/// Check an output in its final place
+fn verify_output(
+    dest: &Path,
+    info: Option<&ProbeInfo>,
+    level: Verify,
+    slot: Slot,
+    ctx: &Context,
+) -> Result<()> {
+    if let Some(info) = info {
+        let expected = match sample_window(Some(info), &ctx.opts) {
+            Some((_, length)) => Some(length),
+            None => info.duration(),
+        };
+        integrity::check_output(dest, info, expected, &ctx.config, slot)?;
+    }
+    if level == Verify::Full {
+        info!("decoding {:?} to check it", dest);
+        integrity::decode_check(dest, &ctx.config, slot)?;
+    }
+    Ok(())
+}
+
+/// What came of a successful encode
+#[derive(Debug, Default)]
+struct Encoded {
+    /// The video duration, if we probed it
+    duration: Option<f64>,
+    /// Which rule decided the settings, with the probe's help if there was one
+    rule: String,
+    /// Decisions worth recording in reports
+    notes: Vec<String>,
+}
+
+/// Split `jobs` into those whose source has been left alone for `wait`, and those still changing
+///
+/// Only sources modified within `wait` are watched, so this only waits if there are some
+fn settle(jobs: Vec<Job>, wait: Duration, store: &dyn Storage) -> Result<(Vec<Job>, Vec<Job>)> {
+    let now = SystemTime::now();
+    let mut before = Vec::new();
+    for job in &jobs {
+        let info = store.stat(&job.source)?;
+        let age = info
+            .and_then(|i| i.modified)
+            .and_then(|m| now.duration_since(m).ok());
+        match age {
+            Some(age) if age >= wait => before.push(None),
+            _ => before.push(Some(info.map(|i| (i.len, i.modified)))),
+        }
+    }
+    let recent = before.iter().filter(|b| b.is_some()).count();
+    if recent > 0 {
+        info!(
+            "waiting {} to see if {} recently changed files are still being written",
+            humantime::format_duration(wait),
+            recent
+        );
+        thread::sleep(wait);
+    }
+    let mut settled = Vec::new();
+    let mut unsettled = Vec::new();
+    for (job, before) in jobs.into_iter().zip(before) {
+        let changed = match before {
+            None => false,
+            Some(before) => {
+                let after = store.stat(&job.source)?.map(|i| (i.len, i.modified));
+                before.is_none() || after != before
+            }
+        };
+        match changed {
+            true => unsettled.push(job),
+            false => settled.push(job),
+        }
+    }
+    Ok((settled, unsettled))
+}
+
+/// `path` if it is a directory, otherwise its nearest ancestor that is - for asking about free
+/// space
+fn existing_dir(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or(Path::new("."))
+        .to_owned()
+}
+
+/// An option that works on the source or destination files directly, if they aren't local
+fn needs_local(opts: &Opts, stores: &Stores) -> Option<&'static str> {
+    let source = [
+        (opts.no_stage, "--no-stage"),
+        (opts.only_resolution.is_some(), "--only-resolution"),
+        (!opts.only_codec.is_empty(), "--only-codec"),
+    ];
+    let dest = [
+        (opts.checksums, "--checksums"),
+        (opts.checksum_manifest, "--checksum-manifest"),
+        (opts.verify.is_some(), "--verify"),
+        (opts.extract_subs, "--extract-subs"),
+        (opts.shared_destination, "--shared-destination"),
+        (opts.dest_free_min.is_some(), "--dest-free-min"),
+        (opts.html_report, "--html-report"),
+        (opts.quarantine_after.is_some(), "--quarantine-after"),
+        (opts.prune_empty_dirs, "--prune-empty-dirs"),
+    ];
+    let both = [
+        (opts.preserve_times, "--preserve-times"),
+        (opts.preserve_perms, "--preserve-perms"),
+    ];
+    let local = (stores.source.is_local(), stores.dest.is_local());
+    source
+        .iter()
+        .filter(|_| !local.0)
+        .chain(dest.iter().filter(|_| !local.1))
+        .chain(both.iter().filter(|_| !(local.0 && local.1)))
+        .find(|(set, _)| *set)
+        .map(|(_, option)| *option)
+}
+
+/// Fail unless the destination has room for `bytes` more, on top of `--dest-free-min`
+fn check_dest_room(dest: &Path, bytes: u64, ctx: &Context) -> Result<()> {
+    if !ctx.stores.dest.is_local() {
+        return Ok(());
+    }
+    let min = ctx.opts.dest_free_min.map_or(0, |min| min.0);
+    disk::check_room(&existing_dir(dest), bytes + min, "the output")
+}
+
+/// Probe and downscale one file
+fn encode_job(job: &Job, slot: Slot, ctx: &Context) -> Result<Encoded> {
+    let _skippable = control::Skippable::new(&job.source);
+    logging::with_fields(logging::file_started(&job.source, &job.dest), || {
+        info!("downscaling {:?} to {:?}", job.source, job.dest)
+    });
+    let stage = !ctx.opts.no_stage;
+    let staging = Staging::new(&job.source, &job.dest, &ctx.temp.path, stage);
+    let stat = || prefetch::before(ctx.stores.source.as_ref(), &job.source);
+    let prefetched = ctx.prefetch.as_ref().and_then(|p| p.take(&job.source));
+    let before = match prefetched {
+        Some(before) => before,
+        None => {
+            let before = stat()?;
+            if stage {
+                let size = before.map_or(0, |(len, _)| len);
+                disk::check_room(&ctx.temp.path, size, "the source")?;
+            }
+            staging.copy_in(ctx.stores.source.as_ref(), &job.source)?;
+            before
+        }
+    };
+    let source_size = before.map_or(0, |(len, _)| len);
+    if stage && stat()? != before {
+        return Err(anyhow!(
+            "the source changed while it was being copied - if it is still being written, --settle can leave files like it for a later run"
+        ));
+    }
+    let verify_source = ctx
+        .overrides(&job.source)
+        .iter()
+        .rev()
+        .find_map(|o| o.verify_source)
+        .unwrap_or(false);
+    if verify_source {
+        info!("checking {:?} decodes cleanly", job.source);
+        integrity::decode_check(&staging.input, &ctx.config, slot)
+            .context("source failed its integrity check")?;
+    }
+    // the screen shows how far through its duration each file is
+    let info = if ctx.opts.needs_probe() || ctx.opts.tui || ctx.opts.serve.is_some() {
+        Some(probe::probe(&staging.input, &ctx.config, slot)?)
+    } else {
+        None
+    };
+    if let Some(duration) = info.as_ref().and_then(|i| i.duration()) {
+        ctx.progress.set_duration(&job.source, duration);
+    }
+    let (mut settings, rule) = choose_settings(job, info.as_ref(), ctx);
+    ctx.progress.regroup(&job.source, &rule);
+    settings.deinterlace = match ctx.opts.deinterlace {
+        Some(Deinterlace::Always) => true,
+        Some(Deinterlace::Auto) => {
+            let interlaced = interlace::detect(&staging.input, &ctx.config, slot)?;
+            if interlaced {
+                info!("deinterlacing {:?}", job.source);
+            }
+            interlaced
+        }
+        None => false,
+    };
+    if let (true, Some(info)) = (ctx.opts.autocrop, &info) {
+        let video = info.video();
+        let size = video
+            .and_then(|v| v.width())
+            .zip(video.and_then(|v| v.height()));
+        if let (Some(duration), Some(size)) = (info.duration(), size) {
+            settings.crop = crop::detect(&staging.input, duration, size, &ctx.config, slot)?;
+            if let Some(crop) = settings.crop {
+                info!("cropping {:?} to {}", job.source, crop);
+            }
+        }
+    }
+    // a directory's explicit crf wins over searching for one
+    let fixed_crf = ctx.overrides(&job.source).iter().any(|o| o.crf.is_some());
+    if let (true, false, Some(info)) = (ctx.opts.auto_crf, fixed_crf, &info) {
+        settings.crf = vmaf::choose_crf(&staging.input, &job.source, info, &settings, slot, ctx)?;
+    }
+    // without an estimate, assume the output could be as big as the source
+    let projected = info
+        .as_ref()
+        .and_then(|info| budget::estimate(info, &settings, &ctx.opts.encoder, ctx.opts.bitrate))
+        .unwrap_or(source_size);
+    disk::check_room(&ctx.temp.path, projected, "the output")?;
+    check_dest_room(&job.dest, projected, ctx)?;
+    let mut notes = Vec::new();
+    let encoder = ctx.opts.encoder.as_str();
+    // hardware encoders fail on things software takes in its stride - odd levels, driver hiccups -
+    // and marginal sources often get through with a faster preset that ignores decoding errors
+    let software = software_fallback(encoder);
+    let mut attempts = vec![Attempt {
+        encoder,
+        lenient: false,
+    }];
+    if let Some(software) = software {
+        attempts.push(Attempt {
+            encoder: software,
+            lenient: false,
+        });
+    }
+    if !ctx.opts.no_lenient_retry {
+        attempts.push(Attempt {
+            encoder: software.unwrap_or(encoder),
+            lenient: true,
+        });
+    }
+    let mut failure: Option<anyhow::Error> = None;
+    for (number, attempt) in attempts.iter().enumerate() {
+        if let Some(e) = failure.take() {
+            notices::warn(
+                &job.source,
+                &format!(
+                    "{} failed ({:#}) - retrying with {}",
+                    attempts[number - 1],
+                    e,
+                    attempt
+                ),
+            );
+            // ffmpeg would ask before overwriting
+            let _ = fs::remove_file(&staging.output);
+        }
+        let mut settings = settings.clone();
+        if attempt.lenient && attempt.encoder.starts_with("libx26") {
+            settings.preset = "veryfast".to_owned();
+        }
+        match downscale(job, &staging, info.as_ref(), &settings, *attempt, slot, ctx) {
+            Ok(()) if number == 0 => break,
+            Ok(()) => {
+                notes.push(format!(
+                    "encoded with {} after {} failed",
+                    attempt, attempts[0]
+                ));
+                break;
+            }
+            // the file has had its chance
+            Err(e)
+                if watchdog::is_killed(&e)
+                    || signals::interrupted()
+                    || control::skip_requested(&job.source) =>
+            {
+                return Err(e)
+            }
+            Err(e) => failure = Some(e),
+        }
+    }
+    if let Some(e) = failure {
+        return Err(match attempts.len() {
+            1 => e,
+            _ => e.context(format!("{} also failed", attempts[attempts.len() - 1])),
+        });
+    }
+    if let Some(reason) = not_worth_it(&staging, &ctx.opts)? {
+        notices::warn(
+            &job.source,
+            &format!("the encode was {} - keeping the original", reason),
+        );
+        notes.push(format!("kept the original - the encode was {}", reason));
+        keep_original(&job.source, &staging, slot, ctx)?;
+    } else if let (true, Some(info)) = (ctx.opts.check_frames, &info) {
+        let (offset, length) = sample_window(Some(info), &ctx.opts)
+            .or_else(|| info.duration().map(|d| (0.0, d)))
+            .unwrap_or_default();
+        let input = &staging.input;
+        let output = &staging.output;
+        if let Some(problem) =
+            integrity::blank_frames(output, input, length, offset, &ctx.config, slot)?
+        {
+            notices::warn(&job.source, &format!("suspect output - {}", problem));
+            notes.push(format!("suspect output, worth a look - {}", problem));
+        }
+    }
+    check_dest_room(&job.dest, fs::metadata(&staging.output)?.len(), ctx)?;
+    if ctx.opts.checksums || ctx.manifest.is_some() {
+        let hash = checksum::sha256_file(&staging.output)?;
+        staging.finish(ctx.stores.dest.as_ref(), &job.dest)?;
+        if ctx.opts.checksums {
+            checksum::write_sidecar(&job.dest, &hash)?;
+        }
+        if let Some(manifest) = &ctx.manifest {
+            let relative = job
+                .dest
+                .strip_prefix(ctx.opts.destination())
+                .unwrap_or(&job.dest);
+            manifest.record(relative, &hash)?;
+        }
+    } else {
+        staging.finish(ctx.stores.dest.as_ref(), &job.dest)?;
+    }
+    if let Some(level) = ctx.opts.verify {
+        if let Err(e) = verify_output(&job.dest, info.as_ref(), level, slot, ctx) {
+            if ctx.opts.remove_bad_outputs {
+                ctx.stores.dest.remove(&job.dest)?;
+            }
+            return Err(e.context("the output failed verification"));
+        }
+    }
+    if ctx.opts.preserve_times || ctx.opts.preserve_perms {
+        staging::copy_attributes(
+            &job.source,
+            &job.dest,
+            ctx.opts.preserve_times,
+            ctx.opts.preserve_perms,
+        )?;
+    }
+    if let Some(review_dir) = &ctx.opts.review_dir {
+        review::retain(&staging.output, &job.dest, review_dir, ctx.opts.review_keep);
+    }
+
+    if let (true, Some(info)) = (ctx.opts.extract_subs, &info) {
+        streams::extract_subtitles(&staging.input, &job.dest, info, slot, ctx)?;
+    }
+    Ok(Encoded {
+        duration: info.and_then(|i| i.duration()),
+        rule,
+        notes,
+    })
+}
+
+fn run_job(job: Job, slot: Slot, ctx: &Context) -> Result<()> {
+    let _prefetched = ctx.prefetch.as_ref().map(|p| p.forget_after(&job.source));
+    if let Some(window) = &ctx.opts.window {
+        window.wait();
+    }
+    if ctx.opts.only_when_idle {
+        ctx.config.idle.wait(|| ctx.progress.running());
+    }
+    if let Some(max) = ctx.opts.max_load {
+        idle::wait_for_load(max);
+    }
+    while signals::paused() && !signals::interrupted() {
+        thread::sleep(Duration::from_millis(250));
+    }
+    if signals::interrupted() {
+        return Err(signals::Interrupted.into());
+    }
+    if control::stopping() {
+        notices::skip(
+            &job.source,
+            "not started - stopping from the control socket",
+        );
+        return Ok(());
+    }
+    if ctx.ends.is_some_and(|ends| Instant::now() >= ends) {
+        if ctx.out_of_time.fetch_add(1, Ordering::SeqCst) == 0 {
+            info!("--max-runtime is used up - finishing the files being encoded, and starting no more");
+        }
+        notices::skip(&job.source, "not started - out of --max-runtime");
+        return Ok(());
+    }
+    if let Some(min) = ctx.opts.dest_free_min {
+        let free = disk::free_space(&existing_dir(&job.dest))?;
+        if free < min.0 {
+            if !ctx.low_space.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "only {} free in the destination, below --dest-free-min {} - stopping after the files being encoded",
+                    human_size(free as i64),
+                    min
+                );
+            }
+            notices::skip(&job.source, "not started - the destination is nearly full");
+            return Ok(());
+        }
+    }
+    let _deadline = watchdog::Deadline::start(&job.source, ctx.opts.timeout.map(|t| *t));
+    let mut transcript = ctx.opts.ffmpeg_logs.as_ref().and_then(|dir| {
+        let mut name: PathBuf = ctx
+            .opts
+            .relative_source(&job.source)
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect();
+        name.as_mut_os_string().push(".log");
+        match watchdog::Transcript::start(dir, &name) {
+            Ok(transcript) => Some(transcript),
+            Err(e) => {
+                warn!(
+                    "could not start an ffmpeg log for {:?}: {:#}",
+                    job.source, e
+                );
+                None
+            }
+        }
+    });
+    let _claim = if ctx.opts.shared_destination {
+        match Claim::acquire(&job.dest, *ctx.opts.claim_lease)? {
+            // another machine may have finished it since we scanned
+            Some(_) if job.dest.exists() => {
+                notices::skip(&job.source, "not overwriting existing output");
+                ctx.progress.finished(&job.source, true);
+                ctx.journal(|j| j.done(&job.source));
+                return Ok(());
+            }
+            Some(claim) => Some(claim),
+            None => {
+                notices::skip(&job.source, "claimed by another machine");
+                ctx.progress.finished(&job.source, true);
+                ctx.journal(|j| j.skipped(&job.source, "claimed by another machine"));
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+    let size = |store: &dyn storage::Storage, path: &Path| match store.stat(path) {
+        Ok(Some(info)) => Some(info.len),
+        _ => None,
+    };
+    let source_size = size(ctx.stores.source.as_ref(), &job.source).unwrap_or(0);
+    let started = Instant::now();
+    ctx.progress.started(&job.source);
+    let outcome = encode_job(&job, slot, ctx);
+    // not this file's fault, so don't count it against it
+    if signals::interrupted() {
+        ctx.progress.finished(&job.source, false);
+        return Err(signals::Interrupted.into());
+    }
+    if outcome.is_err() && control::skip_requested(&job.source) {
+        info!("skipped {:?}", job.source);
+        notices::skip(&job.source, "skipped from the control socket");
+        ctx.progress.finished(&job.source, true);
+        ctx.journal(|j| j.skipped(&job.source, "skipped from the control socket"));
+        return Ok(());
+    }
+    ctx.progress.finished(&job.source, outcome.is_ok());
+    let error = outcome.as_ref().err().map(|e| match &mut transcript {
+        Some(transcript) => {
+            transcript.keep();
+            format!("{:#} - ffmpeg's output is in {:?}", e, transcript.path())
+        }
+        None => format!("{:#}", e),
+    });
+    let elapsed = started.elapsed().as_secs_f64();
+    let dir = job
+        .dest
+        .parent()
+        .and_then(|p| p.strip_prefix(ctx.opts.destination()).ok())
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let output_size = match outcome {
+        Ok(_) => size(ctx.stores.dest.as_ref(), &job.dest),
+        Err(_) => None,
+    };
+    if let Some(output_size) = output_size {
+        ctx.progress.saved(&job.source, source_size, output_size);
+    }
+    let mut quarantined = false;
+    if let Some(quarantine) = &ctx.quarantine {
+        let relative = ctx.opts.relative_source(&job.source);
+        let relative = relative.as_path();
+        match &error {
+            None => quarantine.record_success(relative)?,
+            Some(error) => match quarantine.record_failure(&job.source, relative, error)? {
+                quarantine::Failure::Counted(failures) => warn!(
+                    "{:?} has failed {} of {} times before quarantine",
+                    job.source,
+                    failures,
+                    ctx.opts.quarantine_after.unwrap_or_default()
+                ),
+                quarantine::Failure::Quarantined => {
+                    warn!("quarantined {:?}: {}", job.source, error);
+                    quarantined = true;
+                }
+            },
+        }
+    }
+    // the rule encoding went by, or the group it ended up in if it failed partway
+    let rule = match &outcome {
+        Ok(encoded) => encoded.rule.clone(),
+        Err(_) => ctx
+            .progress
+            .group_of(&job.source)
+            .unwrap_or_else(|| choose_settings(&job, None, ctx).1),
+    };
+    let result = FileResult {
+        source: job.source.clone(),
+        dest: job.dest,
+        dir,
+        rule,
+        source_size,
+        output_size,
+        duration: outcome.as_ref().ok().and_then(|e| e.duration),
+        elapsed,
+        error,
+        notes: match &outcome {
+            Ok(encoded) => encoded.notes.clone(),
+            Err(_) if quarantined => vec!["quarantined".to_owned()],
+            Err(_) => Vec::new(),
+        },
+    };
+    let fields = logging::file_finished(&result, outcome.as_ref().err());
+    match &result.error {
+        None => logging::with_fields(fields, || info!("Succeeded {:?}", result.dest)),
+        Some(error) => {
+            logging::with_fields(fields, || warn!("Failed {:?}: {}", result.source, error))
+        }
+    }
+    if let Some(webhook) = &ctx.webhook {
+        webhook.file_finished(&result);
+    }
+    if let (Some(library), true) = (&ctx.config.library, result.output_size.is_some()) {
+        library.refresh(&result.dest);
+    }
+    if let (Some(hook), true) = (&ctx.opts.post_hook, result.succeeded()) {
+        hooks::after_file(hook, &result);
+    }
+    if let (Some(error), false) = (&result.error, ctx.opts.dry_run) {
+        notify::file_failed(
+            &ctx.config.notifiers,
+            &result.source.to_string_lossy(),
+            error,
+        );
+    }
+    ctx.journal(|j| match &result.error {
+        None => j.done(&result.source),
+        Some(error) => j.failed(&result.source, error),
+    });
+    ctx.results.lock().unwrap().push(result);
+    match outcome {
+        // a quarantined file is dealt with, so the run carries on
+        Err(_) if quarantined => Ok(()),
+        // so does one that hung - it is in the reports as failed
+        Err(e) if watchdog::is_killed(&e) => {
+            warn!("giving up on {:?}: {:#}", job.source, e);
+            Ok(())
+        }
+        outcome => outcome.map(|_| ()),
+    }
+}
+
+/// Write whichever reports were asked for
+fn write_reports(ctx: &Context, started: SystemTime) -> Result<()> {
+    let results = ctx.results.lock().unwrap();
+    let report = Report {
+        started,
+        finished: SystemTime::now(),
+        results: &results,
+    };
+    if let Some(path) = &ctx.opts.report {
+        report.write_json(path)?;
+        info!("wrote report to {:?}", path);
+    }
+    if ctx.opts.html_report {
+        fs::create_dir_all(ctx.opts.destination())?;
+        let path = ctx.opts.destination().join("downscaler-report.html");
+        report.write_html(&path)?;
+        info!("wrote html report to {:?}", path);
+    }
+    Ok(())
+}
+
+/// How far and where the scan goes
+struct Walk {
+    /// How many directories deep to go below the source
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    /// Directories already scanned, by device and inode, so links in a loop don't go round forever
+    visited: HashSet<(u64, u64)>,
+    /// Scan hidden files and directories, and NAS system directories
+    include_hidden: bool,
+    /// Directory names to leave out, lowercased
+    exclude_dirs: Vec<String>,
+}
+
+/// Directories NASes and operating systems keep their own things in - thumbnails, recycle bins,
+/// snapshots - which are worse than useless to encode
+const SYSTEM_DIRS: [&str; 11] = [
+    "#recycle",
+    "#snapshot",
+    "$recycle.bin",
+    "lost+found",
+    "system volume information",
+    "@eadir",
+    "@recycle",
+    "@recently-snapshot",
+    "@sharebin",
+    "@tmp",
+    "@appstore",
+];
+
+/// Hidden files and directories, including macOS `._` files, Synology `@eaDir` and the like
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') || SYSTEM_DIRS.contains(&name.to_lowercase().as_str())
+}
+
+impl Walk {
+    /// Is this the first time the scan has come to `dir`, by any path?
+    fn first_visit(&mut self, dir: &Path, stores: &Stores) -> Result<bool> {
+        match stores.source.stat(dir)?.and_then(|info| info.id) {
+            Some(id) => Ok(self.visited.insert(id)),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Walk the source tree, finding files that need downscaling
+fn scan_recursive(
+    root_source: &Path,
+    root_dest: &Path,
+    suffix: &Vec<OsString>,
+    container: Option<Container>,
+    stores: &Stores,
+    walk: &mut Walk,
+    jobs: &mut Vec<Job>,
+) -> Result<()> {
     let mut source = PathBuf::from(root_source);
     let mut dest = PathBuf::from(root_dest);
     for dir in suffix {
-        source.push(&dir);
-        dest.push(&dir);
+        source.push(dir);
+        dest.push(dir);
     }
-    assert!(&source.is_dir(), "Source is not a directory?!");
+    assert!(stores.source.is_dir(&source), "Source is not a directory?!");
 
-    for entry in fs::read_dir(&source)? {
-        let entry = entry?;
-        let file_type = entry.file_type()?;
-        if file_type.is_dir() {
+    for entry in stores.source.list(&source)? {
+        let path = source.join(&entry.name);
+        let kind = match entry.kind {
+            Kind::Link if walk.follow_symlinks => stores
+                .source
+                .stat(&path)?
+                .map_or(Kind::Other, |info| info.kind),
+            kind => kind,
+        };
+        let name = entry.name.to_string_lossy();
+        if !walk.include_hidden && is_hidden(&name) {
+            let what = match kind {
+                Kind::Dir => "ignoring directory - hidden or for the system",
+                _ => "ignoring file - hidden",
+            };
+            notices::skip(&path, what);
+            continue;
+        }
+        if kind == Kind::Dir {
+            if walk.exclude_dirs.contains(&name.to_lowercase()) {
+                notices::skip(&path, "ignoring directory - in --exclude-dirs");
+                continue;
+            }
+            if walk.max_depth.is_some_and(|max| suffix.len() >= max) {
+                notices::skip(&path, "ignoring directory - deeper than --max-depth");
+                continue;
+            }
+            if walk.follow_symlinks && !walk.first_visit(&path, stores)? {
+                notices::skip(
+                    &path,
+                    "ignoring directory - already scanned, through a link",
+                );
+                continue;
+            }
             let mut new_suffix: Vec<OsString> = suffix.clone();
-            new_suffix.push(entry.file_name());
-            downscale_recursive(root_source, root_dest, &new_suffix)?;
-        } else if file_type.is_file() {
-            let source_file = entry.path();
-            if let Some(ext) = source_file.extension() {
-                if ext == "mp4" || ext == "mkv" {
-                    if !dest.is_dir() {
-                        fs::create_dir_all(&dest)?;
-                    }
-                    let mut dest_file = dest.clone();
-                    dest_file.push(Path::new(&entry.file_name()));
-                    if dest_file.exists() {
-                        debug!("not overwriting {:?}", &dest_file);
-                    } else {
-                        downscale(source_file.into_os_string(), dest_file.into_os_string())?;
-                    }
-                } else {
-                    debug!("ignoring file - wrong extension {:?}", &source_file);
-                }
-            } else {
-                debug!("ignoring file - no extension {:?}", &source_file);
+            new_suffix.push(entry.name);
+            scan_recursive(
+                root_source,
+                root_dest,
+                &new_suffix,
+                container,
+                stores,
+                walk,
+                jobs,
+            )?;
+        } else if kind == Kind::File {
+            jobs.extend(file_job(path, dest.join(&entry.name), container, stores));
+        } else if kind == Kind::Link {
+            notices::skip(&path, "ignoring link - not following links");
+        } else {
+            notices::skip(&path, "ignoring file - not a regular file");
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove empty directories under `dir`, returning how many went - `dir` itself is kept
+fn prune_empty_dirs(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            removed += prune_empty_dirs(&path)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                debug!("removing empty directory {:?}", path);
+                fs::remove_dir(&path)?;
+                removed += 1;
             }
+        }
+    }
+    Ok(removed)
+}
+
+/// The job for `source_file`, if it is a video that hasn't been done - `dest_file` is where it
+/// goes before any change of container
+fn file_job(
+    source_file: PathBuf,
+    mut dest_file: PathBuf,
+    container: Option<Container>,
+    stores: &Stores,
+) -> Option<Job> {
+    match source_file.extension() {
+        Some(ext) if ext == "mp4" || ext == "mkv" => {}
+        Some(_) => {
+            notices::skip(&source_file, "ignoring file - wrong extension");
+            return None;
+        }
+        None => {
+            notices::skip(&source_file, "ignoring file - no extension");
+            return None;
+        }
+    }
+    if let Some(container) = container {
+        dest_file.set_extension(container.extension());
+    }
+    if stores.dest.exists(&dest_file) {
+        notices::skip(&source_file, "not overwriting existing output");
+        return None;
+    }
+    Some(Job {
+        source: source_file,
+        dest: dest_file,
+        planned: None,
+    })
+}
+
+/// Scan every source root for jobs
+fn scan_sources(
+    sources: &[(&Path, PathBuf)],
+    opts: &Opts,
+    stores: &Stores,
+    jobs: &mut Vec<Job>,
+) -> Result<()> {
+    let mut walk = Walk {
+        max_depth: opts.max_depth,
+        follow_symlinks: opts.follow_symlinks,
+        visited: HashSet::new(),
+        include_hidden: opts.include_hidden,
+        exclude_dirs: opts.exclude_dirs.iter().map(|d| d.to_lowercase()).collect(),
+    };
+    for (source, subdir) in sources {
+        if walk.follow_symlinks && !walk.first_visit(source, stores)? {
+            notices::skip(source, "ignoring source - already scanned, through a link");
+            continue;
+        }
+        scan_recursive(
+            source,
+            &opts.output_root().join(subdir),
+            &Vec::new(),
+            opts.container,
+            stores,
+            &mut walk,
+            jobs,
+        )?;
+    }
+    Ok(())
+}
+
+/// The jobs for the files in a `--files-from` list, which go where a scan of the source they are
+/// under would have put them
+fn listed_jobs(
+    list: Vec<PathBuf>,
+    sources: &[(&Path, PathBuf)],
+    opts: &Opts,
+    stores: &Stores,
+    jobs: &mut Vec<Job>,
+) -> Result<()> {
+    // `./videos/a.mkv` from `find` is under `videos`
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    let roots: Vec<PathBuf> = sources.iter().map(|(root, _)| absolute(root)).collect();
+    for path in list {
+        let found = roots
+            .iter()
+            .zip(sources)
+            .find_map(|(absolute_root, (root, subdir))| {
+                let rest = absolute(&path).strip_prefix(absolute_root).ok()?.to_owned();
+                Some((root.join(&rest), opts.output_root().join(subdir).join(rest)))
+            });
+        let (source_file, dest_file) = match found {
+            Some(found) => found,
+            None => {
+                notices::skip(&path, "ignoring file - not under a --source");
+                continue;
+            }
+        };
+        match stores.source.stat(&source_file)?.map(|info| info.kind) {
+            Some(Kind::File) => {
+                jobs.extend(file_job(source_file, dest_file, opts.container, stores))
+            }
+            Some(Kind::Dir) => notices::skip(
+                &source_file,
+                "ignoring directory - --files-from only takes files",
+            ),
+            Some(_) => notices::skip(&source_file, "ignoring file - not a regular file"),
+            None => notices::skip(&source_file, "ignoring file - not found"),
+        }
+    }
+    Ok(())
+}
+
+/// The jobs from a saved plan, skipping any that are no longer what was planned
+fn planned_jobs(plan: Plan, stores: &Stores, jobs: &mut Vec<Job>) {
+    for (source, dest, planned) in plan.jobs {
+        if stores.dest.exists(&dest) {
+            notices::skip(&source, "not overwriting existing output");
+        } else if !planned.matches(stores.source.as_ref(), &source) {
+            notices::skip(&source, "source changed since the plan was made");
         } else {
-            debug!("ignoring file type {:?}", file_type);
+            jobs.push(Job {
+                source,
+                dest,
+                planned: Some(planned),
+            });
         }
     }
+}
+
+/// Probe a source without encoding it - from a copy in the temp directory, if ffprobe can't read
+/// it where it is
+fn probe_source(job: &Job, ctx: &Context) -> Result<ProbeInfo> {
+    if ctx.stores.source.is_local() {
+        return probe::probe(&job.source, &ctx.config, Slot::default());
+    }
+    let staging = Staging::new(&job.source, &job.dest, &ctx.temp.path, true);
+    let size = prefetch::before(ctx.stores.source.as_ref(), &job.source)?.map_or(0, |(len, _)| len);
+    disk::check_room(&ctx.temp.path, size, "the source")?;
+    staging.copy_in(ctx.stores.source.as_ref(), &job.source)?;
+    probe::probe(&staging.input, &ctx.config, Slot::default())
+}
 
+/// Show what would be done, saving it as a plan if asked
+///
+/// `rejected` are the files the `--only-...` filters left out, which listings include
+fn dry_run(
+    jobs: Vec<Job>,
+    rejected: Vec<(Job, String)>,
+    args: Vec<OsString>,
+    ctx: &Context,
+) -> Result<()> {
+    let mut plan = Plan {
+        args: plan::plan_args(args.into_iter())?,
+        jobs: Vec::new(),
+    };
+    let format = ctx.opts.list_format;
+    let mut rows = Vec::new();
+    let probing = ctx.opts.needs_probe() || format.is_some();
+    if probing && !ctx.stores.source.is_local() && !jobs.is_empty() {
+        info!("copying each source to the temp directory to probe it, as ffprobe can't read it remotely");
+    }
+    for job in &jobs {
+        let info = if probing {
+            match probe_source(job, ctx) {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    warn!("could not probe {:?}, leaving it out: {:#}", job.source, e);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let (settings, rule) = choose_settings(job, info.as_ref(), ctx);
+        if format.is_some() {
+            rows.push(listing::Row {
+                source: &job.source,
+                dest: &job.dest,
+                action: "encode",
+                reason: None,
+                rule: Some(rule),
+                settings: Some(settings.describe()),
+                estimated_size: info.as_ref().and_then(|info| {
+                    budget::estimate(info, &settings, &ctx.opts.encoder, ctx.opts.bitrate)
+                }),
+            });
+        } else {
+            info!(
+                "would downscale {:?} to {:?} ({})",
+                job.source,
+                job.dest,
+                settings.describe()
+            );
+        }
+        if ctx.opts.save_plan.is_some() {
+            let planned = Planned::new(ctx.stores.source.as_ref(), &job.source, settings)?;
+            plan.jobs
+                .push((job.source.clone(), job.dest.clone(), planned));
+        }
+    }
+    if let Some(format) = format {
+        rows.extend(rejected.iter().map(|(job, reason)| listing::Row {
+            source: &job.source,
+            dest: &job.dest,
+            action: "skip",
+            reason: Some(reason.clone()),
+            rule: None,
+            settings: None,
+            estimated_size: None,
+        }));
+        rows.sort_by(|a, b| a.source.cmp(b.source));
+        print!("{}", listing::render(&rows, format));
+    }
+    notices::summarise();
+    if let Some(path) = &ctx.opts.save_plan {
+        plan.save(path)?;
+        info!("saved a plan of {} files to {:?}", plan.jobs.len(), path);
+    }
     Ok(())
 }
 
+#[derive(Debug, Subcommand)]
+enum Subcommands {
+    /// Check the `.sha256` sidecars written by `--checksums`
+    Verify {
+        #[clap(value_parser, short, long)]
+        destination: PathBuf,
+    },
+    /// Save a plan of what a run with the options after `--` would do, for `--execute-plan`, e.g.
+    /// `scan plan.txt -- -s videos -d small`
+    Scan {
+        #[clap(value_parser)]
+        file: PathBuf,
+        #[clap(value_parser, last = true, required = true)]
+        options: Vec<OsString>,
+    },
+    /// Save or load the queue of files to encode, with each file's settings
+    #[clap(subcommand)]
+    Queue(QueueCommand),
+    /// Project output sizes per directory from the config file's policies, without encoding
+    /// anything
+    Budget(budget::BudgetOpts),
+    /// Project how much space a run would save per directory, optionally calibrated by encoding a
+    /// few samples
+    Estimate(estimate::EstimateOpts),
+    /// Send a command to a run started with `--control-socket`
+    Ctl {
+        #[clap(value_parser, short = 'S', long)]
+        socket: PathBuf,
+        #[clap(subcommand)]
+        command: control::CtlCommand,
+    },
+    /// Remove working files and temp directories left behind by runs that crashed
+    Clean {
+        /// Look for working files here too, not just in the temp directory
+        #[clap(value_parser, short, long)]
+        destination: Option<PathBuf>,
+        /// The `--temp-dir` runs use, if not the system temp directory
+        #[clap(value_parser, long)]
+        temp_dir: Option<PathBuf>,
+        /// Only list what would be removed
+        #[clap(value_parser, long)]
+        dry_run: bool,
+    },
+    /// Check that ffmpeg, the encoder, and the directories a run with the options after `--` would
+    /// use are all ready, e.g. `doctor -- -s videos -d small`
+    Doctor {
+        #[clap(value_parser, last = true)]
+        options: Vec<OsString>,
+    },
+    /// Take over an existing hand-made mirror, moving each matching file to where downscaler would
+    /// write it
+    Adopt(adopt::AdoptOpts),
+}
+
+#[derive(Debug, Subcommand)]
+enum QueueCommand {
+    /// Save what a run with the options after `--` would encode, e.g.
+    /// `queue export backlog.txt -- -s videos -d small`
+    Export {
+        #[clap(value_parser)]
+        file: PathBuf,
+        #[clap(value_parser, last = true, required = true)]
+        options: Vec<OsString>,
+    },
+    /// Run a saved queue - from new source and destination roots if the files have moved
+    Import {
+        #[clap(value_parser)]
+        file: PathBuf,
+        #[clap(value_parser, short, long)]
+        source: Option<PathBuf>,
+        #[clap(value_parser, short, long)]
+        destination: Option<PathBuf>,
+    },
+}
+
 #[derive(Debug, Parser)]
-#[clap(author, version, about)]
+#[clap(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Opts {
+    #[clap(subcommand)]
+    command: Option<Subcommands>,
+    /// A directory to downscale - repeat for more, with `PATH=SUBDIR` to choose the folder under
+    /// the destination each one's outputs go in
+    #[clap(value_parser, short, long, required_unless_present = "plan")]
+    source: Vec<SourceRoot>,
+    #[clap(value_parser, short, long, required_unless_present = "plan")]
+    destination: Option<PathBuf>,
+    /// Show what would be downscaled, and with what settings, without encoding anything
+    #[clap(value_parser, long)]
+    dry_run: bool,
+    /// With `--dry-run`, list files as a table, JSON or CSV on stdout - with the rule that chose
+    /// each file's settings and an estimated size
+    #[clap(value_enum, long, requires = "dry_run")]
+    list_format: Option<ListFormat>,
+    /// With `--dry-run`, save the plan to this file for a later `--execute-plan` run
+    #[clap(value_parser, long, requires = "dry_run")]
+    save_plan: Option<PathBuf>,
+    /// Run exactly what a saved plan lists, with the options it was made with - changed sources are
+    /// skipped
+    #[clap(
+        value_parser,
+        long = "execute-plan",
+        visible_alias = "plan",
+        conflicts_with = "dry_run"
+    )]
+    plan: Option<PathBuf>,
+    /// Optional config file for extra settings - see the README for the format
     #[clap(value_parser, short, long)]
-    source: PathBuf,
+    config: Option<PathBuf>,
+    /// Turn on the config file overrides with `when = NAME`, e.g. `--flag travel-mode`
+    #[clap(value_parser, long = "flag", value_name = "NAME")]
+    flags: Vec<String>,
+    /// Re-encode audio with this many channels, e.g. 2 for devices that can't play surround
+    #[clap(value_parser, long, conflicts_with = "downmix")]
+    audio_channels: Option<u32>,
+    /// Shorthand for `--audio-channels 2`
+    #[clap(value_parser, long)]
+    downmix: bool,
+    /// Only change the audio channels if the source has more than requested - otherwise copy audio
+    #[clap(value_parser, long)]
+    only_more_channels: bool,
+    /// Only keep audio streams in these languages, e.g. `eng,jpn` - all audio is kept if none match
+    #[clap(value_parser, long, value_delimiter = ',')]
+    audio_langs: Vec<String>,
+    /// Only keep subtitle streams in these languages, e.g. `eng`
+    #[clap(value_parser, long, value_delimiter = ',')]
+    sub_langs: Vec<String>,
+    /// How to handle subtitles - by default ffmpeg picks at most one, and drops some formats
+    #[clap(value_enum, long)]
+    subtitles: Option<SubtitlePolicy>,
+    /// OCR bitmap subtitles the output can't hold (e.g. PGS going to mp4) into text, with the
+    /// `[ocr]` command from the config file
+    #[clap(value_parser, long)]
+    ocr_subs: bool,
+    /// Write this container rather than the source's, e.g. `mp4` for devices that can't play mkv
+    #[clap(value_enum, long)]
+    container: Option<Container>,
+    /// Keep every stream, chapter and attachment from the source, not just ffmpeg's default picks
+    #[clap(
+        value_parser,
+        long,
+        conflicts_with_all = ["audio_langs", "sub_langs", "subtitles", "burn_forced_subs"]
+    )]
+    keep_all_streams: bool,
+    /// Remove all global and per-stream metadata - titles, handler names, encoder tags, and
+    /// languages
+    #[clap(value_parser, long)]
+    strip_metadata: bool,
+    /// Burn forced subtitles (e.g. for foreign-language dialogue) into the video
+    #[clap(value_parser, long)]
+    burn_forced_subs: bool,
+    /// Also write text subtitles to `.lang.srt` files next to each output
+    #[clap(value_parser, long)]
+    extract_subs: bool,
+    /// Give each output the modification time of its source, rather than when it was encoded
+    #[clap(value_parser, long)]
+    preserve_times: bool,
+    /// Give each output the permissions of its source
+    #[clap(value_parser, long)]
+    preserve_perms: bool,
+    /// Write a JSON report of every file processed to this path
+    #[clap(value_parser, long)]
+    report: Option<PathBuf>,
+    /// Write an HTML report to `downscaler-report.html` in the destination
+    #[clap(value_parser, long)]
+    html_report: bool,
+    /// Write a `.sha256` sidecar next to each output, checkable with `sha256sum -c` or the `verify`
+    /// subcommand
+    #[clap(value_parser, long)]
+    checksums: bool,
+    /// Keep every output's checksum in one `downscaler.sha256` manifest at the destination root,
+    /// also checked by `verify`
+    #[clap(value_parser, long)]
+    checksum_manifest: bool,
+    /// Once a source has failed this many times (over any number of runs), set it aside and carry
+    /// on - see `downscaler-quarantine` in the destination
+    #[clap(value_parser, long)]
+    quarantine_after: Option<u32>,
+    /// Also move quarantined sources out of the source tree, into this directory
+    #[clap(value_parser, long, requires = "quarantine_after")]
+    quarantine_dir: Option<PathBuf>,
+    /// Wait to start each file while the 1-minute load average is over this - our own encodes count
+    /// too, so set it above the load this run causes by itself
+    #[clap(value_parser, long)]
+    max_load: Option<f64>,
+    /// Only start files between these local times, e.g. `01:00-07:00` - outside it, files being
+    /// encoded are finished and the rest wait for it to open
+    #[clap(value_parser, long)]
+    window: Option<window::Window>,
+    /// The order to encode files in - otherwise it is the order they are found in, which depends on
+    /// the filesystem
+    #[clap(value_enum, long)]
+    order: Option<order::Order>,
+    /// Encode at most this many files, leaving the rest for later runs
+    #[clap(value_parser, long)]
+    max_files: Option<usize>,
+    /// Start no new files once the run has gone on this long, e.g. `6h` - the files being encoded
+    /// are finished
+    #[clap(value_parser, long)]
+    max_runtime: Option<humantime::Duration>,
+    /// Stop starting new files once the destination has less than this free, e.g. `50G` - files are
+    /// also checked to fit on top of it before they are encoded and stored
+    #[clap(value_parser, long)]
+    dest_free_min: Option<Size>,
+    /// Limit each software encode to about this many threads, so one encode doesn't take every core
+    #[clap(value_parser = clap::value_parser!(u32).range(1..), long)]
+    encode_threads: Option<u32>,
+    /// Split each file at least `--chunk-min-length` long into this many pieces at keyframes, and
+    /// encode them at once - for big files, when one encode doesn't keep every core busy
+    #[clap(value_parser = clap::value_parser!(u32).range(2..), long, conflicts_with_all = ["sample", "two_pass"])]
+    chunks: Option<u32>,
+    /// How long a file has to be for `--chunks` to split it, e.g. `30m`
+    #[clap(value_parser, long, default_value = "20m", requires = "chunks")]
+    chunk_min_length: humantime::Duration,
+    /// Run encodes at this CPU niceness, from 19 (gentlest) down to -20 - below 0 needs root
+    #[clap(value_parser = clap::value_parser!(i32).range(-20..=19), long, allow_hyphen_values = true)]
+    nice: Option<i32>,
+    /// Run encodes in the idle disk I/O class, so they only use the disks when nothing else wants
+    /// them (Linux only)
+    #[clap(value_parser, long)]
+    ionice: bool,
+    /// Have ffmpeg read each source where it is, instead of copying it to the temp directory
+    /// first - for sources on a fast local disk
+    #[clap(value_parser, long)]
+    no_stage: bool,
+    /// Copy up to this many of the next files to the temp directory while the current ones encode -
+    /// for sources on slow network storage. The temp directory needs room for them too
+    #[clap(value_parser = clap::value_parser!(u32).range(1..), long, conflicts_with = "no_stage")]
+    prefetch: Option<u32>,
+    /// Run ffmpeg on this machine over SSH, e.g. `me@gpubox` - files are copied to it and back with
+    /// scp, and everything else runs here
+    #[clap(value_parser, long, value_name = "USER@HOST", conflicts_with_all = ["no_stage", "chunks", "ocr_subs", "extract_subs"])]
+    remote: Option<String>,
+    /// Where `--remote` keeps its copies of the temp files, on the remote machine
+    #[clap(value_parser, long, default_value = "/tmp", requires = "remote")]
+    remote_temp_dir: PathBuf,
+    /// Stage and encode files here instead of the system temp directory - it needs room for the
+    /// largest source and its output
+    #[clap(value_parser, long)]
+    temp_dir: Option<PathBuf>,
+    /// If another run is using the same source and destination, wait for it to finish instead of
+    /// stopping
+    #[clap(value_parser, long)]
+    wait_for_lock: bool,
+    /// Listen for commands from `downscaler ctl` on this Unix socket
+    #[clap(value_parser, long)]
+    control_socket: Option<PathBuf>,
+    /// Serve a web dashboard and JSON API for the run on this address, e.g. `0.0.0.0:8080`
+    #[clap(value_parser, long, conflicts_with = "dry_run")]
+    serve: Option<String>,
+    /// Run this shell command for each file found, skipping the file if it fails - the file's path
+    /// is in `$1`
+    #[clap(value_parser, long)]
+    filter_hook: Option<String>,
+    /// Run this shell command after each file is encoded, with its details in `DOWNSCALER_...`
+    /// environment variables
+    #[clap(value_parser, long)]
+    post_hook: Option<String>,
+    /// Run this shell command once the run is over, with how it went in `DOWNSCALER_...`
+    /// environment variables
+    #[clap(value_parser, long)]
+    post_run_hook: Option<String>,
+    /// POST JSON to this URL when the run starts, as each file finishes, and when the run ends
+    #[clap(value_parser, long)]
+    webhook: Option<String>,
+    /// How log lines look - `json` gives one object per line, with fields for each file's events
+    #[clap(value_enum, long, default_value = "text")]
+    log_format: logging::Format,
+    /// Keep track of the run in FILE as it goes - if FILE is there, carry on with the files it
+    /// has left instead of scanning the sources
+    #[clap(value_parser, long, value_name = "FILE", conflicts_with_all = ["dry_run", "plan"])]
+    journal: Option<PathBuf>,
+    /// Copy each file's ffmpeg output to `DIR/<source path>.log`, kept if the file fails
+    #[clap(value_parser, long, value_name = "DIR")]
+    ffmpeg_logs: Option<PathBuf>,
+    /// Also write the log to this file, without colours
+    #[clap(value_parser, long)]
+    log_file: Option<PathBuf>,
+    /// Start a new `--log-file` once it reaches this size
+    #[clap(value_parser, long, requires = "log_file")]
+    log_max_size: Option<Size>,
+    /// Start a new `--log-file` once it is this old, e.g. `1day`
+    #[clap(value_parser, long, requires = "log_file")]
+    log_rotate_every: Option<humantime::Duration>,
+    /// How many old `--log-file`s to keep, as `PATH.1`, `PATH.2` and so on
+    #[clap(value_parser, long, default_value_t = 7, requires = "log_file")]
+    log_keep: usize,
+    /// Show the run full screen - what is encoding, the queue and recent files - with keys to
+    /// pause, skip and reorder
+    #[clap(value_parser, long, conflicts_with = "dry_run")]
+    tui: bool,
+    /// Only start each file while the machine is otherwise idle, as set in the config file's
+    /// `[idle]` section
+    #[clap(value_parser, long)]
+    only_when_idle: bool,
+    /// Give up on a file that takes longer than this, e.g. `3h`, and move on to the next
+    #[clap(value_parser, long)]
+    timeout: Option<humantime::Duration>,
+    /// Kill ffmpeg if it reports no progress for this long, e.g. `10m`, and move on to the next
+    /// file
+    #[clap(value_parser, long)]
+    stall: Option<humantime::Duration>,
+    /// Don't retry a failed encode with a faster preset, ignoring decoding errors in the source
+    #[clap(value_parser, long)]
+    no_lenient_retry: bool,
+    /// Check each output once it is in place - `probe` checks its streams and duration with
+    /// ffprobe, `full` also decodes all of it
+    #[clap(value_enum, long)]
+    verify: Option<Verify>,
+    /// Look at a few frames of each output, and flag it if they are blank where the source isn't -
+    /// a classic hardware failure
+    #[clap(value_parser, long)]
+    check_frames: bool,
+    /// Remove outputs that fail `--verify`, rather than leaving them for a look
+    #[clap(value_parser, long, requires = "verify")]
+    remove_bad_outputs: bool,
+    /// Keep a copy of the most recent outputs here, for spot-checking quality without going to the
+    /// destination
+    #[clap(value_parser, long)]
+    review_dir: Option<PathBuf>,
+    /// How many outputs to keep in `--review-dir` - older ones are removed
+    #[clap(value_parser, long, default_value_t = 10)]
+    review_keep: usize,
+    /// Only downscale videos with a matching height, e.g. `>=2160`, `<720` or `1080p`
+    #[clap(value_parser, long)]
+    only_resolution: Option<Comparison>,
+    /// Only downscale videos in these codecs, e.g. `h264,mpeg2video`
+    #[clap(value_parser, long, value_delimiter = ',')]
+    only_codec: Vec<String>,
+    /// Only downscale files last modified at least this long ago, e.g. `2y` or `6months`
+    #[clap(value_parser, long)]
+    only_older_than: Option<humantime::Duration>,
+    /// Only go this many directories deep below the source - 0 takes just the files directly in it
+    #[clap(value_parser, long)]
+    max_depth: Option<usize>,
+    /// Follow symbolic links to files and directories, rather than ignoring them - a directory
+    /// reached twice is only scanned once
+    #[clap(value_parser, long)]
+    follow_symlinks: bool,
+    /// Scan hidden files and directories, and NAS system directories like `@eaDir` and `#recycle`,
+    /// which are left out by default
+    #[clap(value_parser, long)]
+    include_hidden: bool,
+    /// Leave out files smaller than this with a release sample name, like `movie-sample.mkv` or
+    /// `Sample/movie.mkv` - `0` keeps them all
+    #[clap(value_parser, long, default_value = "300M")]
+    release_sample_max_size: Size,
+    /// The words that mark a file, or the directory it is in, as a release sample
+    #[clap(value_parser, long, value_delimiter = ',', default_value = "sample")]
+    release_sample_names: Vec<String>,
+    /// Leave out directories with these names, wherever they are, e.g. `extras,featurettes`
+    #[clap(value_parser, long, value_delimiter = ',')]
+    exclude_dirs: Vec<String>,
+    /// Take the files listed in this file, one per line (`-` for stdin), instead of scanning the
+    /// sources - each has to be under a `--source`
+    #[clap(value_parser, long)]
+    files_from: Option<PathBuf>,
+    /// The `--files-from` list is separated by NULs, as from `find -print0`
+    #[clap(value_parser, short = '0', long, requires = "files_from")]
+    null: bool,
+    /// Keep running as a service: encode what needs doing, then scan the sources again every
+    /// `--scan-every` until stopped with SIGTERM
+    #[clap(value_parser, long, conflicts_with_all = ["dry_run", "tui", "files_from", "file", "arr"])]
+    daemon: bool,
+    /// How long `--daemon` waits between scans
+    #[clap(value_parser, long, default_value = "5m")]
+    scan_every: humantime::Duration,
+    /// Write the process id to this file while `--daemon` runs
+    #[clap(value_parser, long, requires = "daemon")]
+    pid_file: Option<PathBuf>,
+    /// Only encode this file, which has to be under a `--source`
+    #[clap(value_parser, long, conflicts_with_all = ["files_from", "arr"])]
+    file: Option<PathBuf>,
+    /// Only encode the file Sonarr or Radarr just imported, for running as their custom script -
+    /// waits for any other run on the same directories to finish
+    #[clap(value_parser, long, conflicts_with = "files_from")]
+    arr: bool,
+    /// Only downscale files modified since this date, e.g. `2024-01-01`, or this long ago, e.g.
+    /// `30d`
+    #[clap(value_parser, long)]
+    since: Option<select::Since>,
+    /// Leave out files that change within this long of being found, e.g. `1m` - as they are
+    /// probably still being downloaded or copied
+    #[clap(value_parser, long)]
+    settle: Option<humantime::Duration>,
+    /// Only downscale files at least this big, e.g. `50M` - leaving out short clips and files that
+    /// are already small
+    #[clap(value_parser, long)]
+    min_size: Option<Size>,
+    /// Only downscale files at most this big, e.g. `20G`
+    #[clap(value_parser, long)]
+    max_size: Option<Size>,
+    /// Other machines may be working on the same destination - claim each output before encoding it
+    #[clap(value_parser, long)]
+    shared_destination: bool,
+    /// With `--shared-destination`, how long an untouched claim lasts before another machine can
+    /// take it over
+    #[clap(value_parser, long, default_value = "10m")]
+    claim_lease: humantime::Duration,
+    /// After the run, remove any empty directories left in the destination
+    #[clap(value_parser, long)]
+    prune_empty_dirs: bool,
+    /// Only encode this much from the middle of each file, e.g. `60s`, into `samples/` in the
+    /// destination - for trying out settings
+    #[clap(value_parser, long, conflicts_with_all = ["plan", "save_plan"])]
+    sample: Option<humantime::Duration>,
+    /// Log every skipped file, rather than a count per directory at the end
+    #[clap(value_parser, long)]
+    verbose_skips: bool,
+    /// If an encode is no smaller than its source, write the original (remuxed if `--container`
+    /// changes it) instead
+    #[clap(value_parser, long)]
+    no_grow: bool,
+    /// Write the original instead of any encode that doesn't save at least this much, e.g. `10%`
+    #[clap(value_parser, long)]
+    min_savings: Option<Percent>,
+    /// ffmpeg video encoder, e.g. `hevc_nvenc` for NVidia hardware encoding
+    #[clap(value_parser, long, default_value = "libx265")]
+    encoder: String,
+    /// Video quality - lower is better and bigger. Hardware encoders get their nearest equivalent
+    #[clap(value_parser, long, default_value_t = 28)]
+    crf: u32,
+    /// Encode to this average bitrate, e.g. `2M`, rather than a constant quality - `--crf` is
+    /// ignored
+    #[clap(value_parser, long)]
+    bitrate: Option<Bitrate>,
+    /// Pick the CRF for each file by encoding samples and scoring them with VMAF - needs ffmpeg
+    /// with libvmaf
+    #[clap(value_parser, long, conflicts_with = "bitrate")]
+    auto_crf: bool,
+    /// With `--auto-crf`, the VMAF score outputs should reach - 0 to 100, where 93 is hard to tell
+    /// from the source
+    #[clap(value_parser, long, default_value_t = 93.0)]
+    target_vmaf: f64,
+    /// With `--bitrate`, analyse the video in a first pass to hit the target more accurately
+    #[clap(value_parser, long, requires = "bitrate")]
+    two_pass: bool,
+    /// Encoder speed preset, e.g. `slow` - ignored for nvenc
+    #[clap(value_parser, long, default_value = "fast")]
+    preset: String,
+    /// Reduce the frame rate of faster sources to this, e.g. 30 for 60 fps sports or screen
+    /// recordings
+    #[clap(value_parser, long)]
+    max_fps: Option<f64>,
+    /// Scale videos down to at most this many lines tall - shorter ones keep their size
+    #[clap(value_parser = clap::value_parser!(u32).range(2..), long, default_value_t = settings::DEFAULT_MAX_HEIGHT)]
+    max_height: u32,
+    /// Deinterlace with bwdif - `auto` checks the first frames for interlacing with ffmpeg's idet
+    /// filter
+    #[clap(value_enum, long)]
+    deinterlace: Option<Deinterlace>,
+    /// Detect black bars at a few points in each video, and crop them off before scaling
+    #[clap(value_parser, long)]
+    autocrop: bool,
+    /// Denoise before scaling - grainy sources compress far better denoised
+    #[clap(value_enum, long)]
+    denoise: Option<Denoise>,
+    /// Output pixel format, e.g. `yuv420p10le`, or `auto` to keep the source's bit depth
+    #[clap(value_parser, long)]
+    pix_fmt: Option<String>,
+    /// Tone map HDR sources, e.g. `sdr` for devices that show HDR washed-out
+    #[clap(value_enum, long)]
+    tonemap: Option<Tonemap>,
+    /// Use a lower frame rate and higher CRF for files that look like screen recordings - tune it
+    /// in the config file
+    #[clap(value_parser, long)]
+    detect_screen_recordings: bool,
+    /// How many files to encode at once - defaults to 1, or enough to fill every GPU in `--gpus`
     #[clap(value_parser, short, long)]
-    destination: PathBuf,
+    jobs: Option<usize>,
+    /// GPU device numbers to spread jobs across, e.g. `0,1`
+    #[clap(value_parser, long, value_delimiter = ',')]
+    gpus: Vec<u32>,
+    /// Most jobs to run at once on any one GPU
+    #[clap(value_parser, long, default_value_t = 1)]
+    jobs_per_gpu: usize,
+    /// How to choose a GPU for each job
+    #[clap(value_enum, long, default_value_t = Balance::RoundRobin)]
+    gpu_balance: Balance,
 }
 
-fn main() -> Result<()> {
+impl Opts {
+    /// Each source root, with the folder under the destination its outputs go in
+    fn sources(&self) -> Result<Vec<(&Path, PathBuf)>> {
+        roots::subdirs(&self.source)
+    }
+
+    /// `source` relative to its root, under that root's folder in the destination - what
+    /// overrides and quarantine records match against
+    fn relative_source(&self, source: &Path) -> PathBuf {
+        self.sources()
+            .unwrap_or_default()
+            .iter()
+            .find_map(|(root, subdir)| source.strip_prefix(root).ok().map(|r| subdir.join(r)))
+            .unwrap_or_else(|| source.to_owned())
+    }
+
+    fn destination(&self) -> &Path {
+        self.destination
+            .as_deref()
+            .expect("clap requires a destination")
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(env::temp_dir)
+    }
+
+    /// Where outputs go - samples are kept apart from real outputs
+    fn output_root(&self) -> PathBuf {
+        match self.sample {
+            Some(_) => self.destination().join("samples"),
+            None => self.destination().to_owned(),
+        }
+    }
+
+    fn audio_channels(&self) -> Option<u32> {
+        if self.downmix {
+            Some(2)
+        } else {
+            self.audio_channels
+        }
+    }
+
+    fn jobs(&self) -> usize {
+        match self.jobs {
+            Some(jobs) => jobs.max(1),
+            None if !self.gpus.is_empty() => self.gpus.len() * self.jobs_per_gpu.max(1),
+            None => 1,
+        }
+    }
+
+    /// Do we need ffprobe information to build the ffmpeg command?
+    fn needs_probe(&self) -> bool {
+        self.only_more_channels
+            || self.extract_subs
+            || self.detect_screen_recordings
+            || self.tonemap.is_some()
+            || self.max_fps.is_some()
+            || self.autocrop
+            || self.auto_crf
+            || self.sample.is_some()
+            || self.verify.is_some()
+            || self.check_frames
+            || self.pix_fmt.as_deref() == Some("auto")
+            || self.report.is_some()
+            || self.chunks.is_some()
+            || self.html_report
+            || streams::explicit_mapping(self)
+    }
+}
+
+fn main() -> ExitCode {
+    let opts = match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) => {
+            let _ = e.print();
+            // --help and --version come this way too
+            return match e.use_stderr() {
+                true => ExitCode::from(exit::Status::Config as u8),
+                false => ExitCode::SUCCESS,
+            };
+        }
+    };
+    let outcome = start(opts);
+    if let Err(e) = &outcome {
+        eprintln!("Error: {:?}", e);
+    }
+    exit::code(&outcome)
+}
+
+/// Set up logging, and do whatever the command line says
+fn start(opts: Opts) -> Result<()> {
     // set log level to info
     // override with `RUST_LOG=debug` or similar
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let env = || Env::default().default_filter_or("info");
+    logging::set_format(opts.log_format);
+    let mut builder = env_logger::Builder::from_env(env());
+    if opts.log_format == logging::Format::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", logging::line(record)));
+    }
+    let logger = builder.build();
+    let max_level = logger.filter();
+    let console: Box<dyn log::Log> = match opts.tui {
+        true => Box::new(tui::Logger(logger)),
+        false => Box::new(logger),
+    };
+    match &opts.log_file {
+        Some(path) => {
+            let rotation = logfile::Rotation {
+                max_size: opts.log_max_size.map(|size| size.0),
+                every: opts.log_rotate_every.map(Into::into),
+                keep: opts.log_keep,
+            };
+            log::set_boxed_logger(Box::new(logfile::Logger {
+                console,
+                // the file takes the same `RUST_LOG` levels as the console
+                filter: env_logger::Builder::from_env(env()).build(),
+                file: logfile::LogFile::create(path, rotation)?,
+            }))?
+        }
+        None => log::set_boxed_logger(console)?,
+    }
+    log::set_max_level(max_level);
+
+    match &opts.command {
+        Some(Subcommands::Verify { destination }) => checksum::verify(destination),
+        Some(Subcommands::Budget(budget)) => budget::run(budget),
+        Some(Subcommands::Estimate(estimate)) => estimate::run(estimate),
+        Some(Subcommands::Doctor { options }) => doctor::run(options),
+        Some(Subcommands::Adopt(adopt)) => adopt::run(adopt),
+        Some(Subcommands::Clean {
+            destination,
+            temp_dir,
+            dry_run,
+        }) => {
+            let store = match destination {
+                Some(destination) => storage::open(destination)?,
+                None => Box::new(storage::Local),
+            };
+            let temp_dir = temp_dir.clone().unwrap_or_else(env::temp_dir);
+            let swept = sweep::sweep(store.as_ref(), destination.as_deref(), &temp_dir, *dry_run)?;
+            info!("{}", swept.summary(*dry_run));
+            Ok(())
+        }
+        Some(Subcommands::Ctl { socket, command }) => control::send(socket, *command),
+        Some(Subcommands::Scan { file, options })
+        | Some(Subcommands::Queue(QueueCommand::Export { file, options })) => {
+            let mut args = vec![OsString::from("downscaler")];
+            args.extend(options.iter().cloned());
+            args.extend(["--dry-run".into(), "--save-plan".into(), file.into()]);
+            let opts = Opts::try_parse_from(&args)?;
+            run(opts, None, args)
+        }
+        Some(Subcommands::Queue(QueueCommand::Import {
+            file,
+            source,
+            destination,
+        })) => {
+            let mut plan = Plan::load(file)?;
+            plan.rebase(source.as_deref(), destination.as_deref())?;
+            run_saved_plan(file, plan)
+        }
+        None => match &opts.plan {
+            Some(path) => run_plan(path),
+            None if opts.daemon => daemon::run(
+                env::args_os().collect(),
+                *opts.scan_every,
+                opts.pid_file.as_deref(),
+            ),
+            None => run(opts, None, env::args_os().collect()),
+        },
+    }
+}
+
+fn run_plan(path: &Path) -> Result<()> {
+    let mut shared = false;
+    for arg in env::args_os().skip(1) {
+        let text = arg.to_string_lossy();
+        match text.as_ref() {
+            "--plan" | "--execute-plan" => {}
+            // so several machines can work through one plan
+            "--shared-destination" => shared = true,
+            _ if arg == path
+                || text.starts_with("--plan=")
+                || text.starts_with("--execute-plan=") => {}
+            _ => {
+                return Err(anyhow!(
+                    "--execute-plan takes its options from the plan file - only --shared-destination can be added"
+                ))
+            }
+        }
+    }
+    let mut plan = Plan::load(path)?;
+    if shared && !plan.args.iter().any(|a| a == "--shared-destination") {
+        plan.args.push("--shared-destination".to_owned());
+    }
+    run_saved_plan(path, plan)
+}
+
+fn run_saved_plan(path: &Path, plan: Plan) -> Result<()> {
+    let args: Vec<OsString> = std::iter::once("downscaler".to_owned())
+        .chain(plan.args.iter().cloned())
+        .map(OsString::from)
+        .collect();
+    let opts = Opts::try_parse_from(&args).context("parsing the options saved in the plan")?;
+    info!("running plan {:?} of {} files", path, plan.jobs.len());
+    run(opts, Some(plan), args)
+}
+
+/// `args` is the command line `opts` came from, for saving in plans
+fn run(opts: Opts, plan: Option<Plan>, args: Vec<OsString>) -> Result<()> {
+    let begun = Instant::now();
+    notices::set_verbose_skips(opts.verbose_skips);
+    watchdog::set_stall_limit(opts.stall.map(|s| *s));
+    if opts.tui && !tui::available() {
+        return Err(anyhow!(
+            "--tui needs a terminal, for both its input and output"
+        ));
+    }
+    watchdog::set_interactive(opts.tui);
+    watchdog::set_live(opts.tui || opts.serve.is_some());
+    priority::set(opts.nice, opts.ionice);
+    if let Some(host) = &opts.remote {
+        remote::set(host, &opts.temp_dir(), &opts.remote_temp_dir);
+    }
+    let single = match (&opts.file, opts.arr) {
+        (Some(file), _) => Some(file.clone()),
+        (None, true) => match arr::event()? {
+            arr::Event::Imported(file) => Some(file),
+            arr::Event::Other(event) => {
+                info!("nothing to encode for a {} event", event);
+                return Ok(());
+            }
+        },
+        (None, false) => None,
+    };
+
+    let sources = opts.sources().map_err(exit::config)?;
+    // one store reads every source root
+    let location = storage::location(sources[0].0);
+    if let Some((other, _)) = sources
+        .iter()
+        .find(|(source, _)| storage::location(source) != location)
+    {
+        return Err(exit::config(anyhow!(
+            "{:?} isn't on the same storage as {:?} - every --source has to be",
+            other,
+            sources[0].0
+        )));
+    }
+    let stores = Stores {
+        source: storage::open(sources[0].0)?,
+        dest: storage::open(opts.destination())?,
+    };
+    if let Some(option) = needs_local(&opts, &stores) {
+        return Err(exit::config(anyhow!(
+            "{} works on the files directly, so needs a local source and destination",
+            option
+        )));
+    }
+    for (source, _) in &sources {
+        if !stores.source.is_dir(source) {
+            return Err(exit::environment(anyhow!(
+                "Source path {:?} does not exist",
+                source
+            )));
+        }
+    }
+
+    let config = match &opts.config {
+        Some(path) => Config::load(path).map_err(exit::config)?,
+        None => Config::default(),
+    };
+
+    if !opts.max_height.is_multiple_of(2) {
+        return Err(exit::config(anyhow!("--max-height has to be even")));
+    }
+    if opts.two_pass && opts.encoder.ends_with("_qsv") {
+        return Err(exit::config(anyhow!(
+            "--two-pass isn't supported with qsv encoders"
+        )));
+    }
+    if opts.ocr_subs && config.ocr.is_none() {
+        return Err(exit::config(anyhow!(
+            "--ocr-subs needs an [ocr] command in the config file"
+        )));
+    }
+    doctor::require_tools(&config).map_err(exit::environment)?;
+
+    // dry runs only read, so can overlap anything
+    let _lock = match opts.dry_run {
+        false => Some(lock::RunLock::acquire(
+            &sources
+                .iter()
+                .map(|(source, _)| *source)
+                .collect::<Vec<_>>(),
+            opts.destination(),
+            opts.wait_for_lock || opts.arr,
+        )?),
+        true => None,
+    };
+    if !opts.dry_run {
+        let swept = sweep::sweep(
+            stores.dest.as_ref(),
+            Some(opts.destination()),
+            &opts.temp_dir(),
+            false,
+        )?;
+        if !swept.removed.is_empty() {
+            info!("{}", swept.summary(false));
+        }
+    }
 
-    let opts = Opts::try_parse()?;
+    let gpus = if opts.gpus.is_empty() {
+        None
+    } else {
+        Some(GpuPool::new(
+            opts.gpus.clone(),
+            opts.jobs_per_gpu.max(1),
+            opts.gpu_balance,
+        ))
+    };
 
-    if !Path::new(&opts.source).is_dir() {
-        return Err(anyhow!("Source path {:?} does not exist", &opts.source));
+    let resumed = match &opts.journal {
+        Some(path) if path.exists() => {
+            let saved = journal::Saved::load(path)?;
+            if saved.destination != opts.destination() {
+                return Err(exit::config(anyhow!(
+                    "journal {:?} is of a run to {:?} - remove it to start again",
+                    path,
+                    saved.destination
+                )));
+            }
+            Some(saved)
+        }
+        _ => None,
+    };
+
+    let mut jobs = Vec::new();
+    let mut rejected = Vec::new();
+    match (plan, &resumed) {
+        (Some(plan), _) => planned_jobs(plan, &stores, &mut jobs),
+        // these files were chosen last time
+        (None, Some(saved)) => {
+            let remaining = saved.remaining();
+            info!(
+                "carrying on from the journal - {} of {} files left, {} failed last time",
+                remaining.len(),
+                saved.total(),
+                saved.failed()
+            );
+            listed_jobs(remaining, &sources, &opts, &stores, &mut jobs)?;
+        }
+        (None, None) => {
+            match (single, &opts.files_from) {
+                (Some(file), _) => listed_jobs(vec![file], &sources, &opts, &stores, &mut jobs)?,
+                (None, Some(list)) => listed_jobs(
+                    filelist::read(list, opts.null)?,
+                    &sources,
+                    &opts,
+                    &stores,
+                    &mut jobs,
+                )?,
+                (None, None) => scan_sources(&sources, &opts, &stores, &mut jobs)?,
+            }
+            for job in std::mem::take(&mut jobs) {
+                let rejection = match (
+                    select::rejection(&job.source, stores.source.as_ref(), &config, &opts),
+                    &opts.filter_hook,
+                ) {
+                    (None, Some(hook)) => hooks::filter(hook, &job.source, &job.dest)?,
+                    (rejection, _) => rejection,
+                };
+                match rejection {
+                    Some(reason) => {
+                        notices::skip(&job.source, &format!("ignoring file - {}", reason));
+                        rejected.push((job, reason));
+                    }
+                    None => jobs.push(job),
+                }
+            }
+            if let Some(wait) = opts.settle {
+                let (settled, unsettled) =
+                    settle(std::mem::take(&mut jobs), *wait, stores.source.as_ref())?;
+                jobs = settled;
+                for job in unsettled {
+                    let reason = "still being written".to_owned();
+                    notices::skip(&job.source, &format!("ignoring file - {}", reason));
+                    rejected.push((job, reason));
+                }
+            }
+        }
+    }
+    let quarantine = match opts.quarantine_after {
+        Some(after)
+            if !opts.dry_run || opts.destination().join(quarantine::QUARANTINE).exists() =>
+        {
+            fs::create_dir_all(opts.destination())?;
+            let quarantine = quarantine::Quarantine::open(
+                opts.destination(),
+                after,
+                opts.quarantine_dir.clone(),
+            )?;
+            jobs.retain(|job| {
+                let keep = !quarantine.is_quarantined(&opts.relative_source(&job.source));
+                if !keep {
+                    notices::skip(&job.source, "quarantined after failing repeatedly");
+                }
+                keep
+            });
+            Some(quarantine)
+        }
+        _ => None,
+    };
+    info!("found {} files to downscale", jobs.len());
+    if let Some(order) = opts.order {
+        order::sort(&mut jobs, order, stores.source.as_ref())?;
+    }
+    if let Some(max) = opts.max_files.filter(|max| jobs.len() > *max) {
+        info!(
+            "only doing the first {} with --max-files - {} are left for later runs",
+            max,
+            jobs.len() - max
+        );
+        jobs.truncate(max);
+    }
+
+    let manifest = if opts.checksum_manifest && !opts.dry_run {
+        fs::create_dir_all(opts.destination())?;
+        Some(checksum::Manifest::open(opts.destination())?)
+    } else {
+        None
+    };
+    let temp = staging::RunDir::create(&opts.temp_dir())?;
+    let mqtt = match config.mqtt.clone().filter(|_| !opts.dry_run) {
+        Some(mqtt) => Some(mqtt.keep_secrets_in(&temp.path)?),
+        None => None,
+    };
+    let ends = opts.max_runtime.map(|max| begun + *max);
+    let webhook = match opts.dry_run {
+        true => None,
+        false => opts.webhook.as_deref().map(webhook::Webhook::new),
+    };
+    let journal = match (&opts.journal, opts.dry_run) {
+        (Some(path), false) if resumed.is_some() => Some(journal::Journal::append(
+            path,
+            jobs.iter().map(|job| job.source.as_path()),
+        )?),
+        (Some(path), false) => Some(journal::Journal::create(
+            path,
+            opts.destination(),
+            jobs.iter()
+                .map(|job| (job.source.as_path(), job.dest.as_path())),
+        )?),
+        _ => None,
+    };
+    let prefetch = opts
+        .prefetch
+        .map(|ahead| prefetch::Prefetch::new(ahead as usize, &temp.path));
+    let ctx = Context {
+        progress: Progress::new(jobs.len(), mqtt),
+        opts,
+        config,
+        gpus,
+        stores,
+        manifest,
+        quarantine,
+        temp,
+        results: Mutex::new(Vec::new()),
+        low_space: AtomicBool::new(false),
+        ends,
+        out_of_time: AtomicUsize::new(0),
+        webhook,
+        journal,
+        prefetch,
+    };
+    if ctx.opts.dry_run {
+        return dry_run(jobs, rejected, args, &ctx);
+    }
+    for job in &jobs {
+        let (_, rule) = choose_settings(job, None, &ctx);
+        ctx.progress.add_to_group(&job.source, &rule);
+    }
+    ctx.progress
+        .set_queue(jobs.iter().map(|job| job.source.clone()).collect());
+    if let Some(webhook) = &ctx.webhook {
+        let sources: Vec<&Path> = ctx.opts.source.iter().map(|r| r.path.as_path()).collect();
+        webhook.run_started(jobs.len(), &sources, ctx.opts.destination());
+    }
+    signals::install();
+    let started = SystemTime::now();
+    let finished = AtomicBool::new(false);
+    let queue = Mutex::new(jobs);
+    let taking = AtomicUsize::new(ctx.opts.jobs().max(1));
+    let enqueue = |path: &Path| -> Result<String> {
+        if finished.load(Ordering::Relaxed) || control::stopping() {
+            return Err(anyhow!("the run is ending"));
+        }
+        let mut found = Vec::new();
+        let sources = ctx.opts.sources()?;
+        listed_jobs(
+            vec![path.to_owned()],
+            &sources,
+            &ctx.opts,
+            &ctx.stores,
+            &mut found,
+        )?;
+        let job = found.pop().ok_or_else(|| {
+            anyhow!(
+                "{:?} is not an .mkv or .mp4 file under a --source without an output yet",
+                path
+            )
+        })?;
+        let mut queue = queue.lock().unwrap();
+        // the workers have all stopped, so it would never be started
+        if taking.load(Ordering::SeqCst) == 0 {
+            return Err(anyhow!("the run is ending"));
+        }
+        if queue.iter().any(|queued| queued.source == job.source)
+            || ctx.progress.active().contains(&job.source)
+        {
+            return Err(anyhow!("{:?} is already in the run", job.source));
+        }
+        let (_, rule) = choose_settings(&job, None, &ctx);
+        ctx.progress.add_to_group(&job.source, &rule);
+        ctx.progress.enqueued(&job.source);
+        let reply = format!("added {:?} - {} files queued", job.source, queue.len() + 1);
+        queue.push(job);
+        Ok(reply)
+    };
+    let outcome = thread::scope(|scope| {
+        if let Some(address) = &ctx.opts.serve {
+            let finished = &finished;
+            let run = web::Run {
+                progress: &ctx.progress,
+                results: &ctx.results,
+                enqueue: &enqueue,
+            };
+            scope.spawn(move || {
+                if let Err(e) = web::serve(address, &run, finished) {
+                    warn!("dashboard: {:#}", e);
+                }
+            });
+        }
+        if let Some(path) = &ctx.opts.control_socket {
+            let finished = &finished;
+            let progress = &ctx.progress;
+            scope.spawn(move || {
+                if let Err(e) = control::serve(path, progress, finished) {
+                    warn!("control socket: {:#}", e);
+                }
+            });
+        }
+        if ctx.opts.tui {
+            let finished = &finished;
+            let progress = &ctx.progress;
+            scope.spawn(move || {
+                if let Err(e) = tui::run(progress, finished) {
+                    warn!("{:#}", e);
+                }
+            });
+        }
+        if let Some(prefetch) = &ctx.prefetch {
+            let (queue, finished) = (&queue, &finished);
+            let (progress, store) = (&ctx.progress, ctx.stores.source.as_ref());
+            scope.spawn(move || prefetch.run(queue, progress, store, finished));
+        }
+        scope.spawn(|| {
+            let mut paused = false;
+            while !finished.load(Ordering::Relaxed) {
+                if signals::status_requested() {
+                    info!("{}", ctx.progress.status());
+                }
+                if signals::paused() != paused {
+                    paused = !paused;
+                    match paused {
+                        true => info!("paused - send SIGUSR2 again to resume"),
+                        false => info!("resumed"),
+                    }
+                }
+                thread::sleep(Duration::from_millis(250));
+            }
+        });
+        let outcome = workers::run_all(
+            &queue,
+            &taking,
+            ctx.opts.jobs(),
+            ctx.gpus.as_ref(),
+            |pending: &[Job]| {
+                ctx.progress
+                    .next(pending.iter().map(|job| job.source.as_path()))
+            },
+            |job, slot| run_job(job, slot, &ctx),
+        );
+        finished.store(true, Ordering::Relaxed);
+        outcome
+    });
+    ctx.progress.ended();
+    let outcome = finish_run(&ctx, outcome, started);
+    if let Some(journal) = &ctx.journal {
+        journal.finish();
+    }
+    if let Some(webhook) = &ctx.webhook {
+        webhook.run_finished(ctx.progress.counts(), ctx.progress.elapsed(), &outcome);
+    }
+    if let Some(hook) = &ctx.opts.post_run_hook {
+        let sources: Vec<&Path> = ctx.opts.source.iter().map(|r| r.path.as_path()).collect();
+        hooks::after_run(
+            hook,
+            ctx.progress.counts(),
+            ctx.progress.elapsed(),
+            &outcome,
+            &sources,
+            ctx.opts.destination(),
+        );
     }
+    let error = outcome.as_ref().err().map(|e| format!("{:#}", e));
+    notify::run_finished(
+        &ctx.config.notifiers,
+        &ctx.progress.headline(),
+        ctx.progress.counts().failed,
+        error.as_deref(),
+    );
+    // files that failed without stopping the run still count
+    outcome.and_then(|()| match ctx.progress.counts().failed {
+        0 => Ok(()),
+        failed => {
+            Err(exit::Status::SomeFailed
+                .wrap(anyhow!("the run finished, but {} files failed", failed)))
+        }
+    })
+}
 
-    downscale_recursive(&opts.source, &opts.destination, &Vec::new())
+/// Summarise the run and write its reports, giving how it went as a whole
+fn finish_run(ctx: &Context, outcome: Result<()>, started: SystemTime) -> Result<()> {
+    if signals::interrupted() {
+        return Err(exit::Status::Interrupted.wrap(anyhow!(
+            "interrupted - the files in progress were abandoned, and their temp files removed"
+        )));
+    }
+    notices::summarise();
+    for line in ctx.progress.group_summary() {
+        info!("{}", line);
+    }
+    if let Some(quarantine) = &ctx.quarantine {
+        quarantine.summarise();
+    }
+    if ctx.opts.prune_empty_dirs && ctx.opts.destination().is_dir() {
+        let removed = prune_empty_dirs(ctx.opts.destination())?;
+        info!("removed {} empty directories from the destination", removed);
+    }
+    let left = ctx.out_of_time.load(Ordering::SeqCst);
+    if left > 0 {
+        info!(
+            "ran for the --max-runtime of {} - {} files are left for the next run",
+            ctx.opts
+                .max_runtime
+                .expect("only counted with --max-runtime"),
+            left
+        );
+    }
+    let outcome = outcome.and(write_reports(ctx, started));
+    if ctx.low_space.load(Ordering::SeqCst) {
+        return outcome.and(Err(anyhow!(
+            "stopped early - the destination dropped below --dest-free-min"
+        )));
+    }
+    outcome
 }
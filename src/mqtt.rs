@@ -0,0 +1,109 @@
+//! Publishing progress to an MQTT broker, e.g. for Home Assistant dashboards
+//!
+//! Messages are sent with `mosquitto_pub`, retained so a dashboard shows the latest state as soon
+//! as it connects. Each metric has its own topic under the configured prefix. The user and
+//! password go in a `mosquitto_pub` options file in the run's temp directory, rather than on its
+//! command line.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+
+use crate::config::Entry;
+use crate::secrets::SecretFile;
+
+/// The `[mqtt]` config section
+#[derive(Debug, Clone)]
+pub struct Mqtt {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Topics are `<prefix>/<metric>`
+    pub prefix: String,
+    /// The options file holding the user and password, once there is one
+    options: Option<Arc<SecretFile>>,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            host: "localhost".to_owned(),
+            port: 1883,
+            user: None,
+            password: None,
+            prefix: "downscaler".to_owned(),
+            options: None,
+        }
+    }
+}
+
+impl Mqtt {
+    pub fn set(&mut self, entry: &Entry) -> Result<()> {
+        match entry.key.as_str() {
+            "host" => self.host = entry.value.clone(),
+            "port" => self.port = entry.parse()?,
+            "user" => self.user = Some(entry.value.clone()),
+            "password" => self.password = Some(entry.value.clone()),
+            "prefix" => self.prefix = entry.value.trim_end_matches('/').to_owned(),
+            other => {
+                return Err(anyhow!(
+                    "line {}: unknown mqtt setting {}",
+                    entry.line,
+                    other
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the user and password in an options file under `dir`, for as long as this is used
+    pub fn keep_secrets_in(mut self, dir: &Path) -> Result<Mqtt> {
+        let mut options = String::new();
+        if let Some(user) = &self.user {
+            options.push_str(&format!("-u {}\n", user));
+        }
+        if let Some(password) = &self.password {
+            options.push_str(&format!("-P {}\n", password));
+        }
+        if options.is_empty() {
+            return Ok(self);
+        }
+        // mosquitto_pub only reads options from `$XDG_CONFIG_HOME/mosquitto_pub`
+        let dir = dir.join("mqtt");
+        fs::create_dir_all(&dir).with_context(|| format!("creating {:?}", dir))?;
+        let file = SecretFile::create(&dir.join("mosquitto_pub"), &options)?;
+        self.options = Some(Arc::new(file));
+        Ok(self)
+    }
+
+    /// Publish one retained value - failures are only warnings, as progress is not worth stopping
+    /// for
+    pub fn publish(&self, metric: &str, value: &str) {
+        let mut cmd = Command::new("mosquitto_pub");
+        cmd.args(["-h", &self.host, "-p", &self.port.to_string()]);
+        if let Some(dir) = self.options.as_ref().and_then(|o| o.path.parent()) {
+            cmd.env("XDG_CONFIG_HOME", dir);
+        }
+        cmd.args([
+            "-r",
+            "-t",
+            &format!("{}/{}", self.prefix, metric),
+            "-m",
+            value,
+        ])
+        .stdin(Stdio::null());
+        match cmd.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("mosquitto_pub failed for {}: {}", metric, status),
+            Err(e) => warn!("could not run mosquitto_pub: {}", e),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Collects repetitive per-file messages, so a big tree doesn't flood the log
+//!
+//! Skipped files are counted per directory and summarised at the end, unless `--verbose-skips` is
+//! set. Repeated warnings are logged the first time in each directory, then counted.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use log::info;
+use log::warn;
+
+static VERBOSE_SKIPS: AtomicBool = AtomicBool::new(false);
+
+/// Counts keyed by (directory, message)
+type Counts = BTreeMap<(String, String), usize>;
+
+static SKIPS: Mutex<Counts> = Mutex::new(BTreeMap::new());
+static WARNINGS: Mutex<Counts> = Mutex::new(BTreeMap::new());
+
+pub fn set_verbose_skips(verbose: bool) {
+    VERBOSE_SKIPS.store(verbose, Ordering::Relaxed);
+}
+
+fn dir_of(path: &Path) -> String {
+    path.parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A file we are deliberately not processing
+pub fn skip(path: &Path, reason: &str) {
+    if VERBOSE_SKIPS.load(Ordering::Relaxed) {
+        info!("{} {:?}", reason, path);
+        return;
+    }
+    *SKIPS
+        .lock()
+        .unwrap()
+        .entry((dir_of(path), reason.to_owned()))
+        .or_insert(0) += 1;
+}
+
+/// A warning about a file - `message` should not include the path, so repeats can be recognised
+pub fn warn(path: &Path, message: &str) {
+    let mut warnings = WARNINGS.lock().unwrap();
+    let count = warnings
+        .entry((dir_of(path), message.to_owned()))
+        .or_insert(0);
+    if *count == 0 {
+        warn!("{}: {:?}", message, path);
+    }
+    *count += 1;
+}
+
+/// Log the counts of everything collected since last time
+pub fn summarise() {
+    for ((dir, reason), count) in std::mem::take(&mut *SKIPS.lock().unwrap()) {
+        info!("{:?}: {} x {}", dir, count, reason);
+    }
+    for ((dir, message), count) in std::mem::take(&mut *WARNINGS.lock().unwrap()) {
+        if count > 1 {
+            warn!("{:?}: {} more x {}", dir, count - 1, message);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Telling someone when a run ends or a file fails - through ntfy, Discord, Telegram or email
+//!
+//! Each `[notify <kind>]` config section adds one backend, and there can be several:
+//!
+//! ```text
+//! [notify ntfy]
+//! url = https://ntfy.sh/my-downscaler
+//!
+//! [notify email]
+//! smtp = smtps://smtp.example.com:465
+//! user = me@example.com
+//! password = secret
+//! from = me@example.com
+//! to = me@example.com
+//! # only when the run ends, not for every failed file
+//! on_failure = false
+//! ```
+//!
+//! Everything is sent with `curl`, and a notification that can't be sent is only a warning. URLs,
+//! tokens and passwords are given to curl in a config file only we can read, as its command line
+//! is there for every local user to see.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::warn;
+
+use crate::config::Entry;
+use crate::curl::Curl;
+use crate::json;
+
+/// Discord refuses longer messages
+const DISCORD_LIMIT: usize = 2000;
+
+#[derive(Debug, Clone)]
+enum Backend {
+    /// A topic URL, and an access token for protected topics
+    Ntfy { url: String, token: Option<String> },
+    /// A channel's webhook URL
+    Discord { webhook: String },
+    /// A bot's token, and the chat it sends to
+    Telegram { token: String, chat_id: String },
+    Email {
+        /// Like `smtps://smtp.example.com:465`, or `smtp://...:587` for STARTTLS
+        smtp: String,
+        user: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+/// One `[notify <kind>]` config section
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    backend: Backend,
+    /// Send a message for each file that fails
+    on_failure: bool,
+    /// Send a message when the run ends
+    on_end: bool,
+}
+
+impl Notifier {
+    pub fn new(kind: &str, entries: &[Entry]) -> Result<Notifier> {
+        let mut on_failure = true;
+        let mut on_end = true;
+        let mut values = Vec::new();
+        for entry in entries {
+            match entry.key.as_str() {
+                "on_failure" => on_failure = entry.parse()?,
+                "on_end" => on_end = entry.parse()?,
+                _ => values.push(entry),
+            }
+        }
+        let take = |key: &str| {
+            values
+                .iter()
+                .find(|entry| entry.key == key)
+                .map(|entry| entry.value.clone())
+        };
+        let known: &[&str] = match kind {
+            "ntfy" => &["url", "token"],
+            "discord" => &["webhook"],
+            "telegram" => &["token", "chat_id"],
+            "email" => &["smtp", "user", "password", "from", "to"],
+            other => return Err(anyhow!("unknown notification backend [notify {}]", other)),
+        };
+        if let Some(entry) = values
+            .iter()
+            .find(|entry| !known.contains(&entry.key.as_str()))
+        {
+            return Err(anyhow!(
+                "line {}: unknown {} setting {}",
+                entry.line,
+                kind,
+                entry.key
+            ));
+        }
+        let need = |key: &str| {
+            take(key).ok_or_else(|| anyhow!("[notify {}] needs a {} setting", kind, key))
+        };
+        let backend = match kind {
+            "ntfy" => Backend::Ntfy {
+                url: need("url")?,
+                token: take("token"),
+            },
+            "discord" => Backend::Discord {
+                webhook: need("webhook")?,
+            },
+            "telegram" => Backend::Telegram {
+                token: need("token")?,
+                chat_id: need("chat_id")?,
+            },
+            // the only kind left
+            _ => Backend::Email {
+                smtp: need("smtp")?,
+                user: take("user"),
+                password: take("password"),
+                from: need("from")?,
+                to: need("to")?
+                    .split(',')
+                    .map(|to| to.trim().to_owned())
+                    .filter(|to| !to.is_empty())
+                    .collect(),
+            },
+        };
+        Ok(Notifier {
+            backend,
+            on_failure,
+            on_end,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.backend {
+            Backend::Ntfy { .. } => "ntfy",
+            Backend::Discord { .. } => "discord",
+            Backend::Telegram { .. } => "telegram",
+            Backend::Email { .. } => "email",
+        }
+    }
+
+    /// Send one message - `urgent` ones are for failures
+    fn send(&self, title: &str, message: &str, urgent: bool) -> Result<()> {
+        let mut curl = Curl::new(30);
+        curl.args(["-o", "/dev/null"]);
+        // most webhook and topic URLs are as good as a password
+        let body = match &self.backend {
+            Backend::Ntfy { url, token } => {
+                curl.args(["-H", &format!("Title: {}", title)]);
+                if urgent {
+                    curl.args(["-H", "Priority: high", "-H", "Tags: warning"]);
+                }
+                if let Some(token) = token {
+                    curl.secret("header", &format!("Authorization: Bearer {}", token));
+                }
+                curl.args(["--data-binary", "@-"]).secret_url(url);
+                message.to_owned()
+            }
+            Backend::Discord { webhook } => {
+                let content: String = format!("**{}**\n{}", title, message)
+                    .chars()
+                    .take(DISCORD_LIMIT)
+                    .collect();
+                curl.args(["-H", "Content-Type: application/json"])
+                    .args(["--data-binary", "@-"])
+                    .secret_url(webhook);
+                json::Object::new().str("content", &content).build()
+            }
+            Backend::Telegram { token, chat_id } => {
+                curl.args(["--data-urlencode", &format!("chat_id={}", chat_id)])
+                    .args(["--data-urlencode", "text@-"])
+                    .secret_url(&format!(
+                        "https://api.telegram.org/bot{}/sendMessage",
+                        token
+                    ));
+                format!("{}\n{}", title, message)
+            }
+            Backend::Email {
+                smtp,
+                user,
+                password,
+                from,
+                to,
+            } => {
+                curl.args(["--url", smtp, "--mail-from", from]);
+                for to in to {
+                    curl.args(["--mail-rcpt", to]);
+                }
+                match user {
+                    // never send a password without TLS
+                    Some(user) => {
+                        let password = password.as_deref().unwrap_or_default();
+                        curl.arg("--ssl-reqd")
+                            .secret("user", &format!("{}:{}", user, password));
+                    }
+                    None => {
+                        curl.arg("--ssl");
+                    }
+                }
+                curl.args(["--upload-file", "-"]);
+                format!(
+                    "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+                    from,
+                    to.join(", "),
+                    title,
+                    message.replace('\n', "\r\n")
+                )
+            }
+        };
+        curl.input(body).run()?;
+        Ok(())
+    }
+}
+
+fn send_all<'a>(
+    notifiers: impl Iterator<Item = &'a Notifier>,
+    title: &str,
+    message: &str,
+    urgent: bool,
+) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.send(title, message, urgent) {
+            warn!("could not send {} notification: {:#}", notifier.name(), e);
+        }
+    }
+}
+
+/// A file failed to encode
+pub fn file_failed(notifiers: &[Notifier], source: &str, error: &str) {
+    send_all(
+        notifiers.iter().filter(|n| n.on_failure),
+        "downscaler: a file failed",
+        &format!("{}\n{}", source, error),
+        true,
+    );
+}
+
+/// The run is over - `summary` says how it went, and `error` is why it failed as a whole
+pub fn run_finished(notifiers: &[Notifier], summary: &str, failed: usize, error: Option<&str>) {
+    let title = match (error, failed) {
+        (Some(_), _) => "downscaler: run failed".to_owned(),
+        (None, 0) => "downscaler: run finished".to_owned(),
+        (None, failed) => format!("downscaler: run finished, {} failed", failed),
+    };
+    let message = match error {
+        Some(error) => format!("{}\n{}", summary, error),
+        None => summary.to_owned(),
+    };
+    send_all(
+        notifiers.iter().filter(|n| n.on_end),
+        &title,
+        &message,
+        error.is_some() || failed > 0,
+    );
+}
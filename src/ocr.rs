@@ -0,0 +1,192 @@
+//! Turning bitmap subtitles into text with an external OCR tool, for containers that can't hold
+//! them
+//!
+//! mp4 can't carry PGS (Blu-ray) subtitles, so without this they are dropped when converting. With
+//! `--ocr-subs`, each one is extracted, passed to the `[ocr]` command from the config file, and the
+//! resulting `.srt` muxed in as text instead.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::info;
+
+use crate::config::Config;
+use crate::config::Entry;
+use crate::filters::VideoFilters;
+use crate::notices;
+use crate::probe::ProbeInfo;
+use crate::probe::Stream;
+use crate::staging::path_hash;
+use crate::streams;
+use crate::streams::Container;
+use crate::workers::Slot;
+use crate::Context;
+use crate::Opts;
+
+/// The `[ocr]` config section
+///
+/// `command` is split on whitespace, then `{input}`, `{output}` and `{lang}` are replaced in
+/// each argument - so paths with spaces are safe
+#[derive(Debug, Clone)]
+pub struct OcrCommand {
+    pub command: String,
+}
+
+impl OcrCommand {
+    pub fn from_entries(entries: &[Entry]) -> Result<OcrCommand> {
+        let mut command = None;
+        for entry in entries {
+            match entry.key.as_str() {
+                "command" => command = Some(entry.value.clone()),
+                other => {
+                    return Err(anyhow!(
+                        "line {}: unknown ocr setting {}",
+                        entry.line,
+                        other
+                    ))
+                }
+            }
+        }
+        let command = command.ok_or_else(|| anyhow!("the [ocr] section needs a command"))?;
+        Ok(OcrCommand { command })
+    }
+
+    fn run(
+        &self,
+        input: &Path,
+        output: &Path,
+        language: &str,
+        config: &Config,
+        slot: Slot,
+    ) -> Result<()> {
+        let args: Vec<String> = self
+            .command
+            .split_whitespace()
+            .map(|arg| {
+                arg.replace("{input}", &input.to_string_lossy())
+                    .replace("{output}", &output.to_string_lossy())
+                    .replace("{lang}", language)
+            })
+            .collect();
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| anyhow!("the [ocr] command is empty"))?;
+        let mut cmd = config.command(program, slot);
+        cmd.args(args);
+        crate::run_command(cmd)
+    }
+}
+
+/// One subtitle stream converted to text
+#[derive(Debug)]
+pub struct OcrSub {
+    /// The source stream it replaces
+    pub index: usize,
+    pub srt: PathBuf,
+    pub language: String,
+}
+
+/// The converted subtitles for one job - the temp files are removed when dropped
+#[derive(Debug, Default)]
+pub struct OcrSubs {
+    pub subs: Vec<OcrSub>,
+    files: Vec<PathBuf>,
+}
+
+impl Drop for OcrSubs {
+    fn drop(&mut self) {
+        for file in &self.files {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+/// The subtitle streams that will be dropped unless we OCR them
+fn needs_ocr<'a>(
+    source: &Path,
+    output: &Path,
+    info: &'a ProbeInfo,
+    filters: &VideoFilters,
+    opts: &Opts,
+) -> Vec<&'a Stream> {
+    let container = Container::from_path(output);
+    streams::selected_subtitles(source, info, opts)
+        .into_iter()
+        .filter(|s| filters.burned != Some(s.index))
+        .filter(|s| {
+            streams::is_image_subtitle(s.codec_name()) && !container.can_hold(s.codec_name())
+        })
+        .collect()
+}
+
+/// OCR every bitmap subtitle stream the output can't hold
+///
+/// A stream that fails is warned about and dropped, as it would have been without OCR
+pub fn convert(
+    input: &Path,
+    source: &Path,
+    output: &Path,
+    info: &ProbeInfo,
+    filters: &VideoFilters,
+    slot: Slot,
+    ctx: &Context,
+) -> Result<OcrSubs> {
+    let (opts, config) = (&ctx.opts, &ctx.config);
+    let ocr = config
+        .ocr
+        .as_ref()
+        .ok_or_else(|| anyhow!("--ocr-subs needs an [ocr] command in the config file"))?;
+    let mut converted = OcrSubs::default();
+    let hash = path_hash(source);
+    for stream in needs_ocr(source, output, info, filters, opts) {
+        // OCR tools recognise PGS by its usual .sup extension
+        let ext = match stream.codec_name() {
+            "hdmv_pgs_subtitle" => "sup",
+            _ => "mks",
+        };
+        let temp = &ctx.temp.path;
+        let image = temp.join(format!("downscaler_{}_sub{}.{}", hash, stream.index, ext));
+        let srt = temp.join(format!("downscaler_{}_sub{}.srt", hash, stream.index));
+        converted.files.push(image.clone());
+        converted.files.push(srt.clone());
+        info!(
+            "running OCR on {} {} subtitles from stream {} of {:?}",
+            stream.language(),
+            stream.codec_name(),
+            stream.index,
+            source
+        );
+        let mut cmd = config.command("ffmpeg", slot);
+        cmd.arg("-nostdin")
+            .arg("-i")
+            .arg(input)
+            .args(["-map", &format!("0:{}", stream.index), "-c:s", "copy"])
+            .args(["-loglevel", "warning", "-nostats", "-hide_banner", "-y"])
+            .arg(&image);
+        let outcome = crate::run_command(cmd)
+            .and_then(|_| ocr.run(&image, &srt, stream.language(), config, slot))
+            .and_then(|_| match fs::metadata(&srt) {
+                Ok(m) if m.len() > 0 => Ok(()),
+                _ => Err(anyhow!("no subtitles were written")),
+            });
+        match outcome {
+            Ok(()) => converted.subs.push(OcrSub {
+                index: stream.index,
+                srt,
+                language: stream.language().to_owned(),
+            }),
+            Err(e) => notices::warn(
+                source,
+                &format!(
+                    "dropping {} subtitles - OCR failed: {:#}",
+                    stream.codec_name(),
+                    e
+                ),
+            ),
+        }
+    }
+    Ok(converted)
+}
@@ -0,0 +1,54 @@
+//! The order files are encoded in, for `--order`
+
+use std::cmp::Reverse;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::storage::Storage;
+use crate::Job;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Order {
+    /// Biggest sources first, for the biggest savings soonest
+    LargestFirst,
+    SmallestFirst,
+    /// Least recently modified first
+    OldestFirst,
+    /// Most recently modified first, for new content soonest
+    NewestFirst,
+    /// By path
+    Alphabetical,
+}
+
+/// Sort `jobs` into `order` - ties, and files that can't be read, go by path
+pub fn sort(jobs: &mut Vec<Job>, order: Order, store: &dyn Storage) -> Result<()> {
+    let mut keyed = Vec::with_capacity(jobs.len());
+    for job in jobs.drain(..) {
+        let info = match order {
+            Order::Alphabetical => None,
+            _ => store.stat(&job.source)?,
+        };
+        let size = info.map_or(0, |i| i.len);
+        let modified = info
+            .and_then(|i| i.modified)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        keyed.push(((size, modified), job));
+    }
+    match order {
+        Order::LargestFirst => {
+            keyed.sort_by_key(|((size, _), job)| (Reverse(*size), job.source.clone()))
+        }
+        Order::SmallestFirst => keyed.sort_by_key(|((size, _), job)| (*size, job.source.clone())),
+        Order::OldestFirst => {
+            keyed.sort_by_key(|((_, modified), job)| (*modified, job.source.clone()))
+        }
+        Order::NewestFirst => {
+            keyed.sort_by_key(|((_, modified), job)| (Reverse(*modified), job.source.clone()))
+        }
+        Order::Alphabetical => keyed.sort_by(|(_, a), (_, b)| a.source.cmp(&b.source)),
+    }
+    jobs.extend(keyed.into_iter().map(|(_, job)| job));
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Per-directory settings, from `[override <dir>]` config sections
+//!
+//! `<dir>` is relative to the source root, and matches everything under it. When several
+//! sections match a file, they are applied from the least to the most specific, so a deeper
+//! directory's settings win.
+//!
+//! An override can also be temporary - only used while a `--flag` is given (`when = travel-mode`),
+//! or between two dates (`from` and `until`, both inclusive).
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use crate::config::Entry;
+use crate::settings::Settings;
+
+#[derive(Debug, Clone, Default)]
+pub struct Override {
+    /// Directory relative to the source root
+    pub dir: PathBuf,
+    /// Decode the whole source before encoding, to catch corrupt downloads
+    pub verify_source: Option<bool>,
+    pub crf: Option<u32>,
+    pub preset: Option<String>,
+    /// Only used while this `--flag` is set
+    pub when: Option<String>,
+    pub from: Option<SystemTime>,
+    /// The end of the last day it is used
+    pub until: Option<SystemTime>,
+}
+
+fn parse_bool(entry: &Entry) -> Result<bool> {
+    match entry.value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(anyhow!(
+            "line {}: {} should be true or false",
+            entry.line,
+            entry.key
+        )),
+    }
+}
+
+/// The start of a `YYYY-MM-DD` day, in UTC
+fn parse_date(entry: &Entry) -> Result<SystemTime> {
+    humantime::parse_rfc3339_weak(&format!("{} 00:00:00", entry.value.trim())).map_err(|_| {
+        anyhow!(
+            "line {}: {} should be a date like 2024-12-25",
+            entry.line,
+            entry.key
+        )
+    })
+}
+
+impl Override {
+    pub fn new(dir: &str, entries: &[Entry]) -> Result<Override> {
+        let mut found = Override {
+            dir: PathBuf::from(dir.trim_matches('/')),
+            ..Override::default()
+        };
+        for entry in entries {
+            match entry.key.as_str() {
+                "verify_source" => found.verify_source = Some(parse_bool(entry)?),
+                "crf" => found.crf = Some(entry.parse()?),
+                "preset" => found.preset = Some(entry.value.clone()),
+                "when" => found.when = Some(entry.value.clone()),
+                "from" => found.from = Some(parse_date(entry)?),
+                "until" => {
+                    found.until = Some(parse_date(entry)? + Duration::from_secs(24 * 60 * 60))
+                }
+                other => {
+                    return Err(anyhow!(
+                        "line {}: unknown override setting {}",
+                        entry.line,
+                        other
+                    ))
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Does this apply to `relative`, a source path relative to the source root?
+    pub fn matches(&self, relative: &Path) -> bool {
+        relative.starts_with(&self.dir)
+    }
+
+    /// Is this in use right now, with these `--flag`s?
+    pub fn is_active(&self, flags: &[String], now: SystemTime) -> bool {
+        self.when.as_ref().is_none_or(|flag| flags.contains(flag))
+            && self.from.is_none_or(|from| now >= from)
+            && self.until.is_none_or(|until| now < until)
+    }
+
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(crf) = self.crf {
+            settings.crf = crf;
+        }
+        if let Some(preset) = &self.preset {
+            settings.preset = preset.clone();
+        }
+    }
+}
+
+/// The overrides in use for `relative`, least specific first
+pub fn matching<'a>(
+    overrides: &'a [Override],
+    relative: &Path,
+    flags: &[String],
+) -> Vec<&'a Override> {
+    let now = SystemTime::now();
+    let mut found: Vec<&Override> = overrides
+        .iter()
+        .filter(|o| o.matches(relative) && o.is_active(flags, now))
+        .collect();
+    found.sort_by_key(|o| o.dir.components().count());
+    found
+}
@@ -0,0 +1,264 @@
+//! Plans saved by `downscaler scan` or `--dry-run --save-plan`, run exactly by a later
+//! `--execute-plan`
+//!
+//! A plan records the command line options, every file to encode, the size and modification
+//! time of each source, and the settings chosen for it - including how far it is scaled down.
+//! Running a plan skips any file that has changed since, so nothing is encoded that wasn't
+//! reviewed - and an unchanged source probes the same way again.
+//!
+//! The format is line based, one record per line with tabs between fields, after the version
+//! header every state file has:
+//!
+//! ```text
+//! downscaler-plan 2
+//! arg --source
+//! arg /media/in
+//! job <source> <dest> <size> <mtime> <crf> <preset> <tune> <max fps> <max height>
+//! ```
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::roots::SourceRoot;
+use crate::settings::Settings;
+use crate::state;
+use crate::state::Format;
+use crate::storage::Storage;
+
+const FORMAT: Format = Format {
+    name: "downscaler-plan",
+    version: 2,
+    migrations: &[add_max_height],
+};
+
+/// Version 1 plans were always scaled to 720p
+fn add_max_height(lines: Vec<String>) -> Result<Vec<String>> {
+    Ok(lines
+        .into_iter()
+        .map(|line| match line.starts_with("job\t") {
+            true => format!("{}\t720", line),
+            false => line,
+        })
+        .collect())
+}
+
+/// What a plan says about one file
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub size: u64,
+    /// Source modification time, in whole seconds since the epoch
+    pub modified: u64,
+    pub settings: Settings,
+}
+
+impl Planned {
+    pub fn new(store: &dyn Storage, source: &Path, settings: Settings) -> Result<Planned> {
+        let (size, modified) = identity(store, source)?;
+        Ok(Planned {
+            size,
+            modified,
+            settings,
+        })
+    }
+
+    /// Is the source still the file that was planned?
+    pub fn matches(&self, store: &dyn Storage, source: &Path) -> bool {
+        identity(store, source).is_ok_and(|id| id == (self.size, self.modified))
+    }
+}
+
+fn identity(store: &dyn Storage, source: &Path) -> Result<(u64, u64)> {
+    let info = store
+        .stat(source)?
+        .ok_or_else(|| anyhow!("{:?} no longer exists", source))?;
+    let modified = info
+        .modified
+        .ok_or_else(|| anyhow!("no modification time for {:?}", source))?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok((info.len, modified))
+}
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// The command line the plan was made with, minus the dry run options
+    pub args: Vec<String>,
+    pub jobs: Vec<(PathBuf, PathBuf, Planned)>,
+}
+
+fn utf8(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("can't save {:?} in a plan - it isn't valid UTF-8", path))
+}
+
+/// The current command line, without the options that only make sense for a dry run
+pub fn plan_args(args: impl Iterator<Item = OsString>) -> Result<Vec<String>> {
+    let mut kept = Vec::new();
+    let mut args = args.skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg
+            .into_string()
+            .map_err(|a| anyhow!("can't save argument {:?} in a plan", a))?;
+        match arg.as_str() {
+            "--dry-run" => {}
+            "--save-plan" => {
+                args.next();
+            }
+            _ if arg.starts_with("--save-plan=") => {}
+            _ => kept.push(arg),
+        }
+    }
+    Ok(kept)
+}
+
+impl Plan {
+    /// Everything after the header
+    fn to_text(&self) -> Result<String> {
+        let mut text = String::new();
+        for arg in &self.args {
+            text.push_str(&format!("arg\t{}\n", state::escape(arg)));
+        }
+        for (source, dest, planned) in &self.jobs {
+            let settings = &planned.settings;
+            let fields = [
+                state::escape(utf8(source)?),
+                state::escape(utf8(dest)?),
+                planned.size.to_string(),
+                planned.modified.to_string(),
+                settings.crf.to_string(),
+                state::escape(&settings.preset),
+                state::escape(settings.tune.as_deref().unwrap_or_default()),
+                settings.max_fps.map(|f| f.to_string()).unwrap_or_default(),
+                settings.max_height.to_string(),
+            ];
+            text.push_str(&format!("job\t{}\n", fields.join("\t")));
+        }
+        Ok(text)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        FORMAT.save(path, &self.to_text()?)
+    }
+
+    /// Replace the value of a saved option with what `new` makes of the old one, returning the
+    /// old one - failing unless it was given exactly once
+    fn replace_arg(
+        &mut self,
+        short: &str,
+        long: &str,
+        new: impl FnOnce(&str) -> Result<String>,
+    ) -> Result<String> {
+        let prefix = format!("{}=", long);
+        let mut found = Vec::new();
+        for i in 0..self.args.len() {
+            if (self.args[i] == short || self.args[i] == long) && i + 1 < self.args.len() {
+                found.push((i + 1, String::new()));
+            } else if self.args[i].starts_with(&prefix) {
+                found.push((i, prefix.clone()));
+            }
+        }
+        let (i, prefix) = match found.len() {
+            0 => return Err(anyhow!("the plan's options have no {}", long)),
+            1 => found.remove(0),
+            n => {
+                return Err(anyhow!(
+                    "the plan has {} {} options - only one can be moved",
+                    n,
+                    long
+                ))
+            }
+        };
+        let old = self.args[i][prefix.len()..].to_owned();
+        self.args[i] = format!("{}{}", prefix, new(&old)?);
+        Ok(old)
+    }
+
+    /// Move the plan to new source and/or destination roots, e.g. to run it on another machine
+    /// where the same files are mounted somewhere else
+    pub fn rebase(&mut self, source: Option<&Path>, destination: Option<&Path>) -> Result<()> {
+        let rebased = |path: &Path, old: &Path, new: &Path| -> Result<PathBuf> {
+            let rest = path
+                .strip_prefix(old)
+                .map_err(|_| anyhow!("{:?} isn't under {:?}", path, old))?;
+            Ok(new.join(rest))
+        };
+        if let Some(new) = source {
+            let old = self.replace_arg("-s", "--source", |old| {
+                // a root keeps its `=SUBDIR`
+                let old: SourceRoot = old.parse().map_err(|e: String| anyhow!(e))?;
+                Ok(SourceRoot {
+                    path: PathBuf::from(utf8(new)?),
+                    subdir: old.subdir,
+                }
+                .to_string())
+            })?;
+            let old: SourceRoot = old.parse().map_err(|e: String| anyhow!(e))?;
+            for job in &mut self.jobs {
+                job.0 = rebased(&job.0, &old.path, new)?;
+            }
+        }
+        if let Some(new) = destination {
+            let old = self.replace_arg("-d", "--destination", |_| Ok(utf8(new)?.to_owned()))?;
+            for job in &mut self.jobs {
+                job.1 = rebased(&job.1, Path::new(&old), new)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Plan> {
+        let lines = FORMAT.load(path)?;
+        Plan::parse(&lines).with_context(|| format!("parsing plan {:?}", path))
+    }
+
+    fn parse(lines: &[String]) -> Result<Plan> {
+        let mut plan = Plan::default();
+        for (index, line) in lines.iter().enumerate() {
+            let fields: Vec<String> = line.split('\t').map(state::unescape).collect();
+            // after the header
+            let bad = || anyhow!("line {}: bad record", index + 2);
+            match fields.first().map(|f| f.as_str()) {
+                Some("arg") if fields.len() == 2 => plan.args.push(fields[1].clone()),
+                Some("job") if fields.len() == 10 => {
+                    let optional = |field: &String| (!field.is_empty()).then(|| field.clone());
+                    let settings = Settings {
+                        crf: fields[5].parse().map_err(|_| bad())?,
+                        preset: fields[6].clone(),
+                        tune: optional(&fields[7]),
+                        max_fps: match optional(&fields[8]) {
+                            Some(fps) => Some(fps.parse().map_err(|_| bad())?),
+                            None => None,
+                        },
+                        max_height: fields[9]
+                            .parse()
+                            .ok()
+                            .filter(|h: &u32| h.is_multiple_of(2) && *h > 0)
+                            .ok_or_else(bad)?,
+                        deinterlace: false,
+                        crop: None,
+                    };
+                    let planned = Planned {
+                        size: fields[3].parse().map_err(|_| bad())?,
+                        modified: fields[4].parse().map_err(|_| bad())?,
+                        settings,
+                    };
+                    plan.jobs.push((
+                        PathBuf::from(&fields[1]),
+                        PathBuf::from(&fields[2]),
+                        planned,
+                    ));
+                }
+                _ if line.is_empty() => {}
+                _ => return Err(bad()),
+            }
+        }
+        Ok(plan)
+    }
+}
@@ -0,0 +1,182 @@
+//! Copying the next files in while the current ones encode, for `--prefetch N`
+//!
+//! Staging a file from slow network storage can take as long as encoding it, with the CPU idle
+//! meanwhile. With `--prefetch`, up to N of the files the workers will start next are copied to the
+//! temp directory ahead of time, one at a time, and a worker starting one of them uses the copy -
+//! waiting for it to finish if it is still going. A copy that fails is simply made again by the
+//! worker, which reports the error if it fails again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::debug;
+
+use crate::disk;
+use crate::progress::Progress;
+use crate::signals;
+use crate::staging;
+use crate::storage::Storage;
+use crate::Job;
+
+/// The size and modification time of a source before it was copied, to tell if it changed meanwhile
+pub type Before = Option<(u64, Option<SystemTime>)>;
+
+pub fn before(store: &dyn Storage, source: &Path) -> Result<Before> {
+    let info = store.stat(source)?;
+    Ok(info.map(|info| (info.len, info.modified)))
+}
+
+enum Fetch {
+    Copying,
+    Copied(Before),
+    Failed,
+    /// Its job finished without it while it was being copied
+    Abandoned,
+}
+
+pub struct Prefetch {
+    ahead: usize,
+    temp: PathBuf,
+    fetches: Mutex<HashMap<PathBuf, Fetch>>,
+    changed: Condvar,
+}
+
+/// Drops a source's copy once its job is over, if the job never used it
+pub struct Forget<'a> {
+    prefetch: &'a Prefetch,
+    source: PathBuf,
+}
+
+impl Drop for Forget<'_> {
+    fn drop(&mut self) {
+        self.prefetch.forget(&self.source);
+    }
+}
+
+impl Prefetch {
+    pub fn new(ahead: usize, temp: &Path) -> Prefetch {
+        Prefetch {
+            ahead,
+            temp: temp.to_owned(),
+            fetches: Mutex::new(HashMap::new()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Keep the next of the jobs in `queue` copied in, until `finished`
+    pub fn run(
+        &self,
+        queue: &Mutex<Vec<Job>>,
+        progress: &Progress,
+        store: &dyn Storage,
+        finished: &AtomicBool,
+    ) {
+        while !finished.load(Ordering::Relaxed) && !signals::interrupted() {
+            match self.next(queue, progress) {
+                Some(source) => self.fetch(&source, store),
+                None => thread::sleep(Duration::from_millis(250)),
+            }
+        }
+    }
+
+    /// The source to copy next, if there is room for another
+    fn next(&self, queue: &Mutex<Vec<Job>>, progress: &Progress) -> Option<PathBuf> {
+        let mut fetches = self.fetches.lock().unwrap();
+        let held = fetches
+            .values()
+            .filter(|f| !matches!(f, Fetch::Abandoned))
+            .count();
+        if held >= self.ahead || signals::paused() {
+            return None;
+        }
+        let queue = queue.lock().unwrap();
+        let waiting: Vec<&Path> = queue
+            .iter()
+            .map(|job| job.source.as_path())
+            .filter(|source| !fetches.contains_key(*source))
+            .collect();
+        if waiting.is_empty() {
+            return None;
+        }
+        let index = progress.upcoming(waiting.iter().copied());
+        let source = waiting[index.min(waiting.len() - 1)].to_owned();
+        fetches.insert(source.clone(), Fetch::Copying);
+        Some(source)
+    }
+
+    fn fetch(&self, source: &Path, store: &dyn Storage) {
+        let local = staging::input_path(source, &self.temp);
+        debug!("prefetching {:?} to {:?}", source, local);
+        let copied = before(store, source).and_then(|before| {
+            disk::check_room(&self.temp, before.map_or(0, |(len, _)| len), "the source")?;
+            store.fetch(source, &local)?;
+            Ok(before)
+        });
+        let mut fetches = self.fetches.lock().unwrap();
+        let wanted = matches!(fetches.get(source), Some(Fetch::Copying));
+        match copied {
+            Ok(before) if wanted => {
+                fetches.insert(source.to_owned(), Fetch::Copied(before));
+            }
+            outcome => {
+                if let Err(e) = outcome {
+                    debug!("could not prefetch {:?}: {:#}", source, e);
+                }
+                let _ = fs::remove_file(&local);
+                match wanted {
+                    true => fetches.insert(source.to_owned(), Fetch::Failed),
+                    false => fetches.remove(source),
+                };
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Take the copy of `source`, waiting for it if it is still being made - `None` if there isn't
+    /// one, so the worker has to copy it itself
+    pub fn take(&self, source: &Path) -> Option<Before> {
+        let mut fetches = self.fetches.lock().unwrap();
+        loop {
+            match fetches.remove(source)? {
+                Fetch::Copying => {
+                    fetches.insert(source.to_owned(), Fetch::Copying);
+                    debug!("waiting for {:?} to finish copying in", source);
+                    fetches = self.changed.wait(fetches).unwrap();
+                }
+                Fetch::Copied(before) => return Some(before),
+                Fetch::Failed | Fetch::Abandoned => return None,
+            }
+        }
+    }
+
+    /// Forget about `source` once the job for it is over
+    pub fn forget_after(&self, source: &Path) -> Forget<'_> {
+        Forget {
+            prefetch: self,
+            source: source.to_owned(),
+        }
+    }
+
+    fn forget(&self, source: &Path) {
+        let mut fetches = self.fetches.lock().unwrap();
+        match fetches.remove(source) {
+            Some(Fetch::Copying) => {
+                fetches.insert(source.to_owned(), Fetch::Abandoned);
+            }
+            Some(Fetch::Copied(_)) => {
+                let _ = fs::remove_file(staging::input_path(source, &self.temp));
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Running encodes, probes and checksums at a lower CPU and disk priority, for `--nice` and
+//! `--ionice`
+//!
+//! The priority is set in the child between fork and exec, so every thread ffmpeg starts
+//! inherits it - setting it on a running process only changes its first thread on Linux.
+
+use std::process::Command;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy)]
+struct Priority {
+    nice: Option<i32>,
+    idle_io: bool,
+}
+
+static PRIORITY: Mutex<Priority> = Mutex::new(Priority {
+    nice: None,
+    idle_io: false,
+});
+
+pub fn set(nice: Option<i32>, idle_io: bool) {
+    *PRIORITY.lock().unwrap() = Priority { nice, idle_io };
+}
+
+/// Have `cmd` start at the priority set for the run
+pub fn apply(cmd: &mut Command) {
+    let priority = *PRIORITY.lock().unwrap();
+    if priority.nice.is_none() && !priority.idle_io {
+        return;
+    }
+    downscaler_ffi::lower_priority(cmd, priority.nice, priority.idle_io);
+}
+
+/// Run the rest of this thread at the priority set for the run, for work done in-process like
+/// hashing outputs - only threads started for the job should, as it can't be undone
+pub fn lower_this_thread() {
+    let priority = *PRIORITY.lock().unwrap();
+    if priority.nice.is_none() && !priority.idle_io {
+        return;
+    }
+    downscaler_ffi::lower_thread_priority(priority.nice, priority.idle_io);
+}
+
+/// The same priority for a command run by a shell elsewhere, as words to put in front of it
+pub fn wrapper() -> Vec<String> {
+    let priority = *PRIORITY.lock().unwrap();
+    let mut words = Vec::new();
+    if let Some(nice) = priority.nice {
+        words.extend(["nice".to_owned(), "-n".to_owned(), nice.to_string()]);
+    }
+    if priority.idle_io {
+        words.extend(["ionice", "-c", "3"].map(str::to_owned));
+    }
+    words
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::debug;
+
+use crate::config::Config;
+use crate::remote;
+use crate::workers::Slot;
+
+/// A single stream as reported by `ffprobe -show_streams`
+///
+/// Fields are kept as the raw strings ffprobe gives us, with accessors for the bits we care about
+#[derive(Debug, Clone)]
+pub struct Stream {
+    pub index: usize,
+    fields: HashMap<String, String>,
+}
+
+impl Stream {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(|s| s.as_str())
+    }
+
+    pub fn codec_type(&self) -> &str {
+        self.get("codec_type").unwrap_or("unknown")
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.codec_type() == "audio"
+    }
+
+    pub fn is_subtitle(&self) -> bool {
+        self.codec_type() == "subtitle"
+    }
+
+    pub fn codec_name(&self) -> &str {
+        self.get("codec_name").unwrap_or("unknown")
+    }
+
+    pub fn is_forced(&self) -> bool {
+        self.get("disposition.forced") == Some("1")
+    }
+
+    /// The language tag, or "und" (undetermined) like ffmpeg itself uses
+    pub fn language(&self) -> &str {
+        self.get("tags.language").unwrap_or("und")
+    }
+
+    pub fn is_video(&self) -> bool {
+        // cover art and thumbnails show up as video streams
+        self.codec_type() == "video" && self.get("disposition.attached_pic") != Some("1")
+    }
+
+    pub fn width(&self) -> Option<u32> {
+        self.get("width").and_then(|w| w.parse().ok())
+    }
+
+    pub fn height(&self) -> Option<u32> {
+        self.get("height").and_then(|h| h.parse().ok())
+    }
+
+    /// Average frames per second
+    pub fn frame_rate(&self) -> Option<f64> {
+        self.get("avg_frame_rate").and_then(parse_rate)
+    }
+
+    /// The base frame rate - differs from the average for variable frame rate video
+    pub fn base_frame_rate(&self) -> Option<f64> {
+        self.get("r_frame_rate").and_then(parse_rate)
+    }
+
+    /// Bits per colour sample, e.g. 10 for `yuv420p10le`
+    pub fn bit_depth(&self) -> Option<u32> {
+        if let Some(bits) = self.get("bits_per_raw_sample").and_then(|b| b.parse().ok()) {
+            return Some(bits);
+        }
+        let pix_fmt = self.get("pix_fmt")?;
+        Some(if pix_fmt.contains("p12") {
+            12
+        } else if pix_fmt.contains("p10") || pix_fmt.starts_with("p010") {
+            10
+        } else {
+            8
+        })
+    }
+
+    /// HDR video - PQ or HLG transfer characteristics, or BT.2020 primaries
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.get("color_transfer"),
+            Some("smpte2084" | "arib-std-b67")
+        ) || self.get("color_primaries") == Some("bt2020")
+    }
+
+    pub fn channels(&self) -> Option<u32> {
+        self.get("channels").and_then(|c| c.parse().ok())
+    }
+}
+
+/// What ffprobe could tell us about a file
+#[derive(Debug, Clone)]
+pub struct ProbeInfo {
+    pub streams: Vec<Stream>,
+    format: HashMap<String, String>,
+}
+
+impl ProbeInfo {
+    pub fn format_get(&self, key: &str) -> Option<&str> {
+        self.format.get(key).map(|s| s.as_str())
+    }
+
+    /// Duration in seconds, if the container knows it
+    pub fn duration(&self) -> Option<f64> {
+        self.format_get("duration").and_then(|d| d.parse().ok())
+    }
+
+    /// The main video stream
+    pub fn video(&self) -> Option<&Stream> {
+        self.streams.iter().find(|s| s.is_video())
+    }
+
+    /// Overall bits per second, if the container knows it
+    pub fn bit_rate(&self) -> Option<f64> {
+        self.format_get("bit_rate").and_then(|b| b.parse().ok())
+    }
+
+    pub fn audio_streams(&self) -> impl Iterator<Item = &Stream> {
+        self.streams.iter().filter(|s| s.is_audio())
+    }
+
+    pub fn subtitle_streams(&self) -> impl Iterator<Item = &Stream> {
+        self.streams.iter().filter(|s| s.is_subtitle())
+    }
+}
+
+/// ffprobe gives rates as fractions like `30000/1001`
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/').unwrap_or((rate, "1"));
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+/// ffprobe's "flat" format escapes quotes, backslashes and control chars with a backslash
+fn unquote(value: &str) -> String {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('t') => result.push('\t'),
+                Some(other) => result.push(other),
+                None => {}
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn parse_flat(output: &str) -> ProbeInfo {
+    let mut streams: Vec<Stream> = Vec::new();
+    let mut format = HashMap::new();
+    for line in output.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let value = unquote(value);
+        if let Some(rest) = key.strip_prefix("streams.stream.") {
+            let (index, field) = match rest.split_once('.') {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let index: usize = match index.parse() {
+                Ok(i) => i,
+                Err(_) => continue,
+            };
+            while streams.len() <= index {
+                streams.push(Stream {
+                    index: streams.len(),
+                    fields: HashMap::new(),
+                });
+            }
+            streams[index].fields.insert(field.to_owned(), value);
+        } else if let Some(field) = key.strip_prefix("format.") {
+            format.insert(field.to_owned(), value);
+        }
+    }
+    ProbeInfo { streams, format }
+}
+
+/// Run ffprobe over a file, returning stream and container information
+pub fn probe(path: &Path, config: &Config, slot: Slot) -> Result<ProbeInfo> {
+    debug!("probing {:?}", path);
+    let mut cmd = config.command("ffprobe", slot);
+    cmd.args([
+        "-v",
+        "error",
+        "-show_streams",
+        "-show_format",
+        "-of",
+        "flat",
+    ])
+    .arg(path);
+    let output = remote::prepare(cmd)?.0.output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed on {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_flat(&String::from_utf8_lossy(&output.stdout)))
+}
@@ -0,0 +1,434 @@
+//! Tracking how far through the run we are, for anything outside that wants to know
+//!
+//! Progress is also kept per group - the override (or other rule) each file's settings came from -
+//! so the end of a run can say how each policy went rather than just giving one total.
+//!
+//! It also keeps the queue of files not yet started, which can be reordered while the run goes
+//! on, and the last few files finished, for the `--tui` screen.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::daemon;
+use crate::mqtt::Mqtt;
+use crate::report::human_size;
+
+#[derive(Debug, Default, Clone)]
+struct State {
+    done: usize,
+    failed: usize,
+    /// Sources currently being encoded and when they started, in that order
+    active: Vec<(PathBuf, Instant)>,
+}
+
+/// How many finished files to remember
+const RECENT_KEPT: usize = 50;
+
+/// A file that has finished
+#[derive(Debug, Clone)]
+pub struct Recent {
+    pub source: PathBuf,
+    pub succeeded: bool,
+    /// Source and output bytes, for files that made an output
+    pub sizes: Option<(u64, u64)>,
+}
+
+/// Where to move a file in the queue
+#[derive(Debug, Clone, Copy)]
+pub enum Move {
+    Up,
+    Down,
+    Top,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    total: usize,
+    done: usize,
+    failed: usize,
+    /// Bytes saved by the files done so far
+    saved: i64,
+}
+
+#[derive(Debug, Default)]
+struct Groups {
+    of: HashMap<PathBuf, String>,
+    groups: BTreeMap<String, Group>,
+}
+
+/// Where the run has got to, in numbers
+#[derive(Debug, Clone, Copy)]
+pub struct Counts {
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+    /// Bytes saved by the files done so far
+    pub saved: i64,
+}
+
+pub struct Progress {
+    /// Files in the run, including any added since it started
+    total: AtomicUsize,
+    started: Instant,
+    state: Mutex<State>,
+    groups: Mutex<Groups>,
+    /// Sources not started yet, in the order they will be
+    queue: Mutex<Vec<PathBuf>>,
+    recent: Mutex<VecDeque<Recent>>,
+    /// Seconds of video in each source, where known
+    durations: Mutex<HashMap<PathBuf, f64>>,
+    mqtt: Option<Mqtt>,
+}
+
+impl Progress {
+    pub fn new(total: usize, mqtt: Option<Mqtt>) -> Progress {
+        Progress {
+            total: AtomicUsize::new(total),
+            started: Instant::now(),
+            state: Mutex::new(State::default()),
+            groups: Mutex::new(Groups::default()),
+            queue: Mutex::new(Vec::new()),
+            recent: Mutex::new(VecDeque::new()),
+            durations: Mutex::new(HashMap::new()),
+            mqtt,
+        }
+    }
+
+    /// The sources to be encoded, in order
+    pub fn set_queue(&self, sources: Vec<PathBuf>) {
+        *self.queue.lock().unwrap() = sources;
+    }
+
+    /// Add a source to the end of the queue, once the run has started
+    pub fn enqueued(&self, source: &Path) {
+        self.total.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().unwrap().push(source.to_owned());
+    }
+
+    /// The sources not started yet
+    pub fn queued(&self) -> Vec<PathBuf> {
+        self.queue.lock().unwrap().clone()
+    }
+
+    /// Which of `pending` to start next - the first in the queue's order - taking it off the queue
+    pub fn next<'a>(&self, pending: impl Iterator<Item = &'a Path>) -> usize {
+        let pending: Vec<&Path> = pending.collect();
+        let mut queue = self.queue.lock().unwrap();
+        let index = first_queued(&queue, &pending);
+        if let Some(next) = pending.get(index) {
+            queue.retain(|q| q != next);
+        }
+        index
+    }
+
+    /// Which of `pending` will be started first, leaving the queue as it is
+    pub fn upcoming<'a>(&self, pending: impl Iterator<Item = &'a Path>) -> usize {
+        let pending: Vec<&Path> = pending.collect();
+        first_queued(&self.queue.lock().unwrap(), &pending)
+    }
+
+    /// Move the `index`th queued source, returning where it ends up
+    pub fn move_queued(&self, index: usize, to: Move) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        if index >= queue.len() {
+            return index;
+        }
+        let target = match to {
+            Move::Up => index.saturating_sub(1),
+            Move::Down => (index + 1).min(queue.len() - 1),
+            Move::Top => 0,
+        };
+        let source = queue.remove(index);
+        queue.insert(target, source);
+        target
+    }
+
+    pub fn set_duration(&self, source: &Path, seconds: f64) {
+        self.durations
+            .lock()
+            .unwrap()
+            .insert(source.to_owned(), seconds);
+    }
+
+    pub fn duration(&self, source: &Path) -> Option<f64> {
+        self.durations.lock().unwrap().get(source).copied()
+    }
+
+    /// The files finished most recently, oldest first
+    pub fn recent(&self) -> Vec<Recent> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The files being encoded right now, and when each started
+    pub fn active_since(&self) -> Vec<(PathBuf, Instant)> {
+        self.state.lock().unwrap().active.clone()
+    }
+
+    pub fn counts(&self) -> Counts {
+        let state = self.state.lock().unwrap().clone();
+        self.counts_of(&state)
+    }
+
+    fn counts_of(&self, state: &State) -> Counts {
+        let saved = self
+            .groups
+            .lock()
+            .unwrap()
+            .groups
+            .values()
+            .map(|g| g.saved)
+            .sum();
+        Counts {
+            total: self.total(),
+            done: state.done,
+            failed: state.failed,
+            saved,
+        }
+    }
+
+    /// How long the run has been going
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// A short line on how far through the run is, and how much it has saved
+    pub fn headline(&self) -> String {
+        let state = self.state.lock().unwrap().clone();
+        self.headline_of(&state)
+    }
+
+    fn headline_of(&self, state: &State) -> String {
+        let counts = self.counts_of(state);
+        format!(
+            "{} of {} finished - {} done, {} failed - {} saved - {}",
+            counts.done + counts.failed,
+            counts.total,
+            counts.done,
+            counts.failed,
+            human_size(counts.saved),
+            rounded(self.started.elapsed())
+        )
+    }
+
+    /// Say which group a source belongs to - before it starts
+    pub fn add_to_group(&self, source: &Path, group: &str) {
+        let mut groups = self.groups.lock().unwrap();
+        groups.of.insert(source.to_owned(), group.to_owned());
+        groups.groups.entry(group.to_owned()).or_default().total += 1;
+    }
+
+    /// Move a source to the group its settings really came from, once it has been probed - some
+    /// rules go by what is in the file, which isn't known before it starts
+    pub fn regroup(&self, source: &Path, group: &str) {
+        let mut groups = self.groups.lock().unwrap();
+        let Groups { of, groups } = &mut *groups;
+        match of.insert(source.to_owned(), group.to_owned()) {
+            Some(old) if old == group => return,
+            Some(old) => {
+                if let Some(was) = groups.get_mut(&old) {
+                    was.total -= 1;
+                    if was.total == 0 {
+                        groups.remove(&old);
+                    }
+                }
+            }
+            None => {}
+        }
+        groups.entry(group.to_owned()).or_default().total += 1;
+    }
+
+    pub fn group_of(&self, source: &Path) -> Option<String> {
+        self.groups.lock().unwrap().of.get(source).cloned()
+    }
+
+    /// Count the bytes a finished source saved towards its group
+    pub fn saved(&self, source: &Path, source_size: u64, output_size: u64) {
+        let bytes = source_size as i64 - output_size as i64;
+        if let Some(recent) = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .rfind(|r| r.source == source)
+        {
+            recent.sizes = Some((source_size, output_size));
+        }
+        let mut groups = self.groups.lock().unwrap();
+        let Groups { of, groups } = &mut *groups;
+        if let Some(group) = of.get(source).and_then(|g| groups.get_mut(g)) {
+            group.saved += bytes;
+        }
+    }
+
+    /// One line per group, like `override movies/4k: 82 of 120 done, 1.9 TB saved`
+    pub fn group_summary(&self) -> Vec<String> {
+        let groups = self.groups.lock().unwrap();
+        groups
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let mut line = format!(
+                    "{}: {} of {} done, {} saved",
+                    name,
+                    group.done,
+                    group.total,
+                    human_size(group.saved)
+                );
+                if group.failed > 0 {
+                    line.push_str(&format!(", {} failed", group.failed));
+                }
+                line
+            })
+            .collect()
+    }
+
+    pub fn started(&self, source: &Path) {
+        let snapshot = {
+            let mut state = self.state.lock().unwrap();
+            state.active.push((source.to_owned(), Instant::now()));
+            state.clone()
+        };
+        self.publish("running", &snapshot);
+    }
+
+    pub fn finished(&self, source: &Path, succeeded: bool) {
+        let snapshot = {
+            let mut state = self.state.lock().unwrap();
+            state.active.retain(|(a, _)| a != source);
+            if succeeded {
+                state.done += 1;
+            } else {
+                state.failed += 1;
+            }
+            state.clone()
+        };
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.push_back(Recent {
+                source: source.to_owned(),
+                succeeded,
+                sizes: None,
+            });
+            if recent.len() > RECENT_KEPT {
+                recent.pop_front();
+            }
+        }
+        {
+            let mut groups = self.groups.lock().unwrap();
+            let Groups { of, groups } = &mut *groups;
+            if let Some(group) = of.get(source).and_then(|g| groups.get_mut(g)) {
+                if succeeded {
+                    group.done += 1;
+                } else {
+                    group.failed += 1;
+                }
+            }
+        }
+        self.publish("running", &snapshot);
+    }
+
+    /// How many files are being encoded right now
+    pub fn running(&self) -> usize {
+        self.state.lock().unwrap().active.len()
+    }
+
+    /// The files being encoded right now
+    pub fn active(&self) -> Vec<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state.active.iter().map(|(p, _)| p.clone()).collect()
+    }
+
+    fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// A one-line summary of where the run is, for `kill -USR1`
+    pub fn status(&self) -> String {
+        let total = self.total();
+        let state = self.state.lock().unwrap().clone();
+        let finished = state.done + state.failed;
+        let remaining = total.saturating_sub(finished);
+        let waiting = remaining.saturating_sub(state.active.len());
+        let percent = if total > 0 {
+            finished as f64 * 100.0 / total as f64
+        } else {
+            100.0
+        };
+        let current = match state.active.as_slice() {
+            [] => "nothing running".to_owned(),
+            active => format!(
+                "encoding {}",
+                active
+                    .iter()
+                    .map(|(p, started)| format!("{:?} for {}", p, rounded(started.elapsed())))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        format!(
+            "status after {}: {} of {} finished ({:.1}%) - {} done, {} failed, {} remaining of which {} waiting - {}",
+            rounded(self.started.elapsed()),
+            finished,
+            total,
+            percent,
+            state.done,
+            state.failed,
+            remaining,
+            waiting,
+            current
+        )
+    }
+
+    /// The run is over, whether or not every job ran
+    pub fn ended(&self) {
+        let snapshot = self.state.lock().unwrap().clone();
+        self.publish("idle", &snapshot);
+    }
+
+    fn publish(&self, status: &str, state: &State) {
+        daemon::notify(&format!("STATUS={}", self.headline_of(state)));
+        let mqtt = match &self.mqtt {
+            Some(mqtt) => mqtt,
+            None => return,
+        };
+        let total = self.total();
+        let finished = state.done + state.failed;
+        let percent = if total > 0 {
+            finished as f64 * 100.0 / total as f64
+        } else {
+            100.0
+        };
+        let current = state
+            .active
+            .last()
+            .map(|(p, _)| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        mqtt.publish("status", status);
+        let waiting = total.saturating_sub(finished + state.active.len());
+        mqtt.publish("queue", &waiting.to_string());
+        mqtt.publish("current", &current);
+        mqtt.publish("percent", &format!("{:.1}", percent));
+        mqtt.publish("failures", &state.failed.to_string());
+    }
+}
+
+/// A duration to the second, for people
+fn rounded(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(duration.as_secs()))
+}
+
+/// The index in `pending` of whichever comes first in `queue`
+fn first_queued(queue: &[PathBuf], pending: &[&Path]) -> usize {
+    queue
+        .iter()
+        .find_map(|q| pending.iter().position(|p| p == q))
+        .unwrap_or(0)
+}
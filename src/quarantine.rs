@@ -0,0 +1,184 @@
+//! Setting aside sources that keep failing, so one bad download doesn't stop every run
+//!
+//! With `--quarantine-after N`, each failed source is counted in `downscaler-quarantine` at the
+//! destination root, along with the last error. Once a source has failed N times it is
+//! quarantined: the run carries on without it, and later runs skip it until its line is deleted.
+//! With `--quarantine-dir` the file itself is also moved out of the source tree.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+
+use crate::state;
+use crate::state::Format;
+use crate::storage;
+
+pub const QUARANTINE: &str = "downscaler-quarantine";
+
+const FORMAT: Format = Format {
+    name: "downscaler-quarantine",
+    version: 1,
+    migrations: &[],
+};
+
+#[derive(Debug, Clone)]
+struct Entry {
+    failures: u32,
+    error: String,
+}
+
+#[derive(Debug)]
+pub struct Quarantine {
+    path: PathBuf,
+    /// How many failures quarantine a file
+    after: u32,
+    /// Where quarantined files are moved, if anywhere
+    dir: Option<PathBuf>,
+    /// Keyed by the path relative to the source root
+    entries: Mutex<BTreeMap<String, Entry>>,
+    /// Quarantined during this run, with their errors
+    added: Mutex<Vec<(String, String)>>,
+}
+
+/// What happened to a failed file
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Counted, but not failed often enough yet
+    Counted(u32),
+    Quarantined,
+}
+
+fn key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Quarantine {
+    /// The quarantine list for `destination`, keeping whatever it already holds
+    pub fn open(destination: &Path, after: u32, dir: Option<PathBuf>) -> Result<Quarantine> {
+        let path = destination.join(QUARANTINE);
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            for (number, line) in FORMAT.load(&path)?.iter().enumerate() {
+                let fields: Vec<String> = line.split('\t').map(state::unescape).collect();
+                let parsed = match fields.as_slice() {
+                    [source, failures, error] => failures.parse().ok().map(|failures| {
+                        (
+                            source.clone(),
+                            Entry {
+                                failures,
+                                error: error.clone(),
+                            },
+                        )
+                    }),
+                    _ => None,
+                };
+                let (source, entry) = parsed.ok_or_else(|| {
+                    anyhow!("{:?} line {}: not a quarantine entry", path, number + 2)
+                })?;
+                entries.insert(source, entry);
+            }
+        }
+        Ok(Quarantine {
+            path,
+            after: after.max(1),
+            dir,
+            entries: Mutex::new(entries),
+            added: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Has `relative` failed often enough to be left alone?
+    pub fn is_quarantined(&self, relative: &Path) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&key(relative))
+            .is_some_and(|e| e.failures >= self.after)
+    }
+
+    /// Count a failure of `source`, moving it to the quarantine directory once it has failed enough
+    pub fn record_failure(&self, source: &Path, relative: &Path, error: &str) -> Result<Failure> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key(relative)).or_insert(Entry {
+            failures: 0,
+            error: String::new(),
+        });
+        entry.failures += 1;
+        entry.error = error.to_owned();
+        let failed = entry.failures;
+        self.save(&entries)?;
+        drop(entries);
+        if failed < self.after {
+            return Ok(Failure::Counted(failed));
+        }
+        if let Some(dir) = &self.dir {
+            let moved = dir.join(relative);
+            let outcome = moved
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| storage::move_file(source, &moved));
+            if let Err(e) = outcome {
+                warn!("could not move {:?} to {:?}: {}", source, moved, e);
+            }
+        }
+        self.added
+            .lock()
+            .unwrap()
+            .push((key(relative), error.to_owned()));
+        Ok(Failure::Quarantined)
+    }
+
+    /// Log the files quarantined during this run
+    pub fn summarise(&self) {
+        let added = self.added.lock().unwrap();
+        if added.is_empty() {
+            return;
+        }
+        warn!(
+            "quarantined {} files this run - they are listed in {:?}",
+            added.len(),
+            self.path
+        );
+        for (source, error) in added.iter() {
+            warn!("  {}: {}", source, error);
+        }
+    }
+
+    /// Forget past failures of a file that has now worked
+    pub fn record_success(&self, relative: &Path) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(&key(relative)).is_some() {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+
+    // called with the lock held, so two workers can't write it at once
+    fn save(&self, entries: &BTreeMap<String, Entry>) -> Result<()> {
+        let body: String = entries
+            .iter()
+            .map(|(source, entry)| {
+                format!(
+                    "{}\t{}\t{}\n",
+                    state::escape(source),
+                    entry.failures,
+                    state::escape(&entry.error)
+                )
+            })
+            .collect();
+        FORMAT
+            .save(&self.path, &body)
+            .with_context(|| format!("updating {:?}", self.path))
+    }
+}
@@ -0,0 +1,67 @@
+//! Target bitrate encoding, as an alternative to constant quality
+//!
+//! CRF gives consistent quality but unpredictable sizes. A target bitrate, capped with a VBV
+//! buffer, keeps outputs within what a device or network stream can handle.
+
+use std::path::Path;
+use std::str::FromStr;
+
+/// Bits per second, parsed from e.g. `2M`, `2500k` or `800000`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bitrate(u64);
+
+impl FromStr for Bitrate {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (number, multiplier) = match text.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&text[..i], 1_000.0),
+            Some((i, 'm' | 'M')) => (&text[..i], 1_000_000.0),
+            _ => (text, 1.0),
+        };
+        let value: f64 = number
+            .parse()
+            .map_err(|_| format!("expected a bitrate like `2M` or `2500k`, not {:?}", text))?;
+        if value <= 0.0 {
+            return Err(format!("bitrate must be positive, not {:?}", text));
+        }
+        Ok(Bitrate((value * multiplier) as u64))
+    }
+}
+
+/// One pass of a two-pass encode, and the stats file the passes share
+#[derive(Debug, Clone, Copy)]
+pub struct Pass<'a> {
+    pub number: u8,
+    pub log: &'a Path,
+}
+
+/// How video size is controlled
+#[derive(Debug, Clone, Copy)]
+pub enum Rate<'a> {
+    /// Constant quality, from the CRF setting
+    Quality,
+    Bitrate {
+        bitrate: Bitrate,
+        pass: Option<Pass<'a>>,
+    },
+}
+
+impl Bitrate {
+    pub fn bits_per_second(self) -> u64 {
+        self.0
+    }
+
+    /// The target, with peaks allowed up to half as much again
+    pub fn args(self) -> Vec<String> {
+        vec![
+            "-b:v".to_owned(),
+            self.0.to_string(),
+            "-maxrate".to_owned(),
+            (self.0 * 3 / 2).to_string(),
+            "-bufsize".to_owned(),
+            (self.0 * 2).to_string(),
+        ]
+    }
+}
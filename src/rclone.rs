@@ -0,0 +1,221 @@
+//! Sources and destinations on any rclone remote, for `rclone://remote/path` paths
+//!
+//! `rclone://gdrive/films` is what rclone calls `gdrive:films` - Google Drive, B2, WebDAV, SFTP
+//! and whatever else `rclone config` has set up. Everything goes through the `rclone` binary, so
+//! its config file and `RCLONE_*` environment variables apply as usual.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::debug;
+
+use crate::storage::DirEntry;
+use crate::storage::FileInfo;
+use crate::storage::Kind;
+use crate::storage::Storage;
+
+/// rclone's exit statuses for a directory or file that isn't there
+const NOT_FOUND: [i32; 2] = [3, 4];
+
+#[derive(Debug, Clone)]
+pub struct Rclone {
+    remote: String,
+}
+
+/// A number, boolean or string field of the one object `rclone lsjson --stat` prints
+fn json_field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let quoted = format!("\"{}\":", key);
+    // a name with the key in it has its quotes escaped, so the real one is the first unescaped one
+    let start = json
+        .match_indices(&quoted)
+        .find(|(at, _)| !json[..*at].ends_with('\\'))
+        .map(|(at, _)| at + quoted.len())?;
+    let value = json[start..].trim_start();
+    match value.strip_prefix('"') {
+        Some(text) => text.split('"').next(),
+        None => value.split([',', '}']).next().map(str::trim),
+    }
+}
+
+/// One line of `rclone lsf`, where directories end in `/`
+fn parse_line(line: &str) -> DirEntry {
+    let (name, kind) = match line.strip_suffix('/') {
+        Some(dir) => (dir, Kind::Dir),
+        None => (line, Kind::File),
+    };
+    DirEntry {
+        name: OsString::from(name),
+        kind,
+    }
+}
+
+impl Rclone {
+    /// For `rest`, the part of a path after `rclone://`
+    pub fn new(rest: &str) -> Result<Rclone> {
+        let remote = rest.split('/').next().unwrap_or_default();
+        if remote.is_empty() {
+            return Err(anyhow!("rclone://{} has no remote name", rest));
+        }
+        Ok(Rclone {
+            remote: remote.to_owned(),
+        })
+    }
+
+    /// What rclone calls a full `rclone://remote/path` path - `remote:path`
+    fn target(&self, path: &Path) -> Result<String> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("{:?} isn't valid UTF-8", path))?;
+        let rest = path
+            .strip_prefix("rclone://")
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+            .filter(|(remote, _)| *remote == self.remote)
+            .map(|(_, rest)| rest)
+            .ok_or_else(|| anyhow!("{:?} isn't on rclone remote {}", path, self.remote))?;
+        Ok(format!("{}:{}", self.remote, rest.trim_start_matches('/')))
+    }
+
+    /// Run `rclone` with `args`, giving its stdout - or `None` for something that isn't there
+    fn rclone(&self, args: &[&str]) -> Result<Option<String>> {
+        debug!("running rclone {}", args.join(" "));
+        let output = Command::new("rclone")
+            .args(args)
+            .env("TZ", "UTC")
+            .output()
+            .map_err(|e| anyhow!("running rclone - is it installed? {}", e))?;
+        match output.status.code() {
+            Some(0) => Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned())),
+            Some(code) if NOT_FOUND.contains(&code) => Ok(None),
+            _ => Err(anyhow!(
+                "rclone {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    fn lsf(&self, target: &str) -> Result<Option<String>> {
+        self.rclone(&["lsf", "--max-depth", "1", target])
+    }
+}
+
+impl Storage for Rclone {
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>> {
+        let target = self.target(dir)?;
+        let listed = self
+            .lsf(&target)?
+            .ok_or_else(|| anyhow!("{} isn't there", target))?;
+        Ok(listed.lines().map(parse_line).collect())
+    }
+
+    /// Bucket remotes list a path that isn't there as an empty directory, so this asks about the
+    /// path itself rather than listing it
+    fn stat(&self, path: &Path) -> Result<Option<FileInfo>> {
+        let target = self.target(path)?;
+        let json = self.rclone(&["lsjson", "--stat", "--no-mimetype", &target])?;
+        let json = match json.as_deref().map(str::trim) {
+            Some(json) if json.starts_with('{') => json.to_owned(),
+            _ => return Ok(None),
+        };
+        let kind = match json_field(&json, "IsDir") {
+            Some("true") => Kind::Dir,
+            Some("false") => Kind::File,
+            _ => return Ok(None),
+        };
+        Ok(Some(FileInfo {
+            kind,
+            len: match kind {
+                Kind::Dir => 0,
+                _ => json_field(&json, "Size")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0),
+            },
+            // in UTC, as rclone is run with TZ=UTC
+            modified: json_field(&json, "ModTime").and_then(|t| humantime::parse_rfc3339(t).ok()),
+            id: None,
+        }))
+    }
+
+    fn fetch(&self, path: &Path, local: &Path) -> Result<()> {
+        let target = self.target(path)?;
+        self.rclone(&["copyto", &target, &local.to_string_lossy()])?
+            .ok_or_else(|| anyhow!("{} isn't there", target))?;
+        Ok(())
+    }
+
+    /// rclone uploads to a partial file and renames it, where the remote has names to rename
+    fn store(&self, local: &Path, path: &Path) -> Result<()> {
+        let target = self.target(path)?;
+        self.rclone(&["copyto", &local.to_string_lossy(), &target])?
+            .ok_or_else(|| anyhow!("could not copy {:?} to {}", local, target))?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.rclone(&["deletefile", &self.target(path)?])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_fields_are_read_as_numbers_booleans_or_strings() {
+        let json = r#"{"Path":"a.mkv","Name":"a.mkv","Size":1234,"ModTime":"2024-05-01T12:00:00Z","IsDir":false}"#;
+        assert_eq!(json_field(json, "Size"), Some("1234"));
+        assert_eq!(json_field(json, "IsDir"), Some("false"));
+        assert_eq!(json_field(json, "ModTime"), Some("2024-05-01T12:00:00Z"));
+        assert_eq!(json_field(json, "Missing"), None);
+    }
+
+    #[test]
+    fn json_keys_inside_names_are_skipped() {
+        // a file called `x","IsDir":true,"Size":1:` - its quotes are escaped
+        let json = r#"{"Path":"x\",\"IsDir\":true,\"Size\":1:","Name":"x\",\"IsDir\":true,\"Size\":1:","Size":99,"IsDir":false}"#;
+        assert_eq!(json_field(json, "IsDir"), Some("false"));
+        assert_eq!(json_field(json, "Size"), Some("99"));
+    }
+
+    #[test]
+    fn json_names_with_backslashes_and_newlines_are_skipped() {
+        let json = r#"{"Path":"dir\\","Name":"a\nb\"Size\":1","Size":7,"IsDir":true}"#;
+        assert_eq!(json_field(json, "Size"), Some("7"));
+        assert_eq!(json_field(json, "IsDir"), Some("true"));
+    }
+
+    #[test]
+    fn targets_are_only_on_this_remote() {
+        let rclone = Rclone::new("gdrive/films").unwrap();
+        let target = |path: &str| rclone.target(Path::new(path));
+        assert_eq!(
+            target("rclone://gdrive/films/a.mkv").unwrap(),
+            "gdrive:films/a.mkv"
+        );
+        assert_eq!(target("rclone://gdrive").unwrap(), "gdrive:");
+        assert_eq!(target("rclone://gdrive//films").unwrap(), "gdrive:films");
+        assert!(target("rclone://gdrive2/films").is_err());
+        assert!(target("rclone://other/gdrive/films").is_err());
+        assert!(target("/gdrive/films").is_err());
+    }
+
+    #[test]
+    fn remotes_need_a_name() {
+        assert!(Rclone::new("").is_err());
+        assert!(Rclone::new("/films").is_err());
+    }
+
+    #[test]
+    fn listed_directories_end_in_a_slash() {
+        let dir = parse_line("extras/");
+        assert_eq!(dir.name, "extras");
+        assert!(matches!(dir.kind, Kind::Dir));
+        let file = parse_line("a.mkv");
+        assert_eq!(file.name, "a.mkv");
+        assert!(matches!(file.kind, Kind::File));
+    }
+}
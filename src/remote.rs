@@ -0,0 +1,392 @@
+//! Running ffmpeg on another machine over SSH, for `--remote user@host`
+//!
+//! Everything else - scanning, deciding, storing outputs - stays on this machine. Each ffmpeg and
+//! ffprobe command is run through `ssh` instead, on copies of the temp files under
+//! `--remote-temp-dir`: files in the temp directory that a command names are copied across with
+//! `scp` first, and any it writes there are copied back once it succeeds. The remote copies are
+//! removed along with the local ones.
+//!
+//! Commands the watchdog runs record their remote pid in a `.pid` file next to the first temp
+//! file they name, so pausing, resuming and killing them acts on the remote process - signalling
+//! the local `ssh` would leave it running.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+use log::warn;
+
+use crate::priority;
+
+#[derive(Debug, Clone)]
+struct Remote {
+    host: String,
+    /// The local temp directory, and where its files go on the remote
+    local: PathBuf,
+    remote: PathBuf,
+}
+
+/// A file's size and modification time, to tell when it has changed
+type Stamp = (u64, Option<SystemTime>);
+
+static REMOTE: Mutex<Option<Remote>> = Mutex::new(None);
+/// The local files the remote has an up to date copy of
+static SYNCED: Mutex<Option<HashMap<PathBuf, Stamp>>> = Mutex::new(None);
+/// Remote directories already made
+static MADE: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Run commands on `host`, keeping copies of what is in the local `temp` directory in `remote_temp`
+pub fn set(host: &str, temp: &Path, remote_temp: &Path) {
+    *REMOTE.lock().unwrap() = Some(Remote {
+        host: host.to_owned(),
+        local: temp.to_owned(),
+        remote: remote_temp.to_owned(),
+    });
+}
+
+fn current() -> Option<Remote> {
+    REMOTE.lock().unwrap().clone()
+}
+
+/// `word`, safe from the remote shell
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+fn synced(path: &Path, stamp: Option<Stamp>) {
+    let mut synced = SYNCED.lock().unwrap();
+    let synced = synced.get_or_insert_with(HashMap::new);
+    match stamp {
+        Some(stamp) => synced.insert(path.to_owned(), stamp),
+        None => synced.remove(path),
+    };
+}
+
+impl Remote {
+    fn there(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.local).ok()?;
+        Some(self.remote.join(relative))
+    }
+
+    /// `arg` with any paths in the temp directory changed to the remote's - even inside a filter
+    fn translate(&self, arg: &str) -> String {
+        let local = format!("{}/", self.local.to_string_lossy().trim_end_matches('/'));
+        let remote = format!("{}/", self.remote.to_string_lossy().trim_end_matches('/'));
+        arg.replace(&local, &remote)
+    }
+
+    fn target(&self, path: &Path) -> String {
+        format!("{}:{}", self.host, path.to_string_lossy())
+    }
+
+    fn ssh(&self) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.args(["-n", "-o", "BatchMode=yes"]).arg(&self.host);
+        cmd
+    }
+
+    /// Run a shell command line on the remote, returning its output
+    fn shell(&self, line: &str) -> Result<String> {
+        let output = self
+            .ssh()
+            .arg(line)
+            .output()
+            .context("running ssh - is it installed?")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ssh {} failed: {}",
+                self.host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn scp(&self, from: &str, to: &str) -> Result<()> {
+        let output = Command::new("scp")
+            .args(["-q", "-B", from, to])
+            .output()
+            .context("running scp - is it installed?")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "scp {} {} failed: {}",
+                from,
+                to,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copy the local file `path` to `there`, unless the remote has it already
+    fn upload(&self, path: &Path, there: &Path) -> Result<()> {
+        let stamp = stamp(path);
+        let known = SYNCED
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|s| s.get(path).copied());
+        if stamp.is_some() && known == stamp {
+            return Ok(());
+        }
+        if let Some(dir) = there.parent() {
+            let made = MADE
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|m| m.contains(dir));
+            if !made {
+                self.shell(&format!("mkdir -p {}", quote(&dir.to_string_lossy())))?;
+                MADE.lock()
+                    .unwrap()
+                    .get_or_insert_with(HashSet::new)
+                    .insert(dir.to_owned());
+            }
+        }
+        debug!("copying {:?} to {}", path, self.target(there));
+        self.scp(&path.to_string_lossy(), &self.target(there))
+            .with_context(|| format!("copying {:?} to {}", path, self.host))?;
+        synced(path, stamp);
+        Ok(())
+    }
+}
+
+/// Where a command records its pid on the remote, for a file `there` it names
+fn pid_path(there: &Path) -> PathBuf {
+    let mut name = there.as_os_str().to_owned();
+    name.push(".pid");
+    PathBuf::from(name)
+}
+
+/// The files a command may write in the temp directory, to copy back once it succeeds - and
+/// where its remote pid is, to signal it while it runs
+#[derive(Debug, Default)]
+pub struct Fetch {
+    remote: Option<Remote>,
+    outputs: Vec<(PathBuf, PathBuf)>,
+    pid_file: Option<PathBuf>,
+}
+
+impl Fetch {
+    /// Is the command running on the remote, where `signal` can reach it?
+    pub fn signalled(&self) -> bool {
+        self.pid_file.is_some()
+    }
+
+    /// Send `signal`, like `STOP`, to the command on the remote - which fails if it hasn't
+    /// recorded its pid yet
+    pub fn signal(&self, signal: &str) -> Result<()> {
+        let (remote, pid_file) = match (&self.remote, &self.pid_file) {
+            (Some(remote), Some(pid_file)) => (remote, pid_file),
+            _ => return Err(anyhow!("not running on a remote")),
+        };
+        remote.shell(&format!(
+            "kill -{} \"$(cat {})\"",
+            signal,
+            quote(&pid_file.to_string_lossy())
+        ))?;
+        Ok(())
+    }
+
+    /// Copy back whatever the command wrote
+    pub fn fetch(self) -> Result<()> {
+        let remote = match (self.remote, self.outputs.is_empty()) {
+            (Some(remote), false) => remote,
+            _ => return Ok(()),
+        };
+        let names: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|(_, there)| quote(&there.to_string_lossy()))
+            .collect();
+        // only some of them are files - a pass log prefix, or a segment pattern, isn't
+        let written = remote.shell(&format!(
+            "for f in {}; do [ -f \"$f\" ] && echo \"$f\"; done; true",
+            names.join(" ")
+        ))?;
+        let written: HashSet<&str> = written.lines().collect();
+        for (path, there) in &self.outputs {
+            if !written.contains(there.to_string_lossy().as_ref()) {
+                continue;
+            }
+            debug!("copying {} back to {:?}", remote.target(there), path);
+            remote
+                .scp(&remote.target(there), &path.to_string_lossy())
+                .with_context(|| format!("copying {:?} back from {}", path, remote.host))?;
+            synced(path, stamp(path));
+        }
+        Ok(())
+    }
+}
+
+/// `cmd` as it should be run - over SSH with `--remote`, with the files it reads copied across
+pub fn prepare(cmd: Command) -> Result<(Command, Fetch)> {
+    prepare_as(cmd, false)
+}
+
+/// `prepare`, also recording the command's pid on the remote so the `Fetch` can signal it
+pub fn prepare_signalled(cmd: Command) -> Result<(Command, Fetch)> {
+    prepare_as(cmd, true)
+}
+
+fn prepare_as(cmd: Command, signalled: bool) -> Result<(Command, Fetch)> {
+    let remote = match current() {
+        Some(remote) => remote,
+        None => return Ok((cmd, Fetch::default())),
+    };
+    let mut words = Vec::new();
+    let envs: Vec<String> = cmd
+        .get_envs()
+        .filter_map(|(key, value)| {
+            let value = value?.to_string_lossy();
+            Some(format!("{}={}", key.to_string_lossy(), quote(&value)))
+        })
+        .collect();
+    if !envs.is_empty() {
+        words.push("env".to_owned());
+        words.extend(envs);
+    }
+    words.extend(priority::wrapper());
+    words.push(quote(&cmd.get_program().to_string_lossy()));
+    let mut outputs = Vec::new();
+    let mut pid_file = None;
+    for arg in cmd.get_args() {
+        words.push(quote(&remote.translate(&arg.to_string_lossy())));
+        let path = Path::new(arg);
+        let there = match remote.there(path) {
+            Some(there) => there,
+            None => continue,
+        };
+        if signalled && pid_file.is_none() {
+            pid_file = Some(pid_path(&there));
+        }
+        match path.is_file() {
+            true => remote.upload(path, &there)?,
+            false => outputs.push((path.to_owned(), there)),
+        }
+    }
+    let mut line = words.join(" ");
+    if let Some(pid_file) = &pid_file {
+        // exec keeps the shell's pid, through env and nice, for ffmpeg itself
+        line = format!(
+            "echo $$ > {} && exec {}",
+            quote(&pid_file.to_string_lossy()),
+            line
+        );
+    }
+    let mut ssh = remote.ssh();
+    ssh.arg(line);
+    Ok((
+        ssh,
+        Fetch {
+            remote: Some(remote),
+            outputs,
+            pid_file,
+        },
+    ))
+}
+
+/// Remove the remote copies of `files` and their pid files, and of any files starting with
+/// `prefix`
+pub fn remove(files: &[PathBuf], prefix: &Path) {
+    let remote = match current() {
+        Some(remote) => remote,
+        None => return,
+    };
+    let mut words: Vec<String> = Vec::new();
+    for file in files {
+        synced(file, None);
+        if let Some(there) = remote.there(file) {
+            words.push(quote(&there.to_string_lossy()));
+            words.push(quote(&pid_path(&there).to_string_lossy()));
+        }
+    }
+    if let Some(there) = remote.there(prefix) {
+        words.push(format!("{}*", quote(&there.to_string_lossy())));
+    }
+    if let Err(e) = remote.shell(&format!("rm -f {}", words.join(" "))) {
+        warn!("could not remove temp files on {}: {:#}", remote.host, e);
+    }
+}
+
+/// Remove the remote copy of the temp directory `dir`
+pub fn remove_dir(dir: &Path) {
+    let remote = match current() {
+        Some(remote) => remote,
+        None => return,
+    };
+    if let Some(there) = remote.there(dir) {
+        if let Err(e) = remote.shell(&format!("rm -rf {}", quote(&there.to_string_lossy()))) {
+            warn!("could not remove {:?} on {}: {:#}", there, remote.host, e);
+        }
+        if let Some(made) = MADE.lock().unwrap().as_mut() {
+            made.retain(|d| !d.starts_with(&there));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote() -> Remote {
+        Remote {
+            host: "me@gpubox".to_owned(),
+            local: PathBuf::from("/var/tmp/downscaler/"),
+            remote: PathBuf::from("/tmp"),
+        }
+    }
+
+    #[test]
+    fn words_are_single_quoted() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("$HOME `id` \"x\" \\ \n"), "'$HOME `id` \"x\" \\ \n'");
+    }
+
+    #[test]
+    fn temp_paths_are_translated_even_in_filters() {
+        let remote = remote();
+        assert_eq!(
+            remote.translate("/var/tmp/downscaler/run-1/in.mkv"),
+            "/tmp/run-1/in.mkv"
+        );
+        assert_eq!(
+            remote.translate("subtitles=filename='/var/tmp/downscaler/run-1/in.mkv':si=0"),
+            "subtitles=filename='/tmp/run-1/in.mkv':si=0"
+        );
+    }
+
+    #[test]
+    fn paths_outside_the_temp_directory_are_left_alone() {
+        let remote = remote();
+        for arg in [
+            "/var/tmp/downscaler-other/in.mkv",
+            "/var/tmp/downscaler",
+            "-c:v",
+            "/films/a.mkv",
+        ] {
+            assert_eq!(remote.translate(arg), arg);
+        }
+        assert_eq!(remote.there(Path::new("/films/a.mkv")), None);
+        assert_eq!(
+            remote.there(Path::new("/var/tmp/downscaler/run-1/in.mkv")),
+            Some(PathBuf::from("/tmp/run-1/in.mkv"))
+        );
+    }
+}
@@ -0,0 +1,374 @@
+//! Per-file results for a run, written out as JSON and/or a static HTML page
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::json;
+use crate::state;
+
+/// What happened to one file
+#[derive(Debug, Clone)]
+pub struct FileResult {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// Directory relative to the destination root, for grouping
+    pub dir: String,
+    /// The override or other rule its settings came from, for grouping
+    pub rule: String,
+    pub source_size: u64,
+    pub output_size: Option<u64>,
+    /// Length of the video in seconds, if we probed it
+    pub duration: Option<f64>,
+    /// Wall-clock seconds spent encoding
+    pub elapsed: f64,
+    pub error: Option<String>,
+    /// Decisions made along the way, like keeping the original
+    pub notes: Vec<String>,
+}
+
+impl FileResult {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// How many times faster than realtime the encode ran
+    pub fn speed(&self) -> Option<f64> {
+        match self.duration {
+            Some(duration) if self.elapsed > 0.0 && self.succeeded() => {
+                Some(duration / self.elapsed)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .str("source", &self.source.to_string_lossy())
+            .str("dest", &self.dest.to_string_lossy())
+            .str("dir", &self.dir)
+            .str("rule", &self.rule)
+            .num("source_size", self.source_size)
+            .opt_num("output_size", self.output_size)
+            .opt_num("duration", self.duration)
+            .num("elapsed", format!("{:.3}", self.elapsed))
+            .opt_num("speed", self.speed().map(|s| format!("{:.3}", s)))
+            .opt_str("error", self.error.as_deref())
+            .raw(
+                "notes",
+                json::array(self.notes.iter().map(|n| json::string(n))),
+            )
+            .build()
+    }
+}
+
+/// Totals for one destination directory, or one rule
+#[derive(Debug, Default)]
+struct DirSummary {
+    files: usize,
+    failed: usize,
+    source_size: u64,
+    output_size: u64,
+    duration: f64,
+    elapsed: f64,
+}
+
+impl DirSummary {
+    fn saved(&self) -> i64 {
+        self.source_size as i64 - self.output_size as i64
+    }
+
+    fn speed(&self) -> Option<f64> {
+        if self.elapsed > 0.0 && self.duration > 0.0 {
+            Some(self.duration / self.elapsed)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Report<'a> {
+    pub started: SystemTime,
+    pub finished: SystemTime,
+    pub results: &'a [FileResult],
+}
+
+impl Report<'_> {
+    fn grouped(&self, key: fn(&FileResult) -> &str) -> BTreeMap<&str, DirSummary> {
+        let mut dirs: BTreeMap<&str, DirSummary> = BTreeMap::new();
+        for result in self.results {
+            let summary = dirs.entry(key(result)).or_default();
+            summary.files += 1;
+            match result.output_size {
+                Some(output_size) if result.succeeded() => {
+                    summary.source_size += result.source_size;
+                    summary.output_size += output_size;
+                    if let (Some(duration), Some(_)) = (result.duration, result.speed()) {
+                        summary.duration += duration;
+                        summary.elapsed += result.elapsed;
+                    }
+                }
+                _ => summary.failed += 1,
+            }
+        }
+        dirs
+    }
+
+    pub fn to_json(&self) -> String {
+        json::Object::new()
+            .str(
+                "started",
+                &humantime::format_rfc3339_seconds(self.started).to_string(),
+            )
+            .str(
+                "finished",
+                &humantime::format_rfc3339_seconds(self.finished).to_string(),
+            )
+            .raw(
+                "files",
+                json::array(self.results.iter().map(|r| r.to_json())),
+            )
+            .build()
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        state::write_atomic(path, self.to_json().as_bytes())
+    }
+
+    pub fn write_html(&self, path: &Path) -> Result<()> {
+        state::write_atomic(path, self.to_html().as_bytes())
+    }
+
+    pub fn to_html(&self) -> String {
+        let dirs = self.grouped(|r| r.dir.as_str());
+        let succeeded: Vec<&FileResult> = self.results.iter().filter(|r| r.succeeded()).collect();
+        let failed: Vec<&FileResult> = self.results.iter().filter(|r| !r.succeeded()).collect();
+        let total_source: u64 = succeeded.iter().map(|r| r.source_size).sum();
+        let total_output: u64 = succeeded.iter().filter_map(|r| r.output_size).sum();
+
+        let mut html = String::new();
+        html.push_str(HTML_HEAD);
+        let _ = write!(
+            html,
+            "<h1>Downscaler report</h1>\n<p>Run from {} to {}: {} files encoded, {} failed, {} saved ({} &rarr; {})</p>\n",
+            humantime::format_rfc3339_seconds(self.started),
+            humantime::format_rfc3339_seconds(self.finished),
+            succeeded.len(),
+            failed.len(),
+            human_size(total_source as i64 - total_output as i64),
+            human_size(total_source as i64),
+            human_size(total_output as i64),
+        );
+
+        html.push_str("<h2>Savings per directory</h2>\n");
+        let max_saved = dirs.values().map(|d| d.saved()).max().unwrap_or(0).max(1);
+        html.push_str(&bar_chart(
+            dirs.iter()
+                .map(|(dir, d)| (dir_label(dir), d.saved() as f64, human_size(d.saved()))),
+            max_saved as f64,
+        ));
+        html.push_str(&summary_table(
+            "Directory",
+            &dirs
+                .iter()
+                .map(|(dir, d)| (dir_label(dir), d))
+                .collect::<Vec<_>>(),
+        ));
+
+        html.push_str("<h2>Savings per rule</h2>\n");
+        let rules = self.grouped(|r| r.rule.as_str());
+        html.push_str(&summary_table(
+            "Rule",
+            &rules
+                .iter()
+                .map(|(rule, d)| (rule.to_string(), d))
+                .collect::<Vec<_>>(),
+        ));
+
+        html.push_str("<h2>Encode speed per directory</h2>\n");
+        let max_speed = dirs.values().filter_map(|d| d.speed()).fold(0.0, f64::max);
+        html.push_str(&bar_chart(
+            dirs.iter().filter_map(|(dir, d)| {
+                d.speed().map(|s| (dir_label(dir), s, format!("{:.2}x", s)))
+            }),
+            max_speed,
+        ));
+
+        if !failed.is_empty() {
+            html.push_str("<h2>Failures</h2>\n<table class=\"sortable\">\n<thead><tr><th>File</th><th>Error</th></tr></thead>\n<tbody>\n");
+            for result in &failed {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    escape(&result.source.to_string_lossy()),
+                    escape(result.error.as_deref().unwrap_or_default()),
+                );
+            }
+            html.push_str("</tbody>\n</table>\n");
+        }
+
+        html.push_str("<h2>Files</h2>\n<table class=\"sortable\">\n<thead><tr><th>File</th><th>Directory</th><th>Source</th><th>Output</th><th>Saved</th><th>Encode time</th><th>Speed</th><th>Notes</th></tr></thead>\n<tbody>\n");
+        for result in &succeeded {
+            let output_size = result.output_size.unwrap_or(0) as i64;
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td>{}{}{}<td data-sort=\"{:.0}\">{}</td>{}<td>{}</td></tr>",
+                escape(
+                    &result
+                        .dest
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ),
+                escape(&dir_label(&result.dir)),
+                size_cell(result.source_size as i64),
+                size_cell(output_size),
+                size_cell(result.source_size as i64 - output_size),
+                result.elapsed,
+                humantime::format_duration(std::time::Duration::from_secs(result.elapsed as u64)),
+                speed_cell(result.speed()),
+                escape(&result.notes.join("; ")),
+            );
+        }
+        html.push_str("</tbody>\n</table>\n");
+        html.push_str(HTML_TAIL);
+        html
+    }
+}
+
+/// A sortable table of totals, one row per group
+fn summary_table(heading: &str, groups: &[(String, &DirSummary)]) -> String {
+    let mut html = format!("<table class=\"sortable\">\n<thead><tr><th>{}</th><th>Files</th><th>Failed</th><th>Source</th><th>Output</th><th>Saved</th><th>Speed</th></tr></thead>\n<tbody>\n", heading);
+    for (label, d) in groups {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td>{}{}{}{}</tr>",
+            escape(label),
+            d.files,
+            d.failed,
+            size_cell(d.source_size as i64),
+            size_cell(d.output_size as i64),
+            size_cell(d.saved()),
+            speed_cell(d.speed()),
+        );
+    }
+    html.push_str("</tbody>\n</table>\n");
+    html
+}
+
+fn dir_label(dir: &str) -> String {
+    if dir.is_empty() {
+        ".".to_owned()
+    } else {
+        dir.to_owned()
+    }
+}
+
+/// Sizes in binary units, e.g. `1.4 GiB` - negative if a file grew
+pub fn human_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes.unsigned_abs() as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    let sign = if bytes < 0 { "-" } else { "" };
+    if unit == 0 {
+        format!("{}{} {}", sign, value, UNITS[unit])
+    } else {
+        format!("{}{:.1} {}", sign, value, UNITS[unit])
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn size_cell(bytes: i64) -> String {
+    format!("<td data-sort=\"{}\">{}</td>", bytes, human_size(bytes))
+}
+
+fn speed_cell(speed: Option<f64>) -> String {
+    match speed {
+        Some(speed) => format!("<td data-sort=\"{:.3}\">{:.2}x</td>", speed, speed),
+        None => "<td data-sort=\"0\"></td>".to_owned(),
+    }
+}
+
+/// A simple horizontal bar chart - each bar is the value as a percentage of `max`
+fn bar_chart(bars: impl Iterator<Item = (String, f64, String)>, max: f64) -> String {
+    let mut html = String::from("<div class=\"chart\">\n");
+    for (label, value, text) in bars {
+        let percent = if max > 0.0 {
+            (value / max * 100.0).clamp(0.0, 100.0)
+        } else {
+            0.0
+        };
+        let _ = writeln!(
+            html,
+            "<div class=\"row\"><span class=\"label\">{}</span><span class=\"bar\" style=\"width: {:.1}%\"></span><span class=\"value\">{}</span></div>",
+            escape(&label),
+            percent,
+            escape(&text),
+        );
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Downscaler report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; }
+th { background: #eee; cursor: pointer; }
+th[data-dir=asc]::after { content: " \25b2"; }
+th[data-dir=desc]::after { content: " \25bc"; }
+.chart { margin-bottom: 1em; max-width: 60em; }
+.chart .row { display: flex; align-items: center; margin: 2px 0; }
+.chart .label { width: 20em; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; }
+.chart .bar { display: inline-block; height: 1em; background: #4a8; margin-right: 0.5em; }
+</style>
+</head>
+<body>
+"#;
+
+const HTML_TAIL: &str = r#"<script>
+document.querySelectorAll("table.sortable").forEach(function (table) {
+  table.querySelectorAll("th").forEach(function (th, column) {
+    th.addEventListener("click", function () {
+      var ascending = th.dataset.dir !== "asc";
+      table.querySelectorAll("th").forEach(function (other) { delete other.dataset.dir; });
+      th.dataset.dir = ascending ? "asc" : "desc";
+      var body = table.tBodies[0];
+      var key = function (row) {
+        var cell = row.cells[column];
+        return cell.dataset.sort !== undefined ? cell.dataset.sort : cell.textContent;
+      };
+      var rows = Array.prototype.slice.call(body.rows);
+      rows.sort(function (a, b) {
+        var x = key(a), y = key(b);
+        var nx = parseFloat(x), ny = parseFloat(y);
+        var order = (!isNaN(nx) && !isNaN(ny)) ? nx - ny : x.localeCompare(y);
+        return ascending ? order : -order;
+      });
+      rows.forEach(function (row) { body.appendChild(row); });
+    });
+  });
+});
+</script>
+</body>
+</html>
+"#;
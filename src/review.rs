@@ -0,0 +1,60 @@
+//! Keeping copies of the most recent outputs somewhere local, for spot-checking quality
+//!
+//! The destination is often a slow NAS, so this saves pulling files back just to look at them.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+use log::warn;
+
+use crate::staging::path_hash;
+use crate::storage;
+
+/// Move a finished temp output into the review directory, then prune it to the newest `keep` files
+///
+/// Failures are only warnings - review copies are a convenience, not part of the job
+pub fn retain(output: &Path, dest: &Path, review_dir: &Path, keep: usize) {
+    if let Err(e) = try_retain(output, dest, review_dir, keep) {
+        warn!("could not keep a review copy of {:?}: {:#}", dest, e);
+    }
+}
+
+fn try_retain(output: &Path, dest: &Path, review_dir: &Path, keep: usize) -> Result<()> {
+    fs::create_dir_all(review_dir).with_context(|| format!("creating {:?}", review_dir))?;
+    // the hash keeps same-named files from different directories apart
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = dest.extension().unwrap_or_default().to_string_lossy();
+    let target = review_dir.join(format!("{}.{}.{}", stem, &path_hash(dest)[..8], ext));
+    debug!("keeping review copy {:?}", target);
+    // the temp directory is likely on another filesystem
+    storage::move_file(output, &target)
+        .with_context(|| format!("moving {:?} to {:?}", output, target))?;
+    prune(review_dir, keep)
+}
+
+/// Remove all but the `keep` most recently modified files
+fn prune(review_dir: &Path, keep: usize) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(review_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((metadata.modified()?, entry.path()));
+        }
+    }
+    files.sort();
+    let excess = files.len().saturating_sub(keep);
+    for (_, path) in files.into_iter().take(excess) {
+        debug!("pruning review copy {:?}", path);
+        match fs::remove_file(&path) {
+            // another worker got there first
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            other => other.with_context(|| format!("removing {:?}", path))?,
+        }
+    }
+    Ok(())
+}
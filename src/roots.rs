@@ -0,0 +1,94 @@
+//! Source roots - each `--source`, and the folder under the destination its outputs go in
+//!
+//! `--source PATH=SUBDIR` puts the outputs from `PATH` under `SUBDIR` in the destination. With a
+//! single source and no `SUBDIR` they go straight into the destination, as they always have; with
+//! several, each goes under its own directory name, so `-s /mnt/tv -s /mnt/movies` ends up with
+//! `tv/` and `movies/` side by side. An empty `SUBDIR`, as in `PATH=`, means the destination
+//! itself - and lets a path with `=` in it through.
+
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRoot {
+    pub path: PathBuf,
+    /// Given with `=SUBDIR`
+    pub subdir: Option<PathBuf>,
+}
+
+impl FromStr for SourceRoot {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (path, subdir) = match text.rsplit_once('=') {
+            Some((path, subdir)) => (path, Some(PathBuf::from(subdir))),
+            None => (text, None),
+        };
+        if path.is_empty() {
+            return Err(format!("expected PATH or PATH=SUBDIR, not {:?}", text));
+        }
+        if subdir
+            .as_deref()
+            .is_some_and(|s| s.is_absolute() || s.components().any(|c| c.as_os_str() == ".."))
+        {
+            return Err(format!(
+                "the SUBDIR in {:?} has to stay inside the destination",
+                text
+            ));
+        }
+        Ok(SourceRoot {
+            path: PathBuf::from(path),
+            subdir,
+        })
+    }
+}
+
+impl fmt::Display for SourceRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(subdir) = &self.subdir {
+            write!(f, "={}", subdir.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Each root with where its outputs go under the destination, checking no two share a place
+/// unless they were put there on purpose
+pub fn subdirs(roots: &[SourceRoot]) -> Result<Vec<(&Path, PathBuf)>> {
+    let mut placed: Vec<(&Path, PathBuf)> = Vec::new();
+    for root in roots {
+        let subdir = match (&root.subdir, roots.len()) {
+            (Some(subdir), _) => subdir.clone(),
+            (None, 1) => PathBuf::new(),
+            (None, _) => PathBuf::from(root.path.file_name().ok_or_else(|| {
+                anyhow!(
+                    "{:?} has no directory name to put its outputs under - use {}=SUBDIR",
+                    root.path,
+                    root.path.display()
+                )
+            })?),
+        };
+        placed.push((&root.path, subdir));
+    }
+    for (i, root) in roots.iter().enumerate() {
+        let clash = placed
+            .iter()
+            .enumerate()
+            .find(|(j, (_, subdir))| *j != i && *subdir == placed[i].1);
+        if let (None, Some((_, (other, subdir)))) = (&root.subdir, clash) {
+            return Err(anyhow!(
+                "{:?} and {:?} would both go in {:?} - give them their own with PATH=SUBDIR",
+                root.path,
+                other,
+                subdir
+            ));
+        }
+    }
+    Ok(placed)
+}
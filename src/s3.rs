@@ -0,0 +1,255 @@
+//! Sources and destinations in S3 or anything that speaks its API, for `s3://bucket/prefix` paths
+//!
+//! Goes through the `aws` CLI, so credentials, regions and profiles are set up as they are for it.
+//! For other S3-compatible services - MinIO, Backblaze B2, Wasabi and so on - set
+//! `AWS_ENDPOINT_URL`, or `endpoint_url` in the profile.
+//!
+//! S3 has no directories, only keys with `/` in them - a directory is any prefix ending in `/`
+//! that some key starts with. An upload only appears once it is complete, so outputs still
+//! appear all at once.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::debug;
+
+use crate::storage::DirEntry;
+use crate::storage::FileInfo;
+use crate::storage::Kind;
+use crate::storage::Storage;
+
+#[derive(Debug, Clone)]
+pub struct S3 {
+    bucket: String,
+}
+
+/// The `aws` CLI's "not found" errors, from s3api and s3 commands alike
+fn not_found(stderr: &str) -> bool {
+    stderr.contains("Not Found") || stderr.contains("NoSuchKey") || stderr.contains("(404)")
+}
+
+impl S3 {
+    /// For `rest`, the part of a path after `s3://`
+    pub fn new(rest: &str) -> Result<S3> {
+        let bucket = rest.split('/').next().unwrap_or_default();
+        if bucket.is_empty() {
+            return Err(anyhow!("s3://{} has no bucket name", rest));
+        }
+        Ok(S3 {
+            bucket: bucket.to_owned(),
+        })
+    }
+
+    /// The key for a full `s3://bucket/key` path
+    fn key(&self, path: &Path) -> Result<String> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("{:?} isn't valid UTF-8", path))?;
+        let key = path
+            .strip_prefix("s3://")
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+            .filter(|(bucket, _)| *bucket == self.bucket)
+            .map(|(_, key)| key)
+            .ok_or_else(|| anyhow!("{:?} isn't in s3://{}", path, self.bucket))?;
+        Ok(key.trim_start_matches('/').to_owned())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", self.bucket, key)
+    }
+
+    /// Run `aws` with `args`, giving its stdout - or `None` for something that isn't there
+    fn aws(&self, args: &[&str]) -> Result<Option<String>> {
+        debug!("running aws {}", args.join(" "));
+        let output = Command::new("aws")
+            .args(args)
+            .output()
+            .map_err(|e| anyhow!("running the aws CLI - is it installed? {}", e))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.status.success() {
+            true => Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned())),
+            false if not_found(&stderr) => Ok(None),
+            false => Err(anyhow!(
+                "aws {} failed: {}",
+                args[..2].join(" "),
+                stderr.trim()
+            )),
+        }
+    }
+
+    /// Whether anything has a key starting with `prefix`
+    fn any_under(&self, prefix: &str) -> Result<bool> {
+        let listed = self.aws(&[
+            "s3api",
+            "list-objects-v2",
+            "--bucket",
+            &self.bucket,
+            "--prefix",
+            prefix,
+            "--max-items",
+            "1",
+            "--query",
+            "KeyCount",
+            "--output",
+            "text",
+        ])?;
+        Ok(listed.is_some_and(|count| count.trim().parse::<u64>().unwrap_or(0) > 0))
+    }
+}
+
+/// A directory's prefix - its key with a `/` on the end, unless it is the whole bucket
+fn dir_prefix(key: &str) -> String {
+    match key.is_empty() || key.ends_with('/') {
+        true => key.to_owned(),
+        false => format!("{}/", key),
+    }
+}
+
+/// S3's `2024-05-01T12:00:00+00:00`, which is always UTC
+fn parse_time(text: &str) -> Option<SystemTime> {
+    humantime::parse_rfc3339(&text.trim().replace("+00:00", "Z")).ok()
+}
+
+impl Storage for S3 {
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>> {
+        let prefix = dir_prefix(&self.key(dir)?);
+        // each page's prefixes, then its keys, one per line - as `None` when a page has none
+        let listed = self
+            .aws(&[
+                "s3api",
+                "list-objects-v2",
+                "--bucket",
+                &self.bucket,
+                "--prefix",
+                &prefix,
+                "--delimiter",
+                "/",
+                "--query",
+                "[CommonPrefixes[].[Prefix], Contents[].[Key]][]",
+                "--output",
+                "text",
+            ])?
+            .unwrap_or_default();
+        let mut entries = Vec::new();
+        for line in listed.lines() {
+            let name = match line.strip_prefix(&prefix) {
+                Some(name) if !name.is_empty() => name,
+                // the marker some tools make for an empty directory, or `None`
+                _ => continue,
+            };
+            let (name, kind) = match name.strip_suffix('/') {
+                Some(dir) => (dir, Kind::Dir),
+                None => (name, Kind::File),
+            };
+            entries.push(DirEntry {
+                name: OsString::from(name),
+                kind,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<FileInfo>> {
+        let key = self.key(path)?;
+        let dir = FileInfo {
+            kind: Kind::Dir,
+            len: 0,
+            modified: None,
+            id: None,
+        };
+        if key.is_empty() {
+            return Ok(Some(dir));
+        }
+        let head = self.aws(&[
+            "s3api",
+            "head-object",
+            "--bucket",
+            &self.bucket,
+            "--key",
+            &key,
+            "--query",
+            "[ContentLength, LastModified]",
+            "--output",
+            "text",
+        ])?;
+        if let Some(head) = head {
+            let mut fields = head.split_whitespace();
+            let len = fields.next().and_then(|l| l.parse().ok()).unwrap_or(0);
+            return Ok(Some(FileInfo {
+                kind: Kind::File,
+                len,
+                modified: fields.next().and_then(parse_time),
+                id: None,
+            }));
+        }
+        match self.any_under(&dir_prefix(&key))? {
+            true => Ok(Some(dir)),
+            false => Ok(None),
+        }
+    }
+
+    fn fetch(&self, path: &Path, local: &Path) -> Result<()> {
+        let url = self.url(&self.key(path)?);
+        let local = local.to_string_lossy();
+        self.aws(&["s3", "cp", "--only-show-errors", &url, &local])?
+            .ok_or_else(|| anyhow!("{} isn't there", url))?;
+        Ok(())
+    }
+
+    fn store(&self, local: &Path, path: &Path) -> Result<()> {
+        let url = self.url(&self.key(path)?);
+        let local = local.to_string_lossy();
+        self.aws(&["s3", "cp", "--only-show-errors", &local, &url])?
+            .ok_or_else(|| anyhow!("s3://{} isn't there", self.bucket))?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let url = self.url(&self.key(path)?);
+        self.aws(&["s3", "rm", "--only-show-errors", &url])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_only_taken_from_this_bucket() {
+        let s3 = S3::new("films/4k").unwrap();
+        let key = |path: &str| s3.key(Path::new(path));
+        assert_eq!(key("s3://films/4k/a.mkv").unwrap(), "4k/a.mkv");
+        assert_eq!(key("s3://films").unwrap(), "");
+        assert_eq!(key("s3://films//a.mkv").unwrap(), "a.mkv");
+        assert!(key("s3://films-old/a.mkv").is_err());
+        assert!(key("s3://other/films/a.mkv").is_err());
+        assert!(key("/films/a.mkv").is_err());
+    }
+
+    #[test]
+    fn buckets_need_a_name() {
+        assert!(S3::new("").is_err());
+        assert!(S3::new("/films").is_err());
+    }
+
+    #[test]
+    fn directory_prefixes_end_in_a_slash() {
+        assert_eq!(dir_prefix(""), "");
+        assert_eq!(dir_prefix("4k"), "4k/");
+        assert_eq!(dir_prefix("4k/"), "4k/");
+    }
+
+    #[test]
+    fn times_are_utc() {
+        assert_eq!(
+            parse_time("2024-05-01T12:00:00+00:00"),
+            humantime::parse_rfc3339("2024-05-01T12:00:00Z").ok()
+        );
+        assert_eq!(parse_time("yesterday"), None);
+    }
+}
@@ -0,0 +1,153 @@
+//! Spotting screen recordings, which compress far better with their own settings
+//!
+//! Screen captures are mostly still, flat-coloured frames - a lower frame rate, a higher CRF and
+//! flat-content tuning can shrink them a lot more than film-style settings, with no visible loss.
+//!
+//! Detection is a score from a few cheap ffprobe heuristics, so no frames need decoding.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use log::info;
+
+use crate::config::Entry;
+use crate::probe::ProbeInfo;
+use crate::settings::Settings;
+
+/// Frame rates that cameras and video production use - anything else suggests a capture tool
+const STANDARD_RATES: [f64; 8] = [23.976, 24.0, 25.0, 29.97, 30.0, 50.0, 59.94, 60.0];
+
+/// Names screen capture tools commonly give their files, or put in metadata
+const NAME_HINTS: [&str; 4] = [
+    "screen recording",
+    "screen capture",
+    "screencast",
+    "screen_recording",
+];
+
+/// The `[screen-recording]` config section - detection thresholds and the profile to apply
+#[derive(Debug, Clone)]
+pub struct ScreenProfile {
+    pub crf: u32,
+    pub fps: f64,
+    /// Encoder `-tune`, only used with libx264 and libx265
+    pub tune: String,
+    /// Durations in seconds at least this long count towards a screen recording
+    pub min_duration: f64,
+    /// Bits per pixel per frame at or below this suggest mostly static content
+    pub max_bits_per_pixel: f64,
+    /// Heights at least this large count towards a screen recording
+    pub min_height: u32,
+    /// How many points a file needs to be treated as a screen recording
+    pub min_score: u32,
+}
+
+impl Default for ScreenProfile {
+    fn default() -> Self {
+        ScreenProfile {
+            crf: 32,
+            fps: 15.0,
+            tune: "animation".to_owned(),
+            min_duration: 600.0,
+            max_bits_per_pixel: 0.02,
+            min_height: 1440,
+            min_score: 3,
+        }
+    }
+}
+
+impl ScreenProfile {
+    pub fn set(&mut self, entry: &Entry) -> Result<()> {
+        match entry.key.as_str() {
+            "crf" => self.crf = entry.parse()?,
+            "fps" => self.fps = entry.parse()?,
+            "tune" => self.tune = entry.value.clone(),
+            "min_duration" => self.min_duration = entry.parse()?,
+            "max_bits_per_pixel" => self.max_bits_per_pixel = entry.parse()?,
+            "min_height" => self.min_height = entry.parse()?,
+            "min_score" => self.min_score = entry.parse()?,
+            other => {
+                return Err(anyhow!(
+                    "line {}: unknown screen-recording setting {}",
+                    entry.line,
+                    other
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Score how much `info` looks like a screen recording, with the reasons why
+    fn score(&self, source: &Path, info: &ProbeInfo) -> (u32, Vec<&'static str>) {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+        let mut hint = source
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        for key in ["tags.encoder", "tags.title", "tags.comment"] {
+            if let Some(tag) = info.format_get(key) {
+                hint.push(' ');
+                hint.push_str(&tag.to_lowercase());
+            }
+        }
+        if NAME_HINTS.iter().any(|h| hint.contains(h)) {
+            // a name or tag is strong evidence on its own
+            score += 3;
+            reasons.push("name or tags");
+        }
+        if info.duration().is_some_and(|d| d >= self.min_duration) {
+            score += 1;
+            reasons.push("long");
+        }
+        let video = match info.video() {
+            Some(video) => video,
+            None => return (score, reasons),
+        };
+        let (width, height) = (video.width().unwrap_or(0), video.height().unwrap_or(0));
+        // 16:10 and other desktop shapes, rather than the usual video sizes
+        let odd_shape = height > 0 && (width * 9 != height * 16) && (width * 3 != height * 4);
+        if height >= self.min_height || odd_shape {
+            score += 1;
+            reasons.push("desktop resolution");
+        }
+        if let Some(fps) = video.frame_rate() {
+            let variable = video
+                .base_frame_rate()
+                .is_some_and(|base| (base - fps).abs() > 0.5);
+            let standard = STANDARD_RATES.iter().any(|r| (r - fps).abs() < 0.05);
+            if variable || !standard {
+                score += 1;
+                reasons.push("unusual frame rate");
+            }
+            if let Some(bit_rate) = info.bit_rate() {
+                let pixels = f64::from(width) * f64::from(height) * fps;
+                if pixels > 0.0 && bit_rate / pixels <= self.max_bits_per_pixel {
+                    score += 1;
+                    reasons.push("low motion");
+                }
+            }
+        }
+        (score, reasons)
+    }
+
+    /// Switch `settings` to the screen recording profile, if `info` looks like one - returning
+    /// whether it did
+    pub fn apply(&self, source: &Path, info: &ProbeInfo, settings: &mut Settings) -> bool {
+        let (score, reasons) = self.score(source, info);
+        if score < self.min_score {
+            return false;
+        }
+        info!(
+            "treating {:?} as a screen recording ({})",
+            source,
+            reasons.join(", ")
+        );
+        settings.crf = self.crf;
+        settings.max_fps = Some(settings.max_fps.map_or(self.fps, |max| max.min(self.fps)));
+        settings.tune = Some(self.tune.clone());
+        true
+    }
+}
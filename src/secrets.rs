@@ -0,0 +1,61 @@
+//! Handing passwords and tokens to the tools we run, in files only we can read
+//!
+//! Anything on a command line can be seen by every local user with `ps`, so secrets go in an
+//! options file made readable by this user only, and the tool is pointed at that instead.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use anyhow::Result;
+
+/// Files made so far, so each gets a name of its own
+static MADE: AtomicUsize = AtomicUsize::new(0);
+
+/// A file only this user can read, removed when dropped
+#[derive(Debug)]
+pub struct SecretFile {
+    pub path: PathBuf,
+}
+
+impl SecretFile {
+    /// Write `contents` to `path`, which mustn't exist yet
+    pub fn create(path: &Path, contents: &str) -> Result<SecretFile> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("creating {:?}", path))?;
+        let secret = SecretFile {
+            path: path.to_owned(),
+        };
+        file.write_all(contents.as_bytes())
+            .with_context(|| format!("writing {:?}", path))?;
+        Ok(secret)
+    }
+
+    /// A file with a new name in `dir`, ending in `suffix`
+    pub fn create_in(dir: &Path, suffix: &str, contents: &str) -> Result<SecretFile> {
+        let name = format!(
+            "downscaler-{}-{}.{}",
+            process::id(),
+            MADE.fetch_add(1, Ordering::Relaxed),
+            suffix
+        );
+        SecretFile::create(&dir.join(name), contents)
+    }
+}
+
+impl Drop for SecretFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
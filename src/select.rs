@@ -0,0 +1,263 @@
+//! Narrowing a run down to some of the files found, e.g. "just the old 4K h264 ones"
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use log::warn;
+
+use crate::config::Config;
+use crate::probe;
+use crate::storage::Storage;
+use crate::workers::Slot;
+use crate::Opts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+/// A number with an optional comparison, like `>=2160` or `720p`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparison {
+    op: Op,
+    value: u32,
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (op, rest) = [
+            (">=", Op::GreaterOrEqual),
+            ("<=", Op::LessOrEqual),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Equal),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest)))
+        .unwrap_or((Op::Equal, text));
+        let rest = rest.trim();
+        let number = rest.strip_suffix('p').unwrap_or(rest);
+        let value = number
+            .parse()
+            .map_err(|_| format!("expected a height like `>=2160` or `720p`, not {:?}", text))?;
+        Ok(Comparison { op, value })
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Op::Less => "<",
+            Op::LessOrEqual => "<=",
+            Op::Equal => "",
+            Op::GreaterOrEqual => ">=",
+            Op::Greater => ">",
+        };
+        write!(f, "{}{}", op, self.value)
+    }
+}
+
+impl Comparison {
+    pub fn matches(&self, value: u32) -> bool {
+        match self.op {
+            Op::Less => value < self.value,
+            Op::LessOrEqual => value <= self.value,
+            Op::Equal => value == self.value,
+            Op::GreaterOrEqual => value >= self.value,
+            Op::Greater => value > self.value,
+        }
+    }
+}
+
+/// ffprobe says `hevc` where people often say `h265`
+fn same_codec(wanted: &str, codec: &str) -> bool {
+    let wanted = match wanted.to_lowercase().as_str() {
+        "h265" | "x265" => "hevc".to_owned(),
+        "x264" | "avc" => "h264".to_owned(),
+        other => other.to_owned(),
+    };
+    wanted == codec.to_lowercase()
+}
+
+/// A point in time for `--since` - a `YYYY-MM-DD` date (the start of that day, in UTC), or a
+/// duration back from now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    Date(SystemTime),
+    Ago(Duration),
+}
+
+impl FromStr for Since {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if let Ok(date) = humantime::parse_rfc3339_weak(&format!("{} 00:00:00", text)) {
+            return Ok(Since::Date(date));
+        }
+        humantime::parse_duration(text)
+            .map(Since::Ago)
+            .map_err(|_| {
+                format!(
+                    "expected a date like `2024-01-01` or a duration like `30d`, not {:?}",
+                    text
+                )
+            })
+    }
+}
+
+impl fmt::Display for Since {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Since::Date(date) => {
+                let date = humantime::format_rfc3339(*date).to_string();
+                write!(f, "{}", &date[..10])
+            }
+            Since::Ago(ago) => write!(f, "{} ago", humantime::format_duration(*ago)),
+        }
+    }
+}
+
+impl Since {
+    fn cutoff(&self) -> SystemTime {
+        match self {
+            Since::Date(date) => *date,
+            Since::Ago(ago) => SystemTime::now()
+                .checked_sub(*ago)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+        }
+    }
+}
+
+/// Do any of the `--only-...` options need ffprobe?
+fn needs_probe(opts: &Opts) -> bool {
+    opts.only_resolution.is_some() || !opts.only_codec.is_empty()
+}
+
+/// Is `source` one of the short samples that come with releases, like `movie-sample.mkv` or
+/// `Sample/movie.mkv`?
+fn is_release_sample(source: &Path, size: u64, opts: &Opts) -> bool {
+    if size >= opts.release_sample_max_size.0 {
+        return false;
+    }
+    let named = |name: &str| {
+        name.split(|c: char| !c.is_alphanumeric()).any(|word| {
+            opts.release_sample_names
+                .iter()
+                .any(|n| word.eq_ignore_ascii_case(n))
+        })
+    };
+    let stem = source.file_stem().unwrap_or_default().to_string_lossy();
+    let dir = source
+        .parent()
+        .and_then(|p| p.file_name())
+        .unwrap_or_default()
+        .to_string_lossy();
+    named(&stem) || named(&dir)
+}
+
+/// Why `source` is left out of this run, if it is
+///
+/// The `--only-...` options that need ffprobe open `source` directly, so need it to be local
+pub fn rejection(
+    source: &Path,
+    store: &dyn Storage,
+    config: &Config,
+    opts: &Opts,
+) -> Option<String> {
+    let info = store.stat(source).ok().flatten();
+    let modified = info.and_then(|info| info.modified);
+    if let Some(age) = opts.only_older_than {
+        let old_enough = modified
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .is_some_and(|a| a >= *age);
+        if !old_enough {
+            return Some(format!("not older than {}", age));
+        }
+    }
+    if let Some(since) = opts.since {
+        if modified.is_none_or(|m| m < since.cutoff()) {
+            return Some(format!("not modified since {}", since));
+        }
+    }
+    let size = info.map_or(0, |info| info.len);
+    if is_release_sample(source, size, opts) {
+        return Some("a release sample".to_owned());
+    }
+    if opts.min_size.is_some() || opts.max_size.is_some() {
+        if let Some(min) = opts.min_size.filter(|min| size < min.0) {
+            return Some(format!("smaller than {}", min));
+        }
+        if let Some(max) = opts.max_size.filter(|max| size > max.0) {
+            return Some(format!("larger than {}", max));
+        }
+    }
+    if !needs_probe(opts) {
+        return None;
+    }
+    // probing the source directly reads little more than the headers, so is cheap even remotely
+    let info = match probe::probe(source, config, Slot::default()) {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("could not probe {:?}: {:#}", source, e);
+            return Some("could not probe".to_owned());
+        }
+    };
+    let video = info.video();
+    if let Some(resolution) = opts.only_resolution {
+        if !video
+            .and_then(|v| v.height())
+            .is_some_and(|h| resolution.matches(h))
+        {
+            return Some(format!("resolution not {}", resolution));
+        }
+    }
+    if !opts.only_codec.is_empty() {
+        let codec = video.map(|v| v.codec_name()).unwrap_or_default();
+        if !opts.only_codec.iter().any(|c| same_codec(c, codec)) {
+            return Some(format!("codec not {}", opts.only_codec.join(" or ")));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_the_start_of_the_day_in_utc() {
+        let since: Since = "2024-01-31".parse().unwrap();
+        let midnight = humantime::parse_rfc3339("2024-01-31T00:00:00Z").unwrap();
+        assert_eq!(since, Since::Date(midnight));
+        assert_eq!(since.cutoff(), midnight);
+        assert_eq!(since.to_string(), "2024-01-31");
+    }
+
+    #[test]
+    fn durations_are_back_from_now() {
+        let since: Since = " 30d ".parse().unwrap();
+        assert_eq!(since, Since::Ago(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(since.to_string(), "30days ago");
+        let cutoff = since.cutoff();
+        let ago = SystemTime::now().duration_since(cutoff).unwrap();
+        assert!(ago >= Duration::from_secs(30 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn bad_points_in_time_are_refused() {
+        for text in ["", "yesterday", "2024-13-01", "2024-01-01T00:00:00Z", "-3d"] {
+            assert!(text.parse::<Since>().is_err(), "{:?}", text);
+        }
+    }
+}
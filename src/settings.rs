@@ -0,0 +1,53 @@
+//! Encode settings for one file - the command line defaults, adjusted per file
+
+use crate::crop::Crop;
+use crate::Opts;
+
+/// How tall outputs are at most, unless `--max-height` says otherwise
+pub const DEFAULT_MAX_HEIGHT: u32 = 720;
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Quality, as CRF or the encoder's nearest equivalent
+    pub crf: u32,
+    pub preset: String,
+    /// Encoder tuning, for encoders that have it
+    pub tune: Option<String>,
+    /// Reduce the frame rate to this, if the source is faster
+    pub max_fps: Option<f64>,
+    /// Scale down to at most this many lines
+    pub max_height: u32,
+    /// Add a deinterlacing filter - decided on each run, so not saved in plans
+    pub deinterlace: bool,
+    /// Crop off black bars - also decided on each run
+    pub crop: Option<Crop>,
+}
+
+impl Settings {
+    pub fn from_opts(opts: &Opts) -> Settings {
+        Settings {
+            crf: opts.crf,
+            preset: opts.preset.clone(),
+            tune: None,
+            max_fps: opts.max_fps,
+            max_height: opts.max_height,
+            deinterlace: false,
+            crop: None,
+        }
+    }
+
+    /// A short summary for log messages
+    pub fn describe(&self) -> String {
+        let mut text = format!(
+            "crf {}, preset {}, at most {}p",
+            self.crf, self.preset, self.max_height
+        );
+        if let Some(tune) = &self.tune {
+            text.push_str(&format!(", tune {}", tune));
+        }
+        if let Some(max_fps) = self.max_fps {
+            text.push_str(&format!(", at most {} fps", max_fps));
+        }
+        text
+    }
+}
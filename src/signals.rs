@@ -0,0 +1,93 @@
+//! Stopping cleanly on Ctrl-C or SIGTERM, logging a status report on SIGUSR1 or SIGINFO, and
+//! pausing or resuming on SIGUSR2
+//!
+//! The handlers only set flags. Running commands are killed when the watchdog next checks on
+//! them, the job fails, and the staged files are removed as it unwinds - so an interrupted run
+//! leaves no half-written temp files behind. A second Ctrl-C kills downscaler straight away.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use downscaler_ffi as ffi;
+use log::warn;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn install() {
+    use ffi::OnSignal;
+
+    let handlers = [
+        (libc::SIGINT, &INTERRUPTED, OnSignal::SetOnce),
+        (libc::SIGTERM, &INTERRUPTED, OnSignal::SetOnce),
+        (libc::SIGUSR1, &STATUS_REQUESTED, OnSignal::Set),
+        (libc::SIGUSR2, &PAUSED, OnSignal::Toggle),
+        // Ctrl-T in a BSD or macOS terminal
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ))]
+        (libc::SIGINFO, &STATUS_REQUESTED, OnSignal::Set),
+    ];
+    for (signal, flag, action) in handlers {
+        if let Err(e) = ffi::flag_on_signal(signal, flag, action) {
+            warn!("could not handle signal {}: {}", signal, e);
+        }
+    }
+}
+
+/// Has someone asked for a status report since we last looked?
+pub fn status_requested() -> bool {
+    STATUS_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Has the user asked us to stop?
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Should running commands be stopped, and no new files started?
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+}
+
+/// Freeze a child process while paused
+pub fn stop(pid: u32) {
+    let _ = ffi::kill(pid, libc::SIGSTOP);
+}
+
+/// Let a frozen child process carry on
+pub fn resume(pid: u32) {
+    let _ = ffi::kill(pid, libc::SIGCONT);
+}
+
+/// Is there a process with this pid?
+pub fn is_running(pid: u32) -> bool {
+    // signal 0 only checks the process could be signalled - EPERM means it exists
+    match ffi::kill(pid, 0) {
+        Ok(()) => true,
+        Err(e) => e.raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+/// A job stopped because the run was interrupted
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl Error for Interrupted {}
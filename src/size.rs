@@ -0,0 +1,98 @@
+//! File sizes on the command line, e.g. `500G` or `1.5TiB`, and proportions of them like `10%`
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::report::human_size;
+
+/// A number of bytes - suffixes are binary, so `1G` is 1024^3 bytes, matching how sizes are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Size(pub u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("expected a size like `500M` or `1.5T`, not {:?}", text);
+        let trimmed = text.trim();
+        let unit_start = trimmed
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(unit_start);
+        let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            _ => return Err(bad()),
+        };
+        let value: f64 = number.trim().parse().map_err(|_| bad())?;
+        if value < 0.0 {
+            return Err(bad());
+        }
+        Ok(Size((value * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", human_size(self.0 as i64))
+    }
+}
+
+/// A percentage, e.g. `10%` - the `%` is optional
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Percent(pub f64);
+
+impl FromStr for Percent {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let number = text.trim().trim_end_matches('%');
+        match number.trim().parse() {
+            Ok(value) if (0.0..=100.0).contains(&value) => Ok(Percent(value)),
+            _ => Err(format!("expected a percentage like `10%`, not {:?}", text)),
+        }
+    }
+}
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(text: &str) -> u64 {
+        text.parse::<Size>().unwrap().0
+    }
+
+    #[test]
+    fn suffixes_are_binary() {
+        assert_eq!(size("512"), 512);
+        assert_eq!(size("2k"), 2048);
+        assert_eq!(size("500M"), 500 << 20);
+        assert_eq!(size("1.5 TiB"), 3 << 39);
+        assert_eq!(size("10GB"), 10 << 30);
+    }
+
+    #[test]
+    fn bad_sizes_are_refused() {
+        for text in ["", "G", "-1G", "5P", "5 gigs", "1.2.3M"] {
+            assert!(text.parse::<Size>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn percentages_are_out_of_100() {
+        assert_eq!("10%".parse::<Percent>().unwrap(), Percent(10.0));
+        assert_eq!(" 2.5 ".parse::<Percent>().unwrap(), Percent(2.5));
+        assert!("101%".parse::<Percent>().is_err());
+        assert!("-1%".parse::<Percent>().is_err());
+        assert!("ten%".parse::<Percent>().is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Staging files through a temp directory, so ffmpeg never reads from or writes to slow or
+//! flaky network storage directly, and a half-written output never appears under its real name
+//!
+//! Everything goes in a directory made for the run, so two runs - or two files with the same
+//! name - can't collide
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+use log::warn;
+
+use crate::remote;
+use crate::storage::Storage;
+
+/// A short, filesystem-safe identifier for a source file
+///
+/// Source names can be long and full of odd characters, so temp names never reuse them
+pub fn path_hash(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// A temp directory of our own for one run, removed with everything in it when dropped
+///
+/// Each run gets a new one, so concurrent runs never share temp files
+#[derive(Debug)]
+pub struct RunDir {
+    pub path: PathBuf,
+}
+
+impl RunDir {
+    pub fn create(base: &Path) -> Result<RunDir> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        // creating a directory either succeeds or finds it exists, so it can't be shared by
+        // accident
+        for attempt in 0.. {
+            let path = base.join(format!(
+                "downscaler-run-{}-{:x}-{}",
+                process::id(),
+                started,
+                attempt
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    debug!("using temp directory {:?}", path);
+                    return Ok(RunDir { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("creating temp directory {:?}", path))
+                }
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Drop for RunDir {
+    fn drop(&mut self) {
+        remote::remove_dir(&self.path);
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("could not remove {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Where a source is copied to in the temp directory `temp`
+pub fn input_path(source: &Path, temp: &Path) -> PathBuf {
+    temp.join(format!(
+        "downscaler_{}_in.{}",
+        path_hash(source),
+        extension(source)
+    ))
+}
+
+/// The extension tells ffmpeg which container to write, so temp names keep it
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The temp and working files for one job - any that still exist are removed when dropped
+#[derive(Debug)]
+pub struct Staging {
+    /// Copy of the source in the temp directory - or the source itself, if it isn't staged
+    pub input: PathBuf,
+    staged: bool,
+    /// Where ffmpeg writes the encoded file
+    pub output: PathBuf,
+    /// Stats file prefix for two-pass encodes - encoders add their own suffixes
+    pub pass_log: PathBuf,
+}
+
+impl Staging {
+    /// With `stage` unset, ffmpeg reads `source` where it is, and only the output goes in `temp`
+    pub fn new(source: &Path, dest: &Path, temp: &Path, stage: bool) -> Staging {
+        let hash = path_hash(source);
+        Staging {
+            input: match stage {
+                true => input_path(source, temp),
+                false => source.to_owned(),
+            },
+            staged: stage,
+            output: temp.join(format!("downscaler_{}_out.{}", hash, extension(dest))),
+            pass_log: temp.join(format!("downscaler_{}_pass", hash)),
+        }
+    }
+
+    pub fn copy_in(&self, store: &dyn Storage, source: &Path) -> Result<()> {
+        if !self.staged {
+            return Ok(());
+        }
+        debug!("staging {:?} to {:?}", source, self.input);
+        store.fetch(source, &self.input)
+    }
+
+    /// Move the encoded output into place, all at once
+    ///
+    /// The destination directory is only created now, so failed encodes don't leave empty ones
+    /// behind
+    pub fn finish(&self, store: &dyn Storage, dest: &Path) -> Result<()> {
+        store.store(&self.output, dest)
+    }
+}
+
+/// Copy the modification time and/or permissions of `source` onto `dest`
+pub fn copy_attributes(source: &Path, dest: &Path, times: bool, perms: bool) -> Result<()> {
+    let metadata = fs::metadata(source).with_context(|| format!("reading {:?}", source))?;
+    if times {
+        let modified = metadata.modified()?;
+        fs::File::options()
+            .write(true)
+            .open(dest)
+            .and_then(|f| f.set_modified(modified))
+            .with_context(|| format!("setting the modification time of {:?}", dest))?;
+    }
+    if perms {
+        fs::set_permissions(dest, metadata.permissions())
+            .with_context(|| format!("setting the permissions of {:?}", dest))?;
+    }
+    Ok(())
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let mut leftovers = vec![self.output.clone()];
+        if self.staged {
+            leftovers.push(self.input.clone());
+        }
+        if let (Some(dir), Some(prefix)) = (self.pass_log.parent(), self.pass_log.file_name()) {
+            let prefix = prefix.to_string_lossy();
+            if let Ok(entries) = fs::read_dir(dir) {
+                leftovers.extend(
+                    entries
+                        .filter_map(|e| e.ok())
+                        .filter(|e| e.file_name().to_string_lossy().starts_with(&*prefix))
+                        .map(|e| e.path()),
+                );
+            }
+        }
+        remote::remove(&leftovers, &self.pass_log);
+        for path in leftovers {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("could not remove {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Our own state files - versioned, and only ever replaced atomically
+//!
+//! Each file starts with a `<name> <version>` header line. Files from older versions are upgraded
+//! in memory by a chain of migrations when read, and a copy of the original is kept next to it as
+//! `<file>.v<N>.bak` before anything overwrites it. Files from newer versions are refused rather
+//! than misread.
+//!
+//! Writes go to a temp file in the same directory which is synced and renamed into place, so a
+//! crash or full disk leaves either the old file or the new one, never half of each.
+
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+
+/// Upgrades the body lines of a file from one version to the next
+pub type Migration = fn(Vec<String>) -> Result<Vec<String>>;
+
+/// A kind of state file
+pub struct Format {
+    pub name: &'static str,
+    pub version: u32,
+    /// `migrations[0]` upgrades version 1 to 2, and so on - so there are always `version - 1`
+    pub migrations: &'static [Migration],
+}
+
+impl Format {
+    fn header(&self) -> String {
+        format!("{} {}", self.name, self.version)
+    }
+
+    /// The body lines of `text`, upgraded to the current version - and the version it was
+    fn parse(&self, text: &str) -> Result<(Vec<String>, u32)> {
+        let mut lines = text.lines();
+        let version: u32 = lines
+            .next()
+            .and_then(|header| header.strip_prefix(self.name))
+            .and_then(|rest| rest.strip_prefix(' '))
+            .and_then(|rest| rest.trim().parse().ok())
+            .filter(|version| *version > 0)
+            .ok_or_else(|| anyhow!("not a {} file", self.name))?;
+        if version > self.version {
+            return Err(anyhow!(
+                "{} version {} is newer than this downscaler understands ({}) - upgrade downscaler",
+                self.name,
+                version,
+                self.version
+            ));
+        }
+        let mut body: Vec<String> = lines.map(|l| l.to_owned()).collect();
+        for (from, migrate) in self
+            .migrations
+            .iter()
+            .enumerate()
+            .skip(version as usize - 1)
+        {
+            body = migrate(body)
+                .with_context(|| format!("upgrading from version {} to {}", from + 1, from + 2))?;
+        }
+        Ok((body, version))
+    }
+
+    /// Read a file, upgrading it if it was written by an older version
+    pub fn load(&self, path: &Path) -> Result<Vec<String>> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        let (body, version) = self
+            .parse(&text)
+            .with_context(|| format!("reading {:?}", path))?;
+        if version < self.version {
+            let backup = backup_path(path, version);
+            if !backup.exists() {
+                fs::copy(path, &backup).with_context(|| format!("backing up {:?}", path))?;
+            }
+            info!(
+                "upgraded {:?} from version {} - the original is kept in {:?}",
+                path, version, backup
+            );
+        }
+        Ok(body)
+    }
+
+    pub fn save(&self, path: &Path, body: &str) -> Result<()> {
+        write_atomic(path, format!("{}\n{}", self.header(), body).as_bytes())
+    }
+}
+
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".v{}.bak", version));
+    path.with_file_name(name)
+}
+
+/// Backslash-escape the characters that would break a tab-separated record
+pub fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Undo `escape`
+pub fn unescape(field: &str) -> String {
+    let mut text = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => text.push('\t'),
+                Some('n') => text.push('\n'),
+                Some(other) => text.push(other),
+                None => {}
+            }
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+/// Replace `path` with `contents` all at once
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}.tmp", process::id()));
+    let temp = path.with_file_name(name);
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e).with_context(|| format!("writing {:?}", path));
+    }
+    // make the rename itself durable - not every platform can open a directory, so this is best
+    // effort
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_suffix(body: Vec<String>) -> Result<Vec<String>> {
+        Ok(body.into_iter().map(|l| format!("{}\tv2", l)).collect())
+    }
+
+    fn upper(body: Vec<String>) -> Result<Vec<String>> {
+        Ok(body.into_iter().map(|l| l.to_uppercase()).collect())
+    }
+
+    fn refuse(_: Vec<String>) -> Result<Vec<String>> {
+        Err(anyhow!("bad record"))
+    }
+
+    const FORMAT: Format = Format {
+        name: "test",
+        version: 3,
+        migrations: &[add_suffix, upper],
+    };
+
+    #[test]
+    fn current_version_is_read_as_is() {
+        let (body, version) = FORMAT.parse("test 3\na\nb\n").unwrap();
+        assert_eq!(body, ["a", "b"]);
+        assert_eq!(version, 3);
+    }
+
+    #[test]
+    fn older_versions_run_every_migration_since() {
+        let (body, version) = FORMAT.parse("test 1\na\n").unwrap();
+        assert_eq!(body, ["A\tV2"]);
+        assert_eq!(version, 1);
+        let (body, version) = FORMAT.parse("test 2\na\n").unwrap();
+        assert_eq!(body, ["A"]);
+        assert_eq!(version, 2);
+    }
+
+    #[test]
+    fn failed_migrations_say_which_step() {
+        let format = Format {
+            name: "test",
+            version: 2,
+            migrations: &[refuse],
+        };
+        let error = format.parse("test 1\na\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("upgrading from version 1 to 2"));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let error = FORMAT.parse("test 4\na\n").unwrap_err();
+        assert!(error.to_string().contains("newer than this downscaler"));
+    }
+
+    #[test]
+    fn garbage_headers_are_refused() {
+        for text in [
+            "",
+            "other 1\n",
+            "test\n",
+            "test x\n",
+            "test 0\n",
+            "tests 1\n",
+            "test1\n",
+        ] {
+            let error = FORMAT.parse(text).unwrap_err();
+            assert_eq!(error.to_string(), "not a test file", "for {:?}", text);
+        }
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        for field in [
+            "plain",
+            "a\tb",
+            "two\nlines",
+            r"back\slash",
+            r"\t not a tab",
+            "\\\t\n",
+            "",
+        ] {
+            let escaped = escape(field);
+            assert!(!escaped.contains(['\t', '\n']), "{:?}", escaped);
+            assert_eq!(unescape(&escaped), field);
+        }
+    }
+}
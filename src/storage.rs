@@ -0,0 +1,220 @@
+//! Where sources are read from and outputs written to
+//!
+//! Scanning, staging and finishing only ever go through the [`Storage`] trait, so a new kind of
+//! remote only needs an implementation here. Everything ffmpeg touches is a local temp file, so
+//! a backend just needs to list, stat, fetch and store whole files.
+//!
+//! Plain paths are [`Local`] - which includes SMB and NFS shares, as long as they are mounted -
+//! `s3://bucket/prefix` paths are [`S3`], and `rclone://remote/path` paths are [`Rclone`].
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::debug;
+
+use crate::rclone::Rclone;
+use crate::s3::S3;
+use crate::staging::path_hash;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    /// A symbolic link, as listed - `stat` says what it points to
+    Link,
+    /// Devices, sockets and so on, and links to nowhere
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileInfo {
+    pub kind: Kind,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Device and inode numbers, where the storage has them - the same for every path to a file
+    pub id: Option<(u64, u64)>,
+}
+
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Everything in `dir`, in no particular order
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>>;
+
+    /// What is at `path` - `None` if nothing is
+    fn stat(&self, path: &Path) -> Result<Option<FileInfo>>;
+
+    /// Copy `path` to the local file `local`
+    fn fetch(&self, path: &Path, local: &Path) -> Result<()>;
+
+    /// Copy the local file `local` to `path`, creating its directory if need be
+    ///
+    /// Nothing should ever be seen at `path` until the whole file is there
+    fn store(&self, local: &Path, path: &Path) -> Result<()>;
+
+    fn remove(&self, path: &Path) -> Result<()>;
+
+    fn is_dir(&self, path: &Path) -> bool {
+        matches!(self.stat(path), Ok(Some(info)) if info.kind == Kind::Dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        matches!(self.stat(path), Ok(Some(_)))
+    }
+
+    /// Whether paths in it are files on this machine, for the options that work on them directly
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// The storage for a `--source` or `--destination`
+pub fn open(root: &Path) -> Result<Box<dyn Storage>> {
+    match root.to_str().and_then(|r| r.split_once("://")) {
+        Some(("s3", rest)) => Ok(Box::new(S3::new(rest)?)),
+        Some(("rclone", rest)) => Ok(Box::new(Rclone::new(rest)?)),
+        Some((scheme, _)) => Err(anyhow!(
+            "{:?}: {}:// isn't a supported storage - mount it and use a local path, or set it up as an rclone remote and use rclone://",
+            root,
+            scheme
+        )),
+        None => Ok(Box::new(Local)),
+    }
+}
+
+/// Which storage `root` is on - e.g. `s3://bucket` - or `None` for a local path
+pub fn location(root: &Path) -> Option<String> {
+    let (scheme, rest) = root.to_str()?.split_once("://")?;
+    Some(format!(
+        "{}://{}",
+        scheme,
+        rest.split('/').next().unwrap_or_default()
+    ))
+}
+
+/// The source and destination storage for a run
+#[derive(Debug)]
+pub struct Stores {
+    pub source: Box<dyn Storage>,
+    pub dest: Box<dyn Storage>,
+}
+
+/// Files on the local filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Local;
+
+fn kind_of(file_type: fs::FileType) -> Kind {
+    if file_type.is_dir() {
+        Kind::Dir
+    } else if file_type.is_file() {
+        Kind::File
+    } else if file_type.is_symlink() {
+        Kind::Link
+    } else {
+        Kind::Other
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl Storage for Local {
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("reading {:?}", dir))? {
+            let entry = entry?;
+            entries.push(DirEntry {
+                name: entry.file_name(),
+                kind: kind_of(entry.file_type()?),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<FileInfo>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(FileInfo {
+                kind: kind_of(metadata.file_type()),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                id: file_id(&metadata),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {:?}", path)),
+        }
+    }
+
+    fn fetch(&self, path: &Path, local: &Path) -> Result<()> {
+        fs::copy(path, local).with_context(|| format!("copying {:?} to {:?}", path, local))?;
+        Ok(())
+    }
+
+    /// Copies to a hidden working file next to `path` first, so the final rename is atomic
+    fn store(&self, local: &Path, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+        }
+        let working = path.with_file_name(format!(".downscaler_{}.working", path_hash(path)));
+        debug!("copying {:?} to {:?}", local, working);
+        let stored = fs::copy(local, &working)
+            .with_context(|| format!("copying {:?} to {:?}", local, working))
+            .and_then(|_| {
+                move_file(&working, path)
+                    .with_context(|| format!("renaming {:?} to {:?}", working, path))
+            });
+        if stored.is_err() {
+            let _ = fs::remove_file(&working);
+        }
+        stored
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path).with_context(|| format!("removing {:?}", path))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Rename `from` to `to`, or if they turn out to be on different filesystems, copy it to a working
+/// file next to `to`, sync that, and rename it into place - so `to` still appears all at once
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        renamed => return renamed,
+    }
+    debug!(
+        "{:?} is on another filesystem to {:?} - copying it",
+        from, to
+    );
+    let working = to.with_file_name(format!(".downscaler_{}_moved.working", path_hash(to)));
+    let copied = fs::copy(from, &working)
+        .and_then(|_| File::open(&working)?.sync_all())
+        .and_then(|_| fs::rename(&working, to));
+    if copied.is_err() {
+        let _ = fs::remove_file(&working);
+        return copied;
+    }
+    fs::remove_file(from)
+}
@@ -0,0 +1,337 @@
+//! Choosing which streams end up in the output, and how they are encoded
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use clap::ValueEnum;
+use log::debug;
+use log::info;
+
+use crate::filters::VideoFilters;
+use crate::notices;
+use crate::ocr::OcrSub;
+use crate::probe::ProbeInfo;
+use crate::probe::Stream;
+use crate::workers::Slot;
+use crate::Context;
+use crate::Opts;
+
+fn wanted(stream: &Stream, langs: &[String]) -> bool {
+    langs
+        .iter()
+        .any(|lang| lang.eq_ignore_ascii_case(stream.language()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SubtitlePolicy {
+    /// Copy subtitles the output container can hold, dropping any it can't
+    Copy,
+    /// Remove all subtitles
+    Drop,
+    /// Like copy, but convert text subtitles to a format the container can hold
+    Convert,
+}
+
+/// The containers we write - the same as the source unless `--container` says otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Container {
+    #[clap(name = "mkv")]
+    Matroska,
+    Mp4,
+}
+
+impl Container {
+    pub fn from_path(path: &Path) -> Container {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mp4") => Container::Mp4,
+            _ => Container::Matroska,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    pub fn can_hold(self, codec: &str) -> bool {
+        match self {
+            Container::Matroska => codec != "mov_text",
+            Container::Mp4 => codec == "mov_text" || codec == "dvd_subtitle",
+        }
+    }
+
+    /// The text subtitle format to convert to if we can't copy
+    pub fn text_codec(self) -> &'static str {
+        match self {
+            Container::Matroska => "srt",
+            Container::Mp4 => "mov_text",
+        }
+    }
+}
+
+pub fn is_text_subtitle(codec: &str) -> bool {
+    matches!(
+        codec,
+        "subrip" | "ass" | "ssa" | "webvtt" | "mov_text" | "text"
+    )
+}
+
+/// Bitmap subtitles, which can only become text by OCR
+pub fn is_image_subtitle(codec: &str) -> bool {
+    matches!(codec, "hdmv_pgs_subtitle" | "dvd_subtitle" | "dvb_subtitle")
+}
+
+/// If any language filters or a subtitle policy are given we map every stream explicitly, rather
+/// than using ffmpeg's defaults
+pub fn explicit_mapping(opts: &Opts) -> bool {
+    !opts.audio_langs.is_empty()
+        || !opts.sub_langs.is_empty()
+        || opts.burn_forced_subs
+        || opts.ocr_subs
+        || matches!(
+            opts.subtitles,
+            Some(SubtitlePolicy::Copy | SubtitlePolicy::Convert)
+        )
+}
+
+/// The audio streams that will end up in the output, in output order
+pub fn selected_audio<'a>(input: &Path, info: &'a ProbeInfo, opts: &Opts) -> Vec<&'a Stream> {
+    let all: Vec<&Stream> = info.audio_streams().collect();
+    if !opts.audio_langs.is_empty() {
+        let matching: Vec<&Stream> = all
+            .iter()
+            .copied()
+            .filter(|s| wanted(s, &opts.audio_langs))
+            .collect();
+        if !matching.is_empty() {
+            return matching;
+        }
+        if !all.is_empty() {
+            notices::warn(
+                input,
+                &format!(
+                    "no audio matches {:?} - keeping all audio streams",
+                    opts.audio_langs
+                ),
+            );
+        }
+        return all;
+    }
+    if explicit_mapping(opts) || opts.keep_all_streams {
+        all
+    } else {
+        // ffmpeg's default is the single stream with the most channels, earliest first
+        all.iter()
+            .rev()
+            .max_by_key(|s| s.channels().unwrap_or(0))
+            .copied()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// The subtitle streams we map explicitly - only meaningful when `explicit_mapping` is true
+pub fn selected_subtitles<'a>(input: &Path, info: &'a ProbeInfo, opts: &Opts) -> Vec<&'a Stream> {
+    if opts.subtitles == Some(SubtitlePolicy::Drop) {
+        return Vec::new();
+    }
+    let all = info.subtitle_streams();
+    if opts.sub_langs.is_empty() {
+        return all.collect();
+    }
+    let matching: Vec<&Stream> = all.filter(|s| wanted(s, &opts.sub_langs)).collect();
+    if matching.is_empty() {
+        debug!("no subtitles in {:?} match {:?}", input, opts.sub_langs);
+    }
+    matching
+}
+
+/// What to do with a subtitle stream - the codec to write it as, or None to drop it
+fn subtitle_codec(
+    input: &Path,
+    stream: &Stream,
+    container: Container,
+    policy: SubtitlePolicy,
+) -> Option<&'static str> {
+    let codec = stream.codec_name();
+    if container.can_hold(codec) {
+        return Some("copy");
+    }
+    if policy == SubtitlePolicy::Convert && is_text_subtitle(codec) {
+        debug!(
+            "converting subtitle stream {} from {} to {}",
+            stream.index,
+            codec,
+            container.text_codec()
+        );
+        return Some(container.text_codec());
+    }
+    notices::warn(
+        input,
+        &format!(
+            "dropping {} subtitles - the output container can't hold them",
+            codec
+        ),
+    );
+    None
+}
+
+/// `-map` and subtitle codec arguments, if we are not leaving stream selection to ffmpeg
+///
+/// OCR'd subtitles are extra inputs after the source, in the order given
+pub fn map_args(
+    input: &Path,
+    output: &Path,
+    info: Option<&ProbeInfo>,
+    filters: &VideoFilters,
+    ocr: &[OcrSub],
+    opts: &Opts,
+) -> Vec<String> {
+    if opts.keep_all_streams {
+        // copy everything, then the video and audio arguments override the codecs they care about
+        return ["-map", "0", "-c", "copy", "-map_chapters", "0"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+    }
+    let info = match info {
+        Some(info) if explicit_mapping(opts) => info,
+        _ if opts.subtitles == Some(SubtitlePolicy::Drop) => return vec!["-sn".to_owned()],
+        _ => return Vec::new(),
+    };
+    let mut args = vec!["-map".to_owned(), filters.video_map().to_owned()];
+    for stream in selected_audio(input, info, opts) {
+        args.push("-map".to_owned());
+        args.push(format!("0:{}", stream.index));
+    }
+    let container = Container::from_path(output);
+    let policy = opts.subtitles.unwrap_or(SubtitlePolicy::Copy);
+    let subtitles = selected_subtitles(input, info, opts)
+        .into_iter()
+        .filter(|stream| filters.burned != Some(stream.index))
+        .filter_map(|stream| {
+            if let Some(position) = ocr.iter().position(|o| o.index == stream.index) {
+                let language = Some(ocr[position].language.as_str());
+                return Some((
+                    format!("{}:0", position + 1),
+                    container.text_codec(),
+                    language,
+                ));
+            }
+            subtitle_codec(input, stream, container, policy)
+                .map(|codec| (format!("0:{}", stream.index), codec, None))
+        })
+        .collect::<Vec<_>>();
+    for (map, _, _) in &subtitles {
+        args.push("-map".to_owned());
+        args.push(map.clone());
+    }
+    for (output_index, (_, codec, language)) in subtitles.iter().enumerate() {
+        args.push(format!("-c:s:{}", output_index));
+        args.push(codec.to_string());
+        // an OCR'd file has no language of its own
+        if let Some(language) = language {
+            args.push(format!("-metadata:s:s:{}", output_index));
+            args.push(format!("language={}", language));
+        }
+    }
+    args
+}
+
+/// Audio codec arguments - copy unless we need to change the channel layout
+pub fn audio_args(input: &Path, info: Option<&ProbeInfo>, opts: &Opts) -> Vec<String> {
+    let copy = vec!["-c:a".to_owned(), "copy".to_owned()];
+    let channels = match opts.audio_channels() {
+        Some(channels) => channels,
+        None => return copy,
+    };
+    let info = match info {
+        Some(info) if opts.only_more_channels => info,
+        _ => {
+            return vec![
+                "-c:a".to_owned(),
+                "aac".to_owned(),
+                "-ac".to_owned(),
+                channels.to_string(),
+            ]
+        }
+    };
+    let mut args = Vec::new();
+    for (output_index, stream) in selected_audio(input, info, opts).iter().enumerate() {
+        match stream.channels() {
+            Some(source_channels) if source_channels > channels => {
+                debug!(
+                    "downmixing audio stream {} from {} channels to {}",
+                    stream.index, source_channels, channels
+                );
+                args.push(format!("-c:a:{}", output_index));
+                args.push("aac".to_owned());
+                args.push(format!("-ac:a:{}", output_index));
+                args.push(channels.to_string());
+            }
+            _ => {
+                args.push(format!("-c:a:{}", output_index));
+                args.push("copy".to_owned());
+            }
+        }
+    }
+    args
+}
+
+/// Sidecar name for a subtitle stream, e.g. `movie.eng.srt` or `movie.eng.forced.srt`
+///
+/// A second stream with the same name gets the stream index added, e.g. `movie.eng.3.srt`
+fn sidecar_path(output: &Path, stream: &Stream, used: &mut HashSet<PathBuf>) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{}.{}", stem, stream.language());
+    if stream.is_forced() {
+        name.push_str(".forced");
+    }
+    let mut path = output.with_file_name(format!("{}.srt", name));
+    if used.contains(&path) {
+        path = output.with_file_name(format!("{}.{}.srt", name, stream.index));
+    }
+    used.insert(path.clone());
+    path
+}
+
+/// Write text subtitle streams out as `.srt` files next to the output, for players that prefer them
+pub fn extract_subtitles(
+    input: &Path,
+    output: &Path,
+    info: &ProbeInfo,
+    slot: Slot,
+    ctx: &Context,
+) -> Result<()> {
+    let mut used = HashSet::new();
+    for stream in selected_subtitles(input, info, &ctx.opts) {
+        if !is_text_subtitle(stream.codec_name()) {
+            debug!(
+                "not extracting {} subtitle stream {} - not a text format",
+                stream.codec_name(),
+                stream.index
+            );
+            continue;
+        }
+        let sidecar = sidecar_path(output, stream, &mut used);
+        if sidecar.exists() {
+            debug!("not overwriting {:?}", sidecar);
+            continue;
+        }
+        info!("extracting subtitles to {:?}", sidecar);
+        let mut cmd = ctx.config.command("ffmpeg", slot);
+        cmd.arg("-nostdin")
+            .arg("-i")
+            .arg(input)
+            .args(["-map", &format!("0:{}", stream.index), "-c:s", "srt"])
+            .args(["-loglevel", "warning", "-nostats", "-hide_banner"])
+            .arg(&sidecar);
+        crate::run_command(cmd)?;
+    }
+    Ok(())
+}
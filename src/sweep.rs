@@ -0,0 +1,194 @@
+//! Removing what crashed runs leave behind - at the start of every run, and with `downscaler clean`
+//!
+//! A run that dies without unwinding can leave `.downscaler_<hash>.working` files next to its
+//! outputs, and its temp directory behind. Working files are stale once nothing has written to
+//! them for an hour - an upload in progress keeps touching its file - and temp directories once
+//! the process that made them has gone. Loose `downscaler_*` temp files from older versions are
+//! treated like working files. Run locks are left alone, as they only mean anything while held.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::info;
+use log::warn;
+
+use crate::report::human_size;
+use crate::signals;
+use crate::storage::Kind;
+use crate::storage::Storage;
+
+/// How long a working or temp file has to be untouched to be stale
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// What was, or with a dry run would be, removed
+#[derive(Debug, Default)]
+pub struct Swept {
+    pub removed: Vec<(PathBuf, u64)>,
+}
+
+impl Swept {
+    pub fn summary(&self, dry_run: bool) -> String {
+        let bytes: u64 = self.removed.iter().map(|(_, size)| size).sum();
+        format!(
+            "{} {} from {} stale files and temp directories",
+            if dry_run {
+                "would reclaim"
+            } else {
+                "reclaimed"
+            },
+            human_size(bytes as i64),
+            self.removed.len()
+        )
+    }
+
+    fn remove(
+        &mut self,
+        path: &Path,
+        size: u64,
+        dry_run: bool,
+        remove: impl FnOnce() -> Result<()>,
+    ) {
+        if !dry_run {
+            if let Err(e) = remove() {
+                warn!("could not remove {:?}: {:#}", path, e);
+                return;
+            }
+        }
+        info!(
+            "{} {:?} ({})",
+            if dry_run { "would remove" } else { "removed" },
+            path,
+            human_size(size as i64)
+        );
+        self.removed.push((path.to_owned(), size));
+    }
+}
+
+fn is_old(modified: Option<SystemTime>) -> bool {
+    modified
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .is_some_and(|age| age > STALE_AFTER)
+}
+
+/// Stale working files anywhere under `root`
+pub fn destination(
+    store: &dyn Storage,
+    root: &Path,
+    dry_run: bool,
+    swept: &mut Swept,
+) -> Result<()> {
+    for entry in store.list(root)? {
+        let path = root.join(&entry.name);
+        let name = entry.name.to_string_lossy();
+        match entry.kind {
+            Kind::Dir => destination(store, &path, dry_run, swept)?,
+            Kind::File if name.starts_with(".downscaler_") && name.ends_with(".working") => {
+                let info = match store.stat(&path)? {
+                    Some(info) if is_old(info.modified) => info,
+                    _ => continue,
+                };
+                swept.remove(&path, info.len, dry_run, || store.remove(&path));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The pid in a run directory name, `downscaler-run-<pid>-...`
+fn run_pid(name: &str) -> Option<u32> {
+    name.strip_prefix("downscaler-run-")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map(|m| m.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+/// Temp directories of runs that have gone, and old loose temp files, in `temp`
+pub fn temp(temp: &Path, dry_run: bool, swept: &mut Swept) -> Result<()> {
+    for entry in fs::read_dir(temp)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            match run_pid(&name) {
+                Some(pid) if !signals::is_running(pid) => {
+                    swept.remove(&path, dir_size(&path), dry_run, || {
+                        Ok(fs::remove_dir_all(&path)?)
+                    })
+                }
+                _ => {}
+            }
+        } else if metadata.is_file()
+            && name.starts_with("downscaler_")
+            && !name.ends_with(".lock")
+            && is_old(metadata.modified().ok())
+        {
+            swept.remove(&path, metadata.len(), dry_run, || {
+                Ok(fs::remove_file(&path)?)
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Sweep the destination, if given, and `temp_dir`
+pub fn sweep(
+    store: &dyn Storage,
+    destination: Option<&Path>,
+    temp_dir: &Path,
+    dry_run: bool,
+) -> Result<Swept> {
+    let mut swept = Swept::default();
+    if let Some(destination) = destination.filter(|d| store.is_dir(d)) {
+        self::destination(store, destination, dry_run, &mut swept)?;
+    }
+    temp(temp_dir, dry_run, &mut swept)?;
+    Ok(swept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_directories_name_their_pid() {
+        assert_eq!(run_pid("downscaler-run-1234-5678"), Some(1234));
+        assert_eq!(run_pid("downscaler-run-42"), Some(42));
+    }
+
+    #[test]
+    fn other_names_have_no_pid() {
+        for name in [
+            "downscaler-run-",
+            "downscaler-run-x-1",
+            "downscaler-1234",
+            "other-run-1234",
+            "downscaler-run--1",
+        ] {
+            assert_eq!(run_pid(name), None, "{:?}", name);
+        }
+    }
+}
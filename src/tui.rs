@@ -0,0 +1,311 @@
+//! A full-screen view of a run, for `--tui`
+//!
+//! It shows the files being encoded with how far each has got, the queue of files still to do,
+//! the files finished most recently with what they saved, and the latest log lines - redrawn a few
+//! times a second with plain ANSI escapes. Keys pause and resume the run, skip the files being
+//! encoded, stop after them, and move a queued file up or down the queue.
+//!
+//! Log lines are kept while the screen is up rather than written over it, and printed once it
+//! closes, so nothing is lost from the scrollback.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use downscaler_ffi as ffi;
+
+use crate::control;
+use crate::logging;
+use crate::progress::Move;
+use crate::progress::Progress;
+use crate::report::human_size;
+use crate::signals;
+use crate::watchdog;
+
+/// How often the screen is redrawn
+const REDRAW: Duration = Duration::from_millis(250);
+
+/// How many log lines to keep while the screen is up
+const LOG_KEPT: usize = 1000;
+
+const KEYS: &str =
+    "p pause/resume  s skip current  q stop after current  up/down choose  t to top  + up  - down";
+
+static SCREEN_UP: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Wraps the usual logger, keeping records for the screen while it is up rather than writing
+/// them over it
+pub struct Logger(pub env_logger::Logger);
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !SCREEN_UP.load(Ordering::SeqCst) {
+            return self.0.log(record);
+        }
+        if !self.0.matches(record) {
+            return;
+        }
+        let line = logging::line(record);
+        let mut log = LOG.lock().unwrap();
+        log.push_back(line);
+        if log.len() > LOG_KEPT {
+            log.pop_front();
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The terminal in raw mode on the alternate screen, put back when dropped
+struct Screen {
+    saved: ffi::Saved,
+}
+
+impl Screen {
+    fn open() -> Result<Screen> {
+        let saved = ffi::raw_mode().map_err(|_| anyhow!("--tui needs a terminal"))?;
+        SCREEN_UP.store(true, Ordering::SeqCst);
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        Ok(Screen { saved })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        self.saved.restore();
+        SCREEN_UP.store(false, Ordering::SeqCst);
+        let mut stderr = io::stderr();
+        for line in LOG.lock().unwrap().drain(..) {
+            let _ = writeln!(stderr, "{}", line);
+        }
+    }
+}
+
+/// Can `--tui` draw on this terminal?
+pub fn available() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Columns and rows
+fn size() -> (usize, usize) {
+    match ffi::terminal_size() {
+        Some((columns, rows)) => (columns as usize, rows as usize),
+        None => (80, 24),
+    }
+}
+
+/// The bytes typed since last time, waiting up to `wait` for some
+fn read_keys(wait: Duration) -> Vec<u8> {
+    let mut buffer = [0u8; 64];
+    let read = ffi::read_stdin(&mut buffer, wait);
+    buffer[..read].to_vec()
+}
+
+/// `text` cut to `width` characters - from the front for paths, whose ends matter most
+fn fit(text: &str, width: usize, keep_end: bool) -> String {
+    let count = text.chars().count();
+    if count <= width {
+        return text.to_owned();
+    }
+    match keep_end {
+        true => {
+            let tail: String = text.chars().skip(count - width + 1).collect();
+            format!("…{}", tail)
+        }
+        false => text.chars().take(width).collect(),
+    }
+}
+
+fn name(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn elapsed(since: Instant) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(since.elapsed().as_secs()))
+}
+
+/// What the screen shows - `selected` is the chosen file in the queue
+fn draw(progress: &Progress, selected: usize, width: usize, height: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let paused = if signals::paused() { "  [paused]" } else { "" };
+    let stopping = if control::stopping() {
+        "  [stopping]"
+    } else {
+        ""
+    };
+    lines.push(format!(
+        "downscaler  {}{}{}",
+        progress.headline(),
+        paused,
+        stopping
+    ));
+
+    let active = progress.active_since();
+    lines.push(String::new());
+    lines.push(format!("ENCODING ({})", active.len()));
+    for (source, started) in &active {
+        let live = watchdog::live(source);
+        let percent = match (live, progress.duration(source)) {
+            (Some(live), Some(duration)) if duration > 0.0 => {
+                format!("{:>3.0}%", (live.seconds / duration * 100.0).min(100.0))
+            }
+            _ => "   -".to_owned(),
+        };
+        let speed = live
+            .and_then(|l| l.speed)
+            .map_or("     ".to_owned(), |s| format!("{:>4.1}x", s));
+        let text = format!(
+            "  {} {} {:>9}  ",
+            percent,
+            speed,
+            elapsed(*started).to_string()
+        );
+        let room = width.saturating_sub(text.chars().count());
+        lines.push(format!("{}{}", text, fit(&name(source), room, true)));
+    }
+
+    // the queue and recent files share what is left with the log
+    let footer = 2;
+    let left = height.saturating_sub(lines.len() + footer + 6);
+    let queue_rows = (left * 2 / 5).max(1);
+    let recent_rows = (left / 4).max(1);
+
+    let queued = progress.queued();
+    lines.push(String::new());
+    lines.push(format!("QUEUE ({})", queued.len()));
+    // keep the chosen file in view
+    let first = selected.saturating_sub(queue_rows.saturating_sub(1));
+    for (i, source) in queued.iter().enumerate().skip(first).take(queue_rows) {
+        let marker = if i == selected { "> " } else { "  " };
+        lines.push(format!(
+            "{}{}",
+            marker,
+            fit(&name(source), width.saturating_sub(2), true)
+        ));
+    }
+
+    let recent = progress.recent();
+    lines.push(String::new());
+    lines.push("RECENT".to_owned());
+    for finished in recent.iter().rev().take(recent_rows) {
+        let outcome = match (finished.succeeded, finished.sizes) {
+            (true, Some((source, output))) => {
+                let percent = match source {
+                    0 => String::new(),
+                    source => format!(
+                        " ({:+.0}%)",
+                        (output as f64 - source as f64) * 100.0 / source as f64
+                    ),
+                };
+                format!(
+                    "{} -> {}{}",
+                    human_size(source as i64),
+                    human_size(output as i64),
+                    percent
+                )
+            }
+            (true, None) => "done".to_owned(),
+            (false, _) => "failed".to_owned(),
+        };
+        let text = format!("  {:<32} ", outcome);
+        let room = width.saturating_sub(text.chars().count());
+        lines.push(format!(
+            "{}{}",
+            text,
+            fit(&name(&finished.source), room, true)
+        ));
+    }
+
+    lines.push(String::new());
+    lines.push("LOG".to_owned());
+    let log_rows = height.saturating_sub(lines.len() + footer);
+    {
+        let log = LOG.lock().unwrap();
+        let skip = log.len().saturating_sub(log_rows);
+        for line in log.iter().skip(skip) {
+            lines.push(format!("  {}", fit(line, width.saturating_sub(2), false)));
+        }
+    }
+    while lines.len() < height.saturating_sub(footer) {
+        lines.push(String::new());
+    }
+    lines.truncate(height.saturating_sub(footer));
+    lines.push(String::new());
+    lines.push(fit(KEYS, width, false));
+    lines
+        .into_iter()
+        .map(|line| fit(&line, width, false))
+        .collect()
+}
+
+/// Act on the keys typed, returning the newly chosen queue entry
+fn handle(keys: &[u8], progress: &Progress, mut selected: usize) -> usize {
+    let mut i = 0;
+    while i < keys.len() {
+        match keys[i] {
+            b'p' => signals::set_paused(!signals::paused()),
+            b's' => control::skip(progress.active()),
+            b'q' => control::stop(),
+            b't' => selected = progress.move_queued(selected, Move::Top),
+            b'+' => selected = progress.move_queued(selected, Move::Up),
+            b'-' => selected = progress.move_queued(selected, Move::Down),
+            b'k' => selected = selected.saturating_sub(1),
+            b'j' => selected += 1,
+            // arrow keys are `ESC [ A` and `ESC [ B`
+            0x1b if keys.get(i + 1) == Some(&b'[') => {
+                match keys.get(i + 2) {
+                    Some(b'A') => selected = selected.saturating_sub(1),
+                    Some(b'B') => selected += 1,
+                    _ => {}
+                }
+                i += 2;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    selected.min(progress.queued().len().saturating_sub(1))
+}
+
+/// Show the run until `finished` is set
+pub fn run(progress: &Progress, finished: &AtomicBool) -> Result<()> {
+    let _screen = Screen::open()?;
+    let mut selected = 0;
+    let mut out = io::stdout();
+    while !finished.load(Ordering::Relaxed) && !signals::interrupted() {
+        let (width, height) = size();
+        let lines = draw(progress, selected, width, height);
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            frame.push_str(line);
+            frame.push_str("\x1b[K");
+            if i + 1 < lines.len() {
+                frame.push_str("\r\n");
+            }
+        }
+        frame.push_str("\x1b[J");
+        let _ = out.write_all(frame.as_bytes());
+        let _ = out.flush();
+        selected = handle(&read_keys(REDRAW), progress, selected);
+    }
+    Ok(())
+}
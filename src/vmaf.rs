@@ -0,0 +1,229 @@
+//! Choosing a CRF per file by measured quality, rather than one value for everything
+//!
+//! With `--auto-crf`, a few short samples of each file are encoded at candidate CRFs and scored
+//! against the source with VMAF (ffmpeg needs to be built with libvmaf). A binary search finds the
+//! highest CRF - so the smallest output - whose average score still meets `--target-vmaf`.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+use log::debug;
+use log::info;
+
+use crate::filters::VideoFilters;
+use crate::notices;
+use crate::probe::ProbeInfo;
+use crate::rate::Rate;
+use crate::settings::Settings;
+use crate::staging::path_hash;
+use crate::workers::Slot;
+use crate::Context;
+
+/// Candidate CRFs - anything outside this is either huge or visibly poor at 720p
+const MIN_CRF: u32 = 16;
+const MAX_CRF: u32 = 40;
+
+/// Where to take samples from, as fractions of the duration
+const SAMPLE_POINTS: [f64; 3] = [0.2, 0.5, 0.8];
+
+/// Seconds in each sample
+const SAMPLE_LENGTH: f64 = 8.0;
+
+/// A sample encode, removed when dropped
+struct SampleFile(PathBuf);
+
+impl Drop for SampleFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Sample start times - the whole file, for anything too short to bother sampling
+fn sample_starts(duration: f64) -> Vec<(f64, f64)> {
+    if duration < SAMPLE_LENGTH * SAMPLE_POINTS.len() as f64 * 2.0 {
+        return vec![(0.0, duration)];
+    }
+    SAMPLE_POINTS
+        .iter()
+        .map(|point| (duration * point, SAMPLE_LENGTH))
+        .collect()
+}
+
+/// Parse `VMAF score: 94.123` from libvmaf's log
+fn score_from_log(log: &str) -> Option<f64> {
+    log.lines()
+        .filter_map(|line| line.split_once("VMAF score:"))
+        .filter_map(|(_, score)| score.trim().parse().ok())
+        .next_back()
+}
+
+/// The source frame size after cropping, which encodes are scaled back up to for scoring
+fn reference_size(info: &ProbeInfo, settings: &Settings) -> Option<(u32, u32)> {
+    match settings.crop {
+        Some(crop) => Some(crop.size()),
+        None => {
+            let video = info.video()?;
+            video.width().zip(video.height())
+        }
+    }
+}
+
+struct Search<'a> {
+    input: &'a Path,
+    source: &'a Path,
+    info: &'a ProbeInfo,
+    filters: VideoFilters,
+    size: (u32, u32),
+    samples: Vec<(f64, f64)>,
+    slot: Slot,
+    ctx: &'a Context,
+}
+
+impl Search<'_> {
+    /// The average VMAF of the samples encoded at `crf`
+    fn score(&self, settings: &Settings, crf: u32) -> Result<f64> {
+        let (opts, config) = (&self.ctx.opts, &self.ctx.config);
+        let settings = Settings {
+            crf,
+            ..settings.clone()
+        };
+        let mut total = 0.0;
+        for (i, (start, length)) in self.samples.iter().enumerate() {
+            let sample = SampleFile(self.ctx.temp.path.join(format!(
+                "downscaler_{}_vmaf{}.mkv",
+                path_hash(self.source),
+                i
+            )));
+            let seek = ["-ss".to_owned(), format!("{:.2}", start)];
+            let length = ["-t".to_owned(), format!("{:.2}", length)];
+
+            let mut cmd = config.command("ffmpeg", self.slot);
+            cmd.arg("-nostdin")
+                .args(&seek)
+                .args(&length)
+                .arg("-i")
+                .arg(self.input)
+                .args(["-map", self.filters.video_map()])
+                .args(crate::video_args(
+                    &opts.encoder,
+                    &settings,
+                    Rate::Quality,
+                    self.slot,
+                    opts.encode_threads,
+                ))
+                .args(crate::pix_fmt_args(
+                    &opts.encoder,
+                    opts.pix_fmt.as_deref(),
+                    Some(self.info),
+                ))
+                .args(self.filters.args())
+                .args(["-an", "-sn", "-y"])
+                .args(["-loglevel", "warning", "-nostats", "-hide_banner"])
+                .arg(&sample.0);
+            crate::run_command(cmd).context("encoding a sample")?;
+
+            let (width, height) = self.size;
+            let mut reference = Vec::new();
+            if settings.deinterlace {
+                reference.push("bwdif".to_owned());
+            }
+            if let Some(crop) = settings.crop {
+                reference.push(crop.filter());
+            }
+            reference.push("setpts=PTS-STARTPTS".to_owned());
+            let graph = format!(
+                "[0:V:0]scale={}:{}:flags=bicubic,setpts=PTS-STARTPTS[distorted];\
+                 [1:V:0]{}[reference];[distorted][reference]libvmaf",
+                width,
+                height,
+                reference.join(",")
+            );
+            let mut cmd = config.command("ffmpeg", self.slot);
+            cmd.args(["-nostdin", "-hide_banner", "-nostats"])
+                .arg("-i")
+                .arg(&sample.0)
+                .args(&seek)
+                .args(&length)
+                .arg("-i")
+                .arg(self.input)
+                .args(["-lavfi", &graph, "-f", "null", "-"]);
+            let log = crate::run_for_log(cmd).context("scoring a sample")?;
+            total += score_from_log(&log)
+                .ok_or_else(|| anyhow!("no VMAF score from ffmpeg - is it built with libvmaf?"))?;
+        }
+        let score = total / self.samples.len() as f64;
+        debug!("crf {} scores {:.2} for {:?}", crf, score, self.source);
+        Ok(score)
+    }
+}
+
+/// The highest CRF whose samples meet the target VMAF
+pub fn choose_crf(
+    input: &Path,
+    source: &Path,
+    info: &ProbeInfo,
+    settings: &Settings,
+    slot: Slot,
+    ctx: &Context,
+) -> Result<u32> {
+    let target = ctx.opts.target_vmaf;
+    let (duration, size) = match (info.duration(), reference_size(info, settings)) {
+        (Some(duration), Some(size)) if duration > 0.0 => (duration, size),
+        _ => {
+            notices::warn(
+                source,
+                &format!("can't sample for --auto-crf, using crf {}", settings.crf),
+            );
+            return Ok(settings.crf);
+        }
+    };
+    info!(
+        "searching for a crf that scores VMAF {} for {:?}",
+        target, source
+    );
+    let search = Search {
+        input,
+        source,
+        info,
+        filters: VideoFilters::new(input, source, Some(info), settings, &ctx.opts),
+        size,
+        samples: sample_starts(duration),
+        slot,
+        ctx,
+    };
+    // scores only go down as the CRF goes up, so binary search for the last one that passes
+    let (mut low, mut high) = (MIN_CRF, MAX_CRF);
+    let mut best = None;
+    while low <= high {
+        let crf = (low + high) / 2;
+        let score = search.score(settings, crf)?;
+        if score >= target {
+            best = Some((crf, score));
+            low = crf + 1;
+        } else if crf == MIN_CRF {
+            break;
+        } else {
+            high = crf - 1;
+        }
+    }
+    match best {
+        Some((crf, score)) => {
+            info!("chose crf {} (VMAF {:.2}) for {:?}", crf, score, source);
+            Ok(crf)
+        }
+        None => {
+            notices::warn(
+                source,
+                &format!(
+                    "no crf down to {} reaches VMAF {} - using {}",
+                    MIN_CRF, target, MIN_CRF
+                ),
+            );
+            Ok(MIN_CRF)
+        }
+    }
+}
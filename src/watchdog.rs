@@ -0,0 +1,422 @@
+//! Killing external commands that hang, so one broken file can't stop a batch forever
+//!
+//! `--timeout` limits how long each file may take, across every command run for it. `--stall`
+//! kills a command that has shown no sign of life for that long - nothing on stderr and, for
+//! ffmpeg, no `-progress` reports, which it sends twice a second while it is still encoding.
+//!
+//! With `--tui` or `--serve`, those reports are also kept to show how far each encode is, and with
+//! `--tui` commands' stderr is kept off the terminal the screen is drawn on.
+//!
+//! With `--ffmpeg-logs`, every command's stderr for a file is also copied to a log of its own,
+//! which is only kept if the file fails.
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::warn;
+
+use crate::control;
+use crate::control::Skipped;
+use crate::priority;
+use crate::remote;
+use crate::signals;
+use crate::signals::Interrupted;
+
+/// How much of a command's stderr to keep for its error
+const STDERR_TAIL: usize = 4096;
+
+/// How often to check on a running command
+const POLL: Duration = Duration::from_millis(250);
+
+static STALL: Mutex<Option<Duration>> = Mutex::new(None);
+static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+static TRACK_LIVE: AtomicBool = AtomicBool::new(false);
+static LIVE: Mutex<Option<HashMap<PathBuf, Live>>> = Mutex::new(None);
+
+/// How far ffmpeg has got with the file a worker is on, from its `-progress` reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Live {
+    /// Seconds of output written
+    pub seconds: f64,
+    /// How many times faster than real time it is going
+    pub speed: Option<f64>,
+}
+
+thread_local! {
+    /// When the file this worker is on runs out of time
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    /// The file this worker is on, in case it is skipped from the control socket
+    static CURRENT: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    /// Where the commands for this file are logging their stderr to
+    static TRANSCRIPT: RefCell<Option<File>> = const { RefCell::new(None) };
+}
+
+pub fn set_stall_limit(limit: Option<Duration>) {
+    *STALL.lock().unwrap() = limit;
+}
+
+/// Keep commands' stderr to ourselves, for `--tui`
+pub fn set_interactive(interactive: bool) {
+    INTERACTIVE.store(interactive, Ordering::SeqCst);
+}
+
+/// Keep track of how far ffmpeg has got, for `live`
+pub fn set_live(track: bool) {
+    TRACK_LIVE.store(track, Ordering::SeqCst);
+}
+
+/// How far the current ffmpeg command for `source` has got, if one is running
+pub fn live(source: &Path) -> Option<Live> {
+    LIVE.lock().unwrap().as_ref()?.get(source).copied()
+}
+
+/// Note one line of an ffmpeg `-progress` report for `source`
+fn report(source: &Path, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    let (key, value) = match line.trim().split_once('=') {
+        Some(pair) => pair,
+        None => return,
+    };
+    let mut live = LIVE.lock().unwrap();
+    let entry = live
+        .get_or_insert_with(HashMap::new)
+        .entry(source.to_owned())
+        .or_default();
+    match key {
+        // `out_time_ms` is in microseconds too, despite its name
+        "out_time_us" | "out_time_ms" => {
+            if let Ok(us) = value.parse::<f64>() {
+                entry.seconds = us / 1_000_000.0;
+            }
+        }
+        "speed" => entry.speed = value.trim_end_matches('x').trim().parse().ok(),
+        _ => {}
+    }
+}
+
+/// Limits the commands run on this thread for `source` until dropped
+pub struct Deadline(());
+
+impl Deadline {
+    pub fn start(source: &Path, limit: Option<Duration>) -> Deadline {
+        DEADLINE.with(|d| d.set(limit.map(|l| Instant::now() + l)));
+        CURRENT.with(|c| *c.borrow_mut() = Some(source.to_owned()));
+        Deadline(())
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(None));
+        if let Some(source) = CURRENT.with(|c| c.borrow_mut().take()) {
+            if let Some(live) = LIVE.lock().unwrap().as_mut() {
+                live.remove(&source);
+            }
+        }
+    }
+}
+
+/// What this worker's commands are watched with, to carry over to threads it runs commands on
+pub struct Watch {
+    deadline: Option<Instant>,
+    current: Option<PathBuf>,
+    transcript: Option<File>,
+}
+
+pub fn watch() -> Watch {
+    Watch {
+        deadline: DEADLINE.with(Cell::get),
+        current: CURRENT.with(|c| c.borrow().clone()),
+        transcript: TRANSCRIPT.with(|t| t.borrow().as_ref().and_then(|f| f.try_clone().ok())),
+    }
+}
+
+impl Watch {
+    /// Watch this thread's commands the same way, for as long as it runs
+    pub fn enter(self) {
+        DEADLINE.with(|d| d.set(self.deadline));
+        CURRENT.with(|c| *c.borrow_mut() = self.current);
+        TRANSCRIPT.with(|t| *t.borrow_mut() = self.transcript);
+    }
+}
+
+/// Copies the stderr of the commands this worker runs to `dir/name`, until dropped - when the log
+/// is removed, unless it has been kept
+pub struct Transcript {
+    dir: PathBuf,
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Transcript {
+    pub fn start(dir: &Path, name: &Path) -> anyhow::Result<Transcript> {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        TRANSCRIPT.with(|t| *t.borrow_mut() = Some(file));
+        Ok(Transcript {
+            dir: dir.to_owned(),
+            path,
+            keep: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        TRANSCRIPT.with(|t| t.borrow_mut().take());
+        if self.keep || fs::remove_file(&self.path).is_err() {
+            return;
+        }
+        // and the folders it was in, if nothing else failed there
+        for dir in self.path.ancestors().skip(1) {
+            if !dir.starts_with(&self.dir) || dir == self.dir || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// A command was killed by the watchdog
+#[derive(Debug)]
+pub struct Killed(String);
+
+impl fmt::Display for Killed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "killed - {}", self.0)
+    }
+}
+
+impl Error for Killed {}
+
+/// Was this failure, or anything it was caused by, the watchdog?
+pub fn is_killed(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| e.is::<Killed>())
+}
+
+/// `cmd`, reporting progress on stdout if it is ffmpeg
+fn with_progress(cmd: &Command) -> Command {
+    let mut watched = Command::new(cmd.get_program());
+    // -progress is a global option, so it has to come before the outputs
+    watched.args(["-progress", "pipe:1"]).args(cmd.get_args());
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => watched.env(key, value),
+            None => watched.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        watched.current_dir(dir);
+    }
+    watched
+}
+
+/// Send `signal` to `child` with `local`, or by name to the command it runs with `--remote` -
+/// returning whether it was sent
+fn send(child: &Child, fetch: &remote::Fetch, signal: &str, local: fn(u32)) -> bool {
+    if !fetch.signalled() {
+        local(child.id());
+        return true;
+    }
+    match fetch.signal(signal) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "could not send SIG{} to the remote command: {:#}",
+                signal, e
+            );
+            false
+        }
+    }
+}
+
+/// Stop `child` for good - and the command it runs with `--remote`, which outlives its `ssh`
+fn kill(child: &mut Child, fetch: &remote::Fetch) {
+    if fetch.signalled() {
+        if let Err(e) = fetch.signal("TERM") {
+            warn!("could not stop the remote command: {:#}", e);
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Run `cmd`, passing its stderr through, and return how it exited and the end of its stderr
+///
+/// Fails with `Killed` if it overran the file's deadline or stalled, and `Interrupted` on Ctrl-C
+pub fn run(cmd: Command) -> anyhow::Result<(ExitStatus, Vec<u8>)> {
+    let stall = *STALL.lock().unwrap();
+    let mut deadline = DEADLINE.with(|d| d.get());
+    let interactive = INTERACTIVE.load(Ordering::SeqCst);
+    // other tools may well be quiet while they work, so only ffmpeg can stall
+    let watched =
+        (stall.is_some() || TRACK_LIVE.load(Ordering::SeqCst)) && cmd.get_program() == "ffmpeg";
+    let program = cmd.get_program().to_owned();
+    let cmd = match watched {
+        true => with_progress(&cmd),
+        false => cmd,
+    };
+    if signals::interrupted() {
+        return Err(Interrupted.into());
+    }
+    let (mut cmd, fetch) = remote::prepare_signalled(cmd)?;
+    if watched {
+        cmd.stdout(Stdio::piped());
+    }
+    priority::apply(&mut cmd);
+    let mut transcript =
+        TRANSCRIPT.with(|t| t.borrow().as_ref().and_then(|file| file.try_clone().ok()));
+    if let Some(file) = &mut transcript {
+        let command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let _ = writeln!(
+            file,
+            "=== {} {}",
+            humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
+            command
+        );
+    }
+    let mut child = cmd.stderr(Stdio::piped()).spawn()?;
+    let active = Arc::new(Mutex::new(Instant::now()));
+    if let Some(stdout) = child.stdout.take() {
+        let active = active.clone();
+        let source = CURRENT.with(|c| c.borrow().clone());
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).split(b'\n') {
+                *active.lock().unwrap() = Instant::now();
+                if let (Some(source), Ok(line)) = (&source, line) {
+                    report(source, &line);
+                }
+            }
+        });
+    }
+    // not joined if the command is killed - anything it started may still hold stderr open
+    let tail = child.stderr.take().map(|mut stderr| {
+        let active = active.clone();
+        thread::spawn(move || {
+            let mut tail: Vec<u8> = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = match stderr.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => read,
+                };
+                *active.lock().unwrap() = Instant::now();
+                if !interactive {
+                    let _ = io::stderr().write_all(&buffer[..read]);
+                }
+                if let Some(file) = &mut transcript {
+                    let _ = file.write_all(&buffer[..read]);
+                }
+                tail.extend_from_slice(&buffer[..read]);
+                if tail.len() > STDERR_TAIL {
+                    tail.drain(..tail.len() - STDERR_TAIL);
+                }
+            }
+            tail
+        })
+    });
+
+    let mut stopped: Option<Instant> = None;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // Ctrl-C reaches ffmpeg too, which stops it with an error of its own
+            if signals::interrupted() {
+                return Err(Interrupted.into());
+            }
+            let tail = tail.and_then(|t| t.join().ok()).unwrap_or_default();
+            if status.success() {
+                fetch.fetch()?;
+            }
+            return Ok((status, tail));
+        }
+        // checked even while paused, so stopping or skipping doesn't wait for a resume
+        let paused_for = stopped.map(|since| since.elapsed()).unwrap_or_default();
+        let abandon: Option<anyhow::Error> = if signals::interrupted() {
+            Some(Interrupted.into())
+        } else if CURRENT.with(|c| c.borrow().as_deref().is_some_and(control::skip_requested)) {
+            Some(Skipped.into())
+        } else if deadline.is_some_and(|d| Instant::now() >= d + paused_for) {
+            let reason = "the file took longer than --timeout".to_owned();
+            warn!("killing {:?}: {}", program, reason);
+            Some(Killed(reason).into())
+        } else {
+            None
+        };
+        if let Some(error) = abandon {
+            // a frozen command can't act on being stopped, and a remote one can't be reached
+            if stopped.is_some() {
+                send(&child, &fetch, "CONT", signals::resume);
+            }
+            kill(&mut child, &fetch);
+            return Err(error);
+        }
+        match (signals::paused(), stopped) {
+            // tried again next time round if it hasn't started on the remote yet
+            (true, None) if send(&child, &fetch, "STOP", signals::stop) => {
+                stopped = Some(Instant::now());
+            }
+            (false, Some(since)) => {
+                send(&child, &fetch, "CONT", signals::resume);
+                stopped = None;
+                // time spent paused doesn't count against the file
+                let paused = since.elapsed();
+                deadline = deadline.map(|d| d + paused);
+                DEADLINE.with(|d| d.set(d.get().map(|d| d + paused)));
+                *active.lock().unwrap() = Instant::now();
+            }
+            _ => {}
+        }
+        if stopped.is_some() {
+            thread::sleep(POLL);
+            continue;
+        }
+        let quiet = active.lock().unwrap().elapsed();
+        if stall.is_some_and(|stall| watched && quiet >= stall) {
+            let reason = format!(
+                "no progress for {}",
+                humantime::format_duration(Duration::from_secs(quiet.as_secs()))
+            );
+            warn!("killing {:?}: {}", program, reason);
+            kill(&mut child, &fetch);
+            return Err(Killed(reason).into());
+        }
+        thread::sleep(POLL);
+    }
+}
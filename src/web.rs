@@ -0,0 +1,401 @@
+//! A small web dashboard and JSON API for a running instance, for `--serve ADDRESS`
+//!
+//! `GET /` is a page showing where the run is, refreshed every few seconds, with buttons for the
+//! control commands and a box to add a file. Behind it:
+//!
+//! - `GET /api/status` - counts, the files being encoded and how far each has got, the queue, and
+//!   the files finished most recently
+//! - `GET /api/results` - every file finished in this run, as in the JSON report
+//! - `POST /api/pause`, `/api/resume`, `/api/skip` and `/api/stop` - as for `downscaler ctl`
+//! - `POST /api/enqueue` - add the file whose path is the request body to the end of the queue
+//!
+//! There is no authentication, so only listen where everyone who can reach it may run the show.
+//! POSTs need an `X-Downscaler: 1` header, which a web page on another site can't add without
+//! the browser asking first - so a page open in the browser can't send them behind your back.
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+
+use crate::control;
+use crate::json;
+use crate::progress::Progress;
+use crate::report::FileResult;
+use crate::signals;
+use crate::watchdog;
+
+/// The largest request body read - a path, or nothing
+const MAX_BODY: usize = 64 * 1024;
+
+/// The longest request or header line read, and how many headers
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// The header POSTs need, which a cross-site form can't send
+const HEADER: &str = "x-downscaler";
+
+/// What the server can see and do
+pub struct Run<'a> {
+    pub progress: &'a Progress,
+    pub results: &'a Mutex<Vec<FileResult>>,
+    /// Add a source file to the end of the queue, saying what happened
+    pub enqueue: &'a (dyn Fn(&Path) -> Result<String> + Sync),
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// Was `X-Downscaler: 1` sent?
+    marked: bool,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn message(status: &'static str, key: &str, message: &str) -> Response {
+        Response::json(status, json::Object::new().str(key, message).build())
+    }
+}
+
+/// Read one line into `line`, refusing any longer than `MAX_LINE`
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let read = reader.take(MAX_LINE as u64).read_line(line)?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(anyhow!("request line longer than {} bytes", MAX_LINE));
+    }
+    Ok(read)
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target),
+        _ => return Err(anyhow!("bad request line {:?}", line.trim())),
+    };
+    let path = target.split('?').next().unwrap_or_default().to_owned();
+    let mut length = 0;
+    let mut marked = false;
+    for count in 0.. {
+        let mut header = String::new();
+        if read_line(&mut reader, &mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(anyhow!("more than {} headers", MAX_HEADERS));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse()?;
+            } else if name.eq_ignore_ascii_case(HEADER) {
+                marked = value.trim() == "1";
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(anyhow!("request body of {} bytes is too big", length));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        marked,
+        body,
+    })
+}
+
+fn status(progress: &Progress) -> String {
+    let counts = progress.counts();
+    let active = progress.active_since().into_iter().map(|(source, since)| {
+        let live = watchdog::live(&source);
+        let percent = match (live, progress.duration(&source)) {
+            (Some(live), Some(duration)) if duration > 0.0 => Some(format!(
+                "{:.1}",
+                (live.seconds / duration * 100.0).min(100.0)
+            )),
+            _ => None,
+        };
+        json::Object::new()
+            .str("source", &source.to_string_lossy())
+            .num("elapsed", since.elapsed().as_secs())
+            .opt_num("percent", percent)
+            .opt_num("speed", live.and_then(|l| l.speed))
+            .build()
+    });
+    let queue = progress
+        .queued()
+        .into_iter()
+        .map(|source| json::string(&source.to_string_lossy()));
+    let recent = progress.recent().into_iter().rev().map(|recent| {
+        json::Object::new()
+            .str("source", &recent.source.to_string_lossy())
+            .raw("succeeded", recent.succeeded.to_string())
+            .opt_num("source_size", recent.sizes.map(|(source, _)| source))
+            .opt_num("output_size", recent.sizes.map(|(_, output)| output))
+            .build()
+    });
+    json::Object::new()
+        .num("total", counts.total)
+        .num("done", counts.done)
+        .num("failed", counts.failed)
+        .num("saved", counts.saved)
+        .num("elapsed", progress.elapsed().as_secs())
+        .raw("paused", signals::paused().to_string())
+        .raw("stopping", control::stopping().to_string())
+        .raw("active", json::array(active))
+        .raw("queue", json::array(queue))
+        .raw("recent", json::array(recent))
+        .build()
+}
+
+fn respond(request: &Request, run: &Run<'_>) -> Response {
+    let method = request.method.as_str();
+    if method == "POST" && !request.marked {
+        return Response::message(
+            "403 Forbidden",
+            "error",
+            "POSTs need an X-Downscaler: 1 header",
+        );
+    }
+    match (method, request.path.as_str()) {
+        ("GET", "/") => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: PAGE.to_owned(),
+        },
+        ("GET", "/api/status") => Response::json("200 OK", status(run.progress)),
+        ("GET", "/api/results") => Response::json(
+            "200 OK",
+            json::array(run.results.lock().unwrap().iter().map(|r| r.to_json())),
+        ),
+        ("POST", "/api/pause" | "/api/resume" | "/api/skip" | "/api/stop") => {
+            let command = &request.path["/api/".len()..];
+            let reply = control::answer(command, run.progress);
+            info!("dashboard: {} - {}", command, reply);
+            Response::message("200 OK", "message", &reply)
+        }
+        ("POST", "/api/enqueue") => {
+            let body = String::from_utf8_lossy(&request.body);
+            let path = body.trim();
+            if path.is_empty() {
+                return Response::message("400 Bad Request", "error", "no path given");
+            }
+            match (run.enqueue)(Path::new(path)) {
+                Ok(reply) => {
+                    info!("dashboard: {}", reply);
+                    Response::message("200 OK", "message", &reply)
+                }
+                Err(e) => Response::message("400 Bad Request", "error", &format!("{:#}", e)),
+            }
+        }
+        (
+            _,
+            "/" | "/api/status" | "/api/results" | "/api/pause" | "/api/resume" | "/api/skip"
+            | "/api/stop" | "/api/enqueue",
+        ) => Response::message("405 Method Not Allowed", "error", "wrong method"),
+        _ => Response::message("404 Not Found", "error", "not found"),
+    }
+}
+
+fn handle(stream: TcpStream, run: &Run<'_>) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let response = match read_request(&stream) {
+        Ok(request) => respond(&request, run),
+        Err(e) => Response::message("400 Bad Request", "error", &format!("{:#}", e)),
+    };
+    let _ = write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+}
+
+pub fn serve(address: &str, run: &Run<'_>, finished: &AtomicBool) -> Result<()> {
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("listening on {}", address))?;
+    // polled, so the loop notices when the run is over
+    listener.set_nonblocking(true)?;
+    info!("dashboard on http://{}/", listener.local_addr()?);
+    while !finished.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => handle(stream, run),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100))
+            }
+            Err(e) => warn!("dashboard: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// The dashboard, which draws itself from `/api/status`
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>downscaler</title>
+<style>
+body { font-family: sans-serif; margin: 1em; }
+h2 { font-size: 1.1em; margin-top: 1.5em; }
+li { word-break: break-all; margin: 0.3em 0; }
+button { font-size: 1em; padding: 0.4em 0.8em; margin: 0.2em 0.2em 0.2em 0; }
+input { font-size: 1em; width: 100%; box-sizing: border-box; margin: 0.3em 0; }
+progress { width: 100%; }
+.failed { color: #b00; }
+.muted { color: #666; }
+</style>
+</head>
+<body>
+<h1>downscaler</h1>
+<p id="headline">loading...</p>
+<div>
+<button onclick="command('pause')">Pause</button>
+<button onclick="command('resume')">Resume</button>
+<button onclick="command('skip')">Skip current</button>
+<button onclick="command('stop')">Stop after current</button>
+</div>
+<p id="message" class="muted"></p>
+<h2>Encoding</h2>
+<ul id="active"></ul>
+<h2>Queue</h2>
+<ul id="queue"></ul>
+<form onsubmit="enqueue(); return false;">
+<input id="path" placeholder="/path/to/a/file.mkv under a --source">
+<button type="submit">Add to queue</button>
+</form>
+<h2>Recent</h2>
+<ul id="recent"></ul>
+<script>
+function size(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (Math.abs(bytes) >= 1024 && i < units.length - 1) { bytes /= 1024; i++; }
+  return bytes.toFixed(i ? 1 : 0) + " " + units[i];
+}
+function fill(id, items) {
+  const list = document.getElementById(id);
+  list.replaceChildren(...items.map(([text, cls, bar]) => {
+    const li = document.createElement("li");
+    li.textContent = text;
+    if (cls) li.className = cls;
+    if (bar !== undefined) {
+      const p = document.createElement("progress");
+      p.max = 100;
+      if (bar !== null) p.value = bar;
+      li.appendChild(p);
+    }
+    return li;
+  }));
+  if (!items.length) list.innerHTML = '<li class="muted">none</li>';
+}
+async function refresh() {
+  try {
+    const s = await (await fetch("/api/status")).json();
+    let headline = `${s.done + s.failed} of ${s.total} finished - ${s.done} done, ${s.failed} failed - ${size(s.saved)} saved`;
+    if (s.paused) headline += " [paused]";
+    if (s.stopping) headline += " [stopping]";
+    document.getElementById("headline").textContent = headline;
+    fill("active", s.active.map(a => [
+      `${a.source} - ${a.percent === null ? "?" : a.percent.toFixed(0) + "%"}${a.speed === null ? "" : ", " + a.speed + "x"}, ${a.elapsed}s`,
+      "", a.percent
+    ]));
+    fill("queue", s.queue.map(q => [q]));
+    fill("recent", s.recent.map(r => r.succeeded
+      ? [`${r.source} - ${r.source_size === null ? "done" : size(r.source_size) + " -> " + size(r.output_size)}`]
+      : [`${r.source} - failed`, "failed"]));
+  } catch (e) {
+    document.getElementById("headline").textContent = "the run has ended, or can't be reached";
+  }
+}
+async function post(url, body) {
+  const reply = await (await fetch(url, { method: "POST", headers: { "X-Downscaler": "1" }, body })).json();
+  document.getElementById("message").textContent = reply.message || reply.error;
+  refresh();
+}
+function command(name) { post("/api/" + name); }
+function enqueue() {
+  post("/api/enqueue", document.getElementById("path").value);
+  document.getElementById("path").value = "";
+}
+refresh();
+setInterval(refresh, 3000);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_cut_off_at_the_limit() {
+        let mut line = String::new();
+        let short = "GET / HTTP/1.1\r\n".to_owned();
+        assert_eq!(
+            read_line(&mut short.as_bytes(), &mut line).unwrap(),
+            short.len()
+        );
+        let endless = "x".repeat(MAX_LINE * 2);
+        let mut line = String::new();
+        assert!(read_line(&mut endless.as_bytes(), &mut line).is_err());
+        assert_eq!(line.len(), MAX_LINE);
+    }
+
+    #[test]
+    fn posts_without_the_header_are_refused() {
+        let progress = Progress::new(0, None);
+        let results = Mutex::new(Vec::new());
+        let enqueue = |_: &Path| Ok("queued".to_owned());
+        let run = Run {
+            progress: &progress,
+            results: &results,
+            enqueue: &enqueue,
+        };
+        let request = |marked| Request {
+            method: "POST".to_owned(),
+            path: "/api/enqueue".to_owned(),
+            marked,
+            body: b"/videos/a.mkv".to_vec(),
+        };
+        assert_eq!(respond(&request(false), &run).status, "403 Forbidden");
+        assert_eq!(respond(&request(true), &run).status, "200 OK");
+    }
+}
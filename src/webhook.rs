@@ -0,0 +1,89 @@
+//! POSTing JSON about a run to a URL as it goes, for `--webhook`, e.g. to trigger n8n or Home
+//! Assistant automations
+//!
+//! Every message is an object whose `event` is `run_started`, `file_finished` or `run_finished`,
+//! with the `time` it happened. They are sent with `curl`, one at a time as things happen -
+//! failures are only warnings, as a notification is not worth stopping a run for. The URL goes in
+//! curl's config file rather than on its command line, as many webhook URLs are as good as a
+//! password.
+
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::curl::Curl;
+use crate::json;
+use crate::progress::Counts;
+use crate::report::FileResult;
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_owned(),
+        }
+    }
+
+    pub fn run_started(&self, files: usize, sources: &[&Path], destination: &Path) {
+        self.post(
+            "run_started",
+            json::Object::new()
+                .num("files", files)
+                .raw(
+                    "sources",
+                    json::array(
+                        sources
+                            .iter()
+                            .map(|source| json::string(&source.to_string_lossy())),
+                    ),
+                )
+                .str("destination", &destination.to_string_lossy()),
+        );
+    }
+
+    pub fn file_finished(&self, result: &FileResult) {
+        self.post(
+            "file_finished",
+            json::Object::new()
+                .raw("succeeded", result.succeeded().to_string())
+                .raw("file", result.to_json()),
+        );
+    }
+
+    /// `outcome` is how the run as a whole ended
+    pub fn run_finished(&self, counts: Counts, elapsed: Duration, outcome: &Result<()>) {
+        let error = outcome.as_ref().err().map(|e| format!("{:#}", e));
+        self.post(
+            "run_finished",
+            json::Object::new()
+                .raw("succeeded", outcome.is_ok().to_string())
+                .opt_str("error", error.as_deref())
+                .num("files", counts.total)
+                .num("done", counts.done)
+                .num("failed", counts.failed)
+                .num("saved", counts.saved)
+                .num("elapsed", elapsed.as_secs()),
+        );
+    }
+
+    fn post(&self, event: &str, fields: json::Object) {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let body = fields.str("event", event).str("time", &time).build();
+        let sent = Curl::new(10)
+            .args(["-o", "/dev/null", "-H", "Content-Type: application/json"])
+            .args(["--data-binary", "@-"])
+            .secret_url(&self.url)
+            .input(body)
+            .run();
+        if let Err(e) = sent {
+            warn!("webhook {} failed: {:#}", event, e);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Only starting files at certain times of day, for `--window 01:00-07:00`
+//!
+//! Times are local. A window can run past midnight, and files already encoding when it closes
+//! are left to finish - the rest wait for it to open again.
+
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+
+use crate::control;
+use crate::signals;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily span of time, in minutes after midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(text: &str) -> Option<u32> {
+    let (hours, minutes) = text.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    // 24:00 is allowed, for windows ending at midnight
+    match (hours, minutes) {
+        (0..=23, 0..=59) | (24, 0) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("expected a time window like `01:00-07:00`, not {:?}", text);
+        let (start, end) = text.split_once('-').ok_or_else(bad)?;
+        let (start, end) = (
+            parse_time(start).ok_or_else(bad)?,
+            parse_time(end).ok_or_else(bad)?,
+        );
+        if start % MINUTES_PER_DAY == end % MINUTES_PER_DAY {
+            return Err(format!("{:?} starts and ends at the same time", text));
+        }
+        Ok(Window {
+            start: start % MINUTES_PER_DAY,
+            end,
+        })
+    }
+}
+
+fn hh_mm(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", hh_mm(self.start), hh_mm(self.end))
+    }
+}
+
+/// Minutes since local midnight
+fn local_minutes() -> u32 {
+    if let Some((hour, minute)) = downscaler_ffi::local_time() {
+        return hour * 60 + minute;
+    }
+    // no local time to go on, so UTC
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    ((since_epoch.as_secs() / 60) % u64::from(MINUTES_PER_DAY)) as u32
+}
+
+impl Window {
+    pub fn contains(&self, minutes: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minutes)
+        } else {
+            minutes >= self.start || minutes < self.end
+        }
+    }
+
+    /// Block until the window is open, or the run is interrupted or stopped
+    pub fn wait(&self) {
+        let mut waiting = false;
+        while !signals::interrupted() && !control::stopping() && !self.contains(local_minutes()) {
+            if !waiting {
+                info!(
+                    "outside the --window {} - waiting until {}",
+                    self,
+                    hh_mm(self.start)
+                );
+                waiting = true;
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+        if waiting && !signals::interrupted() && !control::stopping() {
+            info!("the --window {} is open", self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(text: &str) -> Window {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn windows_contain_their_start_but_not_their_end() {
+        let night = window("01:00-07:00");
+        assert!(night.contains(60));
+        assert!(night.contains(6 * 60 + 59));
+        assert!(!night.contains(7 * 60));
+        assert!(!night.contains(0));
+    }
+
+    #[test]
+    fn windows_can_run_past_midnight() {
+        let late = window("22:30-06:00");
+        assert!(late.contains(23 * 60));
+        assert!(late.contains(0));
+        assert!(late.contains(5 * 60));
+        assert!(!late.contains(12 * 60));
+        assert_eq!(late.to_string(), "22:30-06:00");
+    }
+
+    #[test]
+    fn windows_can_end_at_midnight() {
+        let evening = window("18:00-24:00");
+        assert!(evening.contains(23 * 60 + 59));
+        assert!(!evening.contains(0));
+        assert_eq!(evening.to_string(), "18:00-24:00");
+    }
+
+    #[test]
+    fn bad_windows_are_refused() {
+        for text in [
+            "",
+            "01:00",
+            "1-7",
+            "25:00-07:00",
+            "01:60-07:00",
+            "24:30-07:00",
+            "a:00-07:00",
+        ] {
+            assert!(text.parse::<Window>().is_err(), "{:?}", text);
+        }
+        let error = "00:00-24:00".parse::<Window>().unwrap_err();
+        assert!(error.contains("same time"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Running jobs in parallel, optionally spread across several GPUs
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+
+use anyhow::Error;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::debug;
+
+/// Where a job is running - used to pick a GPU and to expand `{worker}` / `{gpu}` in config values
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Slot {
+    pub worker: usize,
+    pub gpu: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Balance {
+    /// Cycle through the GPUs in order
+    RoundRobin,
+    /// Use whichever GPU has the fewest jobs running
+    LeastLoaded,
+}
+
+struct GpuState {
+    active: Vec<usize>,
+    next: usize,
+}
+
+/// Hands out GPUs to jobs, never running more than `limit` jobs on one device
+pub struct GpuPool {
+    devices: Vec<u32>,
+    limit: usize,
+    balance: Balance,
+    state: Mutex<GpuState>,
+    freed: Condvar,
+}
+
+/// A claim on a GPU, released when dropped
+pub struct GpuClaim<'a> {
+    pool: &'a GpuPool,
+    index: usize,
+}
+
+impl GpuClaim<'_> {
+    pub fn device(&self) -> u32 {
+        self.pool.devices[self.index]
+    }
+}
+
+impl Drop for GpuClaim<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        state.active[self.index] -= 1;
+        self.pool.freed.notify_one();
+    }
+}
+
+impl GpuPool {
+    pub fn new(devices: Vec<u32>, limit: usize, balance: Balance) -> GpuPool {
+        let active = vec![0; devices.len()];
+        GpuPool {
+            devices,
+            limit,
+            balance,
+            state: Mutex::new(GpuState { active, next: 0 }),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn pick(&self, state: &GpuState) -> Option<usize> {
+        let count = self.devices.len();
+        match self.balance {
+            Balance::RoundRobin => (0..count)
+                .map(|offset| (state.next + offset) % count)
+                .find(|&i| state.active[i] < self.limit),
+            Balance::LeastLoaded => (0..count)
+                .filter(|&i| state.active[i] < self.limit)
+                .min_by_key(|&i| state.active[i]),
+        }
+    }
+
+    /// Wait for a GPU with spare capacity
+    pub fn claim(&self) -> GpuClaim<'_> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(index) = self.pick(&state) {
+                state.active[index] += 1;
+                state.next = (index + 1) % self.devices.len();
+                return GpuClaim { pool: self, index };
+            }
+            state = self.freed.wait(state).unwrap();
+        }
+    }
+}
+
+/// Run `work` over every job in `queue` using `count` worker threads, with `pick` choosing which
+/// of the jobs left goes next, by its index
+///
+/// Jobs can be added to the queue while it runs; each worker stops once it finds it empty. The
+/// first failure stops any new jobs starting; jobs already running are allowed to finish
+///
+/// `taking` starts at `count`, and counts down as workers stop - only ever with `queue` locked, so
+/// anything adding a job can tell whether one is left to run it
+pub fn run_all<J, P, F>(
+    queue: &Mutex<Vec<J>>,
+    taking: &AtomicUsize,
+    count: usize,
+    gpus: Option<&GpuPool>,
+    pick: P,
+    work: F,
+) -> Result<()>
+where
+    J: Send,
+    P: Fn(&[J]) -> usize + Sync,
+    F: Fn(J, Slot) -> Result<()> + Sync,
+{
+    let failure: Mutex<Option<Error>> = Mutex::new(None);
+    thread::scope(|scope| {
+        for worker in 0..count.max(1) {
+            let (failure, pick, work) = (&failure, &pick, &work);
+            scope.spawn(move || loop {
+                let job = {
+                    let mut queue = queue.lock().unwrap();
+                    if queue.is_empty() || failure.lock().unwrap().is_some() {
+                        taking.fetch_sub(1, Ordering::SeqCst);
+                        break;
+                    }
+                    let index = pick(&queue).min(queue.len() - 1);
+                    queue.remove(index)
+                };
+                let claim = gpus.map(|pool| pool.claim());
+                let slot = Slot {
+                    worker,
+                    gpu: claim.as_ref().map(|c| c.device()),
+                };
+                debug!("worker {} starting job on {:?}", worker, slot.gpu);
+                if let Err(e) = work(job, slot) {
+                    failure.lock().unwrap().get_or_insert(e);
+                }
+            });
+        }
+    });
+    match failure.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}